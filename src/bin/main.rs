@@ -1,98 +1,184 @@
 // src/main.rs
-use clap::{Arg, Command};
-use rjprof::cli::cli_tooling::{generate_flamegraph_svg, parse_config, run_profiler};
+use clap::{Arg, ArgGroup, ArgMatches, Command};
+use rjprof::cli::annotate::annotate;
+use rjprof::cli::attach::{attach_and_profile, parse_duration_secs, send_command};
+use rjprof::cli::cli_tooling::{
+    agent_options, exec_profiler, generate_flamegraph_svg, parse_config, parse_profiling_config,
+    run_profiler,
+};
+use rjprof::cli::ctl::{build_request, send_request, CONTROL_COMMANDS};
+use rjprof::profiling::options::SessionCommand;
+use std::path::Path;
+
+/// How long `attach` profiles without `--duration`
+const DEFAULT_ATTACH_SECS: u64 = 30;
+
+/// Heap reports stop the JVM for a forced GC and a walk of every live object
+const HEAP_HELP: &str = "Also write a live heap histogram, or the histogram plus retained \
+                         sizes (slower); pauses the JVM for a full GC and heap walk each time";
+
+const NATIVE_STACKS_HELP: &str =
+    "Sample CPU time with native frames (libjvm, libc, JNI libraries) \
+                                  around the Java ones, written to cpu_mixed.folded";
 
 fn main() {
     let matches = Command::new("rjprof")
         .version("1.0.0")
         .author("Your Name <your.email@example.com>")
         .about("Rust-based Java profiler with flamegraph generation")
-        .arg(
-            Arg::new("jar")
-                .short('j')
-                .long("jar")
-                .value_name("JAR_FILE")
-                .help("JAR file to profile")
-                .required(true),
-        )
-        .arg(
-            Arg::new("java-opts")
-                .short('J')
-                .long("java-opts")
-                .value_name("OPTS")
-                .help("Additional Java options (can be used multiple times)")
-                .action(clap::ArgAction::Append),
-        )
-        .arg(
-            Arg::new("stack-size")
-                .short('s')
-                .long("stack-size")
-                .value_name("SIZE")
-                .help("Stack size (default: 256k)")
-                .default_value("256k"),
-        )
-        .arg(
-            Arg::new("output")
-                .short('o')
-                .long("output")
-                .value_name("DIR")
-                .help("Output directory for profiling results")
-                .default_value("./profiler_output"),
-        )
-        .arg(
-            Arg::new("agent-path")
-                .short('a')
-                .long("agent-path")
-                .value_name("PATH")
-                .help("Path to the profiler agent library (auto-detected if not specified)"),
-        )
-        .arg(
-            Arg::new("no-flamegraph")
-                .long("no-flamegraph")
-                .help("Disable flamegraph generation")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .arg(
-            Arg::new("no-allocation")
-                .long("no-allocation")
-                .help("Disable allocation tracking")
-                .action(clap::ArgAction::SetTrue),
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("annotate")
+                .about("Show a method's source (or bytecode) with per-line sample percentages")
+                .arg(
+                    Arg::new("method")
+                        .short('m')
+                        .long("method")
+                        .value_name("CLASS.METHOD")
+                        .help("Fully qualified method, e.g. com.acme.Foo.bar")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("src")
+                        .long("src")
+                        .value_name("DIR")
+                        .help("Source root, e.g. src/main/java (bytecode is shown without it)"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("DIR")
+                        .help("Directory of a profile recorded with --lines")
+                        .default_value("./profiler_output"),
+                ),
         )
-        .arg(
-            Arg::new("no-call-graph")
-                .long("no-call-graph")
-                .help("Disable call graph analysis")
-                .action(clap::ArgAction::SetTrue),
+        .subcommand(
+            Command::new("attach")
+                .about("Attach to a running JVM, profile it for a while and write the reports")
+                .arg(
+                    Arg::new("pid")
+                        .value_name("PID")
+                        .help("Process id of the target JVM")
+                        .value_parser(clap::value_parser!(u32))
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("command")
+                        .value_name("COMMAND")
+                        .help(
+                            "Control the agent instead of a timed session: start, stop \
+                             (write reports and reset), dump (write reports) or detach",
+                        )
+                        .value_parser(["start", "stop", "dump", "detach"]),
+                )
+                .arg(
+                    Arg::new("duration")
+                        .short('d')
+                        .long("duration")
+                        .value_name("DURATION")
+                        .help("How long to profile, e.g. 30s, 5m (default: 30s; unlimited with start)"),
+                )
+                .args(agent_args()),
         )
-        .arg(
-            Arg::new("sampling-interval")
-                .long("sampling-interval")
-                .value_name("MS")
-                .help("Sampling interval in milliseconds (for future sampling support)"),
+        .subcommand(
+            Command::new("ctl")
+                .about("Send a command to the control socket of a JVM profiled with --control")
+                .arg(
+                    Arg::new("socket")
+                        .value_name("SOCKET")
+                        .help("Path of the agent's control socket")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("command")
+                        .value_name("COMMAND")
+                        .help(
+                            "status, start [duration], stop, dump [reports|snapshot|folded|json|heap], \
+                             reset, set-filter [prefix...] or threads",
+                        )
+                        .value_parser(CONTROL_COMMANDS)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("args")
+                        .value_name("ARGS")
+                        .num_args(0..)
+                        .trailing_var_arg(true),
+                ),
         )
-        .arg(
-            Arg::new("java-executable")
-                .long("java")
-                .value_name("PATH")
-                .help("Path to Java executable")
-                .default_value("java"),
+        .subcommand(
+            Command::new("run")
+                .about("Profile a Java program: a jar, a main class on a classpath or a module")
+                .args(launch_args())
+                .group(launch_group())
+                .args(profiling_args()),
         )
-        .arg(
-            Arg::new("generate-flamegraph")
-                .long("generate-flamegraph")
-                .help("Generate SVG flamegraph after profiling (requires flamegraph.pl or inferno)")
-                .action(clap::ArgAction::SetTrue),
+        .subcommand(
+            Command::new("exec")
+                .about(
+                    "Run a command (Gradle, Maven, a wrapper script) and profile every JVM it \
+                     starts, through JAVA_TOOL_OPTIONS",
+                )
+                .args(profiling_args())
+                .arg(
+                    Arg::new("command")
+                        .value_name("COMMAND")
+                        .help("Command and its arguments, after --, e.g. -- ./gradlew test --no-daemon")
+                        .num_args(1..)
+                        .last(true)
+                        .required(true),
+                ),
         )
-        .arg(
-            Arg::new("verbose")
-                .short('v')
-                .long("verbose")
-                .help("Verbose output")
-                .action(clap::ArgAction::SetTrue),
-        )
-        .get_matches();
+        .args(launch_args())
+        .group(launch_group())
+        .args(profiling_args())
+        .get_matches_from(command_line());
 
-    let config = match parse_config(&matches) {
+    if let Some(("annotate", sub)) = matches.subcommand() {
+        let method = sub.get_one::<String>("method").unwrap();
+        let output = sub.get_one::<String>("output").unwrap();
+        let src = sub.get_one::<String>("src").map(String::as_str);
+        if let Err(e) = annotate(output, method, src) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("ctl", sub)) = matches.subcommand() {
+        if let Err(e) = ctl(sub) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("exec", sub)) = matches.subcommand() {
+        if let Err(e) = exec(sub) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(("attach", sub)) = matches.subcommand() {
+        if let Err(e) = attach(sub) {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    match matches.subcommand() {
+        Some(("run", sub)) => run(sub),
+        _ => run(&matches),
+    }
+}
+
+/// `rjprof run ...` (or plain `rjprof ...`): launch the program with the agent and report.
+fn run(matches: &ArgMatches) {
+    let config = match parse_config(matches) {
         Ok(config) => config,
         Err(e) => {
             eprintln!("Error: {}", e);
@@ -102,7 +188,9 @@ fn main() {
 
     if matches.get_flag("verbose") {
         println!("🔧 Configuration:");
-        println!("  JAR file: {}", config.jar_file);
+        if let Some(launch) = &config.launch {
+            println!("  Launch: {}", launch);
+        }
         println!("  Agent path: {}", config.agent_path);
         println!("  Output directory: {}", config.output_dir);
         println!("  Stack size: {}", config.stack_size);
@@ -129,3 +217,326 @@ fn main() {
         config.output_dir
     );
 }
+
+/// `java` spells the classpath `-cp` or `-classpath`, which clap can't take as short flags.
+fn command_line() -> Vec<String> {
+    let mut program_args = false;
+    std::env::args()
+        .map(|arg| {
+            program_args |= arg == "--";
+            match arg.as_str() {
+                "-cp" | "-classpath" if !program_args => "--class-path".to_string(),
+                _ => arg,
+            }
+        })
+        .collect()
+}
+
+/// What to run and how to start the JVM.
+fn launch_args() -> Vec<Arg> {
+    vec![
+        Arg::new("jar")
+            .short('j')
+            .long("jar")
+            .value_name("JAR_FILE")
+            .help("JAR file to profile"),
+        Arg::new("classpath")
+            .long("class-path")
+            .visible_alias("classpath")
+            .value_name("PATH")
+            .help("Classpath of MAIN_CLASS (also -cp), e.g. a.jar:b.jar")
+            .requires("main-class"),
+        Arg::new("main-class")
+            .value_name("MAIN_CLASS")
+            .help("Main class to run from the classpath, e.g. com.acme.Main")
+            .requires("classpath"),
+        Arg::new("module")
+            .short('m')
+            .long("module")
+            .value_name("MODULE/CLASS")
+            .help("Main module and class to run, e.g. app/com.acme.Main"),
+        Arg::new("module-path")
+            .short('p')
+            .long("module-path")
+            .value_name("PATH")
+            .help("Module path of --module")
+            .requires("module"),
+        Arg::new("args")
+            .value_name("ARGS")
+            .help("Arguments for the Java program, after --")
+            .num_args(1..)
+            .last(true),
+        Arg::new("java-opts")
+            .short('J')
+            .long("java-opts")
+            .value_name("OPTS")
+            .help("Additional Java options (can be used multiple times)")
+            .action(clap::ArgAction::Append),
+        Arg::new("stack-size")
+            .short('s')
+            .long("stack-size")
+            .value_name("SIZE")
+            .help("Stack size (default: 256k)")
+            .default_value("256k"),
+        Arg::new("java-executable")
+            .long("java")
+            .value_name("PATH")
+            .help("Path to Java executable")
+            .default_value("java"),
+    ]
+}
+
+/// Exactly one of `--jar`, a main class or `--module`.
+fn launch_group() -> ArgGroup {
+    ArgGroup::new("launch")
+        .args(["jar", "main-class", "module"])
+        .required(true)
+}
+
+/// Output, agent and profiling flags, shared by every way of launching the program.
+fn profiling_args() -> Vec<Arg> {
+    vec![
+        Arg::new("output")
+            .short('o')
+            .long("output")
+            .value_name("DIR")
+            .help("Output directory for profiling results")
+            .default_value("./profiler_output"),
+        Arg::new("agent-path")
+            .short('a')
+            .long("agent-path")
+            .value_name("PATH")
+            .help("Path to the profiler agent library (auto-detected if not specified)"),
+        Arg::new("no-flamegraph")
+            .long("no-flamegraph")
+            .help("Disable flamegraph generation")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("no-allocation")
+            .long("no-allocation")
+            .help("Disable allocation tracking")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("no-call-graph")
+            .long("no-call-graph")
+            .help("Disable call graph analysis")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("sampling-interval")
+            .long("sampling-interval")
+            .value_name("MS")
+            .help("Thread state sampling interval in milliseconds (default: 10)"),
+        Arg::new("thread-dump-interval")
+            .long("thread-dump-interval")
+            .value_name("SECS")
+            .help("Write a thread dump (with deadlock detection) every SECS seconds"),
+        Arg::new("generate-flamegraph")
+            .long("generate-flamegraph")
+            .help("Generate SVG flamegraph after profiling (requires flamegraph.pl or inferno)")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("perf-map")
+            .long("perf-map")
+            .help("Write /tmp/perf-<pid>.map so Linux perf can name JIT-compiled frames")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("jitdump")
+            .long("jitdump")
+            .help("Write jit-<pid>.dump into the output directory for `perf inject --jit`")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("lines")
+            .long("lines")
+            .help("Add source line numbers to flamegraph frames (slower)")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("off-cpu")
+            .long("off-cpu")
+            .help("Record blocked time (monitors, wait, sleep, park) as an off-CPU flamegraph")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("native-stacks")
+            .long("native-stacks")
+            .help(NATIVE_STACKS_HELP)
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("heap")
+            .long("heap")
+            .value_name("REPORT")
+            .help(HEAP_HELP)
+            .value_parser(["histogram", "retained"]),
+        Arg::new("snapshot")
+            .long("snapshot")
+            .help("Write a snapshot of the current stats on `kill -USR2 <pid>` or SIGQUIT/jcmd")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("snapshot-reset")
+            .long("snapshot-reset")
+            .help("Reset the stats after each snapshot (implies --snapshot)")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("control")
+            .long("control")
+            .value_name("SOCKET")
+            .help("Serve control commands (see `rjprof ctl`) on a Unix socket at SOCKET"),
+        Arg::new("http")
+            .long("http")
+            .value_name("PORT")
+            .help("Serve /profile, /heap, /threads and /metrics on 127.0.0.1:PORT")
+            .value_parser(clap::value_parser!(u16).range(1..)),
+        Arg::new("metrics-file")
+            .long("metrics-file")
+            .value_name("PATH")
+            .help("Keep Prometheus metrics in PATH for node_exporter's textfile collector"),
+        Arg::new("metrics-interval")
+            .long("metrics-interval")
+            .value_name("SECS")
+            .help("Seconds between --metrics-file updates (default: 15)")
+            .value_parser(clap::value_parser!(u64).range(1..)),
+        Arg::new("continuous")
+            .long("continuous")
+            .help("Write a profile to rotated, timestamped files every --interval and reset the stats")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("interval")
+            .long("interval")
+            .value_name("DURATION")
+            .help("Length of a --continuous interval (e.g. 60s, 5m)")
+            .default_value("60s")
+            .requires("continuous"),
+        Arg::new("retain")
+            .long("retain")
+            .value_name("DURATION")
+            .help("Delete --continuous profiles older than DURATION (e.g. 24h)")
+            .default_value("24h")
+            .requires("continuous"),
+        Arg::new("pyroscope")
+            .long("pyroscope")
+            .value_name("URL")
+            .help("Send each --continuous profile to a Pyroscope server's /ingest API (http://host:port)")
+            .requires("continuous"),
+        Arg::new("upload-format")
+            .long("upload-format")
+            .value_name("FORMAT")
+            .help("Encoding of uploaded profiles (default: folded)")
+            .value_parser(["folded", "pprof"])
+            .requires("pyroscope"),
+        Arg::new("app-name")
+            .long("app-name")
+            .value_name("NAME")
+            .help("Application name of uploaded profiles")
+            .requires("pyroscope"),
+        Arg::new("app-version")
+            .long("app-version")
+            .value_name("VERSION")
+            .help("Add a version label to uploaded profiles")
+            .requires("pyroscope"),
+        Arg::new("spool")
+            .long("spool")
+            .value_name("DIR")
+            .help("Keep profiles in DIR while the Pyroscope server is down (default: <output>/spool)")
+            .requires("pyroscope"),
+        Arg::new("verbose")
+            .short('v')
+            .long("verbose")
+            .help("Verbose output")
+            .action(clap::ArgAction::SetTrue),
+    ]
+}
+
+/// Flags of `profiling_args` that only mean something when rjprof starts the JVM itself
+const LAUNCHER_ONLY_ARGS: [&str; 5] = [
+    "no-flamegraph",
+    "no-allocation",
+    "no-call-graph",
+    "generate-flamegraph",
+    "verbose",
+];
+
+/// The `profiling_args` that configure the agent, for `attach`.
+fn agent_args() -> Vec<Arg> {
+    profiling_args()
+        .into_iter()
+        .filter(|arg| !LAUNCHER_ONLY_ARGS.contains(&arg.get_id().as_str()))
+        .collect()
+}
+
+/// `rjprof exec -- <command>`: profile each JVM the command starts, then list and merge them.
+fn exec(sub: &ArgMatches) -> Result<(), String> {
+    let mut config = parse_profiling_config(sub)?;
+    let command: Vec<String> = sub
+        .get_many::<String>("command")
+        .unwrap()
+        .cloned()
+        .collect();
+    if config.app_name.is_none() {
+        config.app_name = Path::new(&command[0])
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+    }
+
+    exec_profiler(&config, &command, sub.get_flag("verbose"))?;
+
+    if sub.get_flag("generate-flamegraph") {
+        if let Err(e) = generate_flamegraph_svg(&config) {
+            eprintln!("Warning: Failed to generate flamegraph SVG: {}", e);
+        }
+    }
+    println!(
+        "✅ Profiling complete! Results saved to: {}",
+        config.output_dir
+    );
+    Ok(())
+}
+
+/// `rjprof attach <pid> [command]`: a timed session, or one session command.
+fn attach(sub: &ArgMatches) -> Result<(), String> {
+    let pid = *sub.get_one::<u32>("pid").unwrap();
+    let command = sub
+        .get_one::<String>("command")
+        .map(|name| SessionCommand::parse(name))
+        .transpose()?;
+    let duration_secs = sub
+        .get_one::<String>("duration")
+        .map(|d| parse_duration_secs(d))
+        .transpose()?;
+    let config = parse_profiling_config(sub)?;
+    let options = agent_options(&config);
+    let (output, agent_path) = (&config.output_dir, &config.agent_path);
+
+    let Some(command) = command else {
+        let duration_secs = duration_secs.unwrap_or(DEFAULT_ATTACH_SECS);
+        attach_and_profile(pid, agent_path, output, duration_secs, options)?;
+        println!("✅ Profiling complete! Results saved to: {}", output);
+        return Ok(());
+    };
+    send_command(pid, agent_path, command, output, duration_secs, options)?;
+    match command {
+        SessionCommand::Start => println!(
+            "▶️  Profiling JVM {}; run `rjprof attach {} stop` to write the results",
+            pid, pid
+        ),
+        SessionCommand::Stop => println!("✅ Profiling complete! Results saved to: {}", output),
+        SessionCommand::Dump => println!("📝 Snapshot saved to: {}", output),
+        SessionCommand::Detach => println!("🔌 Detached from JVM {}", pid),
+    }
+    Ok(())
+}
+
+/// `rjprof ctl <socket> <command> [args...]`: one request to the agent's control socket.
+fn ctl(sub: &ArgMatches) -> Result<(), String> {
+    let socket = sub.get_one::<String>("socket").unwrap();
+    let command = sub.get_one::<String>("command").unwrap();
+    let args: Vec<String> = sub
+        .get_many::<String>("args")
+        .map(|args| args.cloned().collect())
+        .unwrap_or_default();
+
+    let response = send_request(socket, &build_request(command, &args)?)?;
+    if response["ok"] != true {
+        return Err(response["error"]
+            .as_str()
+            .unwrap_or("the agent rejected the command")
+            .to_string());
+    }
+    // Folded stacks go out as-is, so they can be piped into flamegraph.pl or inferno
+    match response["folded"].as_str() {
+        Some(folded) => {
+            // A closed pipe (e.g. `| head`) is not an error
+            let _ = std::io::Write::write_all(&mut std::io::stdout(), folded.as_bytes());
+        }
+        None => println!(
+            "{}",
+            serde_json::to_string_pretty(&response).map_err(|e| e.to_string())?
+        ),
+    }
+    Ok(())
+}
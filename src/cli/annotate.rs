@@ -0,0 +1,233 @@
+// src/cli/annotate.rs
+use crate::cli::bytecode::{disassemble, parse_hex};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Per-line and per-bci sample counts for one method, read from `line_samples.json`
+#[derive(Debug, Default)]
+pub struct MethodSamples {
+    pub file: Option<String>,
+    pub by_line: BTreeMap<u64, u64>,
+    pub by_bci: BTreeMap<i64, u64>,
+    pub total: u64,
+}
+
+/// Code recorded by the agent for one method, read from `method_code.json`
+#[derive(Debug)]
+pub struct MethodCode {
+    pub file: Option<String>,
+    pub lines: Vec<(i64, u64)>,
+    pub bytecodes: Vec<u8>,
+}
+
+fn read_json(path: &Path) -> Result<Value, String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    serde_json::from_str(&text).map_err(|e| format!("Cannot parse {}: {}", path.display(), e))
+}
+
+/// Collect the samples of `method` (overloads share a name and are merged).
+pub fn method_samples(line_samples: &Value, method: &str) -> MethodSamples {
+    let mut samples = MethodSamples::default();
+    let locations = line_samples["locations"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for location in locations.iter().filter(|l| l["method"] == method) {
+        let n = location["samples"].as_u64().unwrap_or(0);
+        if let Some(line) = location["line"].as_u64() {
+            *samples.by_line.entry(line).or_insert(0) += n;
+        }
+        if let Some(bci) = location["bci"].as_i64() {
+            *samples.by_bci.entry(bci).or_insert(0) += n;
+        }
+        if samples.file.is_none() {
+            samples.file = location["file"].as_str().map(str::to_string);
+        }
+        samples.total += n;
+    }
+    samples
+}
+
+/// Collect the recorded code of every overload of `method`.
+pub fn method_code(code: &Value, method: &str) -> Vec<MethodCode> {
+    code["methods"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .iter()
+        .filter(|m| m["method"] == method)
+        .map(|m| MethodCode {
+            file: m["file"].as_str().map(str::to_string),
+            lines: m["lines"]
+                .as_array()
+                .map(|entries| {
+                    entries
+                        .iter()
+                        .filter_map(|e| Some((e[0].as_i64()?, e[1].as_u64()?)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            bytecodes: m["bytecodes"]
+                .as_str()
+                .and_then(parse_hex)
+                .unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Where `com.acme.Foo.bar` defined in `Foo.java` lives under a source root:
+/// `<root>/com/acme/Foo.java`, falling back to `<root>/Foo.java`.
+pub fn source_path(src_root: &Path, method: &str, file: &str) -> Option<PathBuf> {
+    let class = method.rsplit_once('.').map(|(class, _)| class)?;
+    let package = class.rsplit_once('.').map(|(package, _)| package);
+    let candidates = [
+        package.map(|p| src_root.join(p.replace('.', "/")).join(file)),
+        Some(src_root.join(file)),
+    ];
+    candidates.into_iter().flatten().find(|p| p.is_file())
+}
+
+fn percent(n: u64, total: u64) -> f64 {
+    n as f64 * 100.0 / total.max(1) as f64
+}
+
+fn gutter(n: u64, total: u64) -> String {
+    if n == 0 {
+        format!("{:>7} {:>6}", "", "")
+    } else {
+        format!("{:>6.1}% {:>6}", percent(n, total), n)
+    }
+}
+
+fn print_source(path: &Path, samples: &MethodSamples, code: &[MethodCode]) -> Result<(), String> {
+    let text =
+        fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let source: Vec<&str> = text.lines().collect();
+
+    // The line table bounds the method; widen by one line for the signature and closing brace
+    let table_lines = code
+        .iter()
+        .flat_map(|c| c.lines.iter().map(|&(_, line)| line));
+    let sampled_lines = samples.by_line.keys().copied();
+    let lines: Vec<u64> = table_lines.chain(sampled_lines).collect();
+    let (Some(&min), Some(&max)) = (lines.iter().min(), lines.iter().max()) else {
+        return Err("No line information recorded for this method".to_string());
+    };
+    let first = min.saturating_sub(1).max(1);
+    let last = (max + 1).min(source.len() as u64);
+
+    println!("{}", path.display());
+    for line in first..=last {
+        let n = samples.by_line.get(&line).copied().unwrap_or(0);
+        let text = source.get(line as usize - 1).copied().unwrap_or("");
+        println!("{} {:>5} | {}", gutter(n, samples.total), line, text);
+    }
+    Ok(())
+}
+
+fn print_bytecode(samples: &MethodSamples, code: &[MethodCode]) {
+    for method in code {
+        let line_at = |bci: i64| {
+            method
+                .lines
+                .iter()
+                .filter(|&&(start, _)| start <= bci)
+                .max_by_key(|&&(start, _)| start)
+                .map(|&(_, line)| line)
+        };
+        for instruction in disassemble(&method.bytecodes) {
+            let n = samples
+                .by_bci
+                .get(&(instruction.bci as i64))
+                .copied()
+                .unwrap_or(0);
+            let line = line_at(instruction.bci as i64)
+                .map(|l| format!("L{}", l))
+                .unwrap_or_default();
+            println!(
+                "{} {:>6} {:>5}: {}",
+                gutter(n, samples.total),
+                line,
+                instruction.bci,
+                instruction.text
+            );
+        }
+        println!();
+    }
+}
+
+/// Print `method` with per-line sample percentages from a profile in `profile_dir`.
+/// Uses the source under `src_root` when it can be found, otherwise a bytecode listing.
+pub fn annotate(profile_dir: &str, method: &str, src_root: Option<&str>) -> Result<(), String> {
+    let dir = Path::new(profile_dir);
+    let line_samples = read_json(&dir.join("line_samples.json"))
+        .map_err(|e| format!("{} (was the profile run with --lines?)", e))?;
+    let samples = method_samples(&line_samples, method);
+    // The source listing only uses it for line tables; the bytecode listing needs it
+    let recorded =
+        read_json(&dir.join("method_code.json")).map(|value| method_code(&value, method));
+    let code = recorded.as_deref().unwrap_or_default();
+
+    if samples.total == 0 && code.is_empty() {
+        return Err(format!("No samples recorded for {}", method));
+    }
+
+    println!("🎯 === {} ({} samples) ===", method, samples.total);
+
+    let file = samples
+        .file
+        .clone()
+        .or_else(|| code.iter().find_map(|c| c.file.clone()));
+    let path = src_root
+        .zip(file.as_deref())
+        .and_then(|(root, file)| source_path(Path::new(root), method, file));
+
+    match path {
+        Some(path) => print_source(&path, &samples, code),
+        None => {
+            if src_root.is_some() {
+                println!(
+                    "Source {} not found, showing bytecode",
+                    file.as_deref().unwrap_or("file")
+                );
+            }
+            if let Err(e) = recorded {
+                return Err(match src_root {
+                    Some(_) => e,
+                    None => format!("{} (pass --src to annotate the source instead)", e),
+                });
+            }
+            if code.iter().all(|c| c.bytecodes.is_empty()) {
+                return Err(format!("No bytecode recorded for {}", method));
+            }
+            print_bytecode(&samples, code);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_method_samples_merges_locations() {
+        let profile = json!({
+            "total_samples": 10,
+            "locations": [
+                {"method": "com.acme.Foo.bar", "file": "Foo.java", "line": 12, "bci": 3, "samples": 4},
+                {"method": "com.acme.Foo.bar", "file": "Foo.java", "line": 12, "bci": 5, "samples": 2},
+                {"method": "com.acme.Foo.baz", "file": "Foo.java", "line": 20, "bci": 0, "samples": 4},
+            ]
+        });
+        let samples = method_samples(&profile, "com.acme.Foo.bar");
+        assert_eq!(samples.total, 6);
+        assert_eq!(samples.by_line.get(&12), Some(&6));
+        assert_eq!(samples.by_bci.len(), 2);
+        assert_eq!(samples.file.as_deref(), Some("Foo.java"));
+    }
+}
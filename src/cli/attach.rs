@@ -0,0 +1,289 @@
+// src/cli/attach.rs
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::profiling::options::{AgentOptions, SessionCommand};
+use crate::profiling::session::SESSION_MARKER;
+
+/// Attach protocol version understood by HotSpot's attach listener
+const PROTOCOL_VERSION: &str = "1";
+
+/// How long to wait for the attach listener to come up after SIGQUIT
+const LISTENER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the agent may take to write its reports once the session ends
+const REPORT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Parse a duration such as `30s`, `2m`, `1h`, `500ms` or plain seconds (`30`).
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let value: u64 = number
+        .parse()
+        .map_err(|_| format!("Invalid duration: {}", text))?;
+    let secs = |per_unit: u64| {
+        value
+            .checked_mul(per_unit)
+            .map(Duration::from_secs)
+            .ok_or_else(|| format!("Duration too long: {}", text))
+    };
+    let duration = match unit {
+        "ms" => Duration::from_millis(value),
+        "" | "s" => secs(1)?,
+        "m" => secs(60)?,
+        "h" => secs(3600)?,
+        _ => return Err(format!("Invalid duration unit in {}", text)),
+    };
+    if duration.is_zero() {
+        return Err(format!("Duration must be positive: {}", text));
+    }
+    Ok(duration)
+}
+
+/// Parse a duration the agent takes in seconds; `1500ms` is refused rather than cut short.
+pub fn parse_duration_secs(text: &str) -> Result<u64, String> {
+    let duration = parse_duration(text)?;
+    if duration.subsec_nanos() != 0 {
+        return Err(format!("Duration must be whole seconds: {}", text.trim()));
+    }
+    Ok(duration.as_secs())
+}
+
+/// The pid of `pid` inside its own pid namespace (last NSpid entry), for containers.
+fn namespace_pid(pid: u32) -> u32 {
+    fs::read_to_string(format!("/proc/{}/status", pid))
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("NSpid:"))
+                .and_then(|ids| ids.split_whitespace().last()?.parse().ok())
+        })
+        .unwrap_or(pid)
+}
+
+/// The target's /tmp, seen through its mount namespace when we can.
+fn target_tmp_dir(pid: u32) -> PathBuf {
+    let root_tmp = PathBuf::from(format!("/proc/{}/root/tmp", pid));
+    if root_tmp.is_dir() {
+        root_tmp
+    } else {
+        PathBuf::from("/tmp")
+    }
+}
+
+/// Start the attach listener if it isn't running: create `.attach_pid<pid>` in the
+/// target's working directory (or /tmp) and send SIGQUIT, then wait for the socket.
+fn ensure_attach_listener(pid: u32, socket: &Path) -> Result<(), String> {
+    if socket.exists() {
+        return Ok(());
+    }
+
+    let ns_pid = namespace_pid(pid);
+    let trigger_name = format!(".attach_pid{}", ns_pid);
+    let cwd_trigger = PathBuf::from(format!("/proc/{}/cwd", pid)).join(&trigger_name);
+    let trigger = if fs::write(&cwd_trigger, b"").is_ok() {
+        cwd_trigger
+    } else {
+        let tmp_trigger = target_tmp_dir(pid).join(&trigger_name);
+        fs::write(&tmp_trigger, b"")
+            .map_err(|e| format!("Cannot create {}: {}", tmp_trigger.display(), e))?;
+        tmp_trigger
+    };
+
+    let result = (|| {
+        if unsafe { libc::kill(pid as libc::pid_t, libc::SIGQUIT) } != 0 {
+            return Err(format!(
+                "Cannot signal process {}: {}",
+                pid,
+                std::io::Error::last_os_error()
+            ));
+        }
+        let deadline = Instant::now() + LISTENER_TIMEOUT;
+        while !socket.exists() {
+            if Instant::now() > deadline {
+                return Err(format!(
+                    "Timed out waiting for the attach listener of {} (is it a HotSpot JVM \
+                     started without -XX:+DisableAttachMechanism?)",
+                    pid
+                ));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    })();
+
+    let _ = fs::remove_file(&trigger);
+    result
+}
+
+/// Send one attach command with up to three arguments and return the listener's reply.
+fn execute(pid: u32, command: &str, args: &[&str]) -> Result<String, String> {
+    let socket = target_tmp_dir(pid).join(format!(".java_pid{}", namespace_pid(pid)));
+    ensure_attach_listener(pid, &socket)?;
+
+    let mut stream = UnixStream::connect(&socket)
+        .map_err(|e| format!("Cannot connect to {}: {}", socket.display(), e))?;
+
+    // Each field is NUL-terminated; the listener always expects exactly three arguments
+    let mut request = Vec::new();
+    for field in [PROTOCOL_VERSION, command]
+        .into_iter()
+        .chain((0..3).map(|i| args.get(i).copied().unwrap_or("")))
+    {
+        request.extend_from_slice(field.as_bytes());
+        request.push(0);
+    }
+    stream
+        .write_all(&request)
+        .map_err(|e| format!("Cannot send attach request: {}", e))?;
+
+    let mut reply = String::new();
+    stream
+        .read_to_string(&mut reply)
+        .map_err(|e| format!("Cannot read attach reply: {}", e))?;
+    Ok(reply)
+}
+
+/// Check a `load` reply: a `0` status line, then (JDK 9+) the agent's `return code`.
+pub fn check_load_reply(reply: &str) -> Result<(), String> {
+    let mut lines = reply.lines();
+    match lines.next().map(str::trim) {
+        Some("0") => {}
+        Some(code) => {
+            let detail: Vec<&str> = lines.collect();
+            return Err(format!("attach failed ({}): {}", code, detail.join(" ")));
+        }
+        None => return Err("empty reply from attach listener".to_string()),
+    }
+    for line in lines {
+        if let Some(code) = line.trim().strip_prefix("return code:") {
+            if code.trim() != "0" {
+                return Err(format!(
+                    "agent returned {} (see the JVM's output for details)",
+                    code.trim()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Load the agent library into a running JVM with the given `-agentpath` style options.
+pub fn load_agent(pid: u32, agent_path: &str, options: &str) -> Result<(), String> {
+    let agent_path =
+        fs::canonicalize(agent_path).map_err(|e| format!("Agent library {}: {}", agent_path, e))?;
+    let reply = execute(
+        pid,
+        "load",
+        &[&agent_path.to_string_lossy(), "true", options],
+    )?;
+    check_load_reply(&reply)
+}
+
+/// Create `output_dir` and return its absolute path; the agent resolves relative paths
+/// against the target's working directory.
+fn absolute_output_dir(output_dir: &str) -> Result<PathBuf, String> {
+    fs::create_dir_all(output_dir)
+        .map_err(|e| format!("Failed to create output directory: {}", e))?;
+    fs::canonicalize(output_dir).map_err(|e| format!("Output directory {}: {}", output_dir, e))
+}
+
+/// Send a session command to the agent of a running JVM by loading it again. `start`
+/// also loads the agent the first time; the others need it running already.
+pub fn send_command(
+    pid: u32,
+    agent_path: &str,
+    command: SessionCommand,
+    output_dir: &str,
+    duration_secs: Option<u64>,
+    mut options: AgentOptions,
+) -> Result<(), String> {
+    options.command = Some(command);
+    if command != SessionCommand::Detach {
+        let output_dir = absolute_output_dir(output_dir)?;
+        options.output_dir = Some(output_dir.to_string_lossy().into_owned());
+    }
+    options.duration_secs = duration_secs;
+    load_agent(pid, agent_path, &options.to_option_string())
+}
+
+/// Profile a running JVM for `duration_secs`: load the agent with a timed session writing
+/// into `output_dir`, then wait until its reports are complete.
+pub fn attach_and_profile(
+    pid: u32,
+    agent_path: &str,
+    output_dir: &str,
+    duration_secs: u64,
+    mut options: AgentOptions,
+) -> Result<(), String> {
+    let output_dir = absolute_output_dir(output_dir)?;
+    let marker = output_dir.join(SESSION_MARKER);
+    let _ = fs::remove_file(&marker);
+
+    options.output_dir = Some(output_dir.to_string_lossy().into_owned());
+    options.duration_secs = Some(duration_secs);
+
+    println!("🔗 Attaching to JVM {}...", pid);
+    load_agent(pid, agent_path, &options.to_option_string())?;
+    println!(
+        "📊 Profiling for {}s (results in {})",
+        duration_secs,
+        output_dir.display()
+    );
+
+    thread::sleep(Duration::from_secs(duration_secs));
+    let deadline = Instant::now() + REPORT_TIMEOUT;
+    while !marker.exists() {
+        if !Path::new(&format!("/proc/{}", pid)).exists() {
+            return Err(format!("JVM {} exited before the session finished", pid));
+        }
+        if Instant::now() > deadline {
+            return Err(format!(
+                "Timed out waiting for the agent to write {}",
+                marker.display()
+            ));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("45").unwrap(), Duration::from_secs(45));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+        assert!(parse_duration(&format!("{}m", u64::MAX / 60 + 1)).is_err());
+
+        assert_eq!(parse_duration_secs("2m"), Ok(120));
+        assert_eq!(parse_duration_secs("3000ms"), Ok(3));
+        assert!(parse_duration_secs("1500ms").is_err());
+        assert!(parse_duration_secs("250ms").is_err());
+    }
+
+    #[test]
+    fn test_check_load_reply() {
+        assert!(check_load_reply("0\nreturn code: 0\n").is_ok());
+        assert!(check_load_reply("0\n").is_ok());
+        assert!(check_load_reply("0\nreturn code: -1\n").is_err());
+        assert!(check_load_reply("101\nAgent JAR not found\n").is_err());
+        assert!(check_load_reply("").is_err());
+    }
+}
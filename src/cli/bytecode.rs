@@ -0,0 +1,435 @@
+// JVM bytecode disassembly for `rjprof annotate`, per JVMS chapter 6
+
+/// Mnemonics for opcodes 0 (`nop`) through 202 (`breakpoint`)
+const MNEMONICS: [&str; 203] = [
+    "nop",
+    "aconst_null",
+    "iconst_m1",
+    "iconst_0",
+    "iconst_1",
+    "iconst_2",
+    "iconst_3",
+    "iconst_4",
+    "iconst_5",
+    "lconst_0",
+    "lconst_1",
+    "fconst_0",
+    "fconst_1",
+    "fconst_2",
+    "dconst_0",
+    "dconst_1",
+    "bipush",
+    "sipush",
+    "ldc",
+    "ldc_w",
+    "ldc2_w",
+    "iload",
+    "lload",
+    "fload",
+    "dload",
+    "aload",
+    "iload_0",
+    "iload_1",
+    "iload_2",
+    "iload_3",
+    "lload_0",
+    "lload_1",
+    "lload_2",
+    "lload_3",
+    "fload_0",
+    "fload_1",
+    "fload_2",
+    "fload_3",
+    "dload_0",
+    "dload_1",
+    "dload_2",
+    "dload_3",
+    "aload_0",
+    "aload_1",
+    "aload_2",
+    "aload_3",
+    "iaload",
+    "laload",
+    "faload",
+    "daload",
+    "aaload",
+    "baload",
+    "caload",
+    "saload",
+    "istore",
+    "lstore",
+    "fstore",
+    "dstore",
+    "astore",
+    "istore_0",
+    "istore_1",
+    "istore_2",
+    "istore_3",
+    "lstore_0",
+    "lstore_1",
+    "lstore_2",
+    "lstore_3",
+    "fstore_0",
+    "fstore_1",
+    "fstore_2",
+    "fstore_3",
+    "dstore_0",
+    "dstore_1",
+    "dstore_2",
+    "dstore_3",
+    "astore_0",
+    "astore_1",
+    "astore_2",
+    "astore_3",
+    "iastore",
+    "lastore",
+    "fastore",
+    "dastore",
+    "aastore",
+    "bastore",
+    "castore",
+    "sastore",
+    "pop",
+    "pop2",
+    "dup",
+    "dup_x1",
+    "dup_x2",
+    "dup2",
+    "dup2_x1",
+    "dup2_x2",
+    "swap",
+    "iadd",
+    "ladd",
+    "fadd",
+    "dadd",
+    "isub",
+    "lsub",
+    "fsub",
+    "dsub",
+    "imul",
+    "lmul",
+    "fmul",
+    "dmul",
+    "idiv",
+    "ldiv",
+    "fdiv",
+    "ddiv",
+    "irem",
+    "lrem",
+    "frem",
+    "drem",
+    "ineg",
+    "lneg",
+    "fneg",
+    "dneg",
+    "ishl",
+    "lshl",
+    "ishr",
+    "lshr",
+    "iushr",
+    "lushr",
+    "iand",
+    "land",
+    "ior",
+    "lor",
+    "ixor",
+    "lxor",
+    "iinc",
+    "i2l",
+    "i2f",
+    "i2d",
+    "l2i",
+    "l2f",
+    "l2d",
+    "f2i",
+    "f2l",
+    "f2d",
+    "d2i",
+    "d2l",
+    "d2f",
+    "i2b",
+    "i2c",
+    "i2s",
+    "lcmp",
+    "fcmpl",
+    "fcmpg",
+    "dcmpl",
+    "dcmpg",
+    "ifeq",
+    "ifne",
+    "iflt",
+    "ifge",
+    "ifgt",
+    "ifle",
+    "if_icmpeq",
+    "if_icmpne",
+    "if_icmplt",
+    "if_icmpge",
+    "if_icmpgt",
+    "if_icmple",
+    "if_acmpeq",
+    "if_acmpne",
+    "goto",
+    "jsr",
+    "ret",
+    "tableswitch",
+    "lookupswitch",
+    "ireturn",
+    "lreturn",
+    "freturn",
+    "dreturn",
+    "areturn",
+    "return",
+    "getstatic",
+    "putstatic",
+    "getfield",
+    "putfield",
+    "invokevirtual",
+    "invokespecial",
+    "invokestatic",
+    "invokeinterface",
+    "invokedynamic",
+    "new",
+    "newarray",
+    "anewarray",
+    "arraylength",
+    "athrow",
+    "checkcast",
+    "instanceof",
+    "monitorenter",
+    "monitorexit",
+    "wide",
+    "multianewarray",
+    "ifnull",
+    "ifnonnull",
+    "goto_w",
+    "jsr_w",
+    "breakpoint",
+];
+
+const OP_IINC: u8 = 132;
+const OP_TABLESWITCH: u8 = 170;
+const OP_LOOKUPSWITCH: u8 = 171;
+const OP_WIDE: u8 = 196;
+
+/// One decoded instruction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub bci: usize,
+    pub text: String,
+}
+
+/// How an opcode's fixed-size operands should be shown
+enum Operands {
+    None,
+    /// Signed immediate of this many bytes
+    Immediate(usize),
+    /// Local variable index (1 byte)
+    Local,
+    /// Constant pool index of this many bytes (`#12`)
+    ConstantPool(usize),
+    /// Branch offset of this many bytes, shown as the target bci
+    Branch(usize),
+    /// invokeinterface / invokedynamic / multianewarray: cp index plus trailing bytes
+    ConstantPoolExtra(usize),
+}
+
+fn operands(op: u8) -> Operands {
+    match op {
+        16 => Operands::Immediate(1),                 // bipush
+        17 => Operands::Immediate(2),                 // sipush
+        18 => Operands::ConstantPool(1),              // ldc
+        19 | 20 => Operands::ConstantPool(2),         // ldc_w, ldc2_w
+        21..=25 | 54..=58 | 169 => Operands::Local,   // loads, stores, ret
+        153..=168 | 198 | 199 => Operands::Branch(2), // if*, goto, jsr
+        200 | 201 => Operands::Branch(4),             // goto_w, jsr_w
+        178..=184 | 187 | 189 | 192 | 193 => Operands::ConstantPool(2),
+        185 | 186 => Operands::ConstantPoolExtra(2), // invokeinterface, invokedynamic
+        197 => Operands::ConstantPoolExtra(1),       // multianewarray
+        188 => Operands::Immediate(1),               // newarray (element type)
+        _ => Operands::None,
+    }
+}
+
+fn read_i32(code: &[u8], at: usize) -> Option<i32> {
+    code.get(at..at + 4)
+        .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_signed(code: &[u8], at: usize, len: usize) -> Option<i64> {
+    let bytes = code.get(at..at + len)?;
+    Some(match len {
+        1 => bytes[0] as i8 as i64,
+        2 => i16::from_be_bytes([bytes[0], bytes[1]]) as i64,
+        _ => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as i64,
+    })
+}
+
+fn read_unsigned(code: &[u8], at: usize, len: usize) -> Option<u32> {
+    let bytes = code.get(at..at + len)?;
+    Some(bytes.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32))
+}
+
+/// Decode the instruction at `bci`, returning its text and length.
+fn decode(code: &[u8], bci: usize) -> Option<(String, usize)> {
+    let op = code[bci];
+    let name = MNEMONICS.get(op as usize).copied().unwrap_or("<unknown>");
+    let target = |offset: i64| (bci as i64 + offset).to_string();
+
+    match op {
+        OP_IINC => {
+            let index = read_unsigned(code, bci + 1, 1)?;
+            let delta = read_signed(code, bci + 2, 1)?;
+            Some((format!("iinc {}, {}", index, delta), 3))
+        }
+        OP_WIDE => {
+            let inner = *code.get(bci + 1)?;
+            let inner_name = MNEMONICS
+                .get(inner as usize)
+                .copied()
+                .unwrap_or("<unknown>");
+            let index = read_unsigned(code, bci + 2, 2)?;
+            if inner == OP_IINC {
+                let delta = read_signed(code, bci + 4, 2)?;
+                Some((format!("wide iinc {}, {}", index, delta), 6))
+            } else {
+                Some((format!("wide {} {}", inner_name, index), 4))
+            }
+        }
+        OP_TABLESWITCH | OP_LOOKUPSWITCH => {
+            // Operands start at the next 4-byte boundary
+            let base = (bci + 4) & !3;
+            let default = read_i32(code, base)? as i64;
+            if op == OP_TABLESWITCH {
+                let low = read_i32(code, base + 4)?;
+                let high = read_i32(code, base + 8)?;
+                let count = (high as i64 - low as i64 + 1).max(0) as usize;
+                let len = base + 12 + count * 4 - bci;
+                Some((
+                    format!(
+                        "tableswitch [{}..{}] default -> {}",
+                        low,
+                        high,
+                        target(default)
+                    ),
+                    len,
+                ))
+            } else {
+                let pairs = read_i32(code, base + 4)?.max(0) as usize;
+                let len = base + 8 + pairs * 8 - bci;
+                Some((
+                    format!(
+                        "lookupswitch {} cases default -> {}",
+                        pairs,
+                        target(default)
+                    ),
+                    len,
+                ))
+            }
+        }
+        _ => match operands(op) {
+            Operands::None => Some((name.to_string(), 1)),
+            Operands::Immediate(n) => Some((
+                format!("{} {}", name, read_signed(code, bci + 1, n)?),
+                1 + n,
+            )),
+            Operands::Local => Some((format!("{} {}", name, read_unsigned(code, bci + 1, 1)?), 2)),
+            Operands::ConstantPool(n) => Some((
+                format!("{} #{}", name, read_unsigned(code, bci + 1, n)?),
+                1 + n,
+            )),
+            Operands::Branch(n) => Some((
+                format!("{} -> {}", name, target(read_signed(code, bci + 1, n)?)),
+                1 + n,
+            )),
+            Operands::ConstantPoolExtra(n) => {
+                let index = read_unsigned(code, bci + 1, 2)?;
+                code.get(bci + 3..bci + 3 + n)?;
+                Some((format!("{} #{}", name, index), 3 + n))
+            }
+        },
+    }
+}
+
+/// Decode a method's bytecode into one instruction per bci. Stops at truncated input.
+pub fn disassemble(code: &[u8]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut bci = 0;
+    while bci < code.len() {
+        match decode(code, bci) {
+            Some((text, len)) => {
+                instructions.push(Instruction { bci, text });
+                bci += len.max(1);
+            }
+            None => {
+                instructions.push(Instruction {
+                    bci,
+                    text: "<truncated>".to_string(),
+                });
+                break;
+            }
+        }
+    }
+    instructions
+}
+
+/// Parse the hex string written by the agent into bytes.
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.as_bytes();
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        // iconst_0; istore_1; iload_1; bipush 10; if_icmpge +9; iinc 1,1; goto -10; return
+        let code = parse_hex("033c1b100aa200098401 01a7fff6b1".replace(' ', "").as_str()).unwrap();
+        let text: Vec<(usize, String)> = disassemble(&code)
+            .into_iter()
+            .map(|i| (i.bci, i.text))
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                (0, "iconst_0".to_string()),
+                (1, "istore_1".to_string()),
+                (2, "iload_1".to_string()),
+                (3, "bipush 10".to_string()),
+                (5, "if_icmpge -> 14".to_string()),
+                (8, "iinc 1, 1".to_string()),
+                (11, "goto -> 1".to_string()),
+                (14, "return".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(parse_hex("abc"), None);
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(parse_hex("é1"), None);
+    }
+
+    #[test]
+    fn test_tableswitch_padding() {
+        // iload_0 at 0, tableswitch at 1: operands aligned to bci 4
+        let mut code = vec![0x1a, 0xaa, 0, 0];
+        for v in [20i32, 0, 1, 16, 18] {
+            code.extend_from_slice(&v.to_be_bytes());
+        }
+        code.push(0xb1);
+        let instructions = disassemble(&code);
+        assert_eq!(instructions[1].text, "tableswitch [0..1] default -> 21");
+        assert_eq!(instructions[2].bci, 24);
+        assert_eq!(instructions[2].text, "return");
+    }
+}
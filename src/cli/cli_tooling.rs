@@ -1,13 +1,117 @@
 use clap::{Arg, ArgMatches, Command};
 use std::env;
+use std::fmt;
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command as ProcessCommand, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::cli::attach::parse_duration_secs;
+use crate::cli::jvms::{find_jvm_results, merge_jvm_results, print_jvm_results};
+use crate::profiling::options::{AgentOptions, HeapReport, UploadFormat};
+
+/// What the profiled JVM runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LaunchTarget {
+    /// `-jar <file>`
+    Jar(String),
+    /// `-cp <classpath> <main class>`
+    MainClass {
+        classpath: String,
+        main_class: String,
+    },
+    /// `[-p <module path>] -m <module>/<main class>`
+    Module {
+        module_path: Option<String>,
+        module: String,
+    },
+}
+
+/// Resolve each entry of a `:`-separated path against `base`.
+fn resolve_path_list(paths: &str, base: &Path) -> String {
+    env::split_paths(paths)
+        .map(|path| base.join(path).to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+impl LaunchTarget {
+    /// `java` arguments that start the program, with relative paths resolved against `base`
+    /// (the JVM runs in the output directory).
+    pub fn java_args(&self, base: &Path) -> Vec<String> {
+        match self {
+            LaunchTarget::Jar(jar) => {
+                vec![
+                    "-jar".to_string(),
+                    base.join(jar).to_string_lossy().into_owned(),
+                ]
+            }
+            LaunchTarget::MainClass {
+                classpath,
+                main_class,
+            } => vec![
+                "-cp".to_string(),
+                resolve_path_list(classpath, base),
+                main_class.clone(),
+            ],
+            LaunchTarget::Module {
+                module_path,
+                module,
+            } => {
+                let mut args = Vec::new();
+                if let Some(module_path) = module_path {
+                    args.push("-p".to_string());
+                    args.push(resolve_path_list(module_path, base));
+                }
+                args.push("-m".to_string());
+                args.push(module.clone());
+                args
+            }
+        }
+    }
+
+    /// Default application name: the jar's file name, the main class's simple name or the module.
+    pub fn app_name(&self) -> String {
+        match self {
+            LaunchTarget::Jar(jar) => Path::new(jar)
+                .file_stem()
+                .map_or_else(|| jar.clone(), |stem| stem.to_string_lossy().into_owned()),
+            LaunchTarget::MainClass { main_class, .. } => main_class
+                .rsplit('.')
+                .next()
+                .unwrap_or(main_class)
+                .to_string(),
+            LaunchTarget::Module { module, .. } => {
+                module.split('/').next().unwrap_or(module).to_string()
+            }
+        }
+    }
+}
+
+impl fmt::Display for LaunchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LaunchTarget::Jar(jar) => write!(f, "-jar {}", jar),
+            LaunchTarget::MainClass {
+                classpath,
+                main_class,
+            } => write!(f, "-cp {} {}", classpath, main_class),
+            LaunchTarget::Module {
+                module_path: Some(module_path),
+                module,
+            } => write!(f, "-p {} -m {}", module_path, module),
+            LaunchTarget::Module { module, .. } => write!(f, "-m {}", module),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ProfilerConfig {
-    pub jar_file: String,
+    /// What to run; `None` for `rjprof exec`, which runs a command instead
+    pub launch: Option<LaunchTarget>,
+    /// Arguments passed to the Java program
+    pub program_args: Vec<String>,
     pub java_opts: Vec<String>,
     pub stack_size: String,
     pub output_dir: String,
@@ -16,13 +120,34 @@ pub struct ProfilerConfig {
     pub allocation_tracking: bool,
     pub call_graph: bool,
     pub sampling_interval: Option<u64>,
+    pub thread_dump_interval: Option<u64>,
     pub java_executable: String,
+    pub perf_map: bool,
+    pub jitdump: bool,
+    pub lines: bool,
+    pub off_cpu: bool,
+    pub native_stacks: bool,
+    pub heap: Option<HeapReport>,
+    pub snapshot: bool,
+    pub snapshot_reset: bool,
+    pub control_socket: Option<String>,
+    pub http_port: Option<u64>,
+    pub metrics_file: Option<String>,
+    pub metrics_interval: Option<u64>,
+    pub continuous: Option<u64>,
+    pub retain: Option<u64>,
+    pub pyroscope_url: Option<String>,
+    pub upload_format: Option<UploadFormat>,
+    pub app_name: Option<String>,
+    pub app_version: Option<String>,
+    pub spool_dir: Option<String>,
 }
 
 impl Default for ProfilerConfig {
     fn default() -> Self {
         Self {
-            jar_file: String::new(),
+            launch: None,
+            program_args: vec![],
             java_opts: vec![],
             stack_size: "256k".to_string(),
             output_dir: "./profiler_output".to_string(),
@@ -31,23 +156,65 @@ impl Default for ProfilerConfig {
             allocation_tracking: true,
             call_graph: true,
             sampling_interval: None,
+            thread_dump_interval: None,
             java_executable: "java".to_string(),
+            perf_map: false,
+            jitdump: false,
+            lines: false,
+            off_cpu: false,
+            native_stacks: false,
+            heap: None,
+            snapshot: false,
+            snapshot_reset: false,
+            control_socket: None,
+            http_port: None,
+            metrics_file: None,
+            metrics_interval: None,
+            continuous: None,
+            retain: None,
+            pyroscope_url: None,
+            upload_format: None,
+            app_name: None,
+            app_version: None,
+            spool_dir: None,
         }
     }
 }
 
-pub fn parse_config(matches: &ArgMatches) -> Result<ProfilerConfig, String> {
-    let mut config = ProfilerConfig::default();
+/// A duration argument in whole seconds; the agent works in seconds, so shorter ones round up.
+pub fn duration_secs(matches: &ArgMatches, name: &str) -> Result<u64, String> {
+    let text = matches.get_one::<String>(name).unwrap();
+    parse_duration_secs(text)
+}
 
-    // Required JAR file
-    config.jar_file = matches
-        .get_one::<String>("jar")
-        .ok_or("JAR file is required")?
-        .clone();
+pub fn parse_config(matches: &ArgMatches) -> Result<ProfilerConfig, String> {
+    let mut config = parse_profiling_config(matches)?;
 
-    // Validate JAR file exists
-    if !Path::new(&config.jar_file).exists() {
-        return Err(format!("JAR file not found: {}", config.jar_file));
+    // What to run: a jar, a main class on a classpath or a module
+    let launch = if let Some(jar) = matches.get_one::<String>("jar") {
+        // Validate JAR file exists
+        if !Path::new(jar).exists() {
+            return Err(format!("JAR file not found: {}", jar));
+        }
+        LaunchTarget::Jar(jar.clone())
+    } else if let Some(main_class) = matches.get_one::<String>("main-class") {
+        LaunchTarget::MainClass {
+            classpath: matches
+                .get_one::<String>("classpath")
+                .ok_or("A main class needs a classpath (-cp)")?
+                .clone(),
+            main_class: main_class.clone(),
+        }
+    } else if let Some(module) = matches.get_one::<String>("module") {
+        LaunchTarget::Module {
+            module_path: matches.get_one::<String>("module-path").cloned(),
+            module: module.clone(),
+        }
+    } else {
+        return Err("Nothing to run: give --jar, -cp with a main class, or --module".to_string());
+    };
+    if let Some(args) = matches.get_many::<String>("args") {
+        config.program_args = args.cloned().collect();
     }
 
     // Java options
@@ -58,15 +225,39 @@ pub fn parse_config(matches: &ArgMatches) -> Result<ProfilerConfig, String> {
     // Stack size
     config.stack_size = matches.get_one::<String>("stack-size").unwrap().clone();
 
-    // Output directory
-    config.output_dir = matches.get_one::<String>("output").unwrap().clone();
-
     // Java executable
     config.java_executable = matches
         .get_one::<String>("java-executable")
         .unwrap()
         .clone();
 
+    // Name uploaded profiles after the program unless told otherwise
+    if config.app_name.is_none() {
+        config.app_name = Some(launch.app_name());
+    }
+    config.launch = Some(launch);
+
+    Ok(config)
+}
+
+/// Options shared by every way of launching the profiled program: output, agent and
+/// profiling features.
+/// A flag of the launching subcommands; `attach` doesn't have them.
+fn launcher_flag(matches: &ArgMatches, name: &str) -> bool {
+    matches
+        .try_get_one::<bool>(name)
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(false)
+}
+
+pub fn parse_profiling_config(matches: &ArgMatches) -> Result<ProfilerConfig, String> {
+    let mut config = ProfilerConfig::default();
+
+    // Output directory
+    config.output_dir = matches.get_one::<String>("output").unwrap().clone();
+
     // Agent path (auto-detect if not provided)
     if let Some(agent_path) = matches.get_one::<String>("agent-path") {
         config.agent_path = agent_path.clone();
@@ -80,14 +271,48 @@ pub fn parse_config(matches: &ArgMatches) -> Result<ProfilerConfig, String> {
     }
 
     // Feature flags
-    config.flamegraph = !matches.get_flag("no-flamegraph");
-    config.allocation_tracking = !matches.get_flag("no-allocation");
-    config.call_graph = !matches.get_flag("no-call-graph");
+    config.flamegraph = !launcher_flag(matches, "no-flamegraph");
+    config.allocation_tracking = !launcher_flag(matches, "no-allocation");
+    config.call_graph = !launcher_flag(matches, "no-call-graph");
+    config.perf_map = matches.get_flag("perf-map");
+    config.jitdump = matches.get_flag("jitdump");
+    config.lines = matches.get_flag("lines");
+    config.off_cpu = matches.get_flag("off-cpu");
+    config.native_stacks = matches.get_flag("native-stacks");
+    config.heap = matches
+        .get_one::<String>("heap")
+        .map(|name| HeapReport::parse(name))
+        .transpose()?;
+    config.snapshot_reset = matches.get_flag("snapshot-reset");
+    config.snapshot = matches.get_flag("snapshot") || config.snapshot_reset;
+    config.control_socket = matches.get_one::<String>("control").cloned();
+    config.http_port = matches.get_one::<u16>("http").map(|&port| u64::from(port));
+    config.metrics_file = matches.get_one::<String>("metrics-file").cloned();
+    config.metrics_interval = matches.get_one::<u64>("metrics-interval").copied();
+    if matches.get_flag("continuous") {
+        config.continuous = Some(duration_secs(matches, "interval")?);
+        config.retain = Some(duration_secs(matches, "retain")?);
+    }
+    config.pyroscope_url = matches.get_one::<String>("pyroscope").cloned();
+    config.upload_format = matches
+        .get_one::<String>("upload-format")
+        .map(|name| UploadFormat::parse(name))
+        .transpose()?;
+    config.app_name = matches.get_one::<String>("app-name").cloned();
+    config.app_version = matches.get_one::<String>("app-version").cloned();
+    config.spool_dir = matches.get_one::<String>("spool").cloned();
 
     // Sampling interval
     if let Some(interval) = matches.get_one::<String>("sampling-interval") {
         config.sampling_interval = Some(interval.parse().map_err(|_| "Invalid sampling interval")?);
     }
+    if let Some(interval) = matches.get_one::<String>("thread-dump-interval") {
+        config.thread_dump_interval = Some(
+            interval
+                .parse()
+                .map_err(|_| "Invalid thread dump interval")?,
+        );
+    }
 
     Ok(config)
 }
@@ -110,7 +335,37 @@ pub fn detect_agent_path() -> Result<String, String> {
     Err("Could not find profiler agent library. Please specify with --agent-path".to_string())
 }
 
+/// Agent options for the profiling features in `config`.
+pub fn agent_options(config: &ProfilerConfig) -> AgentOptions {
+    AgentOptions {
+        perf_map: config.perf_map,
+        jitdump: config.jitdump,
+        lines: config.lines,
+        off_cpu: config.off_cpu,
+        native_stacks: config.native_stacks,
+        heap: config.heap,
+        sampling_interval_ms: config.sampling_interval,
+        thread_dump_interval_secs: config.thread_dump_interval,
+        snapshot: config.snapshot,
+        snapshot_reset: config.snapshot_reset,
+        control_socket: config.control_socket.clone(),
+        http_port: config.http_port,
+        metrics_file: config.metrics_file.clone(),
+        metrics_interval_secs: config.metrics_interval,
+        continuous_interval_secs: config.continuous,
+        retain_secs: config.retain,
+        pyroscope_url: config.pyroscope_url.clone(),
+        upload_format: config.upload_format,
+        app_name: config.pyroscope_url.as_ref().and(config.app_name.clone()),
+        app_version: config.app_version.clone(),
+        spool_dir: config.spool_dir.clone(),
+        ..Default::default()
+    }
+}
+
 pub fn run_profiler(config: &ProfilerConfig, verbose: bool) -> Result<(), String> {
+    let launch = config.launch.as_ref().ok_or("Nothing to run")?;
+
     // Create output directory
     if let Err(e) = fs::create_dir_all(&config.output_dir) {
         return Err(format!("Failed to create output directory: {}", e));
@@ -126,8 +381,18 @@ pub fn run_profiler(config: &ProfilerConfig, verbose: bool) -> Result<(), String
     // Build Java command
     let mut java_cmd = ProcessCommand::new(&config.java_executable);
 
-    // Add agent path
-    java_cmd.arg(format!("-agentpath:{}", config.agent_path));
+    // Add agent path, with agent options if any. Relative paths are from where we started
+    let agent_path = original_dir.join(&config.agent_path);
+    let agent_options = agent_options(config).to_option_string();
+    if agent_options.is_empty() {
+        java_cmd.arg(format!("-agentpath:{}", agent_path.display()));
+    } else {
+        java_cmd.arg(format!(
+            "-agentpath:{}={}",
+            agent_path.display(),
+            agent_options
+        ));
+    }
 
     // Add stack size
     java_cmd.arg(format!("-Xss{}", config.stack_size));
@@ -137,9 +402,9 @@ pub fn run_profiler(config: &ProfilerConfig, verbose: bool) -> Result<(), String
         java_cmd.arg(opt);
     }
 
-    // Add JAR file
-    java_cmd.arg("-jar");
-    java_cmd.arg(Path::new(&original_dir).join(&config.jar_file));
+    // Add the program and its arguments
+    java_cmd.args(launch.java_args(&original_dir));
+    java_cmd.args(&config.program_args);
 
     if verbose {
         println!("🚀 Running command: {:?}", java_cmd);
@@ -166,29 +431,124 @@ pub fn run_profiler(config: &ProfilerConfig, verbose: bool) -> Result<(), String
     Ok(())
 }
 
+/// Run any command (a build tool, a shell wrapper) with the agent in `JAVA_TOOL_OPTIONS`, so
+/// every JVM it starts, forks included, is profiled into its own `jvm-<pid>` directory.
+/// Afterwards the JVMs are listed and their folded stacks merged into the output directory.
+pub fn exec_profiler(
+    config: &ProfilerConfig,
+    command: &[String],
+    verbose: bool,
+) -> Result<(), String> {
+    if config.control_socket.is_some()
+        || config.http_port.is_some()
+        || config.metrics_file.is_some()
+    {
+        return Err(
+            "--control, --http and --metrics-file can't be shared by several JVMs".to_string(),
+        );
+    }
+    let (program, args) = command.split_first().ok_or("No command to run")?;
+
+    fs::create_dir_all(&config.output_dir)
+        .map_err(|e| format!("Failed to create output directory: {}", e))?;
+    // The JVMs may run in other working directories
+    let output_dir = fs::canonicalize(&config.output_dir)
+        .map_err(|e| format!("Failed to resolve output directory: {}", e))?;
+    let agent_path = fs::canonicalize(&config.agent_path)
+        .map_err(|e| format!("Failed to resolve agent path: {}", e))?;
+
+    let mut options = agent_options(config);
+    options.output_dir = Some(output_dir.to_string_lossy().into_owned());
+    options.per_process = true;
+    let option_string = options.to_option_string();
+    // Options are separated by commas, so a value holding one (say, the output path)
+    // would be split apart
+    if AgentOptions::parse(&option_string).as_ref() != Ok(&options) {
+        return Err(format!(
+            "Agent options can't hold values with commas: {}",
+            option_string
+        ));
+    }
+    let agent_arg = format!("-agentpath:{}={}", agent_path.display(), option_string);
+    // The JVM splits JAVA_TOOL_OPTIONS at whitespace
+    if agent_arg.contains(char::is_whitespace) {
+        return Err(format!(
+            "JAVA_TOOL_OPTIONS can't hold paths with spaces: {}",
+            agent_arg
+        ));
+    }
+    let tool_options = match env::var("JAVA_TOOL_OPTIONS") {
+        Ok(existing) if !existing.trim().is_empty() => format!("{} {}", existing, agent_arg),
+        _ => agent_arg,
+    };
+
+    let mut child = ProcessCommand::new(program);
+    child.args(args).env("JAVA_TOOL_OPTIONS", &tool_options);
+    if verbose {
+        println!("🚀 Running command: {:?}", child);
+        println!("   JAVA_TOOL_OPTIONS={}", tool_options);
+    }
+    // Only the JVMs of this run count; the output directory may hold older ones
+    let started_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let status = child
+        .status()
+        .map_err(|e| format!("Failed to execute {}: {}", program, e))?;
+
+    let results = find_jvm_results(&output_dir, started_millis);
+    print_jvm_results(&results);
+    merge_jvm_results(&output_dir, &results)?;
+    if !status.success() {
+        return Err(format!(
+            "{} failed with exit code: {:?}",
+            program,
+            status.code()
+        ));
+    }
+    Ok(())
+}
+
 pub fn generate_flamegraph_svg(config: &ProfilerConfig) -> Result<(), String> {
-    let folded_path = Path::new(&config.output_dir).join("flamegraph.folded");
-    let svg_path = Path::new(&config.output_dir).join("flamegraph.svg");
+    render_flamegraph(config, "flamegraph.folded", "flamegraph.svg")?;
+
+    // Off-CPU and mixed-mode CPU stacks are only written with --off-cpu and --native-stacks
+    for (folded, svg) in [
+        ("off_cpu.folded", "off_cpu.svg"),
+        ("cpu_mixed.folded", "cpu_mixed.svg"),
+    ] {
+        if Path::new(&config.output_dir).join(folded).exists() {
+            render_flamegraph(config, folded, svg)?;
+        }
+    }
+    Ok(())
+}
+
+fn render_flamegraph(config: &ProfilerConfig, folded: &str, svg: &str) -> Result<(), String> {
+    let folded_path = Path::new(&config.output_dir).join(folded);
+    let svg_path = Path::new(&config.output_dir).join(svg);
 
     if !folded_path.exists() {
-        return Err("flamegraph.folded file not found".to_string());
+        return Err(format!("{} file not found", folded));
     }
 
-    // Try flamegraph.pl first, then inferno-flamegraph
+    // Try flamegraph.pl first, then inferno-flamegraph. Both read palette.map from the
+    // working directory with --cp, which the agent fills with colours for native frames.
     let flamegraph_commands = vec![
         (
             "flamegraph.pl",
-            vec![folded_path.to_string_lossy().to_string()],
+            vec!["--cp".to_string(), folded.to_string()],
         ),
         (
             "inferno-flamegraph",
-            vec![folded_path.to_string_lossy().to_string()],
+            vec!["--cp".to_string(), folded.to_string()],
         ),
     ];
 
     for (cmd, args) in flamegraph_commands {
         let mut command = ProcessCommand::new(cmd);
         command.args(&args);
+        command.current_dir(&config.output_dir);
         command.stdout(Stdio::piped());
         command.stderr(Stdio::piped());
 
@@ -235,6 +595,39 @@ mod tests {
         // In a real test, you'd create a temporary JAR file
     }
 
+    #[test]
+    fn test_launch_args() {
+        let base = Path::new("/work");
+        let jar = LaunchTarget::Jar("build/app.jar".to_string());
+        assert_eq!(jar.java_args(base), ["-jar", "/work/build/app.jar"]);
+        assert_eq!(jar.app_name(), "app");
+
+        let main_class = LaunchTarget::MainClass {
+            classpath: "a.jar:/opt/lib/b.jar:classes".to_string(),
+            main_class: "com.acme.Main".to_string(),
+        };
+        assert_eq!(
+            main_class.java_args(base),
+            [
+                "-cp",
+                "/work/a.jar:/opt/lib/b.jar:/work/classes",
+                "com.acme.Main"
+            ]
+        );
+        assert_eq!(main_class.app_name(), "Main");
+
+        let module = LaunchTarget::Module {
+            module_path: Some("mods".to_string()),
+            module: "app/com.acme.Main".to_string(),
+        };
+        assert_eq!(
+            module.java_args(base),
+            ["-p", "/work/mods", "-m", "app/com.acme.Main"]
+        );
+        assert_eq!(module.app_name(), "app");
+        assert_eq!(module.to_string(), "-p mods -m app/com.acme.Main");
+    }
+
     #[test]
     fn test_agent_path_detection() {
         // Test that agent path detection doesn't crash
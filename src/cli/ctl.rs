@@ -0,0 +1,99 @@
+// src/cli/ctl.rs
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::cli::attach::parse_duration_secs;
+
+/// Commands understood by the agent's control socket
+pub const CONTROL_COMMANDS: [&str; 7] = [
+    "status",
+    "start",
+    "stop",
+    "dump",
+    "reset",
+    "set-filter",
+    "threads",
+];
+
+/// Longest the agent may take to answer, e.g. while writing reports
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Build the request line for `rjprof ctl <socket> <command> [args...]`:
+/// `start [duration]`, `dump [reports|snapshot|folded|json|heap]`, `set-filter [prefix...]`.
+pub fn build_request(command: &str, args: &[String]) -> Result<Value, String> {
+    let mut request = json!({ "command": command });
+    match command {
+        "status" | "stop" | "reset" | "threads" => {
+            if !args.is_empty() {
+                return Err(format!("{} takes no arguments", command));
+            }
+        }
+        "start" => match args {
+            [] => {}
+            [duration] => request["duration_secs"] = json!(parse_duration_secs(duration)?),
+            _ => return Err("start takes at most one duration".to_string()),
+        },
+        "dump" => match args {
+            [] => {}
+            [format] => request["format"] = json!(format),
+            _ => return Err("dump takes at most one format".to_string()),
+        },
+        "set-filter" => request["exclude"] = json!(args),
+        _ => return Err(format!("Unknown control command: {}", command)),
+    }
+    Ok(request)
+}
+
+/// Send one request to the control socket and return the agent's response.
+pub fn send_request(socket: &str, request: &Value) -> Result<Value, String> {
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| format!("Cannot connect to {}: {}", socket, e))?;
+    stream
+        .set_read_timeout(Some(RESPONSE_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    writeln!(stream, "{}", request).map_err(|e| format!("Cannot send request: {}", e))?;
+
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("Cannot read response: {}", e))?;
+    if line.is_empty() {
+        return Err("The agent closed the connection without answering".to_string());
+    }
+    serde_json::from_str(&line).map_err(|e| format!("Invalid response: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_request() {
+        assert_eq!(
+            build_request("status", &[]).unwrap(),
+            json!({"command": "status"})
+        );
+        assert_eq!(
+            build_request("start", &["2m".to_string()]).unwrap(),
+            json!({"command": "start", "duration_secs": 120})
+        );
+        assert_eq!(
+            build_request("dump", &["folded".to_string()]).unwrap(),
+            json!({"command": "dump", "format": "folded"})
+        );
+        assert_eq!(
+            build_request("set-filter", &["java.".to_string(), "sun.".to_string()]).unwrap(),
+            json!({"command": "set-filter", "exclude": ["java.", "sun."]})
+        );
+        assert_eq!(
+            build_request("set-filter", &[]).unwrap(),
+            json!({"command": "set-filter", "exclude": []})
+        );
+
+        assert!(build_request("stop", &["now".to_string()]).is_err());
+        assert!(build_request("start", &["10x".to_string()]).is_err());
+        assert!(build_request("pause", &[]).is_err());
+    }
+}
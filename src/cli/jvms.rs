@@ -0,0 +1,171 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::profiling::options::PROCESS_DIR_PREFIX;
+use crate::profiling::session::{PROCESS_INFO, SESSION_MARKER};
+
+/// Folded stack files merged across JVMs into the top of the output directory
+const MERGED_FILES: [&str; 3] = ["flamegraph.folded", "off_cpu.folded", "cpu_mixed.folded"];
+
+/// Reports of one JVM profiled with `perprocess`
+#[derive(Debug)]
+pub struct JvmResult {
+    pub pid: u32,
+    pub dir: PathBuf,
+    /// Main class or jar with its arguments, as the JVM was started
+    pub command: String,
+    /// Whether the JVM exited normally and wrote its reports
+    pub complete: bool,
+    /// Wall time in the flamegraph, summed over threads, in nanoseconds
+    pub sampled_nanos: u64,
+}
+
+/// The `jvm-<pid>` directories under `output_dir` of JVMs started at or after
+/// `since_millis` (ms since the epoch), by pid. Directories left by earlier runs are skipped.
+pub fn find_jvm_results(output_dir: &Path, since_millis: u64) -> Vec<JvmResult> {
+    let Ok(entries) = fs::read_dir(output_dir) else {
+        return Vec::new();
+    };
+    let mut results: Vec<JvmResult> = entries
+        .filter_map(|entry| {
+            let dir = entry.ok()?.path();
+            let pid = dir
+                .file_name()?
+                .to_str()?
+                .strip_prefix(PROCESS_DIR_PREFIX)?
+                .parse()
+                .ok()?;
+            let info: Value = fs::read_to_string(dir.join(PROCESS_INFO))
+                .ok()
+                .and_then(|text| serde_json::from_str(&text).ok())
+                .unwrap_or(Value::Null);
+            if info["started_millis"].as_u64()? < since_millis {
+                return None;
+            }
+            let sampled_nanos = fs::read_to_string(dir.join("flamegraph.folded"))
+                .map_or(0, |folded| folded_total(&folded));
+            Some(JvmResult {
+                pid,
+                command: info["command"].as_str().unwrap_or("").to_string(),
+                complete: dir.join(SESSION_MARKER).exists(),
+                sampled_nanos,
+                dir,
+            })
+        })
+        .collect();
+    results.sort_by_key(|result| result.pid);
+    results
+}
+
+/// Sum of the counts of folded stacks.
+pub fn folded_total(folded: &str) -> u64 {
+    folded
+        .lines()
+        .filter_map(|line| line.rsplit_once(' ')?.1.parse::<u64>().ok())
+        .sum()
+}
+
+/// Add up folded stacks from several profiles; the same stack in two of them counts once
+/// with the sum of both. Largest first.
+pub fn merge_folded<'a>(profiles: impl IntoIterator<Item = &'a str>) -> String {
+    let mut totals: HashMap<&str, u64> = HashMap::new();
+    for profile in profiles {
+        for line in profile.lines() {
+            let Some((stack, count)) = line.rsplit_once(' ') else {
+                continue;
+            };
+            if let Ok(count) = count.parse::<u64>() {
+                *totals.entry(stack).or_insert(0) += count;
+            }
+        }
+    }
+    let mut stacks: Vec<(&str, u64)> = totals.into_iter().collect();
+    stacks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    stacks
+        .iter()
+        .map(|(stack, count)| format!("{} {}\n", stack, count))
+        .collect()
+}
+
+/// Write the folded stacks of all JVMs, added up, into `output_dir`.
+pub fn merge_jvm_results(output_dir: &Path, results: &[JvmResult]) -> Result<(), String> {
+    for name in MERGED_FILES {
+        let profiles: Vec<String> = results
+            .iter()
+            .filter_map(|result| fs::read_to_string(result.dir.join(name)).ok())
+            .collect();
+        if profiles.is_empty() {
+            continue;
+        }
+        let path = output_dir.join(name);
+        fs::write(&path, merge_folded(profiles.iter().map(String::as_str)))
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+pub fn print_jvm_results(results: &[JvmResult]) {
+    println!("\n🧵 Profiled JVMs: {}", results.len());
+    println!("{:>8} {:>10} {:>10}  COMMAND", "PID", "SAMPLED", "REPORTS");
+    for result in results {
+        println!(
+            "{:>8} {:>9.2}s {:>10}  {}",
+            result.pid,
+            result.sampled_nanos as f64 / 1e9,
+            if result.complete {
+                "complete"
+            } else {
+                "partial"
+            },
+            result.command
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_folded() {
+        let first = "main;work 300\nmain;idle 100\n";
+        let second = "main;work 200\nworker;run 400\nnot a sample\n";
+        assert_eq!(
+            merge_folded([first, second]),
+            "main;work 500\nworker;run 400\nmain;idle 100\n"
+        );
+        assert_eq!(folded_total(second), 600);
+        assert_eq!(merge_folded([]), "");
+    }
+
+    #[test]
+    fn test_find_jvm_results() {
+        let output_dir = std::env::temp_dir().join(format!("rjprof-jvms-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&output_dir);
+        let jvm = |pid: u32, started_millis: u64| {
+            let dir = output_dir.join(format!("{}{}", PROCESS_DIR_PREFIX, pid));
+            fs::create_dir_all(&dir).unwrap();
+            let info = format!(
+                r#"{{"pid":{},"command":"Main","started_millis":{}}}"#,
+                pid, started_millis
+            );
+            fs::write(dir.join(PROCESS_INFO), info).unwrap();
+            fs::write(dir.join("flamegraph.folded"), "main;work 500\n").unwrap();
+        };
+        // Left over from an earlier run, with and without a start time
+        jvm(300, 1_000);
+        fs::create_dir_all(output_dir.join(format!("{}{}", PROCESS_DIR_PREFIX, 400))).unwrap();
+        jvm(200, 5_000);
+        jvm(100, 6_000);
+
+        let results = find_jvm_results(&output_dir, 5_000);
+        let pids: Vec<u32> = results.iter().map(|result| result.pid).collect();
+        assert_eq!(pids, vec![100, 200]);
+        assert_eq!(results[0].command, "Main");
+        assert_eq!(results[0].sampled_nanos, 500);
+        assert!(!results[0].complete);
+        let _ = fs::remove_dir_all(&output_dir);
+    }
+}
@@ -1,2 +1,7 @@
 // src/cli/mod.rs
+pub mod annotate;
+pub mod attach;
+pub mod bytecode;
 pub mod cli_tooling;
+pub mod ctl;
+pub mod jvms;
@@ -0,0 +1,125 @@
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::bindings::gen_bindings::*;
+
+/// Name prefix of every agent thread, so they can be left out of thread reports.
+pub(crate) const AGENT_THREAD_PREFIX: &str = "rjprof-";
+
+/// Entry point of an agent thread, as handed to `RunAgentThread`.
+pub(crate) type AgentThreadFn =
+    unsafe extern "C" fn(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, arg: *mut c_void);
+
+/// Set when the agent detaches; agent threads return once they see it
+static SHUT_DOWN: Mutex<bool> = Mutex::new(false);
+static SHUT_DOWN_SIGNAL: Condvar = Condvar::new();
+
+/// Agent threads whose entry function hasn't returned yet
+static RUNNING: Mutex<usize> = Mutex::new(0);
+static RUNNING_CHANGED: Condvar = Condvar::new();
+
+/// What `run_agent_thread` calls on the new thread
+struct AgentThreadStart {
+    entry: AgentThreadFn,
+    arg: *mut c_void,
+}
+
+unsafe extern "C" fn run_agent_thread(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    arg: *mut c_void,
+) {
+    let start = Box::from_raw(arg as *mut AgentThreadStart);
+    (start.entry)(jvmti_env, jni_env, start.arg);
+    *RUNNING.lock().unwrap() -= 1;
+    RUNNING_CHANGED.notify_all();
+}
+
+/// Whether agent threads have been told to stop.
+pub(crate) fn agent_shut_down() -> bool {
+    *SHUT_DOWN.lock().unwrap()
+}
+
+/// Sleep for `duration`, waking early if agent threads are told to stop. Returns true
+/// if they were, and the calling thread should return.
+pub(crate) fn sleep_unless_shut_down(duration: Duration) -> bool {
+    let shut_down = SHUT_DOWN.lock().unwrap();
+    let (shut_down, _) = SHUT_DOWN_SIGNAL
+        .wait_timeout_while(shut_down, duration, |shut_down| !*shut_down)
+        .unwrap();
+    *shut_down
+}
+
+/// Tell every agent thread to stop and wait up to `timeout` for them to return. Returns
+/// how many are still running.
+pub(crate) fn stop_agent_threads(timeout: Duration) -> usize {
+    *SHUT_DOWN.lock().unwrap() = true;
+    SHUT_DOWN_SIGNAL.notify_all();
+    let running = RUNNING.lock().unwrap();
+    let (running, _) = RUNNING_CHANGED
+        .wait_timeout_while(running, timeout, |running| *running > 0)
+        .unwrap();
+    *running
+}
+
+/// Start a JVMTI agent thread: a daemon `java.lang.Thread` named `name` that runs `entry`
+/// natively. Agent threads may call JVMTI/JNI freely and are hidden from the profiled
+/// application's event stream. `entry` must return soon after [`agent_shut_down`].
+pub(crate) fn start_agent_thread(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    name: &str,
+    entry: AgentThreadFn,
+    arg: *mut c_void,
+) -> Result<(), String> {
+    if agent_shut_down() {
+        return Err(format!("Not starting {}: the agent is detached", name));
+    }
+    unsafe {
+        let thread_class = (**jni_env).FindClass.unwrap()(jni_env, c"java/lang/Thread".as_ptr());
+        if thread_class.is_null() {
+            return Err("java.lang.Thread not found".to_string());
+        }
+
+        let constructor = (**jni_env).GetMethodID.unwrap()(
+            jni_env,
+            thread_class,
+            c"<init>".as_ptr(),
+            c"(Ljava/lang/String;)V".as_ptr(),
+        );
+        if constructor.is_null() {
+            return Err("java.lang.Thread(String) constructor not found".to_string());
+        }
+
+        let name_c = CString::new(name).map_err(|e| format!("Invalid thread name: {}", e))?;
+        let name_str = (**jni_env).NewStringUTF.unwrap()(jni_env, name_c.as_ptr());
+        let args = [jvalue { l: name_str }];
+        let thread =
+            (**jni_env).NewObjectA.unwrap()(jni_env, thread_class, constructor, args.as_ptr());
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, name_str);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, thread_class);
+        if thread.is_null() {
+            return Err(format!("Failed to create thread object for {}", name));
+        }
+
+        let start = Box::into_raw(Box::new(AgentThreadStart { entry, arg }));
+        *RUNNING.lock().unwrap() += 1;
+        let err = (**jvmti_env).RunAgentThread.unwrap()(
+            jvmti_env,
+            thread,
+            Some(run_agent_thread),
+            start as *mut c_void,
+            JVMTI_THREAD_NORM_PRIORITY as jint,
+        );
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, thread);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            *RUNNING.lock().unwrap() -= 1;
+            drop(Box::from_raw(start));
+            return Err(format!("RunAgentThread failed for {}: {}", name, err));
+        }
+    }
+
+    Ok(())
+}
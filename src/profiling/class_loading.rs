@@ -0,0 +1,345 @@
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::{c_char, c_uchar};
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::cpu_sampler::create_method_ids;
+use crate::profiling::profiling::{elapsed_nanos, format_time, get_class_name};
+
+/// Timeline buckets are widened until the startup timeline fits in this many rows.
+const MAX_TIMELINE_BUCKETS: usize = 20;
+
+/// A load without `ClassPrepare` this long after its first event failed (e.g. a
+/// `VerifyError` or `LinkageError`) and is dropped.
+const PENDING_LOAD_TIMEOUT_NANOS: u64 = 10_000_000_000;
+
+/// In-flight loads tracked per thread; nesting (superclasses, interfaces) stays far below.
+const MAX_PENDING_CLASS_LOADS: usize = 256;
+
+/// Load timings for a single class, from its first event to `ClassPrepare`
+#[derive(Clone, Debug)]
+pub(crate) struct ClassLoadRecord {
+    pub(crate) class_name: String,
+    pub(crate) loader_name: String,
+    pub(crate) loaded_at_nanos: u64,
+    /// `ClassFileLoadHook` to `ClassLoad`: parsing and defining the class
+    pub(crate) define_nanos: u64,
+    /// `ClassLoad` to `ClassPrepare`: linking fields and methods
+    pub(crate) prepare_nanos: u64,
+}
+
+impl ClassLoadRecord {
+    pub(crate) fn total_nanos(&self) -> u64 {
+        self.define_nanos + self.prepare_nanos
+    }
+}
+
+/// Timestamps for a class still being loaded on this thread
+#[derive(Clone, Copy, Default, Debug)]
+struct PendingClassLoad {
+    hook_nanos: Option<u64>,
+    load_nanos: Option<u64>,
+}
+
+impl PendingClassLoad {
+    fn started_nanos(&self) -> u64 {
+        self.hook_nanos.or(self.load_nanos).unwrap_or(0)
+    }
+}
+
+pub(crate) static CLASS_LOADS: Lazy<Mutex<Vec<ClassLoadRecord>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+// Classes are loaded on the requesting thread, so in-flight loads are tracked per thread,
+// keyed by internal name (`java/lang/String`)
+thread_local! {
+    static PENDING_CLASS_LOADS: RefCell<HashMap<String, PendingClassLoad>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Drop loads that never reached `ClassPrepare`, then the oldest ones while the map is
+/// full, so failed loads don't pile up on long-lived threads.
+fn evict_stale_loads(pending: &mut HashMap<String, PendingClassLoad>, now: u64) {
+    pending.retain(|_, load| now.saturating_sub(load.started_nanos()) < PENDING_LOAD_TIMEOUT_NANOS);
+    while pending.len() >= MAX_PENDING_CLASS_LOADS {
+        let Some(oldest) = pending
+            .iter()
+            .min_by_key(|(_, load)| load.started_nanos())
+            .map(|(name, _)| name.clone())
+        else {
+            break;
+        };
+        pending.remove(&oldest);
+    }
+}
+
+/// Internal class name (`java/lang/String`) from a JVMTI signature (`Ljava/lang/String;`).
+fn internal_name(jvmti_env: *mut jvmtiEnv, klass: jclass) -> Option<String> {
+    unsafe {
+        let mut sig_ptr: *mut c_char = std::ptr::null_mut();
+        let res = (**jvmti_env).GetClassSignature.unwrap()(
+            jvmti_env,
+            klass,
+            &mut sig_ptr,
+            std::ptr::null_mut(),
+        );
+        if res != jvmtiError_JVMTI_ERROR_NONE || sig_ptr.is_null() {
+            return None;
+        }
+
+        let sig = CStr::from_ptr(sig_ptr).to_string_lossy().into_owned();
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, sig_ptr as *mut u8);
+        Some(
+            sig.strip_prefix('L')
+                .and_then(|s| s.strip_suffix(';'))
+                .unwrap_or(&sig)
+                .to_string(),
+        )
+    }
+}
+
+/// Class name of the loader that defined `klass`, or `<bootstrap>`.
+fn loader_name(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, klass: jclass) -> String {
+    unsafe {
+        let mut loader: jobject = std::ptr::null_mut();
+        let res = (**jvmti_env).GetClassLoader.unwrap()(jvmti_env, klass, &mut loader);
+        if res != jvmtiError_JVMTI_ERROR_NONE {
+            return "<unknown>".to_string();
+        }
+        if loader.is_null() {
+            return "<bootstrap>".to_string();
+        }
+
+        let loader_class = (**jni_env).GetObjectClass.unwrap()(jni_env, loader);
+        let name = get_class_name(jvmti_env, loader_class);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, loader_class);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, loader);
+        name
+    }
+}
+
+pub(crate) extern "C" fn class_file_load_hook_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    class_being_redefined: jclass,
+    _loader: jobject,
+    name: *const c_char,
+    _protection_domain: jobject,
+    _class_data_len: jint,
+    _class_data: *const c_uchar,
+    _new_class_data_len: *mut jint,
+    _new_class_data: *mut *mut c_uchar,
+) {
+    // Redefinitions and anonymous (hidden) classes aren't part of normal loading
+    if !class_being_redefined.is_null() || name.is_null() {
+        return;
+    }
+
+    let now = elapsed_nanos(jvmti_env);
+    let name = unsafe { CStr::from_ptr(name).to_string_lossy().into_owned() };
+    PENDING_CLASS_LOADS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        evict_stale_loads(&mut pending, now);
+        pending.entry(name).or_default().hook_nanos = Some(now);
+    });
+}
+
+pub(crate) extern "C" fn class_load_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _thread: jthread,
+    klass: jclass,
+) {
+    let now = elapsed_nanos(jvmti_env);
+    if let Some(name) = internal_name(jvmti_env, klass) {
+        PENDING_CLASS_LOADS.with(|pending| {
+            let mut pending = pending.borrow_mut();
+            if !pending.contains_key(&name) {
+                evict_stale_loads(&mut pending, now);
+            }
+            pending.entry(name).or_default().load_nanos = Some(now);
+        });
+    }
+}
+
+pub(crate) extern "C" fn class_prepare_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    _thread: jthread,
+    klass: jclass,
+) {
+    create_method_ids(jvmti_env, klass);
+    let now = elapsed_nanos(jvmti_env);
+    let name = match internal_name(jvmti_env, klass) {
+        Some(name) => name,
+        None => return,
+    };
+
+    let timing = PENDING_CLASS_LOADS
+        .with(|pending| pending.borrow_mut().remove(&name))
+        .unwrap_or_default();
+    let load_nanos = timing.load_nanos.unwrap_or(now);
+    let hook_nanos = timing.hook_nanos.unwrap_or(load_nanos);
+
+    let record = ClassLoadRecord {
+        class_name: name.replace('/', "."),
+        loader_name: loader_name(jvmti_env, jni_env, klass),
+        loaded_at_nanos: hook_nanos,
+        define_nanos: load_nanos.saturating_sub(hook_nanos),
+        prepare_nanos: now.saturating_sub(load_nanos),
+    };
+    CLASS_LOADS.lock().unwrap().push(record);
+}
+
+/// Number of classes loaded per time bucket, with the bucket width chosen so the whole
+/// run fits in `max_buckets` rows (widths step through 1/2/5 x 10^n milliseconds).
+fn startup_timeline(load_times: &[u64], max_buckets: usize) -> (u64, Vec<u64>) {
+    let last = load_times.iter().copied().max().unwrap_or(0);
+    let mut decade = 1_000_000u64;
+    let mut width = decade;
+    for &factor in [1u64, 2, 5].iter().cycle() {
+        width = decade * factor;
+        if last / width < max_buckets as u64 {
+            break;
+        }
+        if factor == 5 {
+            decade *= 10;
+        }
+    }
+
+    let mut buckets = vec![0u64; (last / width + 1) as usize];
+    for &t in load_times {
+        buckets[(t / width) as usize] += 1;
+    }
+    (width, buckets)
+}
+
+/// Print class counts per loader, the slowest classes and a startup timeline.
+pub(crate) fn print_class_loading_summary() {
+    let loads = CLASS_LOADS.lock().unwrap();
+    if loads.is_empty() {
+        return;
+    }
+
+    let total_time: u64 = loads.iter().map(|r| r.total_nanos()).sum();
+    println!("\n📚 === Class loading ===");
+    println!(
+        "{} classes loaded, {} spent defining and preparing",
+        loads.len(),
+        format_time(total_time)
+    );
+
+    let mut by_loader: HashMap<&str, (u64, u64)> = HashMap::new();
+    for record in loads.iter() {
+        let entry = by_loader.entry(&record.loader_name).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += record.total_nanos();
+    }
+    let mut loaders: Vec<_> = by_loader.into_iter().collect();
+    loaders.sort_by_key(|&(_, (count, _))| std::cmp::Reverse(count));
+    for (loader, (count, nanos)) in loaders {
+        println!(
+            "{:<60} {:>6} classes, {:>8}",
+            loader,
+            count,
+            format_time(nanos)
+        );
+    }
+
+    let mut slowest: Vec<&ClassLoadRecord> = loads.iter().collect();
+    slowest.sort_by_key(|r| std::cmp::Reverse(r.total_nanos()));
+    println!("\n🐌 Slowest classes to load:");
+    for record in slowest.iter().take(10) {
+        println!(
+            "{:<60} {:>8} (define {}, prepare {})",
+            record.class_name,
+            format_time(record.total_nanos()),
+            format_time(record.define_nanos),
+            format_time(record.prepare_nanos)
+        );
+    }
+
+    let load_times: Vec<u64> = loads.iter().map(|r| r.loaded_at_nanos).collect();
+    let (width, buckets) = startup_timeline(&load_times, MAX_TIMELINE_BUCKETS);
+    let max_count = buckets.iter().copied().max().unwrap_or(1).max(1);
+    println!("\n🕒 Class loading timeline:");
+    for (i, &count) in buckets.iter().enumerate() {
+        let bar_len = (count * 40).div_ceil(max_count) as usize;
+        println!(
+            "{:>8} - {:<8} {:>6} {}",
+            format_time(i as u64 * width),
+            format_time((i as u64 + 1) * width),
+            count,
+            "#".repeat(bar_len)
+        );
+    }
+}
+
+/// Write every class load in order, with its loader and timings.
+pub(crate) fn write_class_loading(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let loads = CLASS_LOADS.lock().unwrap();
+    let mut file = File::create(path)?;
+
+    writeln!(
+        file,
+        "{:>12} {:>10} {:>10} {:<50} class name",
+        "at", "define", "prepare", "loader"
+    )?;
+    for record in loads.iter() {
+        writeln!(
+            file,
+            "{:>12} {:>10} {:>10} {:<50} {}",
+            format_time(record.loaded_at_nanos),
+            format_time(record.define_nanos),
+            format_time(record.prepare_nanos),
+            record.loader_name,
+            record.class_name
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_timeline_widens_buckets() {
+        let times = [0, 500_000, 1_500_000, 3_000_000];
+        let (width, buckets) = startup_timeline(&times, 20);
+        assert_eq!(width, 1_000_000);
+        assert_eq!(buckets, vec![2, 1, 0, 1]);
+
+        let times = [0, 45_000_000, 99_000_000];
+        let (width, buckets) = startup_timeline(&times, 20);
+        assert_eq!(width, 5_000_000);
+        assert_eq!(buckets.len(), 20);
+        assert_eq!(buckets.iter().sum::<u64>(), 3);
+    }
+
+    #[test]
+    fn test_evict_stale_loads() {
+        let started = |nanos| PendingClassLoad {
+            hook_nanos: Some(nanos),
+            load_nanos: None,
+        };
+        let mut pending = HashMap::new();
+        pending.insert("a/Failed".to_string(), started(0));
+        pending.insert("a/Loading".to_string(), started(PENDING_LOAD_TIMEOUT_NANOS));
+        evict_stale_loads(&mut pending, PENDING_LOAD_TIMEOUT_NANOS + 1);
+        assert_eq!(pending.keys().collect::<Vec<_>>(), vec!["a/Loading"]);
+
+        let mut pending: HashMap<String, PendingClassLoad> = (0..MAX_PENDING_CLASS_LOADS as u64)
+            .map(|i| (format!("a/C{}", i), started(i)))
+            .collect();
+        evict_stale_loads(&mut pending, MAX_PENDING_CLASS_LOADS as u64);
+        assert_eq!(pending.len(), MAX_PENDING_CLASS_LOADS - 1);
+        assert!(!pending.contains_key("a/C0"));
+    }
+}
@@ -0,0 +1,226 @@
+use serde_json::json;
+use std::fs::{self, File};
+use std::os::raw::c_void;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::options::{agent_options, output_path};
+use crate::profiling::pprof::encode_profile;
+use crate::profiling::profiling::{format_folded, stacks_since, SampleMark};
+use crate::profiling::pyroscope::{queue_upload, start_pyroscope_uploader, upload_last_profile};
+use crate::profiling::session::{lock_session, reset_stats, session_active};
+use crate::profiling::snapshot::{format_timestamp, parse_timestamp, stats_json};
+
+/// File name prefix of interval profiles: `profile-<timestamp>.folded`, `.json` and `.pb`
+const PROFILE_PREFIX: &str = "profile-";
+
+const PROFILE_EXTENSIONS: [&str; 3] = ["folded", "json", "pb"];
+
+/// How long profiles are kept unless `retain` says otherwise
+const DEFAULT_RETAIN_SECS: u64 = 24 * 3600;
+
+// Start of the running interval, in seconds since the epoch
+static INTERVAL_STARTED: AtomicU64 = AtomicU64::new(0);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Start of the interval a profile covers, from its file name.
+fn profile_timestamp(file_name: &str) -> Option<u64> {
+    let stem = file_name.strip_prefix(PROFILE_PREFIX)?;
+    let (timestamp, extension) = stem.rsplit_once('.')?;
+    if !PROFILE_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    // Two intervals starting within a second get a `-N` suffix
+    let timestamp = timestamp.split_once('-').map_or(timestamp, |(ts, _)| ts);
+    parse_timestamp(timestamp)
+}
+
+/// The interval profiles among `file_names` that started more than `retain_secs` before `now`.
+pub(crate) fn expired_profiles<'a>(
+    file_names: impl IntoIterator<Item = &'a str>,
+    now: u64,
+    retain_secs: u64,
+) -> Vec<&'a str> {
+    file_names
+        .into_iter()
+        .filter(|name| profile_timestamp(name).is_some_and(|started| started + retain_secs < now))
+        .collect()
+}
+
+/// Delete interval profiles older than the retention period from the output directory.
+fn delete_expired_profiles(retain_secs: u64) {
+    let dir_path = output_path(".");
+    let Ok(entries) = fs::read_dir(&dir_path) else {
+        return;
+    };
+    let names: Vec<String> = entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    for name in expired_profiles(names.iter().map(String::as_str), unix_now(), retain_secs) {
+        let path = output_path(name);
+        if let Err(e) = fs::remove_file(&path) {
+            eprintln!("Error deleting expired profile {}: {}", path, e);
+        }
+    }
+}
+
+/// Files written for one interval
+pub(crate) struct IntervalProfile {
+    /// Path without extension, e.g. `<output>/profile-20261018T153012Z`
+    pub(crate) base: String,
+    pub(crate) from_secs: u64,
+    pub(crate) until_secs: u64,
+    /// Folded stacks, as written to `<base>.folded`
+    pub(crate) folded: String,
+    /// pprof encoding, as written to `<base>.pb`
+    pub(crate) pprof: Vec<u8>,
+}
+
+/// Write the profile of the current interval and reset the stats for the next one.
+pub(crate) fn write_interval_profile(
+    jvmti_env: *mut jvmtiEnv,
+) -> Result<IntervalProfile, Box<dyn std::error::Error>> {
+    let _session = lock_session();
+    let until_secs = unix_now();
+    let from_secs = INTERVAL_STARTED.swap(until_secs, Ordering::Relaxed);
+    let timestamp = format_timestamp(from_secs);
+
+    let mut base = output_path(&format!("{}{}", PROFILE_PREFIX, timestamp));
+    let mut seq = 1;
+    while Path::new(&format!("{}.json", base)).exists() {
+        seq += 1;
+        base = output_path(&format!("{}{}-{}", PROFILE_PREFIX, timestamp, seq));
+    }
+
+    let stacks = stacks_since(SampleMark::default());
+    let folded = format_folded(&stacks);
+    let pprof = encode_profile(
+        &stacks,
+        ("wall", "nanoseconds"),
+        from_secs * 1_000_000_000,
+        until_secs.saturating_sub(from_secs) * 1_000_000_000,
+    );
+    fs::write(format!("{}.folded", base), &folded)?;
+    fs::write(format!("{}.pb", base), &pprof)?;
+
+    let mut profile = stats_json(jvmti_env);
+    profile["from"] = json!(timestamp);
+    profile["until"] = json!(format_timestamp(until_secs));
+    serde_json::to_writer_pretty(File::create(format!("{}.json", base))?, &profile)?;
+
+    reset_stats(jvmti_env);
+    Ok(IntervalProfile {
+        base,
+        from_secs,
+        until_secs,
+        folded,
+        pprof,
+    })
+}
+
+unsafe extern "C" fn continuous_profiler(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    let options = agent_options();
+    let Some(interval_secs) = options.continuous_interval_secs else {
+        return;
+    };
+    let retain_secs = options.retain_secs.unwrap_or(DEFAULT_RETAIN_SECS);
+
+    let interval = Duration::from_secs(interval_secs);
+
+    // The first interval starts with the stats of the session so far
+    INTERVAL_STARTED.store(unix_now(), Ordering::Relaxed);
+    // Deadlines are absolute, so the time spent writing a profile doesn't push the next
+    // one back
+    let mut deadline = Instant::now() + interval;
+    while !sleep_unless_shut_down(deadline.saturating_duration_since(Instant::now())) {
+        deadline += interval;
+        // After a long stall, skip the intervals that were missed rather than rush them
+        let now = Instant::now();
+        if deadline <= now {
+            deadline = now + interval;
+        }
+
+        // Nothing is recorded while a session is stopped
+        if !session_active() {
+            INTERVAL_STARTED.store(unix_now(), Ordering::Relaxed);
+            continue;
+        }
+        match write_interval_profile(jvmti_env) {
+            Ok(profile) => {
+                println!("🔁 Interval profile written to {}.json", profile.base);
+                delete_expired_profiles(retain_secs);
+                queue_upload(&profile);
+            }
+            Err(e) => eprintln!("Error writing interval profile: {}", e),
+        }
+    }
+}
+
+/// Write the last, partial interval at VM death.
+pub(crate) fn finish_continuous_profile(jvmti_env: *mut jvmtiEnv) {
+    if agent_options().continuous_interval_secs.is_none() || !session_active() {
+        return;
+    }
+    match write_interval_profile(jvmti_env) {
+        Ok(profile) => upload_last_profile(&profile),
+        Err(e) => eprintln!("Error writing interval profile: {}", e),
+    }
+}
+
+/// With `continuous=<secs>`, write a profile every interval and reset the stats.
+pub(crate) fn start_continuous_profiler(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if agent_options().continuous_interval_secs.is_none() {
+        return;
+    }
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-continuous",
+        continuous_profiler,
+        ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start continuous profiler: {}", e);
+        return;
+    }
+    start_pyroscope_uploader(jvmti_env, jni_env);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expired_profiles() {
+        // 20261018T153012Z is 1_792_337_412
+        let names = [
+            "profile-20261018T153012Z.folded",
+            "profile-20261018T153012Z-2.json",
+            "profile-20261018T163012Z.pb",
+            "profile-20261018T153012Z.txt",
+            "snapshot-20261018T153012Z.json",
+            "flamegraph.folded",
+        ];
+        let now = 1_792_337_412 + 3600 + 1;
+        assert_eq!(
+            expired_profiles(names, now, 3600),
+            vec![
+                "profile-20261018T153012Z.folded",
+                "profile-20261018T153012Z-2.json"
+            ]
+        );
+        assert!(expired_profiles(names, now, 7200).is_empty());
+    }
+}
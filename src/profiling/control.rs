@@ -0,0 +1,432 @@
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::raw::c_void;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::filter::{excluded_prefixes, set_excluded_prefixes};
+use crate::profiling::heap::{collect_heap_histogram, heap_histogram_json};
+use crate::profiling::options::{agent_options, AgentOptions, SessionCommand};
+use crate::profiling::profiling::{elapsed_nanos, folded_stacks};
+use crate::profiling::session::{
+    lock_session, reset_stats, run_command, session_active, session_generation,
+};
+use crate::profiling::snapshot::{stats_json, write_snapshot};
+use crate::profiling::thread_dump::{take_thread_dump, thread_dump_json};
+
+/// Classes in the histogram of `dump heap`
+const HEAP_TOP_N: usize = 100;
+
+/// How often the server checks for connections while idle
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a client may leave a request half-sent, or its response unread, before we
+/// hang up on it; clients are served one at a time
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What `dump` hands back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Write every report file into the output directory
+    Reports,
+    /// Write a `snapshot-<timestamp>` pair into the output directory
+    Snapshot,
+    /// Return the folded stacks in the response
+    Folded,
+    /// Return the snapshot stats in the response
+    Json,
+    /// Return a live heap histogram in the response (forces a GC and pauses the JVM)
+    Heap,
+}
+
+impl DumpFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "reports" => Ok(DumpFormat::Reports),
+            "snapshot" => Ok(DumpFormat::Snapshot),
+            "folded" => Ok(DumpFormat::Folded),
+            "json" => Ok(DumpFormat::Json),
+            "heap" => Ok(DumpFormat::Heap),
+            _ => Err(format!(
+                "Unknown dump format: {} (expected reports, snapshot, folded, json or heap)",
+                name
+            )),
+        }
+    }
+}
+
+/// One line of the control protocol, e.g. `{"command":"dump","format":"folded"}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControlRequest {
+    Status,
+    Start { duration_secs: Option<u64> },
+    Stop,
+    Dump { format: DumpFormat },
+    Reset,
+    SetFilter { exclude: Vec<String> },
+    Threads,
+}
+
+impl ControlRequest {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let request: Value =
+            serde_json::from_str(line).map_err(|e| format!("Invalid request: {}", e))?;
+        let command = request["command"]
+            .as_str()
+            .ok_or("Request has no \"command\"")?;
+        match command {
+            "status" => Ok(ControlRequest::Status),
+            "start" => {
+                let duration_secs = match &request["duration_secs"] {
+                    Value::Null => None,
+                    value => Some(
+                        value
+                            .as_u64()
+                            .filter(|&secs| secs > 0)
+                            .ok_or("Invalid duration_secs")?,
+                    ),
+                };
+                Ok(ControlRequest::Start { duration_secs })
+            }
+            "stop" => Ok(ControlRequest::Stop),
+            "dump" => {
+                let format = request["format"].as_str().unwrap_or("reports");
+                Ok(ControlRequest::Dump {
+                    format: DumpFormat::parse(format)?,
+                })
+            }
+            "reset" => Ok(ControlRequest::Reset),
+            "set-filter" => {
+                let exclude = match &request["exclude"] {
+                    Value::Null => Vec::new(),
+                    Value::Array(prefixes) => prefixes
+                        .iter()
+                        .map(|p| p.as_str().map(str::to_string))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or("\"exclude\" must be a list of strings")?,
+                    _ => return Err("\"exclude\" must be a list of strings".to_string()),
+                };
+                Ok(ControlRequest::SetFilter { exclude })
+            }
+            "threads" => Ok(ControlRequest::Threads),
+            _ => Err(format!("Unknown command: {}", command)),
+        }
+    }
+}
+
+fn session_command(
+    jni_env: *mut JNIEnv,
+    command: SessionCommand,
+    duration_secs: Option<u64>,
+) -> Result<(), String> {
+    let options = AgentOptions {
+        command: Some(command),
+        duration_secs,
+        ..Default::default()
+    };
+    run_command(jni_env, &options)
+}
+
+/// Run one request and return the fields of its response.
+fn handle_request(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    request: ControlRequest,
+) -> Result<Value, String> {
+    match request {
+        ControlRequest::Status => Ok(json!({
+            "pid": std::process::id(),
+            "session": session_generation(),
+            "active": session_active(),
+            "elapsed_nanos": elapsed_nanos(jvmti_env),
+            "exclude": excluded_prefixes(),
+        })),
+        ControlRequest::Start { duration_secs } => {
+            session_command(jni_env, SessionCommand::Start, duration_secs)?;
+            Ok(json!({ "session": session_generation() }))
+        }
+        ControlRequest::Stop => {
+            session_command(jni_env, SessionCommand::Stop, None)?;
+            Ok(json!({ "session": session_generation() }))
+        }
+        ControlRequest::Dump { format } => match format {
+            DumpFormat::Reports => {
+                session_command(jni_env, SessionCommand::Dump, None)?;
+                Ok(json!({}))
+            }
+            DumpFormat::Snapshot => {
+                let base = write_snapshot(jvmti_env, jni_env).map_err(|e| e.to_string())?;
+                Ok(json!({ "path": format!("{}.json", base) }))
+            }
+            DumpFormat::Folded => {
+                let _session = lock_session();
+                Ok(json!({ "folded": folded_stacks() }))
+            }
+            DumpFormat::Json => {
+                let _session = lock_session();
+                Ok(json!({ "stats": stats_json(jvmti_env) }))
+            }
+            DumpFormat::Heap => {
+                let _session = lock_session();
+                let histogram = collect_heap_histogram(jvmti_env, jni_env)?;
+                Ok(json!({ "heap": heap_histogram_json(&histogram, HEAP_TOP_N) }))
+            }
+        },
+        ControlRequest::Reset => {
+            let _session = lock_session();
+            reset_stats(jvmti_env);
+            Ok(json!({}))
+        }
+        ControlRequest::SetFilter { exclude } => {
+            set_excluded_prefixes(exclude);
+            Ok(json!({ "exclude": excluded_prefixes() }))
+        }
+        ControlRequest::Threads => {
+            let dump = take_thread_dump(jvmti_env, jni_env)?;
+            Ok(json!({ "dump": thread_dump_json(&dump) }))
+        }
+    }
+}
+
+/// `{"ok":true,...}` with the handler's fields, or `{"ok":false,"error":...}`.
+fn response(result: Result<Value, String>) -> Value {
+    match result {
+        Ok(mut fields) => {
+            fields["ok"] = json!(true);
+            fields
+        }
+        Err(error) => json!({ "ok": false, "error": error }),
+    }
+}
+
+/// Answer each request line of one client until it disconnects.
+fn serve_client(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, stream: UnixStream) {
+    if stream.set_read_timeout(Some(CLIENT_TIMEOUT)).is_err()
+        || stream.set_write_timeout(Some(CLIENT_TIMEOUT)).is_err()
+    {
+        return;
+    }
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = ControlRequest::parse(&line)
+            .and_then(|request| handle_request(jvmti_env, jni_env, request));
+        if writeln!(writer, "{}", response(result)).is_err() {
+            return;
+        }
+    }
+}
+
+unsafe extern "C" fn control_server(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    arg: *mut c_void,
+) {
+    let listener = Box::from_raw(arg as *mut UnixListener);
+    // Polled, so the thread notices a detach between clients
+    if let Err(e) = listener.set_nonblocking(true) {
+        eprintln!("Error setting up the control socket: {}", e);
+        return;
+    }
+    // One client at a time: commands change the session and must not interleave
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if stream.set_nonblocking(false).is_ok() {
+                    serve_client(jvmti_env, jni_env, stream);
+                }
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("Error accepting control connection: {}", e),
+        }
+        if sleep_unless_shut_down(ACCEPT_POLL_INTERVAL) {
+            return;
+        }
+    }
+}
+
+/// Device and inode of the socket we bound, so we never remove one bound by another JVM
+static BOUND_SOCKET: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+fn file_identity(path: &str) -> Option<(u64, u64)> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Remove a socket left at `path` by a JVM that is gone. A socket someone still listens
+/// on, or anything that is not a socket, is left alone: the path may be a typo for a
+/// real file.
+fn remove_stale_socket(path: &str) -> Result<(), String> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => Err(format!("Control socket {} already in use", path)),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                fs::remove_file(path).map_err(|e| format!("Cannot remove {}: {}", path, e))
+            }
+            Err(e) => Err(format!("Cannot check {}: {}", path, e)),
+        },
+        Ok(_) => Err(format!("{} exists and is not a socket", path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Cannot inspect {}: {}", path, e)),
+    }
+}
+
+/// Remove the socket at `path` if it is still the one we bound.
+fn remove_bound_socket(path: &str) {
+    let mut bound = BOUND_SOCKET.lock().unwrap();
+    if bound.is_some() && file_identity(path) == *bound {
+        let _ = fs::remove_file(path);
+        *bound = None;
+    }
+}
+
+/// Bind the socket at `path`, replacing a stale one, readable by our user only.
+fn bind_control_socket(path: &str) -> Result<UnixListener, String> {
+    remove_stale_socket(path)?;
+    // Created 0600 rather than restricted after the bind, so no other user can connect
+    // in between. The umask is process-wide, so keep the window to the bind itself.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.map_err(|e| format!("Cannot bind {}: {}", path, e))?;
+    *BOUND_SOCKET.lock().unwrap() = file_identity(path);
+    Ok(listener)
+}
+
+/// With `control=<path>`, serve the control protocol on a Unix socket at `path`.
+pub(crate) fn start_control_server(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    let Some(path) = &agent_options().control_socket else {
+        return;
+    };
+    let listener = match bind_control_socket(path) {
+        Ok(listener) => Box::into_raw(Box::new(listener)),
+        Err(e) => {
+            eprintln!("Failed to open control socket: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-control",
+        control_server,
+        listener as *mut c_void,
+    ) {
+        eprintln!("Failed to start control server: {}", e);
+        drop(unsafe { Box::from_raw(listener) });
+        remove_bound_socket(path);
+        return;
+    }
+    println!("🎛️  Control socket listening on {}", path);
+}
+
+/// Remove the control socket file at VM death, unless another JVM has taken the path.
+pub(crate) fn close_control_socket() {
+    if let Some(path) = &agent_options().control_socket {
+        remove_bound_socket(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_control_request() {
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"status"}"#).unwrap(),
+            ControlRequest::Status
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"start","duration_secs":30}"#).unwrap(),
+            ControlRequest::Start {
+                duration_secs: Some(30)
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"dump"}"#).unwrap(),
+            ControlRequest::Dump {
+                format: DumpFormat::Reports
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"dump","format":"folded"}"#).unwrap(),
+            ControlRequest::Dump {
+                format: DumpFormat::Folded
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"dump","format":"heap"}"#).unwrap(),
+            ControlRequest::Dump {
+                format: DumpFormat::Heap
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"set-filter","exclude":["java.","sun."]}"#)
+                .unwrap(),
+            ControlRequest::SetFilter {
+                exclude: vec!["java.".to_string(), "sun.".to_string()]
+            }
+        );
+        assert_eq!(
+            ControlRequest::parse(r#"{"command":"set-filter"}"#).unwrap(),
+            ControlRequest::SetFilter { exclude: vec![] }
+        );
+
+        assert!(ControlRequest::parse("status").is_err());
+        assert!(ControlRequest::parse(r#"{"command":"pause"}"#).is_err());
+        assert!(ControlRequest::parse(r#"{"command":"dump","format":"svg"}"#).is_err());
+        assert!(ControlRequest::parse(r#"{"command":"start","duration_secs":0}"#).is_err());
+        assert!(ControlRequest::parse(r#"{"command":"set-filter","exclude":"java."}"#).is_err());
+    }
+
+    #[test]
+    fn test_bind_control_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("rjprof-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("ctl.sock");
+        let path = path.to_str().unwrap();
+
+        let first = bind_control_socket(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // A socket still listened on is not taken over
+        assert!(bind_control_socket(path)
+            .unwrap_err()
+            .contains("already in use"));
+        // A socket left behind by an earlier run is replaced
+        drop(first);
+        let _second = bind_control_socket(path).unwrap();
+
+        // Another JVM's socket at the same path is not ours to remove
+        fs::remove_file(path).unwrap();
+        let _other = UnixListener::bind(path).unwrap();
+        remove_bound_socket(path);
+        assert!(fs::symlink_metadata(path).is_ok());
+        *BOUND_SOCKET.lock().unwrap() = file_identity(path);
+        remove_bound_socket(path);
+        assert!(fs::symlink_metadata(path).is_err());
+
+        let file = dir.join("notes.txt");
+        fs::write(&file, "keep me").unwrap();
+        assert!(bind_control_socket(file.to_str().unwrap()).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,536 @@
+use once_cell::sync::{Lazy, OnceCell};
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::native::frame_name;
+use crate::profiling::native_symbols::{code_mappings, find_mapping, frame_symbol, Mapping};
+use crate::profiling::options::agent_options;
+use crate::profiling::process_cpu::ThreadCategory;
+use crate::profiling::profiling::{MethodId, JNI_VERSION_1_8};
+
+/// Delivered after every `CPU_SAMPLE_INTERVAL` of CPU time used by the process, to the
+/// thread that is running
+const CPU_SAMPLE_SIGNAL: c_int = libc::SIGPROF;
+
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the sampler thread moves samples out of the signal handler's buffer
+const DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Samples the signal handler can hold until the sampler thread drains them
+const SAMPLE_SLOTS: usize = 1024;
+
+const MAX_NATIVE_FRAMES: usize = 64;
+const MAX_JAVA_FRAMES: usize = 128;
+
+/// Largest step up the stack between two frame records; a bigger one means the frame
+/// pointer chain is broken (code compiled without frame pointers)
+const MAX_FRAME_SIZE: usize = 1 << 20;
+
+// States of a sample slot
+const FREE: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+/// `ASGCT_CallFrame`: a bytecode index (negative for native frames; unused) and method
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CallFrame {
+    _bci: jint,
+    method: jmethodID,
+}
+
+/// `ASGCT_CallTrace`; `num_frames` is negative when the thread can't be walked
+#[repr(C)]
+struct CallTrace {
+    env: *mut JNIEnv,
+    num_frames: jint,
+    frames: *mut CallFrame,
+}
+
+type AsyncGetCallTrace = unsafe extern "C" fn(*mut CallTrace, jint, *mut c_void);
+
+/// One stack as recorded by the signal handler
+struct RawSample {
+    tid: libc::pid_t,
+    /// The interrupted pc, then return addresses up the frame pointer chain
+    native: [usize; MAX_NATIVE_FRAMES],
+    native_count: usize,
+    /// Java frames, leaf first
+    java: [CallFrame; MAX_JAVA_FRAMES],
+    java_count: jint,
+}
+
+struct Slot {
+    state: AtomicU8,
+    sample: UnsafeCell<RawSample>,
+}
+
+// A slot's sample is only touched by whoever moved its state to WRITING or saw READY
+unsafe impl Send for Slot {}
+unsafe impl Sync for Slot {}
+
+/// A sampled stack: the thread name (`comm`) when it had no Java frames, the Java frames (root
+/// first) and the native addresses (leaf first, return addresses already moved back
+/// into their call instruction)
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct SampledStack {
+    thread: Option<String>,
+    java: Vec<MethodId>,
+    native: Vec<usize>,
+}
+
+struct AsyncGetCallTraceFn(AsyncGetCallTrace);
+
+static SLOTS: OnceCell<Box<[Slot]>> = OnceCell::new();
+/// Whether the timer is armed; a signal still pending after disarming it is ignored
+static SAMPLING: AtomicBool = AtomicBool::new(false);
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static DROPPED_SAMPLES: AtomicU64 = AtomicU64::new(0);
+static JAVA_VM: AtomicPtr<JavaVM> = AtomicPtr::new(ptr::null_mut());
+static ASYNC_GET_CALL_TRACE: OnceCell<AsyncGetCallTraceFn> = OnceCell::new();
+static CPU_SAMPLES: Lazy<Mutex<HashMap<SampledStack, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Remember the VM, so the signal handler can find the JNI environment of a thread.
+pub(crate) fn set_java_vm(vm: *mut JavaVM) {
+    JAVA_VM.store(vm, Ordering::Relaxed);
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe fn registers(context: *mut c_void) -> (usize, usize, usize) {
+    let gregs = &(*(context as *const libc::ucontext_t)).uc_mcontext.gregs;
+    (
+        gregs[libc::REG_RIP as usize] as usize,
+        gregs[libc::REG_RBP as usize] as usize,
+        gregs[libc::REG_RSP as usize] as usize,
+    )
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn registers(context: *mut c_void) -> (usize, usize, usize) {
+    let mcontext = &(*(context as *const libc::ucontext_t)).uc_mcontext;
+    (
+        mcontext.pc as usize,
+        mcontext.regs[29] as usize,
+        mcontext.sp as usize,
+    )
+}
+
+/// The frame record at `fp`: the caller's frame pointer and the return address. Read
+/// with process_vm_readv, which fails on a bad address instead of faulting.
+unsafe fn read_frame_record(fp: usize) -> Option<[usize; 2]> {
+    let mut record = [0usize; 2];
+    let len = std::mem::size_of_val(&record);
+    let local = libc::iovec {
+        iov_base: record.as_mut_ptr() as *mut c_void,
+        iov_len: len,
+    };
+    let remote = libc::iovec {
+        iov_base: fp as *mut c_void,
+        iov_len: len,
+    };
+    let read = libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0);
+    (read == len as isize).then_some(record)
+}
+
+/// Walk the frame pointer chain from the interrupted context; returns the frames written.
+unsafe fn walk_native_stack(context: *mut c_void, pcs: &mut [usize]) -> usize {
+    let (pc, mut fp, sp) = registers(context);
+    pcs[0] = pc;
+    let mut count = 1;
+    while count < pcs.len() && fp >= sp && fp % std::mem::align_of::<usize>() == 0 {
+        let Some([next, return_address]) = read_frame_record(fp) else {
+            break;
+        };
+        if return_address == 0 {
+            break;
+        }
+        pcs[count] = return_address;
+        count += 1;
+        if next <= fp || next - fp > MAX_FRAME_SIZE {
+            break;
+        }
+        fp = next;
+    }
+    count
+}
+
+/// Java frames of the interrupted thread, or 0 if it isn't a Java thread.
+unsafe fn walk_java_stack(context: *mut c_void, frames: &mut [CallFrame]) -> jint {
+    let vm = JAVA_VM.load(Ordering::Relaxed);
+    let Some(AsyncGetCallTraceFn(async_get_call_trace)) = ASYNC_GET_CALL_TRACE.get() else {
+        return 0;
+    };
+    let mut env: *mut JNIEnv = ptr::null_mut();
+    if vm.is_null()
+        || (**vm).GetEnv.unwrap()(
+            vm,
+            (&mut env) as *mut *mut JNIEnv as *mut *mut c_void,
+            JNI_VERSION_1_8,
+        ) != JNI_OK as jint
+    {
+        return 0;
+    }
+    let mut trace = CallTrace {
+        env,
+        num_frames: 0,
+        frames: frames.as_mut_ptr(),
+    };
+    async_get_call_trace(&mut trace, frames.len() as jint, context);
+    trace.num_frames
+}
+
+/// Record the interrupted thread's stacks into a free slot. Async-signal-safe: no locks
+/// and no allocation; a sample is dropped when the sampler thread falls behind.
+extern "C" fn cpu_sample_handler(
+    _signal: c_int,
+    _info: *mut libc::siginfo_t,
+    context: *mut c_void,
+) {
+    let Some(slots) = SLOTS.get() else {
+        return;
+    };
+    if !SAMPLING.load(Ordering::Relaxed) {
+        return;
+    }
+    let slot = &slots[NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % slots.len()];
+    if slot
+        .state
+        .compare_exchange(FREE, WRITING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        DROPPED_SAMPLES.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    unsafe {
+        let saved_errno = *libc::__errno_location();
+        let sample = &mut *slot.sample.get();
+        sample.tid = libc::syscall(libc::SYS_gettid) as libc::pid_t;
+        sample.native_count = walk_native_stack(context, &mut sample.native);
+        sample.java_count = walk_java_stack(context, &mut sample.java);
+        *libc::__errno_location() = saved_errno;
+    }
+    slot.state.store(READY, Ordering::Release);
+}
+
+fn thread_name(tid: libc::pid_t) -> String {
+    fs::read_to_string(format!("/proc/self/task/{}/comm", tid))
+        .map(|name| name.trim_end().to_string())
+        .unwrap_or_else(|_| format!("tid {}", tid))
+}
+
+/// Move the samples recorded by the signal handler into `CPU_SAMPLES`.
+fn drain_samples() {
+    let Some(slots) = SLOTS.get() else {
+        return;
+    };
+    let mut samples = CPU_SAMPLES.lock().unwrap();
+    let mut names: HashMap<libc::pid_t, String> = HashMap::new();
+    for slot in slots.iter() {
+        if slot.state.load(Ordering::Acquire) != READY {
+            continue;
+        }
+        let raw = unsafe { &*slot.sample.get() };
+        let java: Vec<MethodId> = raw.java[..raw.java_count.max(0) as usize]
+            .iter()
+            .rev()
+            .map(|frame| MethodId(frame.method))
+            .collect();
+        let thread = java.is_empty().then(|| {
+            names
+                .entry(raw.tid)
+                .or_insert_with(|| thread_name(raw.tid))
+                .clone()
+        });
+        // A return address points after the call; the call itself is one byte back
+        let native = raw.native[..raw.native_count]
+            .iter()
+            .enumerate()
+            .map(|(i, &pc)| if i == 0 { pc } else { pc.saturating_sub(1) })
+            .collect();
+        slot.state.store(FREE, Ordering::Release);
+
+        let stack = SampledStack {
+            thread,
+            java,
+            native,
+        };
+        *samples.entry(stack).or_insert(0) += 1;
+    }
+}
+
+unsafe extern "C" fn cpu_sample_drainer(
+    _jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    while !sleep_unless_shut_down(DRAIN_INTERVAL) {
+        drain_samples();
+    }
+}
+
+/// Make sure every method of `klass` has a jmethodID: AsyncGetCallTrace can't create
+/// them in a signal handler and reports frames without one as unknown.
+pub(crate) fn create_method_ids(jvmti_env: *mut jvmtiEnv, klass: jclass) {
+    if !agent_options().native_stacks {
+        return;
+    }
+    unsafe {
+        let mut count: jint = 0;
+        let mut methods: *mut jmethodID = ptr::null_mut();
+        let err =
+            (**jvmti_env).GetClassMethods.unwrap()(jvmti_env, klass, &mut count, &mut methods);
+        if err == jvmtiError_JVMTI_ERROR_NONE && !methods.is_null() {
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, methods as *mut u8);
+        }
+    }
+}
+
+fn create_loaded_method_ids(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    unsafe {
+        let mut count: jint = 0;
+        let mut classes: *mut jclass = ptr::null_mut();
+        if (**jvmti_env).GetLoadedClasses.unwrap()(jvmti_env, &mut count, &mut classes)
+            != jvmtiError_JVMTI_ERROR_NONE
+        {
+            return;
+        }
+        for &klass in std::slice::from_raw_parts(classes, count as usize) {
+            create_method_ids(jvmti_env, klass);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, klass);
+        }
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, classes as *mut u8);
+    }
+}
+
+fn install_cpu_sample_signal() -> Result<(), String> {
+    unsafe {
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = cpu_sample_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(CPU_SAMPLE_SIGNAL, &action, ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Arm or disarm the CPU time timer; samples are only taken while a session runs.
+pub(crate) fn set_cpu_sampling(enabled: bool) {
+    if SLOTS.get().is_none() {
+        return;
+    }
+    SAMPLING.store(enabled, Ordering::Relaxed);
+    let interval = if enabled {
+        libc::timeval {
+            tv_sec: 0,
+            tv_usec: CPU_SAMPLE_INTERVAL.as_micros() as libc::suseconds_t,
+        }
+    } else {
+        libc::timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        }
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, ptr::null_mut()) } != 0 {
+        eprintln!(
+            "Failed to set the CPU sampling timer: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Start sampling CPU time with mixed Java and native stacks, with the `nativestacks` option.
+pub(crate) fn start_cpu_sampler(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if !agent_options().native_stacks || SLOTS.get().is_some() {
+        return;
+    }
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"AsyncGetCallTrace".as_ptr()) };
+    if symbol.is_null() {
+        eprintln!("⚠️  AsyncGetCallTrace not found: CPU samples will only have native frames");
+    } else {
+        let function = unsafe { std::mem::transmute::<*mut c_void, AsyncGetCallTrace>(symbol) };
+        let _ = ASYNC_GET_CALL_TRACE.set(AsyncGetCallTraceFn(function));
+    }
+    create_loaded_method_ids(jvmti_env, jni_env);
+
+    let _ = SLOTS.set(
+        (0..SAMPLE_SLOTS)
+            .map(|_| Slot {
+                state: AtomicU8::new(FREE),
+                sample: UnsafeCell::new(RawSample {
+                    tid: 0,
+                    native: [0; MAX_NATIVE_FRAMES],
+                    native_count: 0,
+                    java: [CallFrame {
+                        _bci: 0,
+                        method: ptr::null_mut(),
+                    }; MAX_JAVA_FRAMES],
+                    java_count: 0,
+                }),
+            })
+            .collect(),
+    );
+    if let Err(e) = install_cpu_sample_signal() {
+        eprintln!("Failed to install the CPU sampling signal handler: {}", e);
+        return;
+    }
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-cpu-sampler",
+        cpu_sample_drainer,
+        ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start CPU sampler: {}", e);
+        return;
+    }
+    set_cpu_sampling(true);
+}
+
+/// Forget the CPU samples of the session.
+pub(crate) fn reset_cpu_samples() {
+    drain_samples();
+    CPU_SAMPLES.lock().unwrap().clear();
+    DROPPED_SAMPLES.store(0, Ordering::Relaxed);
+}
+
+/// Split native frames (leaf first) around the Java frames of a sample, as
+/// (callers of the Java frames, callees), both root first. The walk reaches the Java
+/// frames at the first address outside a library (JIT-compiled code or the interpreter);
+/// library frames after the last such address called into Java. Without Java frames the
+/// whole walk is the stack.
+fn split_native_frames(
+    native: &[usize],
+    has_java: bool,
+    in_library: impl Fn(usize) -> bool,
+) -> (Vec<usize>, Vec<usize>) {
+    let root_first = |frames: &[usize]| frames.iter().rev().copied().collect::<Vec<_>>();
+    if !has_java {
+        return (root_first(native), Vec::new());
+    }
+    match native.iter().position(|&pc| !in_library(pc)) {
+        Some(first_java) => {
+            let last_java = native.iter().rposition(|&pc| !in_library(pc)).unwrap();
+            (
+                root_first(&native[last_java + 1..]),
+                root_first(&native[..first_java]),
+            )
+        }
+        None => (Vec::new(), root_first(native)),
+    }
+}
+
+fn native_frame_name(mappings: &[Mapping], pc: usize) -> String {
+    match find_mapping(mappings, pc) {
+        Some(mapping) => frame_symbol(mapping, pc),
+        None => "[unknown]".to_string(),
+    }
+}
+
+/// Write the CPU samples as folded stacks with native frames spliced around the Java
+/// ones, weighted in nanoseconds of CPU time.
+pub(crate) fn write_cpu_samples(
+    jvmti_env: *mut jvmtiEnv,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    drain_samples();
+    let samples = CPU_SAMPLES.lock().unwrap().clone();
+    if samples.is_empty() {
+        return Ok(());
+    }
+    let mappings = code_mappings();
+    let in_library = |pc: usize| find_mapping(&mappings, pc).is_some();
+
+    let mut folded: HashMap<String, u64> = HashMap::new();
+    let mut total = 0;
+    let mut in_native = 0;
+    for (stack, count) in &samples {
+        let (callers, callees) =
+            split_native_frames(&stack.native, !stack.java.is_empty(), in_library);
+        // Threads without Java frames (GC, compiler, VM) go under their category and name
+        let mut frames: Vec<String> = stack
+            .thread
+            .iter()
+            .flat_map(|thread| {
+                let category = ThreadCategory::from_thread_name(thread);
+                [category.label().to_string(), thread.replace(';', ":")]
+            })
+            .collect();
+        frames.extend(callers.iter().map(|&pc| native_frame_name(&mappings, pc)));
+        frames.extend(stack.java.iter().map(|method| {
+            frame_name(jvmti_env, method.0).unwrap_or_else(|| "[unknown]".to_string())
+        }));
+        frames.extend(callees.iter().map(|&pc| native_frame_name(&mappings, pc)));
+
+        total += count;
+        if stack.native.first().is_some_and(|&pc| in_library(pc)) {
+            in_native += count;
+        }
+        *folded.entry(frames.join(";")).or_insert(0) += count;
+    }
+
+    let mut sorted: Vec<_> = folded.into_iter().collect();
+    sorted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let sample_nanos = CPU_SAMPLE_INTERVAL.as_nanos() as u64;
+    let mut file = File::create(path)?;
+    for (stack, count) in sorted {
+        writeln!(file, "{} {}", stack, count * sample_nanos)?;
+    }
+
+    println!(
+        "🧬 CPU samples: {} ({:.1}% in native code, {} dropped)",
+        total,
+        in_native as f64 * 100.0 / total as f64,
+        DROPPED_SAMPLES.load(Ordering::Relaxed)
+    );
+    println!("🧬 Mixed-mode CPU flamegraph data written to '{}'", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_native_frames() {
+        // Libraries below 0x1000; 0x5000.. is JIT-compiled code
+        let in_library = |pc: usize| pc < 0x1000;
+        // leaf: libc <- libjvm <- compiled <- interpreted <- libjvm call helper <- libc start
+        let native = [0x10, 0x20, 0x5000, 0x5100, 0x30, 0x40];
+        assert_eq!(
+            split_native_frames(&native, true, in_library),
+            (vec![0x40, 0x30], vec![0x20, 0x10])
+        );
+        // Interrupted in compiled code, the chain stopped there
+        assert_eq!(
+            split_native_frames(&[0x5000], true, in_library),
+            (vec![], vec![])
+        );
+        // The walk never reached Java code: all of it was called from Java
+        assert_eq!(
+            split_native_frames(&[0x10, 0x20], true, in_library),
+            (vec![], vec![0x20, 0x10])
+        );
+        // GC and compiler threads
+        assert_eq!(
+            split_native_frames(&[0x10, 0x20], false, in_library),
+            (vec![0x20, 0x10], vec![])
+        );
+    }
+}
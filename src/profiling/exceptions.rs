@@ -0,0 +1,50 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::profiling::get_class_name;
+
+// Exceptions thrown per class, caught or not
+static EXCEPTION_COUNTS: Lazy<Mutex<HashMap<String, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) extern "C" fn exception_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    _thread: jthread,
+    _method: jmethodID,
+    _location: jlocation,
+    exception: jobject,
+    _catch_method: jmethodID,
+    _catch_location: jlocation,
+) {
+    let class_name = unsafe {
+        let klass = (**jni_env).GetObjectClass.unwrap()(jni_env, exception);
+        let name = get_class_name(jvmti_env, klass);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, klass);
+        name
+    };
+    *EXCEPTION_COUNTS
+        .lock()
+        .unwrap()
+        .entry(class_name)
+        .or_insert(0) += 1;
+}
+
+/// Exceptions thrown per class, most frequent first.
+pub(crate) fn exception_counts() -> Vec<(String, u64)> {
+    let mut counts: Vec<(String, u64)> = EXCEPTION_COUNTS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(class, &count)| (class.clone(), count))
+        .collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+pub(crate) fn reset_exception_counts() {
+    EXCEPTION_COUNTS.lock().unwrap().clear();
+}
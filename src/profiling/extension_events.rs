@@ -0,0 +1,66 @@
+use std::ffi::CStr;
+
+use crate::bindings::gen_bindings::*;
+
+/// Callback of a HotSpot extension event. The VM passes the parameters listed in the
+/// event info after the JVMTI environment: the JNI environment, then a thread (virtual
+/// thread mount/unmount) or the unloaded class (ClassUnload, unused by us).
+pub(crate) type ExtensionEventCallback =
+    unsafe extern "C" fn(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, object: jobject);
+
+/// Hook the extension events in `callbacks` by id, if this VM has them.
+pub(crate) fn set_extension_event_callbacks(
+    jvmti_env: *mut jvmtiEnv,
+    callbacks: &[(&str, ExtensionEventCallback)],
+) {
+    unsafe {
+        let mut count: jint = 0;
+        let mut events: *mut jvmtiExtensionEventInfo = std::ptr::null_mut();
+        let err = (**jvmti_env).GetExtensionEvents.unwrap()(jvmti_env, &mut count, &mut events);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("GetExtensionEvents failed: {}", err);
+            return;
+        }
+        if events.is_null() {
+            return;
+        }
+
+        let deallocate = |ptr: *mut u8| {
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, ptr);
+        };
+        for event in std::slice::from_raw_parts(events, count as usize) {
+            let id = CStr::from_ptr(event.id).to_string_lossy();
+            let callback = callbacks
+                .iter()
+                .find(|(wanted, _)| *wanted == id)
+                .map(|&(_, callback)| callback);
+
+            if let Some(callback) = callback {
+                // Extension events are declared variadic; the VM calls them with the
+                // parameters listed in the event info
+                let callback: jvmtiExtensionEvent = Some(std::mem::transmute::<
+                    ExtensionEventCallback,
+                    unsafe extern "C" fn(*mut jvmtiEnv, ...),
+                >(callback));
+                let err = (**jvmti_env).SetExtensionEventCallback.unwrap()(
+                    jvmti_env,
+                    event.extension_event_index,
+                    callback,
+                );
+                if err != jvmtiError_JVMTI_ERROR_NONE {
+                    eprintln!("Failed to enable {}: {}", id, err);
+                }
+            }
+
+            if !event.params.is_null() {
+                for param in std::slice::from_raw_parts(event.params, event.param_count as usize) {
+                    deallocate(param.name as *mut u8);
+                }
+                deallocate(event.params as *mut u8);
+            }
+            deallocate(event.id as *mut u8);
+            deallocate(event.short_description as *mut u8);
+        }
+        deallocate(events as *mut u8);
+    }
+}
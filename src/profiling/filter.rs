@@ -0,0 +1,78 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::profiling::{get_method_name_safe, MethodId};
+
+// Method name prefixes left out of the call graph and flamegraph, e.g. `java.` or `com.acme.Util`
+static EXCLUDED_PREFIXES: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+// Fast path for the common case of no filter
+static FILTER_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Bumped by every filter change: frames entered under the old filter may not match
+// their exits under the new one
+static FILTER_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// Whether each method seen so far is excluded, for the current prefixes
+static EXCLUDED_METHODS: Lazy<Mutex<HashMap<MethodId, bool>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Whether a fully qualified method name (`java.util.HashMap.get`) starts with any prefix.
+pub(crate) fn matches_prefix(name: &str, prefixes: &[String]) -> bool {
+    prefixes
+        .iter()
+        .any(|prefix| name.starts_with(prefix.as_str()))
+}
+
+/// Replace the excluded prefixes; an empty list turns the filter off.
+pub(crate) fn set_excluded_prefixes(prefixes: Vec<String>) {
+    let mut excluded = EXCLUDED_PREFIXES.write().unwrap();
+    FILTER_ACTIVE.store(!prefixes.is_empty(), Ordering::Relaxed);
+    *excluded = prefixes;
+    EXCLUDED_METHODS.lock().unwrap().clear();
+    FILTER_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn filter_generation() -> u64 {
+    FILTER_GENERATION.load(Ordering::Relaxed)
+}
+
+pub(crate) fn excluded_prefixes() -> Vec<String> {
+    EXCLUDED_PREFIXES.read().unwrap().clone()
+}
+
+/// Whether MethodEntry/MethodExit should skip `method`; its callees then hang off the
+/// nearest frame that is kept.
+pub(crate) fn is_excluded(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> bool {
+    if !FILTER_ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    if let Some(&excluded) = EXCLUDED_METHODS.lock().unwrap().get(&MethodId(method)) {
+        return excluded;
+    }
+    let excluded = get_method_name_safe(jvmti_env, method)
+        .is_some_and(|name| matches_prefix(&name, &EXCLUDED_PREFIXES.read().unwrap()));
+    EXCLUDED_METHODS
+        .lock()
+        .unwrap()
+        .insert(MethodId(method), excluded);
+    excluded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_prefix() {
+        let prefixes = vec!["java.".to_string(), "com.acme.Util".to_string()];
+        assert!(matches_prefix("java.util.HashMap.get", &prefixes));
+        assert!(matches_prefix("com.acme.Util.parse", &prefixes));
+        assert!(!matches_prefix("javax.swing.JFrame.show", &prefixes));
+        assert!(!matches_prefix("com.acme.Main.main", &prefixes));
+        assert!(!matches_prefix("java.lang.Object.wait", &[]));
+    }
+}
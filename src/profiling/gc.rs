@@ -0,0 +1,74 @@
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::bindings::gen_bindings::*;
+
+/// Upper bounds of the GC pause histogram buckets, in seconds (Prometheus `le`)
+pub(crate) const GC_PAUSE_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// Pauses per bucket; the last slot counts pauses beyond the largest bound
+static PAUSE_COUNTS: [AtomicU64; GC_PAUSE_BUCKETS.len() + 1] =
+    [const { AtomicU64::new(0) }; GC_PAUSE_BUCKETS.len() + 1];
+
+static PAUSE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+// Start of the running collection. GC callbacks run with the VM stopped and may not
+// call JVMTI (not even GetTime), so this is the Rust clock
+static GC_STARTED: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// Index of the bucket a pause falls in: the first bound it doesn't exceed.
+pub(crate) fn bucket_index(nanos: u64) -> usize {
+    let seconds = nanos as f64 / 1e9;
+    GC_PAUSE_BUCKETS
+        .iter()
+        .position(|&bound| seconds <= bound)
+        .unwrap_or(GC_PAUSE_BUCKETS.len())
+}
+
+pub(crate) extern "C" fn garbage_collection_start_callback(_jvmti_env: *mut jvmtiEnv) {
+    *GC_STARTED.lock().unwrap() = Some(Instant::now());
+}
+
+pub(crate) extern "C" fn garbage_collection_finish_callback(_jvmti_env: *mut jvmtiEnv) {
+    let Some(started) = GC_STARTED.lock().unwrap().take() else {
+        return;
+    };
+    let nanos = started.elapsed().as_nanos() as u64;
+    PAUSE_COUNTS[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+    PAUSE_NANOS.fetch_add(nanos, Ordering::Relaxed);
+}
+
+/// GC pauses so far: count per bucket (the last past every bound) and total time.
+pub(crate) fn gc_pauses() -> (Vec<u64>, u64) {
+    let counts = PAUSE_COUNTS
+        .iter()
+        .map(|count| count.load(Ordering::Relaxed))
+        .collect();
+    (counts, PAUSE_NANOS.load(Ordering::Relaxed))
+}
+
+pub(crate) fn reset_gc_pauses() {
+    for count in &PAUSE_COUNTS {
+        count.store(0, Ordering::Relaxed);
+    }
+    PAUSE_NANOS.store(0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(1_000_000), 0);
+        assert_eq!(bucket_index(1_000_001), 1);
+        assert_eq!(bucket_index(30_000_000), 5);
+        assert_eq!(bucket_index(5_000_000_000), 11);
+        assert_eq!(bucket_index(60_000_000_000), 12);
+    }
+}
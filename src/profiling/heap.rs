@@ -0,0 +1,309 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::profiling::{format_bytes, get_class_name, CLASS_ALLOCATION_STATS};
+
+/// Live instances and shallow size for a single class
+#[derive(Clone, Default, Debug)]
+pub(crate) struct HeapHistogramEntry {
+    pub(crate) class_name: String,
+    pub(crate) instance_count: u64,
+    pub(crate) shallow_bytes: u64,
+}
+
+/// Instance count and shallow bytes per class tag, filled in by the heap iteration callback
+type TagCounts = HashMap<jlong, (u64, u64)>;
+
+unsafe extern "C" fn heap_iteration_callback(
+    class_tag: jlong,
+    size: jlong,
+    _tag_ptr: *mut jlong,
+    _length: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let counts = &mut *(user_data as *mut TagCounts);
+    let entry = counts.entry(class_tag).or_insert((0, 0));
+    entry.0 += 1;
+    entry.1 += size as u64;
+    JVMTI_VISIT_OBJECTS as jint
+}
+
+/// Loaded classes tagged with their index (+1), so heap callbacks can resolve `class_tag`s.
+pub(crate) struct TaggedClasses {
+    count: usize,
+    pub(crate) names: HashMap<jlong, String>,
+}
+
+impl TaggedClasses {
+    /// Tag every loaded class; tags run from 1 to `count()` inclusive.
+    ///
+    /// The local references from `GetLoadedClasses` are released straight away so they
+    /// don't show up as JNI-local GC roots; the tags stay until [`clear_all_tags`].
+    pub(crate) fn tag_all(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) -> Result<Self, String> {
+        unsafe {
+            let mut class_count: jint = 0;
+            let mut classes: *mut jclass = ptr::null_mut();
+            let err =
+                (**jvmti_env).GetLoadedClasses.unwrap()(jvmti_env, &mut class_count, &mut classes);
+            if err != jvmtiError_JVMTI_ERROR_NONE {
+                return Err(format!("GetLoadedClasses failed: {}", err));
+            }
+
+            let count = class_count as usize;
+            let mut names = HashMap::with_capacity(count);
+            for (i, &klass) in std::slice::from_raw_parts(classes, count)
+                .iter()
+                .enumerate()
+            {
+                let tag = i as jlong + 1;
+                if (**jvmti_env).SetTag.unwrap()(jvmti_env, klass, tag)
+                    == jvmtiError_JVMTI_ERROR_NONE
+                {
+                    names.insert(tag, get_class_name(jvmti_env, klass));
+                }
+                if !jni_env.is_null() {
+                    (**jni_env).DeleteLocalRef.unwrap()(jni_env, klass);
+                }
+            }
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, classes as *mut u8);
+
+            Ok(Self { count, names })
+        }
+    }
+
+    /// Highest tag handed out to a class; anything above it is free for other objects.
+    pub(crate) fn count(&self) -> usize {
+        self.count
+    }
+}
+
+unsafe extern "C" fn clear_tag_callback(
+    _class_tag: jlong,
+    _size: jlong,
+    tag_ptr: *mut jlong,
+    _length: jint,
+    _user_data: *mut c_void,
+) -> jint {
+    *tag_ptr = 0;
+    JVMTI_VISIT_OBJECTS as jint
+}
+
+/// Reset the tag of every tagged object (classes included) back to zero.
+pub(crate) fn clear_all_tags(jvmti_env: *mut jvmtiEnv) {
+    unsafe {
+        let callbacks = jvmtiHeapCallbacks {
+            heap_iteration_callback: Some(clear_tag_callback),
+            ..std::mem::zeroed()
+        };
+        let err = (**jvmti_env).IterateThroughHeap.unwrap()(
+            jvmti_env,
+            JVMTI_HEAP_FILTER_UNTAGGED as jint,
+            ptr::null_mut(),
+            &callbacks,
+            ptr::null(),
+        );
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("Warning: failed to clear object tags: {}", err);
+        }
+    }
+}
+
+/// Walk the live heap and count instances per class, like `jmap -histo:live`.
+///
+/// Every loaded class is tagged for the duration of the walk so the iteration
+/// callback can bucket objects by class tag. Requires the `can_tag_objects` capability.
+pub(crate) fn collect_heap_histogram(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+) -> Result<Vec<HeapHistogramEntry>, String> {
+    unsafe {
+        // Collect garbage first so only live objects are counted
+        let err = (**jvmti_env).ForceGarbageCollection.unwrap()(jvmti_env);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("Warning: ForceGarbageCollection failed: {}", err);
+        }
+
+        let tagged = TaggedClasses::tag_all(jvmti_env, jni_env)?;
+
+        let callbacks = jvmtiHeapCallbacks {
+            heap_iteration_callback: Some(heap_iteration_callback),
+            ..std::mem::zeroed()
+        };
+        let mut counts: TagCounts = HashMap::new();
+        let err = (**jvmti_env).IterateThroughHeap.unwrap()(
+            jvmti_env,
+            0,
+            ptr::null_mut(),
+            &callbacks,
+            &mut counts as *mut TagCounts as *const c_void,
+        );
+
+        clear_all_tags(jvmti_env);
+
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            return Err(format!("IterateThroughHeap failed: {}", err));
+        }
+
+        Ok(build_histogram(&counts, &tagged.names))
+    }
+}
+
+/// Turn per-tag counts into a histogram sorted by shallow bytes (largest first).
+fn build_histogram(
+    counts: &TagCounts,
+    class_names: &HashMap<jlong, String>,
+) -> Vec<HeapHistogramEntry> {
+    let mut by_name: HashMap<String, HeapHistogramEntry> = HashMap::new();
+    for (tag, &(instances, bytes)) in counts {
+        let class_name = class_names
+            .get(tag)
+            .cloned()
+            .unwrap_or_else(|| "<unknown>".to_string());
+        let entry = by_name
+            .entry(class_name.clone())
+            .or_insert_with(|| HeapHistogramEntry {
+                class_name,
+                ..Default::default()
+            });
+        entry.instance_count += instances;
+        entry.shallow_bytes += bytes;
+    }
+
+    let mut histogram: Vec<HeapHistogramEntry> = by_name.into_values().collect();
+    histogram.sort_by(|a, b| {
+        b.shallow_bytes
+            .cmp(&a.shallow_bytes)
+            .then_with(|| a.class_name.cmp(&b.class_name))
+    });
+    histogram
+}
+
+/// Write the histogram in a `jmap -histo` style table, with allocation totals alongside.
+pub(crate) fn write_heap_histogram(
+    path: &str,
+    histogram: &[HeapHistogramEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let allocated = CLASS_ALLOCATION_STATS.lock().unwrap();
+    let mut file = File::create(path)?;
+
+    writeln!(
+        file,
+        "{:>5} {:>14} {:>16} {:>14} {:>16}  class name",
+        "num", "#instances", "#bytes", "#allocated", "#alloc bytes"
+    )?;
+    writeln!(file, "{}", "-".repeat(100))?;
+
+    let mut total_instances = 0u64;
+    let mut total_bytes = 0u64;
+    for (i, entry) in histogram.iter().enumerate() {
+        let (alloc_count, alloc_bytes) = allocated
+            .get(&entry.class_name)
+            .map(|st| (st.object_count, st.total_bytes))
+            .unwrap_or((0, 0));
+        writeln!(
+            file,
+            "{:>4}: {:>14} {:>16} {:>14} {:>16}  {}",
+            i + 1,
+            entry.instance_count,
+            entry.shallow_bytes,
+            alloc_count,
+            alloc_bytes,
+            entry.class_name
+        )?;
+        total_instances += entry.instance_count;
+        total_bytes += entry.shallow_bytes;
+    }
+    writeln!(file, "Total {:>14} {:>16}", total_instances, total_bytes)?;
+
+    Ok(())
+}
+
+/// The `limit` largest classes of a histogram, with allocation totals alongside.
+pub(crate) fn heap_histogram_json(
+    histogram: &[HeapHistogramEntry],
+    limit: usize,
+) -> serde_json::Value {
+    let allocated = CLASS_ALLOCATION_STATS.lock().unwrap();
+    let classes: Vec<serde_json::Value> = histogram
+        .iter()
+        .take(limit)
+        .map(|entry| {
+            let (alloc_count, alloc_bytes) = allocated
+                .get(&entry.class_name)
+                .map(|st| (st.object_count, st.total_bytes))
+                .unwrap_or((0, 0));
+            json!({
+                "class": entry.class_name,
+                "instances": entry.instance_count,
+                "bytes": entry.shallow_bytes,
+                "allocated_objects": alloc_count,
+                "allocated_bytes": alloc_bytes,
+            })
+        })
+        .collect();
+    json!({
+        "total_instances": histogram.iter().map(|e| e.instance_count).sum::<u64>(),
+        "total_bytes": histogram.iter().map(|e| e.shallow_bytes).sum::<u64>(),
+        "classes": classes,
+    })
+}
+
+/// Print the top classes by live heap, next to how much of each was allocated during the run.
+pub(crate) fn print_heap_histogram(histogram: &[HeapHistogramEntry], top_n: usize) {
+    if histogram.is_empty() {
+        return;
+    }
+
+    let allocated = CLASS_ALLOCATION_STATS.lock().unwrap();
+    let top = std::cmp::min(histogram.len(), top_n);
+    println!("\n🧮 === Top {} classes by live heap ===", top);
+    for entry in histogram.iter().take(top) {
+        let allocated_str = match allocated.get(&entry.class_name) {
+            Some(st) => format!(
+                "{:>8} allocated, {:>10}",
+                st.object_count,
+                format_bytes(st.total_bytes)
+            ),
+            None => String::new(),
+        };
+        println!(
+            "{:<40} {:>8} live, {:>10} | {}",
+            entry.class_name,
+            entry.instance_count,
+            format_bytes(entry.shallow_bytes),
+            allocated_str
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_histogram_merges_and_sorts() {
+        let mut counts: TagCounts = HashMap::new();
+        counts.insert(1, (10, 240));
+        counts.insert(2, (3, 1024));
+        counts.insert(3, (1, 16));
+        counts.insert(0, (2, 32));
+
+        let mut names = HashMap::new();
+        names.insert(1, "java.lang.String".to_string());
+        names.insert(2, "Array: [B".to_string());
+        names.insert(3, "java.lang.String".to_string());
+
+        let histogram = build_histogram(&counts, &names);
+        assert_eq!(histogram.len(), 3);
+        assert_eq!(histogram[0].class_name, "Array: [B");
+        assert_eq!(histogram[1].class_name, "java.lang.String");
+        assert_eq!(histogram[1].instance_count, 11);
+        assert_eq!(histogram[1].shallow_bytes, 256);
+        assert_eq!(histogram[2].class_name, "<unknown>");
+    }
+}
@@ -0,0 +1,574 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::ptr;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::heap::{clear_all_tags, TaggedClasses};
+use crate::profiling::profiling::format_bytes;
+
+/// Node 0 of the reference graph is a virtual root that points at every GC root.
+const ROOT: u32 = 0;
+const NO_DOMINATOR: u32 = u32::MAX;
+/// Paths to GC roots are cut off after this many hops.
+const MAX_PATH_DEPTH: usize = 16;
+
+/// How an object was first reached: referrer node, reference kind and field/array index.
+#[derive(Clone, Copy, Debug)]
+struct FirstReference {
+    referrer: u32,
+    kind: jvmtiHeapReferenceKind,
+    index: jint,
+}
+
+/// Object reference graph built from `FollowReferences`.
+///
+/// Objects are identified by their JVMTI tag: classes keep the tags handed out by
+/// [`TaggedClasses`], every other object gets a fresh tag above those.
+struct HeapGraph {
+    node_of_tag: HashMap<jlong, u32>,
+    tags: Vec<jlong>,
+    class_tags: Vec<jlong>,
+    sizes: Vec<u64>,
+    successors: Vec<Vec<u32>>,
+    first_reference: Vec<Option<FirstReference>>,
+    next_tag: jlong,
+}
+
+impl HeapGraph {
+    fn new(first_free_tag: jlong) -> Self {
+        Self {
+            node_of_tag: HashMap::new(),
+            tags: vec![0],
+            class_tags: vec![0],
+            sizes: vec![0],
+            successors: vec![Vec::new()],
+            first_reference: vec![None],
+            next_tag: first_free_tag,
+        }
+    }
+
+    /// Look up (or create) the node for the object behind `tag_ptr`, tagging it if needed.
+    unsafe fn node_for(&mut self, tag_ptr: *mut jlong, class_tag: jlong, size: jlong) -> u32 {
+        if *tag_ptr == 0 {
+            *tag_ptr = self.next_tag;
+            self.next_tag += 1;
+        }
+
+        let tag = *tag_ptr;
+        if let Some(&node) = self.node_of_tag.get(&tag) {
+            if self.sizes[node as usize] == 0 {
+                self.sizes[node as usize] = size as u64;
+            }
+            return node;
+        }
+
+        let node = self.tags.len() as u32;
+        self.node_of_tag.insert(tag, node);
+        self.tags.push(tag);
+        self.class_tags.push(class_tag);
+        self.sizes.push(size as u64);
+        self.successors.push(Vec::new());
+        self.first_reference.push(None);
+        node
+    }
+}
+
+#[allow(non_upper_case_globals)]
+unsafe extern "C" fn heap_reference_callback(
+    reference_kind: jvmtiHeapReferenceKind,
+    reference_info: *const jvmtiHeapReferenceInfo,
+    class_tag: jlong,
+    referrer_class_tag: jlong,
+    size: jlong,
+    tag_ptr: *mut jlong,
+    referrer_tag_ptr: *mut jlong,
+    _length: jint,
+    user_data: *mut c_void,
+) -> jint {
+    let graph = &mut *(user_data as *mut HeapGraph);
+
+    let referrer = if referrer_tag_ptr.is_null() {
+        ROOT
+    } else {
+        graph.node_for(referrer_tag_ptr, referrer_class_tag, 0)
+    };
+    let node = graph.node_for(tag_ptr, class_tag, size);
+
+    graph.successors[referrer as usize].push(node);
+    if graph.first_reference[node as usize].is_none() {
+        let index = match reference_kind {
+            jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_FIELD
+            | jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_STATIC_FIELD => {
+                (*reference_info).field.index
+            }
+            jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => {
+                (*reference_info).array.index
+            }
+            _ => -1,
+        };
+        graph.first_reference[node as usize] = Some(FirstReference {
+            referrer,
+            kind: reference_kind,
+            index,
+        });
+    }
+
+    JVMTI_VISIT_OBJECTS as jint
+}
+
+/// Retained heap attributed to a class: the union of what its instances dominate
+#[derive(Clone, Default, Debug)]
+pub(crate) struct ClassRetainedStats {
+    pub(crate) class_name: String,
+    pub(crate) instance_count: u64,
+    pub(crate) shallow_bytes: u64,
+    pub(crate) retained_bytes: u64,
+}
+
+/// A single object that dominates a large part of the heap, with one path back to a GC root
+#[derive(Clone, Debug)]
+pub(crate) struct TopRetainer {
+    pub(crate) description: String,
+    pub(crate) retained_bytes: u64,
+    pub(crate) path_to_root: Vec<String>,
+}
+
+/// Result of the dominator analysis over the reachable heap
+#[derive(Clone, Default, Debug)]
+pub(crate) struct RetainedHeapReport {
+    pub(crate) object_count: u64,
+    pub(crate) reachable_bytes: u64,
+    pub(crate) classes: Vec<ClassRetainedStats>,
+    pub(crate) top_retainers: Vec<TopRetainer>,
+}
+
+/// Follow references from the GC roots, build the dominator tree and compute retained sizes.
+///
+/// Requires the `can_tag_objects` capability. Every reachable object is tagged while the
+/// graph is built; the tags are cleared again before returning.
+pub(crate) fn analyze_retained_heap(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    top_n: usize,
+) -> Result<RetainedHeapReport, String> {
+    unsafe {
+        let tagged = TaggedClasses::tag_all(jvmti_env, jni_env)?;
+        let mut graph = HeapGraph::new(tagged.count() as jlong + 1);
+
+        let callbacks = jvmtiHeapCallbacks {
+            heap_reference_callback: Some(heap_reference_callback),
+            ..std::mem::zeroed()
+        };
+        let err = (**jvmti_env).FollowReferences.unwrap()(
+            jvmti_env,
+            0,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &callbacks,
+            &mut graph as *mut HeapGraph as *const c_void,
+        );
+
+        // Drop the object tags we handed out, classes included
+        clear_all_tags(jvmti_env);
+
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            return Err(format!("FollowReferences failed: {}", err));
+        }
+
+        Ok(build_report(&graph, &tagged, top_n))
+    }
+}
+
+fn build_report(graph: &HeapGraph, tagged: &TaggedClasses, top_n: usize) -> RetainedHeapReport {
+    let idom = compute_dominators(&graph.successors);
+    let retained = compute_retained_sizes(&idom, &graph.sizes, &graph.successors);
+
+    let class_tag_limit = tagged.count() as jlong;
+    let type_name = |node: usize| -> String {
+        tagged
+            .names
+            .get(&graph.class_tags[node])
+            .cloned()
+            .unwrap_or_else(|| "<unknown>".to_string())
+    };
+    let describe = |node: usize| -> String {
+        let tag = graph.tags[node];
+        if tag <= class_tag_limit {
+            if let Some(name) = tagged.names.get(&tag) {
+                return format!("class {}", name);
+            }
+        }
+        type_name(node)
+    };
+
+    // Dominator tree children, used for per-class retained sizes and top-level dominators
+    let mut children: Vec<Vec<u32>> = vec![Vec::new(); idom.len()];
+    for (node, &dom) in idom.iter().enumerate().skip(1) {
+        if dom != NO_DOMINATOR {
+            children[dom as usize].push(node as u32);
+        }
+    }
+
+    let node_classes: Vec<String> = (0..graph.tags.len()).map(type_name).collect();
+    let mut classes: Vec<ClassRetainedStats> =
+        retained_by_class(&children, &node_classes, &graph.sizes, &retained)
+            .into_values()
+            .collect();
+    classes.sort_by(|a, b| {
+        b.retained_bytes
+            .cmp(&a.retained_bytes)
+            .then_with(|| a.class_name.cmp(&b.class_name))
+    });
+
+    let mut top_level = children[ROOT as usize].clone();
+    top_level.sort_by_key(|&node| std::cmp::Reverse(retained[node as usize]));
+    let top_retainers = top_level
+        .iter()
+        .take(top_n)
+        .map(|&node| TopRetainer {
+            description: describe(node as usize),
+            retained_bytes: retained[node as usize],
+            path_to_root: path_to_root(graph, node, &describe),
+        })
+        .collect();
+
+    let reachable: Vec<usize> = (1..idom.len())
+        .filter(|&node| idom[node] != NO_DOMINATOR)
+        .collect();
+    RetainedHeapReport {
+        object_count: reachable.len() as u64,
+        reachable_bytes: reachable.iter().map(|&node| graph.sizes[node]).sum(),
+        classes,
+        top_retainers,
+    }
+}
+
+/// Walk first-seen references back to a GC root, e.g. `java.util.HashMap (field #3)`.
+fn path_to_root(graph: &HeapGraph, node: u32, describe: &dyn Fn(usize) -> String) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut current = node;
+    while let Some(reference) = graph.first_reference[current as usize] {
+        if path.len() == MAX_PATH_DEPTH {
+            path.push("...".to_string());
+            break;
+        }
+        let via = describe_reference(reference.kind, reference.index);
+        if reference.referrer == ROOT {
+            path.push(format!("[GC root: {}]", via));
+            break;
+        }
+        path.push(format!(
+            "{} ({})",
+            describe(reference.referrer as usize),
+            via
+        ));
+        current = reference.referrer;
+    }
+    path
+}
+
+#[allow(non_upper_case_globals)]
+fn describe_reference(kind: jvmtiHeapReferenceKind, index: jint) -> String {
+    match kind {
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_CLASS => "class".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_FIELD => format!("field #{}", index),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_ARRAY_ELEMENT => format!("[{}]", index),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_CLASS_LOADER => "class loader".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_SIGNERS => "signers".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_PROTECTION_DOMAIN => {
+            "protection domain".to_string()
+        }
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_INTERFACE => "interface".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_STATIC_FIELD => {
+            format!("static field #{}", index)
+        }
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_CONSTANT_POOL => "constant pool".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_SUPERCLASS => "superclass".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_JNI_GLOBAL => "JNI global".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_SYSTEM_CLASS => "system class".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_MONITOR => "monitor".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_STACK_LOCAL => "stack local".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_JNI_LOCAL => "JNI local".to_string(),
+        jvmtiHeapReferenceKind_JVMTI_HEAP_REFERENCE_THREAD => "thread".to_string(),
+        _ => "other".to_string(),
+    }
+}
+
+/// Post-order of the nodes reachable from `ROOT` (iterative, heaps can be very deep).
+fn post_order(successors: &[Vec<u32>]) -> Vec<u32> {
+    let mut visited = vec![false; successors.len()];
+    let mut order = Vec::with_capacity(successors.len());
+    let mut stack: Vec<(u32, usize)> = vec![(ROOT, 0)];
+    visited[ROOT as usize] = true;
+
+    while let Some((node, next_child)) = stack.last_mut() {
+        let succ = &successors[*node as usize];
+        if *next_child < succ.len() {
+            let child = succ[*next_child];
+            *next_child += 1;
+            if !visited[child as usize] {
+                visited[child as usize] = true;
+                stack.push((child, 0));
+            }
+        } else {
+            order.push(*node);
+            stack.pop();
+        }
+    }
+    order
+}
+
+/// Immediate dominator of every node, using the Cooper-Harvey-Kennedy iterative algorithm.
+/// Unreachable nodes get `NO_DOMINATOR`; the root is its own dominator.
+fn compute_dominators(successors: &[Vec<u32>]) -> Vec<u32> {
+    let order = post_order(successors);
+    let mut post_number = vec![usize::MAX; successors.len()];
+    for (i, &node) in order.iter().enumerate() {
+        post_number[node as usize] = i;
+    }
+
+    let mut predecessors: Vec<Vec<u32>> = vec![Vec::new(); successors.len()];
+    for (node, succ) in successors.iter().enumerate() {
+        if post_number[node] == usize::MAX {
+            continue;
+        }
+        for &child in succ {
+            predecessors[child as usize].push(node as u32);
+        }
+    }
+
+    let mut idom = vec![NO_DOMINATOR; successors.len()];
+    idom[ROOT as usize] = ROOT;
+
+    let intersect = |idom: &[u32], mut a: u32, mut b: u32| -> u32 {
+        while a != b {
+            while post_number[a as usize] < post_number[b as usize] {
+                a = idom[a as usize];
+            }
+            while post_number[b as usize] < post_number[a as usize] {
+                b = idom[b as usize];
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &node in order.iter().rev() {
+            if node == ROOT {
+                continue;
+            }
+            let mut new_idom = NO_DOMINATOR;
+            for &pred in &predecessors[node as usize] {
+                if idom[pred as usize] == NO_DOMINATOR {
+                    continue;
+                }
+                new_idom = if new_idom == NO_DOMINATOR {
+                    pred
+                } else {
+                    intersect(&idom, pred, new_idom)
+                };
+            }
+            if idom[node as usize] != new_idom {
+                idom[node as usize] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+/// Retained size of every node: its own size plus everything it dominates.
+fn compute_retained_sizes(idom: &[u32], sizes: &[u64], successors: &[Vec<u32>]) -> Vec<u64> {
+    let mut retained = sizes.to_vec();
+    // Post-order visits every node before its dominator
+    for node in post_order(successors) {
+        if node != ROOT {
+            retained[idom[node as usize] as usize] += retained[node as usize];
+        }
+    }
+    retained
+}
+
+/// Sum retained sizes per class, counting only instances not already dominated by an
+/// instance of the same class so nested structures (e.g. linked nodes) aren't double counted.
+fn retained_by_class(
+    children: &[Vec<u32>],
+    node_classes: &[String],
+    sizes: &[u64],
+    retained: &[u64],
+) -> HashMap<String, ClassRetainedStats> {
+    let mut by_class: HashMap<String, ClassRetainedStats> = HashMap::new();
+    let mut on_path: HashMap<&str, u32> = HashMap::new();
+    // (node, entering) pairs walk the dominator tree depth-first without recursion
+    let mut stack: Vec<(u32, bool)> = children[ROOT as usize]
+        .iter()
+        .map(|&child| (child, true))
+        .collect();
+
+    while let Some((node, entering)) = stack.pop() {
+        let class_name = node_classes[node as usize].as_str();
+        if !entering {
+            *on_path.get_mut(class_name).unwrap() -= 1;
+            continue;
+        }
+
+        let entry = by_class
+            .entry(class_name.to_string())
+            .or_insert_with(|| ClassRetainedStats {
+                class_name: class_name.to_string(),
+                ..Default::default()
+            });
+        entry.instance_count += 1;
+        entry.shallow_bytes += sizes[node as usize];
+
+        let depth = on_path.entry(class_name).or_insert(0);
+        if *depth == 0 {
+            entry.retained_bytes += retained[node as usize];
+        }
+        *depth += 1;
+
+        stack.push((node, false));
+        for &child in &children[node as usize] {
+            stack.push((child, true));
+        }
+    }
+    by_class
+}
+
+/// Write the retained-size report (classes and top dominators with root paths).
+pub(crate) fn write_retained_heap(
+    path: &str,
+    report: &RetainedHeapReport,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::create(path)?;
+    writeln!(file, "Retained Heap Summary")?;
+    writeln!(file, "=====================")?;
+    writeln!(file, "Reachable objects: {}", report.object_count)?;
+    writeln!(
+        file,
+        "Reachable bytes: {}",
+        format_bytes(report.reachable_bytes)
+    )?;
+
+    writeln!(
+        file,
+        "\n{:>14} {:>16} {:>16}  class name",
+        "#instances", "#shallow", "#retained"
+    )?;
+    for st in &report.classes {
+        writeln!(
+            file,
+            "{:>14} {:>16} {:>16}  {}",
+            st.instance_count, st.shallow_bytes, st.retained_bytes, st.class_name
+        )?;
+    }
+
+    writeln!(file, "\nTop retainers:")?;
+    for retainer in &report.top_retainers {
+        writeln!(
+            file,
+            "\n{} retains {}",
+            retainer.description,
+            format_bytes(retainer.retained_bytes)
+        )?;
+        for hop in &retainer.path_to_root {
+            writeln!(file, "    <- {}", hop)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the top classes by retained size and the biggest dominators with one path to a root.
+pub(crate) fn print_retained_heap(report: &RetainedHeapReport, top_n: usize) {
+    if report.classes.is_empty() {
+        return;
+    }
+
+    let top = std::cmp::min(report.classes.len(), top_n);
+    println!("\n🌳 === Top {} classes by retained heap ===", top);
+    for st in report.classes.iter().take(top) {
+        println!(
+            "{:<40} {:>8} objects, {:>10} shallow, {:>10} retained",
+            st.class_name,
+            st.instance_count,
+            format_bytes(st.shallow_bytes),
+            format_bytes(st.retained_bytes)
+        );
+    }
+
+    if !report.top_retainers.is_empty() {
+        println!(
+            "\n🧲 === Top {} retainers (paths to GC roots) ===",
+            report.top_retainers.len()
+        );
+        for retainer in &report.top_retainers {
+            println!(
+                "{:<60} {:>10} retained",
+                retainer.description,
+                format_bytes(retainer.retained_bytes)
+            );
+            for hop in &retainer.path_to_root {
+                println!("    <- {}", hop);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 0 -> 1, 0 -> 2, 1 -> 3, 2 -> 3, 3 -> 4, 4 -> 5, 5 -> 4
+    fn diamond() -> Vec<Vec<u32>> {
+        vec![
+            vec![1, 2],
+            vec![3],
+            vec![3],
+            vec![4],
+            vec![5],
+            vec![4],
+            vec![],
+        ]
+    }
+
+    #[test]
+    fn test_dominators_of_diamond_with_cycle() {
+        let idom = compute_dominators(&diamond());
+        assert_eq!(idom, vec![0, 0, 0, 0, 3, 4, NO_DOMINATOR]);
+    }
+
+    #[test]
+    fn test_retained_sizes_and_class_totals() {
+        let successors = diamond();
+        let sizes = vec![0, 10, 20, 30, 40, 50, 60];
+        let idom = compute_dominators(&successors);
+        let retained = compute_retained_sizes(&idom, &sizes, &successors);
+        assert_eq!(retained[1], 10);
+        assert_eq!(retained[3], 120);
+        assert_eq!(retained[0], 150);
+
+        let mut children: Vec<Vec<u32>> = vec![Vec::new(); idom.len()];
+        for (node, &dom) in idom.iter().enumerate().skip(1) {
+            if dom != NO_DOMINATOR {
+                children[dom as usize].push(node as u32);
+            }
+        }
+        let classes: Vec<String> = ["<root>", "A", "A", "Node", "Node", "Node", "A"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let by_class = retained_by_class(&children, &classes, &sizes, &retained);
+
+        let node = &by_class["Node"];
+        assert_eq!(node.instance_count, 3);
+        assert_eq!(node.shallow_bytes, 120);
+        assert_eq!(node.retained_bytes, 120);
+        assert_eq!(by_class["A"].instance_count, 2);
+        assert_eq!(by_class["A"].retained_bytes, 30);
+    }
+}
@@ -0,0 +1,466 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::os::raw::c_void;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::heap::{collect_heap_histogram, heap_histogram_json};
+use crate::profiling::metrics::render_metrics;
+use crate::profiling::options::agent_options;
+use crate::profiling::pprof::encode_profile;
+use crate::profiling::profiling::{format_folded, sample_mark, stacks_since, SampleMark};
+use crate::profiling::session::lock_session;
+use crate::profiling::thread_dump::{take_thread_dump, thread_dump_json};
+
+/// Profile length when `/profile` has no `seconds`, as with Go's net/http/pprof
+const DEFAULT_PROFILE_SECS: u64 = 30;
+
+/// Longest `/profile` window accepted
+const MAX_PROFILE_SECS: u64 = 3600;
+
+/// Classes listed by `/heap` unless `top` says otherwise
+const DEFAULT_HEAP_TOP: usize = 100;
+
+/// How often the server checks for connections and finished profiles
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+const INDEX: &str = "rjprof endpoints:\n\
+    /profile?seconds=30&format=folded|svg|pprof|json  flamegraph samples recorded during the window\n\
+    /heap?top=100                                    live heap histogram (forces a GC)\n\
+    /threads                                         thread dump with deadlock detection\n\
+    /metrics                                         Prometheus text format\n";
+
+/// Encoding of a `/profile` response
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProfileFormat {
+    Folded,
+    Svg,
+    Pprof,
+    Json,
+}
+
+impl ProfileFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "folded" => Ok(ProfileFormat::Folded),
+            "svg" => Ok(ProfileFormat::Svg),
+            "pprof" => Ok(ProfileFormat::Pprof),
+            "json" => Ok(ProfileFormat::Json),
+            _ => Err(format!(
+                "Unknown profile format: {} (expected folded, svg, pprof or json)",
+                name
+            )),
+        }
+    }
+}
+
+/// A request the server knows how to answer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Route {
+    Index,
+    /// `seconds` of 0 returns everything recorded in the current session at once
+    Profile {
+        seconds: u64,
+        format: ProfileFormat,
+    },
+    Heap {
+        top: usize,
+    },
+    Threads,
+    Metrics,
+}
+
+/// Status code and message of a request we refuse
+#[derive(Debug, PartialEq, Eq)]
+pub struct HttpError(u16, String);
+
+fn query_params(query: &str) -> HashMap<&str, &str> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+        .collect()
+}
+
+/// Route a request line such as `GET /profile?seconds=10&format=svg HTTP/1.1`.
+pub fn parse_request_line(line: &str) -> Result<Route, HttpError> {
+    let bad_request = |message: String| HttpError(400, message);
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(bad_request("Malformed request line".to_string()));
+    };
+    if method != "GET" {
+        return Err(HttpError(405, format!("Method {} not allowed", method)));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query_params(query);
+
+    match path {
+        "/" => Ok(Route::Index),
+        "/profile" => {
+            let seconds = match params.get("seconds") {
+                Some(seconds) => seconds
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&s| s <= MAX_PROFILE_SECS)
+                    .ok_or_else(|| bad_request(format!("Invalid seconds: {}", seconds)))?,
+                None => DEFAULT_PROFILE_SECS,
+            };
+            let format = ProfileFormat::parse(params.get("format").copied().unwrap_or("folded"))
+                .map_err(bad_request)?;
+            Ok(Route::Profile { seconds, format })
+        }
+        "/heap" => {
+            let top = match params.get("top") {
+                Some(top) => top
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or_else(|| bad_request(format!("Invalid top: {}", top)))?,
+                None => DEFAULT_HEAP_TOP,
+            };
+            Ok(Route::Heap { top })
+        }
+        "/threads" => Ok(Route::Threads),
+        "/metrics" => Ok(Route::Metrics),
+        _ => Err(HttpError(404, format!("No such endpoint: {}", path))),
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Status, content type and body of a response
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn error(HttpError(status, message): HttpError) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+
+    fn send(&self, stream: &mut TcpStream) {
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len()
+        );
+        let _ = stream
+            .write_all(head.as_bytes())
+            .and_then(|_| stream.write_all(&self.body));
+    }
+}
+
+/// A `/profile` request waiting for its window to close
+struct PendingProfile {
+    stream: TcpStream,
+    format: ProfileFormat,
+    mark: SampleMark,
+    started: Instant,
+    seconds: u64,
+}
+
+fn render_profile(format: ProfileFormat, mark: SampleMark, duration: Duration) -> Response {
+    let stacks = {
+        let _session = lock_session();
+        stacks_since(mark)
+    };
+    match format {
+        ProfileFormat::Folded => Response::ok("text/plain; charset=utf-8", format_folded(&stacks)),
+        ProfileFormat::Svg => {
+            let folded = format_folded(&stacks);
+            let mut svg = Vec::new();
+            let mut options = inferno::flamegraph::Options::default();
+            match inferno::flamegraph::from_lines(&mut options, folded.lines(), &mut svg) {
+                Ok(()) => Response::ok("image/svg+xml", svg),
+                Err(e) => Response::error(HttpError(500, format!("Cannot render SVG: {}", e))),
+            }
+        }
+        ProfileFormat::Pprof => {
+            let now_nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            let duration_nanos = duration.as_nanos() as u64;
+            let profile = encode_profile(
+                &stacks,
+                ("wall", "nanoseconds"),
+                now_nanos.saturating_sub(duration_nanos),
+                duration_nanos,
+            );
+            Response::ok("application/octet-stream", profile)
+        }
+        ProfileFormat::Json => {
+            let stacks: Vec<serde_json::Value> = stacks
+                .iter()
+                .map(|(frames, nanos)| json!({ "frames": frames, "self_nanos": nanos }))
+                .collect();
+            let body = json!({
+                "duration_nanos": duration.as_nanos() as u64,
+                "stacks": stacks,
+            });
+            Response::ok("application/json", body.to_string())
+        }
+    }
+}
+
+fn answer(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, route: Route) -> Response {
+    match route {
+        Route::Index => Response::ok("text/plain; charset=utf-8", INDEX),
+        Route::Profile { format, .. } => {
+            render_profile(format, SampleMark::default(), Duration::ZERO)
+        }
+        Route::Heap { top } => {
+            // Heap walks tag every class; reports and snapshots walk the heap under the
+            // same lock
+            let _session = lock_session();
+            match collect_heap_histogram(jvmti_env, jni_env) {
+                Ok(histogram) => Response::ok(
+                    "application/json",
+                    heap_histogram_json(&histogram, top).to_string(),
+                ),
+                Err(e) => Response::error(HttpError(500, e)),
+            }
+        }
+        Route::Threads => match take_thread_dump(jvmti_env, jni_env) {
+            Ok(dump) => Response::ok("application/json", thread_dump_json(&dump).to_string()),
+            Err(e) => Response::error(HttpError(500, e)),
+        },
+        Route::Metrics => Response::ok(
+            "text/plain; version=0.0.4; charset=utf-8",
+            render_metrics(jvmti_env),
+        ),
+    }
+}
+
+/// Read one request and answer it, or return it as pending if it is a timed profile.
+fn serve_connection(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    mut stream: TcpStream,
+) -> Option<PendingProfile> {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let mut reader = BufReader::new(stream.try_clone().ok()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    // Skip the headers; none of the endpoints look at them
+    let mut header = String::new();
+    while reader.read_line(&mut header).ok()? > 2 {
+        header.clear();
+    }
+
+    match parse_request_line(&request_line) {
+        Ok(Route::Profile { seconds, format }) if seconds > 0 => Some(PendingProfile {
+            stream,
+            format,
+            mark: sample_mark(),
+            started: Instant::now(),
+            seconds,
+        }),
+        Ok(route) => {
+            answer(jvmti_env, jni_env, route).send(&mut stream);
+            None
+        }
+        Err(e) => {
+            Response::error(e).send(&mut stream);
+            None
+        }
+    }
+}
+
+unsafe extern "C" fn http_server(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, arg: *mut c_void) {
+    let listener = Box::from_raw(arg as *mut TcpListener);
+    let mut pending: Vec<PendingProfile> = Vec::new();
+    // Requests are answered in turn on this thread; timed profiles wait in `pending`
+    // so they don't hold up the others
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Some(profile) = serve_connection(jvmti_env, jni_env, stream) {
+                    pending.push(profile);
+                }
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => eprintln!("Error accepting HTTP connection: {}", e),
+        }
+
+        let (done, waiting): (Vec<_>, Vec<_>) = pending
+            .drain(..)
+            .partition(|p| p.started.elapsed() >= Duration::from_secs(p.seconds));
+        pending = waiting;
+        for mut profile in done {
+            render_profile(profile.format, profile.mark, profile.started.elapsed())
+                .send(&mut profile.stream);
+        }
+        if sleep_unless_shut_down(POLL_INTERVAL) {
+            return;
+        }
+    }
+}
+
+/// With `http=<port>`, serve live profiles on `127.0.0.1:<port>`.
+pub(crate) fn start_http_server(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    let Some(port) = agent_options().http_port else {
+        return;
+    };
+    let listener = match u16::try_from(port)
+        .map_err(|_| format!("Invalid port: {}", port))
+        .and_then(|port| {
+            TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+                .map_err(|e| format!("Cannot listen on 127.0.0.1:{}: {}", port, e))
+        })
+        .and_then(|listener| {
+            listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+            Ok(listener)
+        }) {
+        Ok(listener) => Box::into_raw(Box::new(listener)),
+        Err(e) => {
+            eprintln!("Failed to start HTTP server: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-http",
+        http_server,
+        listener as *mut c_void,
+    ) {
+        eprintln!("Failed to start HTTP server: {}", e);
+        drop(unsafe { Box::from_raw(listener) });
+        return;
+    }
+    println!("🌐 HTTP endpoint listening on http://127.0.0.1:{}/", port);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request_line() {
+        assert_eq!(
+            parse_request_line("GET /profile HTTP/1.1"),
+            Ok(Route::Profile {
+                seconds: 30,
+                format: ProfileFormat::Folded
+            })
+        );
+        assert_eq!(
+            parse_request_line("GET /profile?seconds=5&format=pprof HTTP/1.1"),
+            Ok(Route::Profile {
+                seconds: 5,
+                format: ProfileFormat::Pprof
+            })
+        );
+        assert_eq!(
+            parse_request_line("GET /heap?top=10 HTTP/1.1"),
+            Ok(Route::Heap { top: 10 })
+        );
+        assert_eq!(
+            parse_request_line("GET /metrics HTTP/1.0"),
+            Ok(Route::Metrics)
+        );
+        assert_eq!(parse_request_line("GET / HTTP/1.1"), Ok(Route::Index));
+
+        assert_eq!(parse_request_line("GET /nope HTTP/1.1").unwrap_err().0, 404);
+        assert_eq!(
+            parse_request_line("POST /metrics HTTP/1.1").unwrap_err().0,
+            405
+        );
+        assert_eq!(
+            parse_request_line("GET /profile?format=png HTTP/1.1")
+                .unwrap_err()
+                .0,
+            400
+        );
+        assert_eq!(
+            parse_request_line("GET /profile?seconds=-1 HTTP/1.1")
+                .unwrap_err()
+                .0,
+            400
+        );
+        assert_eq!(parse_request_line("").unwrap_err().0, 400);
+    }
+
+    /// Send `request` to `serve_connection` over a local connection; the response, if it
+    /// answered at once, and whether it kept the connection as a pending profile.
+    fn exchange(request: &str) -> (String, bool) {
+        use std::io::Read;
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+
+        // None of these routes touch the JVM
+        let pending = serve_connection(std::ptr::null_mut(), std::ptr::null_mut(), server);
+        let is_pending = pending.is_some();
+        drop(pending);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (response, is_pending)
+    }
+
+    #[test]
+    fn test_serve_connection() {
+        let (response, pending) = exchange("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(!pending);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            head,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Length: {}\r\nConnection: close",
+                INDEX.len()
+            )
+        );
+        assert_eq!(body, INDEX);
+
+        let (response, _) = exchange("GET /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.ends_with("\r\n\r\nNo such endpoint: /nope\n"));
+
+        let (response, _) = exchange("POST /metrics HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+        let (response, _) = exchange("GET /profile?format=png HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        // A timed profile is answered later by the server loop
+        let (response, pending) = exchange("GET /profile?seconds=5 HTTP/1.1\r\n\r\n");
+        assert!(pending);
+        assert_eq!(response, "");
+    }
+}
@@ -0,0 +1,410 @@
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::{c_char, c_void};
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::perf_map::record_code_range;
+use crate::profiling::profiling::{
+    elapsed_nanos, format_bytes, format_time, get_method_name_safe, MethodId, METHOD_STATS,
+};
+
+// Compile info records from jvmticmlr.h, which isn't part of the generated bindings.
+const JVMTI_CMLR_INLINE_INFO: jint = 2;
+
+#[repr(C)]
+struct CompiledMethodLoadRecordHeader {
+    kind: jint,
+    majorinfoversion: jint,
+    minorinfoversion: jint,
+    next: *const CompiledMethodLoadRecordHeader,
+}
+
+#[repr(C)]
+struct PCStackInfo {
+    pc: *const c_void,
+    numstackframes: jint,
+    methods: *const jmethodID,
+    bcis: *const jint,
+}
+
+#[repr(C)]
+struct CompiledMethodLoadInlineRecord {
+    header: CompiledMethodLoadRecordHeader,
+    numpcs: jint,
+    pcinfo: *const PCStackInfo,
+}
+
+/// Per-method compilation history
+#[derive(Clone, Default, Debug)]
+pub(crate) struct CompiledMethodStats {
+    pub(crate) compile_count: u64,
+    pub(crate) unload_count: u64,
+    pub(crate) last_code_size: u64,
+    pub(crate) total_code_size: u64,
+    pub(crate) first_compiled_nanos: u64,
+    pub(crate) inlined: HashSet<MethodId>,
+}
+
+/// Kind of JIT event kept in the compilation log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum JitEventKind {
+    Load,
+    Unload,
+}
+
+/// A single entry in the compilation log
+#[derive(Clone, Debug)]
+struct JitEvent {
+    kind: JitEventKind,
+    elapsed_nanos: u64,
+    method_name: String,
+    code_size: u64,
+    inlined_count: usize,
+}
+
+/// Stub or other VM-generated code blob reported by `DynamicCodeGenerated`
+#[derive(Clone, Debug)]
+pub(crate) struct DynamicCode {
+    pub(crate) name: String,
+    pub(crate) address: usize,
+    pub(crate) length: u64,
+}
+
+pub(crate) static COMPILED_METHODS: Lazy<Mutex<HashMap<MethodId, CompiledMethodStats>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Compiled body currently installed. The name is taken at load: at unload the method's
+/// class may be gone, so its jmethodID can't be asked for a name any more.
+struct LiveCode {
+    method: MethodId,
+    method_name: String,
+}
+
+/// Code currently installed, keyed by start address (also dedupes `GenerateEvents` replays)
+static LIVE_CODE: Lazy<Mutex<HashMap<usize, LiveCode>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// How many compiled bodies each method has been inlined into
+static INLINED_INTO: Lazy<Mutex<HashMap<MethodId, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Stubs by start address
+static DYNAMIC_CODE: Lazy<Mutex<HashMap<usize, DynamicCode>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Entries kept in the compilation log; older ones are dropped
+const MAX_JIT_EVENTS: usize = 100_000;
+
+/// The most recent compilation events, and how many older ones were dropped
+#[derive(Default)]
+struct JitLog {
+    events: VecDeque<JitEvent>,
+    dropped: u64,
+}
+
+static JIT_EVENTS: Lazy<Mutex<JitLog>> = Lazy::new(|| Mutex::new(JitLog::default()));
+
+fn log_jit_event(event: JitEvent) {
+    let mut log = JIT_EVENTS.lock().unwrap();
+    if log.events.len() == MAX_JIT_EVENTS {
+        log.events.pop_front();
+        log.dropped += 1;
+    }
+    log.events.push_back(event);
+}
+
+/// Forget the compilation log at a session reset. What is compiled now stays known.
+pub(crate) fn reset_jit_log() {
+    *JIT_EVENTS.lock().unwrap() = JitLog::default();
+}
+
+/// Distinct methods inlined into a compiled body, from the `JVMTI_CMLR_INLINE_INFO` record.
+unsafe fn inlined_methods(compile_info: *const c_void, method: jmethodID) -> HashSet<MethodId> {
+    let mut inlined = HashSet::new();
+    let mut record = compile_info as *const CompiledMethodLoadRecordHeader;
+    while !record.is_null() {
+        if (*record).kind == JVMTI_CMLR_INLINE_INFO {
+            let inline_record = &*(record as *const CompiledMethodLoadInlineRecord);
+            if !inline_record.pcinfo.is_null() {
+                let pcs =
+                    std::slice::from_raw_parts(inline_record.pcinfo, inline_record.numpcs as usize);
+                for pc in pcs {
+                    if pc.methods.is_null() {
+                        continue;
+                    }
+                    let frames = std::slice::from_raw_parts(pc.methods, pc.numstackframes as usize);
+                    for &frame in frames {
+                        if frame != method {
+                            inlined.insert(MethodId(frame));
+                        }
+                    }
+                }
+            }
+        }
+        record = (*record).next;
+    }
+    inlined
+}
+
+pub(crate) extern "C" fn compiled_method_load_callback(
+    jvmti_env: *mut jvmtiEnv,
+    method: jmethodID,
+    code_size: jint,
+    code_addr: *const c_void,
+    _map_length: jint,
+    _map: *const jvmtiAddrLocationMap,
+    compile_info: *const c_void,
+) {
+    {
+        let live = LIVE_CODE.lock().unwrap();
+        if live
+            .get(&(code_addr as usize))
+            .is_some_and(|code| code.method == MethodId(method))
+        {
+            return;
+        }
+    }
+    let method_name =
+        get_method_name_safe(jvmti_env, method).unwrap_or_else(|| "<unknown>".to_string());
+    LIVE_CODE.lock().unwrap().insert(
+        code_addr as usize,
+        LiveCode {
+            method: MethodId(method),
+            method_name: method_name.clone(),
+        },
+    );
+
+    let now = elapsed_nanos(jvmti_env);
+    let inlined = unsafe { inlined_methods(compile_info, method) };
+    let inlined_count = inlined.len();
+
+    {
+        let mut inlined_into = INLINED_INTO.lock().unwrap();
+        for callee in &inlined {
+            *inlined_into.entry(*callee).or_insert(0) += 1;
+        }
+    }
+
+    {
+        let mut compiled = COMPILED_METHODS.lock().unwrap();
+        let entry = compiled.entry(MethodId(method)).or_default();
+        if entry.compile_count == 0 {
+            entry.first_compiled_nanos = now;
+        }
+        entry.compile_count += 1;
+        entry.last_code_size = code_size as u64;
+        entry.total_code_size += code_size as u64;
+        entry.inlined.extend(inlined);
+    }
+
+    record_code_range(&method_name, code_addr as usize, code_size as usize);
+
+    log_jit_event(JitEvent {
+        kind: JitEventKind::Load,
+        elapsed_nanos: now,
+        method_name,
+        code_size: code_size as u64,
+        inlined_count,
+    });
+}
+
+pub(crate) extern "C" fn compiled_method_unload_callback(
+    jvmti_env: *mut jvmtiEnv,
+    method: jmethodID,
+    code_addr: *const c_void,
+) {
+    // `method` only identifies the method here; it must not be passed back to JVMTI
+    let method_name = LIVE_CODE
+        .lock()
+        .unwrap()
+        .remove(&(code_addr as usize))
+        .map_or_else(|| "<unknown>".to_string(), |code| code.method_name);
+
+    let code_size = {
+        let mut compiled = COMPILED_METHODS.lock().unwrap();
+        let entry = compiled.entry(MethodId(method)).or_default();
+        entry.unload_count += 1;
+        entry.last_code_size
+    };
+
+    log_jit_event(JitEvent {
+        kind: JitEventKind::Unload,
+        elapsed_nanos: elapsed_nanos(jvmti_env),
+        method_name,
+        code_size,
+        inlined_count: 0,
+    });
+}
+
+pub(crate) extern "C" fn dynamic_code_generated_callback(
+    _jvmti_env: *mut jvmtiEnv,
+    name: *const c_char,
+    address: *const c_void,
+    length: jint,
+) {
+    let name = if name.is_null() {
+        "<unknown stub>".to_string()
+    } else {
+        unsafe { CStr::from_ptr(name).to_string_lossy().into_owned() }
+    };
+
+    let mut blobs = DYNAMIC_CODE.lock().unwrap();
+    if blobs.contains_key(&(address as usize)) {
+        return;
+    }
+    record_code_range(&name, address as usize, length as usize);
+    blobs.insert(
+        address as usize,
+        DynamicCode {
+            name,
+            address: address as usize,
+            length: length as u64,
+        },
+    );
+}
+
+/// Replay compiled methods and stubs that existed before our events were enabled.
+pub(crate) fn generate_existing_code_events(jvmti_env: *mut jvmtiEnv) {
+    unsafe {
+        for event in [
+            jvmtiEvent_JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent_JVMTI_EVENT_DYNAMIC_CODE_GENERATED,
+        ] {
+            let err = (**jvmti_env).GenerateEvents.unwrap()(jvmti_env, event);
+            if err != jvmtiError_JVMTI_ERROR_NONE {
+                eprintln!("Failed to generate events {}: {}", event, err);
+            }
+        }
+    }
+}
+
+/// Short JIT status for a method, used to annotate the hot-method report.
+pub(crate) fn compilation_status(method: MethodId) -> String {
+    let compiled = COMPILED_METHODS.lock().unwrap();
+    let inlined_into = INLINED_INTO
+        .lock()
+        .unwrap()
+        .get(&method)
+        .copied()
+        .unwrap_or(0);
+
+    match compiled.get(&method) {
+        Some(st) if st.compile_count > 0 => {
+            let mut status = format!(
+                "compiled {}x ({})",
+                st.compile_count,
+                format_bytes(st.last_code_size)
+            );
+            if st.unload_count > 0 {
+                status.push_str(&format!(", unloaded {}x", st.unload_count));
+            }
+            status
+        }
+        _ if inlined_into > 0 => format!("inlined into {}", inlined_into),
+        _ => "interpreted".to_string(),
+    }
+}
+
+/// Print JIT totals, hot methods that never got compiled and the most-unloaded methods.
+pub(crate) fn print_jit_summary(jvmti_env: *mut jvmtiEnv) {
+    let compiled = COMPILED_METHODS.lock().unwrap().clone();
+    let inlined_into = INLINED_INTO.lock().unwrap().clone();
+    let stub_count = DYNAMIC_CODE.lock().unwrap().len();
+
+    let compiled_count = compiled.values().filter(|st| st.compile_count > 0).count();
+    let total_code: u64 = compiled.values().map(|st| st.total_code_size).sum();
+    let total_unloads: u64 = compiled.values().map(|st| st.unload_count).sum();
+
+    println!("\n⚙️  === JIT compilation ===");
+    println!(
+        "{} methods compiled ({} of code), {} unloads, {} dynamic code blobs",
+        compiled_count,
+        format_bytes(total_code),
+        total_unloads,
+        stub_count
+    );
+
+    // Hot methods by self-time that never got their own compiled body
+    let mut hot: Vec<(MethodId, u64)> = {
+        let stats = METHOD_STATS.lock().unwrap();
+        stats.iter().map(|(&m, st)| (m, st.self_nanos)).collect()
+    };
+    hot.sort_by_key(|&(_, nanos)| std::cmp::Reverse(nanos));
+    let never_compiled: Vec<_> = hot
+        .iter()
+        .take(50)
+        .filter(|(m, _)| {
+            compiled.get(m).is_none_or(|st| st.compile_count == 0) && !inlined_into.contains_key(m)
+        })
+        .take(10)
+        .collect();
+    if !never_compiled.is_empty() {
+        println!("\n🐢 Hot methods never compiled:");
+        for (method, self_nanos) in never_compiled {
+            let name = get_method_name_safe(jvmti_env, method.0)
+                .unwrap_or_else(|| "<unknown>".to_string());
+            println!("{:<60} {:>8} self", name, format_time(*self_nanos));
+        }
+    }
+
+    let mut unloaded: Vec<_> = compiled
+        .iter()
+        .filter(|(_, st)| st.unload_count > 0)
+        .collect();
+    unloaded.sort_by_key(|(_, st)| std::cmp::Reverse(st.unload_count));
+    if !unloaded.is_empty() {
+        println!("\n♻️  Most frequently unloaded (deoptimized) methods:");
+        for (method, st) in unloaded.iter().take(10) {
+            let name = get_method_name_safe(jvmti_env, method.0)
+                .unwrap_or_else(|| "<unknown>".to_string());
+            println!(
+                "{:<60} {:>4} compiles, {:>4} unloads",
+                name, st.compile_count, st.unload_count
+            );
+        }
+    }
+}
+
+/// Write the chronological compilation log and the dynamic code blobs.
+pub(crate) fn write_jit_log(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let log = JIT_EVENTS.lock().unwrap();
+    let mut blobs: Vec<DynamicCode> = DYNAMIC_CODE.lock().unwrap().values().cloned().collect();
+    blobs.sort_by_key(|blob| blob.address);
+    let mut file = File::create(path)?;
+
+    writeln!(file, "JIT Compilation Log")?;
+    writeln!(file, "===================")?;
+    if log.dropped > 0 {
+        writeln!(file, "({} older events dropped)", log.dropped)?;
+    }
+    for event in log.events.iter() {
+        let kind = match event.kind {
+            JitEventKind::Load => "LOAD  ",
+            JitEventKind::Unload => "UNLOAD",
+        };
+        writeln!(
+            file,
+            "+{:>10} {} {:<70} {:>8} inlined={}",
+            format_time(event.elapsed_nanos),
+            kind,
+            event.method_name,
+            format_bytes(event.code_size),
+            event.inlined_count
+        )?;
+    }
+
+    writeln!(file, "\nDynamic code ({} blobs)", blobs.len())?;
+    for blob in blobs.iter() {
+        writeln!(
+            file,
+            "{:#018x} {:>8} {}",
+            blob.address,
+            format_bytes(blob.length),
+            blob.name
+        )?;
+    }
+
+    Ok(())
+}
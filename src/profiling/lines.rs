@@ -0,0 +1,441 @@
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_char;
+use std::sync::{Arc, Mutex};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::extension_events::set_extension_event_callbacks;
+use crate::profiling::profiling::{format_bytes, get_method_name_safe, MethodId};
+
+/// Line number table of a method, sorted by start location
+type LineTable = Vec<(jlocation, jint)>;
+
+/// A bytecode position: method and bytecode index
+pub(crate) type CodeLocation = (MethodId, jlocation);
+
+/// A cache of per-method lookups (`None` = not available)
+type MethodCache<T> = Mutex<HashMap<MethodId, Option<T>>>;
+
+/// Methods whose line table and source file are kept; a full cache starts over
+const MAX_CACHED_METHODS: usize = 65_536;
+
+/// HotSpot extension event posted when classes are unloaded
+const CLASS_UNLOAD_EVENT_ID: &str = "com.sun.hotspot.events.ClassUnload";
+
+/// Line tables and source file names, cached per method
+static LINE_TABLES: Lazy<MethodCache<Arc<LineTable>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static SOURCE_FILES: Lazy<MethodCache<String>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Samples of running threads, by top frame method and bytecode index
+pub(crate) static LOCATION_SAMPLES: Lazy<Mutex<HashMap<CodeLocation, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Allocations by allocating method and bytecode index: (objects, bytes)
+pub(crate) static ALLOCATION_SITES: Lazy<Mutex<HashMap<CodeLocation, (u64, u64)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Source line for a bytecode index: the last table entry starting at or before it.
+pub(crate) fn line_for_location(table: &[(jlocation, jint)], location: jlocation) -> Option<jint> {
+    if location < 0 {
+        return None;
+    }
+    let idx = table.partition_point(|&(start, _)| start <= location);
+    idx.checked_sub(1).map(|i| table[i].1)
+}
+
+fn load_line_table(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<LineTable> {
+    unsafe {
+        let mut count: jint = 0;
+        let mut entries: *mut jvmtiLineNumberEntry = std::ptr::null_mut();
+        let err =
+            (**jvmti_env).GetLineNumberTable.unwrap()(jvmti_env, method, &mut count, &mut entries);
+        // Native and abstract methods, and classes compiled without -g:lines, have no table
+        if err != jvmtiError_JVMTI_ERROR_NONE || entries.is_null() {
+            return None;
+        }
+
+        let mut table: LineTable = std::slice::from_raw_parts(entries, count as usize)
+            .iter()
+            .map(|e| (e.start_location, e.line_number))
+            .collect();
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, entries as *mut u8);
+        table.sort_by_key(|&(start, _)| start);
+        Some(table)
+    }
+}
+
+fn load_source_file(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<String> {
+    unsafe {
+        let mut klass: jclass = std::ptr::null_mut();
+        if (**jvmti_env).GetMethodDeclaringClass.unwrap()(jvmti_env, method, &mut klass)
+            != jvmtiError_JVMTI_ERROR_NONE
+        {
+            return None;
+        }
+
+        let mut name_ptr: *mut c_char = std::ptr::null_mut();
+        let err = (**jvmti_env).GetSourceFileName.unwrap()(jvmti_env, klass, &mut name_ptr);
+        if err != jvmtiError_JVMTI_ERROR_NONE || name_ptr.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, name_ptr as *mut u8);
+        Some(name)
+    }
+}
+
+/// The cached value for `method`, or `load`ed and cached. Loading runs without the cache
+/// locked, so other threads aren't held up by its JVMTI calls.
+fn cached<T: Clone>(
+    cache: &MethodCache<T>,
+    method: MethodId,
+    load: impl FnOnce() -> Option<T>,
+) -> Option<T> {
+    if let Some(value) = cache.lock().unwrap().get(&method) {
+        return value.clone();
+    }
+    let value = load();
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= MAX_CACHED_METHODS {
+        cache.clear();
+    }
+    cache.insert(method, value.clone());
+    value
+}
+
+fn line_table(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<Arc<LineTable>> {
+    cached(&LINE_TABLES, MethodId(method), || {
+        load_line_table(jvmti_env, method).map(Arc::new)
+    })
+}
+
+/// Source line of `location` within `method`, if the class has line numbers.
+pub(crate) fn line_number(
+    jvmti_env: *mut jvmtiEnv,
+    method: jmethodID,
+    location: jlocation,
+) -> Option<jint> {
+    line_table(jvmti_env, method).and_then(|table| line_for_location(&table, location))
+}
+
+/// Source file (`Foo.java`) declaring `method`, if recorded in the class file.
+pub(crate) fn source_file(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<String> {
+    cached(&SOURCE_FILES, MethodId(method), || {
+        load_source_file(jvmti_env, method)
+    })
+}
+
+/// Forget cached line tables and source files, e.g. of methods of unloaded classes.
+pub(crate) fn clear_line_caches() {
+    LINE_TABLES.lock().unwrap().clear();
+    SOURCE_FILES.lock().unwrap().clear();
+}
+
+unsafe extern "C" fn class_unload_callback(
+    _jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _class: jobject,
+) {
+    clear_line_caches();
+}
+
+/// Clear the line caches whenever classes are unloaded, if this VM reports unloading.
+pub(crate) fn watch_class_unloads(jvmti_env: *mut jvmtiEnv) {
+    set_extension_event_callbacks(jvmti_env, &[(CLASS_UNLOAD_EVENT_ID, class_unload_callback)]);
+}
+
+/// `Foo.java:42`, or `Foo.java` / `bci 7` / empty when only part is known.
+pub(crate) fn format_location(
+    jvmti_env: *mut jvmtiEnv,
+    method: jmethodID,
+    location: jlocation,
+) -> String {
+    match (
+        source_file(jvmti_env, method),
+        line_number(jvmti_env, method, location),
+    ) {
+        (Some(file), Some(line)) => format!("{}:{}", file, line),
+        (Some(file), None) => file,
+        (None, Some(line)) => format!("line {}", line),
+        (None, None) if location >= 0 => format!("bci {}", location),
+        (None, None) => String::new(),
+    }
+}
+
+/// Method name with its source position, e.g. `com.acme.Foo.bar(Foo.java:42)`.
+pub(crate) fn method_at_location(
+    jvmti_env: *mut jvmtiEnv,
+    method: jmethodID,
+    location: jlocation,
+) -> String {
+    let name = get_method_name_safe(jvmti_env, method).unwrap_or_else(|| "<unknown>".to_string());
+    let position = format_location(jvmti_env, method, location);
+    if position.is_empty() {
+        name
+    } else {
+        format!("{}({})", name, position)
+    }
+}
+
+/// Count a sample of a running thread at its top frame.
+pub(crate) fn record_location_sample(method: jmethodID, location: jlocation) {
+    *LOCATION_SAMPLES
+        .lock()
+        .unwrap()
+        .entry((MethodId(method), location))
+        .or_insert(0) += 1;
+}
+
+/// Sample the top frame of a running thread. Native frames have no bytecode position and
+/// often just wait inside the VM, so they aren't counted.
+pub(crate) fn sample_top_frame(jvmti_env: *mut jvmtiEnv, thread: jthread) {
+    unsafe {
+        let mut frame = std::mem::zeroed::<jvmtiFrameInfo>();
+        let mut count: jint = 0;
+        let err =
+            (**jvmti_env).GetStackTrace.unwrap()(jvmti_env, thread, 0, 1, &mut frame, &mut count);
+        if err == jvmtiError_JVMTI_ERROR_NONE && count == 1 && frame.location >= 0 {
+            record_location_sample(frame.method, frame.location);
+        }
+    }
+}
+
+/// Attribute an allocation to the bytecode in the current thread's top frame.
+pub(crate) fn record_allocation_site(jvmti_env: *mut jvmtiEnv, thread: jthread, size: u64) {
+    let mut method: jmethodID = std::ptr::null_mut();
+    let mut location: jlocation = 0;
+    let err = unsafe {
+        (**jvmti_env).GetFrameLocation.unwrap()(jvmti_env, thread, 0, &mut method, &mut location)
+    };
+    if err != jvmtiError_JVMTI_ERROR_NONE || method.is_null() {
+        return;
+    }
+    let mut sites = ALLOCATION_SITES.lock().unwrap();
+    let entry = sites.entry((MethodId(method), location)).or_insert((0, 0));
+    entry.0 += 1;
+    entry.1 += size;
+}
+
+/// Merge per-bci counts into per-line counts; the line is `None` where it can't be resolved.
+fn by_line<V: Copy + std::ops::AddAssign>(
+    jvmti_env: *mut jvmtiEnv,
+    counts: &HashMap<CodeLocation, V>,
+) -> Vec<((MethodId, Option<jint>), V)> {
+    let mut merged: HashMap<(MethodId, Option<jint>), V> = HashMap::new();
+    for (&(method, location), &value) in counts {
+        let line = line_number(jvmti_env, method.0, location);
+        merged
+            .entry((method, line))
+            .and_modify(|v| *v += value)
+            .or_insert(value);
+    }
+    merged.into_iter().collect()
+}
+
+fn describe_line(jvmti_env: *mut jvmtiEnv, method: MethodId, line: Option<jint>) -> String {
+    let name = get_method_name_safe(jvmti_env, method.0).unwrap_or_else(|| "<unknown>".to_string());
+    match (source_file(jvmti_env, method.0), line) {
+        (Some(file), Some(line)) => format!("{}({}:{})", name, file, line),
+        (Some(file), None) => format!("{}({})", name, file),
+        (None, Some(line)) => format!("{}(line {})", name, line),
+        (None, None) => name,
+    }
+}
+
+/// Print the most sampled source lines and the busiest allocation lines.
+pub(crate) fn print_line_hotspots(jvmti_env: *mut jvmtiEnv, top_n: usize) {
+    // Copied, so lines are resolved without holding up the sampler
+    let samples = LOCATION_SAMPLES.lock().unwrap().clone();
+    let total: u64 = samples.values().sum();
+    if total > 0 {
+        let mut lines = by_line(jvmti_env, &samples);
+        lines.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+        println!(
+            "\n🎯 === Top {} source lines by samples ({} samples) ===",
+            std::cmp::min(top_n, lines.len()),
+            total
+        );
+        for ((method, line), n) in lines.iter().take(top_n) {
+            println!(
+                "{:<70} {:>6} samples {:>5.1}%",
+                describe_line(jvmti_env, *method, *line),
+                n,
+                *n as f64 * 100.0 / total as f64
+            );
+        }
+    }
+
+    let sites = ALLOCATION_SITES.lock().unwrap().clone();
+    if !sites.is_empty() {
+        let mut lines = by_line(
+            jvmti_env,
+            &sites
+                .iter()
+                .map(|(&k, &(_, bytes))| (k, bytes))
+                .collect::<HashMap<_, _>>(),
+        );
+        lines.sort_by_key(|&(_, bytes)| std::cmp::Reverse(bytes));
+        println!(
+            "\n🏭 === Top {} allocation sites by line ===",
+            std::cmp::min(top_n, lines.len())
+        );
+        for ((method, line), bytes) in lines.iter().take(top_n) {
+            println!(
+                "{:<70} {:>10}",
+                describe_line(jvmti_env, *method, *line),
+                format_bytes(*bytes)
+            );
+        }
+    }
+}
+
+/// Write per-line samples for every sampled method, grouped by method, plus a JSON
+/// version (method, file, line, bci, samples) for tools such as `rjprof annotate`.
+pub(crate) fn write_line_hotspots(
+    jvmti_env: *mut jvmtiEnv,
+    text_path: &str,
+    json_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let samples = LOCATION_SAMPLES.lock().unwrap().clone();
+    let total: u64 = samples.values().sum();
+
+    let mut per_method: HashMap<MethodId, Vec<(jlocation, u64)>> = HashMap::new();
+    for (&(method, location), &n) in samples.iter() {
+        per_method.entry(method).or_default().push((location, n));
+    }
+    let mut methods: Vec<(MethodId, u64, Vec<_>)> = per_method
+        .into_iter()
+        .map(|(m, locs)| (m, locs.iter().map(|&(_, n)| n).sum(), locs))
+        .collect();
+    methods.sort_by_key(|&(_, n, _)| std::cmp::Reverse(n));
+
+    let mut file = File::create(text_path)?;
+    let mut records = Vec::new();
+    writeln!(file, "Line-level samples ({} total)", total)?;
+    for (method, method_total, mut locations) in methods {
+        let name =
+            get_method_name_safe(jvmti_env, method.0).unwrap_or_else(|| "<unknown>".to_string());
+        let source = source_file(jvmti_env, method.0);
+        writeln!(
+            file,
+            "\n{} ({}) {} samples {:.1}%",
+            name,
+            source.as_deref().unwrap_or("unknown source"),
+            method_total,
+            method_total as f64 * 100.0 / total.max(1) as f64
+        )?;
+
+        locations.sort_by_key(|&(location, _)| location);
+        for (location, n) in locations {
+            let line = line_number(jvmti_env, method.0, location);
+            writeln!(
+                file,
+                "  {:>10} bci {:>5} {:>8} samples {:>5.1}%",
+                line.map(|l| format!("line {}", l)).unwrap_or_default(),
+                location,
+                n,
+                n as f64 * 100.0 / method_total as f64
+            )?;
+            records.push(json!({
+                "method": name,
+                "file": source,
+                "line": line,
+                "bci": location,
+                "samples": n,
+            }));
+        }
+    }
+
+    serde_json::to_writer_pretty(
+        File::create(json_path)?,
+        &json!({ "total_samples": total, "locations": records }),
+    )?;
+    Ok(())
+}
+
+fn bytecodes(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<Vec<u8>> {
+    unsafe {
+        let mut count: jint = 0;
+        let mut code: *mut u8 = std::ptr::null_mut();
+        let err = (**jvmti_env).GetBytecodes.unwrap()(jvmti_env, method, &mut count, &mut code);
+        if err != jvmtiError_JVMTI_ERROR_NONE || code.is_null() {
+            return None;
+        }
+        let bytes = std::slice::from_raw_parts(code, count as usize).to_vec();
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, code);
+        Some(bytes)
+    }
+}
+
+/// Write the bytecode and line table of every sampled method, so `rjprof annotate` can
+/// show source ranges and bytecode listings after the JVM has exited.
+pub(crate) fn write_method_code(
+    jvmti_env: *mut jvmtiEnv,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut methods: Vec<MethodId> = LOCATION_SAMPLES
+        .lock()
+        .unwrap()
+        .keys()
+        .map(|&(method, _)| method)
+        .collect();
+    methods.sort_by_key(|m| m.0 as usize);
+    methods.dedup();
+
+    let records: Vec<serde_json::Value> = methods
+        .into_iter()
+        .map(|method| {
+            let code = bytecodes(jvmti_env, method.0).unwrap_or_default();
+            let lines = line_table(jvmti_env, method.0)
+                .map(|table| table.to_vec())
+                .unwrap_or_default();
+            json!({
+                "method": get_method_name_safe(jvmti_env, method.0),
+                "file": source_file(jvmti_env, method.0),
+                "lines": lines,
+                "bytecodes": code.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            })
+        })
+        .collect();
+
+    serde_json::to_writer(File::create(path)?, &json!({ "methods": records }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_for_location() {
+        let table = [(0, 10), (4, 11), (9, 13)];
+        assert_eq!(line_for_location(&table, 0), Some(10));
+        assert_eq!(line_for_location(&table, 3), Some(10));
+        assert_eq!(line_for_location(&table, 4), Some(11));
+        assert_eq!(line_for_location(&table, 100), Some(13));
+        assert_eq!(line_for_location(&table, -1), None);
+        assert_eq!(line_for_location(&[], 5), None);
+        assert_eq!(line_for_location(&[(2, 7)], 1), None);
+    }
+
+    #[test]
+    fn test_method_cache() {
+        let cache: MethodCache<u32> = Mutex::new(HashMap::new());
+        let method = |n: usize| MethodId(n as jmethodID);
+        assert_eq!(cached(&cache, method(1), || Some(7)), Some(7));
+        assert_eq!(cached(&cache, method(1), || panic!("cached")), Some(7));
+        assert_eq!(cached(&cache, method(2), || None), None);
+        assert_eq!(cached(&cache, method(2), || panic!("cached")), None);
+
+        for n in 3..=MAX_CACHED_METHODS {
+            cached(&cache, method(n), || Some(0));
+        }
+        assert_eq!(cache.lock().unwrap().len(), MAX_CACHED_METHODS);
+        cached(&cache, method(0), || Some(1));
+        assert_eq!(cache.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt::Write;
+use std::fs;
+use std::os::raw::c_void;
+use std::ptr;
+use std::time::Duration;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::exceptions::exception_counts;
+use crate::profiling::gc::{gc_pauses, GC_PAUSE_BUCKETS};
+use crate::profiling::off_cpu::off_cpu_totals;
+use crate::profiling::options::agent_options;
+use crate::profiling::profiling::{
+    dropped_frames, elapsed_nanos, flamegraph_sample_count, CLASS_ALLOCATION_STATS, METHOD_STATS,
+};
+use crate::profiling::session::{session_active, session_generation};
+use crate::profiling::threads::sampler_stats;
+
+/// Classes (by bytes allocated, or exceptions thrown) given their own series; the rest
+/// are left out to keep the series count bounded
+const METRICS_TOP_CLASSES: usize = 100;
+
+/// How often the textfile is rewritten unless `metricsinterval` says otherwise
+const DEFAULT_METRICS_INTERVAL_SECS: u64 = 15;
+
+/// Builder for the Prometheus text exposition format
+#[derive(Default)]
+pub(crate) struct MetricsText {
+    text: String,
+}
+
+/// Escape a label value: backslash, double quote and newline.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl MetricsText {
+    /// Start a metric family; its samples follow with [`MetricsText::sample`].
+    pub(crate) fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    pub(crate) fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.text.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+                .collect();
+            let _ = write!(self.text, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.text, " {}", value);
+    }
+
+    /// A family with a single unlabelled sample.
+    pub(crate) fn single(&mut self, name: &str, kind: &str, help: &str, value: f64) {
+        self.family(name, kind, help);
+        self.sample(name, &[], value);
+    }
+
+    pub(crate) fn finish(self) -> String {
+        self.text
+    }
+}
+
+/// The profiler's own state in Prometheus text format, for `/metrics`.
+pub(crate) fn render_metrics(jvmti_env: *mut jvmtiEnv) -> String {
+    let mut metrics = MetricsText::default();
+    metrics.single(
+        "rjprof_session",
+        "gauge",
+        "Number of the current profiling session",
+        session_generation() as f64,
+    );
+    metrics.single(
+        "rjprof_session_active",
+        "gauge",
+        "Whether profiling events are being recorded",
+        if session_active() { 1.0 } else { 0.0 },
+    );
+    metrics.single(
+        "rjprof_uptime_seconds",
+        "gauge",
+        "Time since the agent started",
+        elapsed_nanos(jvmti_env) as f64 / 1e9,
+    );
+    metrics.single(
+        "rjprof_flamegraph_samples",
+        "gauge",
+        "Flamegraph samples recorded in this session",
+        flamegraph_sample_count() as f64,
+    );
+    metrics.single(
+        "rjprof_profiled_methods",
+        "gauge",
+        "Distinct methods with timing stats in this session",
+        METHOD_STATS.lock().unwrap().len() as f64,
+    );
+
+    let mut classes: Vec<(String, u64, u64)> = CLASS_ALLOCATION_STATS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|st| (st.class_name.clone(), st.total_bytes, st.object_count))
+        .collect();
+    classes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    classes.truncate(METRICS_TOP_CLASSES);
+    metrics.family(
+        "rjprof_allocated_bytes_total",
+        "counter",
+        "Bytes allocated per class (VMObjectAlloc), top classes only",
+    );
+    for (class, bytes, _) in &classes {
+        metrics.sample(
+            "rjprof_allocated_bytes_total",
+            &[("class", class)],
+            *bytes as f64,
+        );
+    }
+    metrics.family(
+        "rjprof_allocated_objects_total",
+        "counter",
+        "Objects allocated per class (VMObjectAlloc), top classes only",
+    );
+    for (class, _, objects) in &classes {
+        metrics.sample(
+            "rjprof_allocated_objects_total",
+            &[("class", class)],
+            *objects as f64,
+        );
+    }
+
+    let (pause_counts, pause_nanos) = gc_pauses();
+    metrics.family(
+        "rjprof_gc_pause_seconds",
+        "histogram",
+        "Garbage collection pauses",
+    );
+    let mut cumulative = 0;
+    for (i, count) in pause_counts.iter().enumerate() {
+        cumulative += count;
+        let le = GC_PAUSE_BUCKETS
+            .get(i)
+            .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+        metrics.sample(
+            "rjprof_gc_pause_seconds_bucket",
+            &[("le", &le)],
+            cumulative as f64,
+        );
+    }
+    metrics.sample("rjprof_gc_pause_seconds_sum", &[], pause_nanos as f64 / 1e9);
+    metrics.sample("rjprof_gc_pause_seconds_count", &[], cumulative as f64);
+
+    metrics.family(
+        "rjprof_exceptions_total",
+        "counter",
+        "Exceptions thrown per class, caught or not, top classes only",
+    );
+    for (class, count) in exception_counts().iter().take(METRICS_TOP_CLASSES) {
+        metrics.sample(
+            "rjprof_exceptions_total",
+            &[("class", class)],
+            *count as f64,
+        );
+    }
+
+    if agent_options().off_cpu {
+        metrics.family(
+            "rjprof_blocked_seconds_total",
+            "counter",
+            "Time threads spent blocked; reason=\"monitor\" is monitor contention",
+        );
+        for (reason, nanos) in off_cpu_totals() {
+            metrics.sample(
+                "rjprof_blocked_seconds_total",
+                &[("reason", reason.name())],
+                nanos as f64 / 1e9,
+            );
+        }
+    }
+
+    let sampler = sampler_stats();
+    metrics.single(
+        "rjprof_sampler_passes_total",
+        "counter",
+        "Thread state sampling passes",
+        sampler.passes as f64,
+    );
+    metrics.single(
+        "rjprof_sampler_seconds_total",
+        "counter",
+        "Time spent sampling thread states (profiler overhead)",
+        sampler.nanos as f64 / 1e9,
+    );
+    metrics.family(
+        "rjprof_dropped_samples_total",
+        "counter",
+        "Samples lost: sampling intervals missed, or method exits that didn't match the shadow stack",
+    );
+    metrics.sample(
+        "rjprof_dropped_samples_total",
+        &[("source", "thread_states")],
+        sampler.missed as f64,
+    );
+    metrics.sample(
+        "rjprof_dropped_samples_total",
+        &[("source", "flamegraph")],
+        dropped_frames() as f64,
+    );
+    metrics.finish()
+}
+
+/// Replace `path` with the current metrics. node_exporter may read the file at any time,
+/// so it is written next to it and renamed into place.
+fn write_metrics_file(jvmti_env: *mut jvmtiEnv, path: &str) -> std::io::Result<()> {
+    let staging = format!("{}.{}.tmp", path, std::process::id());
+    fs::write(&staging, render_metrics(jvmti_env))?;
+    fs::rename(&staging, path)
+}
+
+unsafe extern "C" fn metrics_writer(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    let options = agent_options();
+    let Some(path) = &options.metrics_file else {
+        return;
+    };
+    let interval = Duration::from_secs(
+        options
+            .metrics_interval_secs
+            .unwrap_or(DEFAULT_METRICS_INTERVAL_SECS),
+    );
+    loop {
+        if let Err(e) = write_metrics_file(jvmti_env, path) {
+            eprintln!("Error writing metrics to {}: {}", path, e);
+        }
+        if sleep_unless_shut_down(interval) {
+            return;
+        }
+    }
+}
+
+/// With `metricsfile=<path>`, rewrite `path` with the metrics every interval, for the
+/// node_exporter textfile collector.
+pub(crate) fn start_metrics_writer(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if agent_options().metrics_file.is_none() {
+        return;
+    }
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-metrics",
+        metrics_writer,
+        ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start metrics writer: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_text() {
+        let mut metrics = MetricsText::default();
+        metrics.family("rjprof_bytes_total", "counter", "Bytes allocated");
+        metrics.sample("rjprof_bytes_total", &[("class", "a\"b\\c\nd")], 1024.0);
+        metrics.single("rjprof_up", "gauge", "Up", 1.0);
+        assert_eq!(
+            metrics.finish(),
+            "# HELP rjprof_bytes_total Bytes allocated\n\
+             # TYPE rjprof_bytes_total counter\n\
+             rjprof_bytes_total{class=\"a\\\"b\\\\c\\nd\"} 1024\n\
+             # HELP rjprof_up Up\n\
+             # TYPE rjprof_up gauge\n\
+             rjprof_up 1\n"
+        );
+    }
+}
@@ -1 +1,29 @@
+pub mod agent_thread;
+pub mod class_loading;
+pub mod continuous;
+pub mod control;
+pub mod cpu_sampler;
+pub mod exceptions;
+pub mod extension_events;
+pub mod filter;
+pub mod gc;
+pub mod heap;
+pub mod heap_graph;
+pub mod http;
+pub mod jit;
+pub mod lines;
+pub mod metrics;
+pub mod native;
+pub mod native_symbols;
+pub mod off_cpu;
+pub mod options;
+pub mod perf_map;
+pub mod pprof;
+pub mod process_cpu;
 pub mod profiling;
+pub mod pyroscope;
+pub mod session;
+pub mod snapshot;
+pub mod thread_dump;
+pub mod threads;
+pub mod virtual_threads;
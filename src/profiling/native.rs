@@ -0,0 +1,175 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::native_symbols::symbolize;
+use crate::profiling::profiling::{
+    format_time, get_method_name_safe, MethodId, MethodStats, METHOD_STATS,
+};
+
+/// Suffix for native frames in the flamegraph and reports
+pub(crate) const NATIVE_SUFFIX: &str = " [native]";
+
+/// Colour of native frames in `palette.map` (flamegraph.pl / inferno `--cp`)
+const NATIVE_FRAME_COLOR: &str = "rgb(90,140,220)";
+
+/// Where a native method got bound to, from a NativeMethodBind event
+#[derive(Clone, Debug)]
+struct NativeBinding {
+    address: usize,
+    bind_count: u64,
+}
+
+static NATIVE_BINDINGS: Lazy<Mutex<HashMap<MethodId, NativeBinding>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// IsMethodNative results, looked up on every method exit
+static NATIVE_METHODS: Lazy<Mutex<HashMap<MethodId, bool>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub(crate) extern "C" fn native_method_bind_callback(
+    _jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _thread: jthread,
+    method: jmethodID,
+    address: *mut c_void,
+    _new_address_ptr: *mut *mut c_void,
+) {
+    // May run in the primordial phase: just remember the address, resolve names later
+    let mut bindings = NATIVE_BINDINGS.lock().unwrap();
+    let binding = bindings.entry(MethodId(method)).or_insert(NativeBinding {
+        address: 0,
+        bind_count: 0,
+    });
+    binding.address = address as usize;
+    binding.bind_count += 1;
+}
+
+pub(crate) fn is_native(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> bool {
+    *NATIVE_METHODS
+        .lock()
+        .unwrap()
+        .entry(MethodId(method))
+        .or_insert_with(|| unsafe {
+            let mut native: jboolean = 0;
+            let err = (**jvmti_env).IsMethodNative.unwrap()(jvmti_env, method, &mut native);
+            err == jvmtiError_JVMTI_ERROR_NONE && native != 0
+        })
+}
+
+/// Method name for a flamegraph frame, with `[native]` appended for native methods.
+pub(crate) fn frame_name(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<String> {
+    let name = get_method_name_safe(jvmti_env, method)?;
+    if is_native(jvmti_env, method) {
+        Some(format!("{}{}", name, NATIVE_SUFFIX))
+    } else {
+        Some(name)
+    }
+}
+
+fn describe_binding(binding: Option<&NativeBinding>) -> String {
+    match binding {
+        Some(binding) => {
+            symbolize(binding.address).unwrap_or_else(|| format!("{:#x}", binding.address))
+        }
+        None => "not bound".to_string(),
+    }
+}
+
+/// Write `palette.map` entries so native frames get their own colour in the SVG.
+pub(crate) fn write_native_palette<'a>(
+    path: &str,
+    frames: impl Iterator<Item = &'a String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut native: Vec<&String> = frames.filter(|f| f.ends_with(NATIVE_SUFFIX)).collect();
+    native.sort();
+    native.dedup();
+    let mut file = File::create(path)?;
+    for frame in native {
+        writeln!(file, "{}->{}", frame, NATIVE_FRAME_COLOR)?;
+    }
+    Ok(())
+}
+
+/// Split per-method time into Java and native, and list the slowest native methods.
+pub(crate) fn print_native_summary(jvmti_env: *mut jvmtiEnv) {
+    let stats: Vec<(MethodId, MethodStats)> = {
+        let guard = METHOD_STATS.lock().unwrap();
+        guard.iter().map(|(&m, st)| (m, *st)).collect()
+    };
+    let (mut native, java): (Vec<_>, Vec<_>) = stats
+        .into_iter()
+        .partition(|(m, _)| is_native(jvmti_env, m.0));
+
+    let native_nanos: u64 = native.iter().map(|(_, st)| st.self_nanos).sum();
+    let java_nanos: u64 = java.iter().map(|(_, st)| st.self_nanos).sum();
+    let total = (native_nanos + java_nanos).max(1) as f64;
+    let bindings = NATIVE_BINDINGS.lock().unwrap().clone();
+
+    println!("\n🧩 === Java vs native time ===");
+    println!(
+        "Java methods:   {:>10} self ({:.1}%) in {} methods",
+        format_time(java_nanos),
+        java_nanos as f64 * 100.0 / total,
+        java.len()
+    );
+    println!(
+        "Native methods: {:>10} self ({:.1}%) in {} methods, {} bindings",
+        format_time(native_nanos),
+        native_nanos as f64 * 100.0 / total,
+        native.len(),
+        bindings.len()
+    );
+
+    native.sort_by_key(|(_, st)| std::cmp::Reverse(st.self_nanos));
+    for (method, st) in native.iter().take(10) {
+        let name =
+            get_method_name_safe(jvmti_env, method.0).unwrap_or_else(|| "<unknown>".to_string());
+        println!(
+            "{:<50} {:>6} calls {:>8} | {}",
+            format!("{}{}", name, NATIVE_SUFFIX),
+            st.count,
+            format_time(st.self_nanos),
+            describe_binding(bindings.get(method))
+        );
+    }
+}
+
+/// Write every NativeMethodBind event seen, with the resolved library symbol.
+pub(crate) fn write_native_methods(
+    jvmti_env: *mut jvmtiEnv,
+    path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bindings = NATIVE_BINDINGS.lock().unwrap().clone();
+    let stats = METHOD_STATS.lock().unwrap().clone();
+
+    let mut rows: Vec<(String, &NativeBinding, Option<&MethodStats>)> = bindings
+        .iter()
+        .map(|(method, binding)| {
+            let name = get_method_name_safe(jvmti_env, method.0)
+                .unwrap_or_else(|| "<unknown>".to_string());
+            (name, binding, stats.get(method))
+        })
+        .collect();
+    rows.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut file = File::create(path)?;
+    writeln!(file, "Native Method Bindings ({})", rows.len())?;
+    writeln!(file, "======================")?;
+    for (name, binding, st) in rows {
+        writeln!(
+            file,
+            "{:<70} {:<60} binds={} calls={} time={}",
+            name,
+            describe_binding(Some(binding)),
+            binding.bind_count,
+            st.map_or(0, |st| st.count),
+            format_time(st.map_or(0, |st| st.self_nanos))
+        )?;
+    }
+    Ok(())
+}
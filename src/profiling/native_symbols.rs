@@ -0,0 +1,293 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs;
+use std::os::raw::{c_int, c_void};
+use std::sync::{Arc, Mutex};
+
+// ELF64 little-endian constants, see elf(5)
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_DYNSYM: u32 = 11;
+const STT_FUNC: u8 = 2;
+const SYMBOL_SIZE: usize = 24;
+
+/// One executable mapping from /proc/self/maps
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Mapping {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) offset: usize,
+    pub(crate) path: String,
+}
+
+/// Function symbols of one ELF file, sorted by file-relative address
+#[derive(Default, Debug)]
+pub(crate) struct SymbolTable {
+    /// (p_offset, p_vaddr, p_filesz) of each PT_LOAD segment
+    segments: Vec<(usize, usize, usize)>,
+    /// (st_value, st_size, name)
+    symbols: Vec<(usize, usize, String)>,
+}
+
+/// Executable mappings, as of the dynamic loader's load/unload counts they were read at
+#[derive(Default)]
+struct LoadedMappings {
+    generation: Option<(u64, u64)>,
+    mappings: Arc<Vec<Mapping>>,
+}
+
+// Symbol tables by library path; loading libjvm's .symtab takes a while, so do it once
+static SYMBOL_TABLES: Lazy<Mutex<HashMap<String, SymbolTable>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static MAPPINGS: Lazy<Mutex<LoadedMappings>> = Lazy::new(|| Mutex::new(LoadedMappings::default()));
+
+/// Parse the executable, file-backed lines of /proc/<pid>/maps.
+pub(crate) fn parse_maps(maps: &str) -> Vec<Mapping> {
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let (start, end) = fields.next()?.split_once('-')?;
+            let perms = fields.next()?;
+            let offset = fields.next()?;
+            let path = fields.nth(2)?;
+            if !perms.contains('x') || !path.starts_with('/') {
+                return None;
+            }
+            Some(Mapping {
+                start: usize::from_str_radix(start, 16).ok()?,
+                end: usize::from_str_radix(end, 16).ok()?,
+                offset: usize::from_str_radix(offset, 16).ok()?,
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<usize> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?) as usize)
+}
+
+fn read_name(data: &[u8], at: usize) -> Option<String> {
+    let bytes = data.get(at..)?;
+    let end = bytes.iter().position(|&b| b == 0)?;
+    Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+}
+
+/// Read PT_LOAD segments and the function symbols of .symtab and .dynsym.
+pub(crate) fn parse_elf(data: &[u8]) -> Option<SymbolTable> {
+    if data.get(0..4)? != ELF_MAGIC || data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+        return None;
+    }
+    let phoff = read_u64(data, 0x20)?;
+    let shoff = read_u64(data, 0x28)?;
+    let phentsize = read_u16(data, 0x36)? as usize;
+    let phnum = read_u16(data, 0x38)? as usize;
+    let shentsize = read_u16(data, 0x3a)? as usize;
+    let shnum = read_u16(data, 0x3c)? as usize;
+
+    let mut table = SymbolTable::default();
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if read_u32(data, ph)? == PT_LOAD {
+            let offset = read_u64(data, ph + 0x08)?;
+            let vaddr = read_u64(data, ph + 0x10)?;
+            let filesz = read_u64(data, ph + 0x20)?;
+            table.segments.push((offset, vaddr, filesz));
+        }
+    }
+
+    let section = |index: usize| -> Option<(u32, usize, usize, usize)> {
+        let sh = shoff + index * shentsize;
+        Some((
+            read_u32(data, sh + 0x04)?,          // sh_type
+            read_u64(data, sh + 0x18)?,          // sh_offset
+            read_u64(data, sh + 0x20)?,          // sh_size
+            read_u32(data, sh + 0x28)? as usize, // sh_link
+        ))
+    };
+    for i in 0..shnum {
+        let (kind, offset, size, link) = section(i)?;
+        if kind != SHT_SYMTAB && kind != SHT_DYNSYM {
+            continue;
+        }
+        let (_, strtab, _, _) = section(link)?;
+        for sym in (offset..offset + size).step_by(SYMBOL_SIZE) {
+            let info = *data.get(sym + 4)?;
+            let value = read_u64(data, sym + 8)?;
+            if info & 0xf != STT_FUNC || value == 0 {
+                continue;
+            }
+            let name = read_name(data, strtab + read_u32(data, sym)? as usize)?;
+            table.symbols.push((value, read_u64(data, sym + 16)?, name));
+        }
+    }
+    table.symbols.sort();
+    table.symbols.dedup_by_key(|s| s.0);
+    Some(table)
+}
+
+impl SymbolTable {
+    /// Name and offset of the function containing file-relative address `vaddr`.
+    pub(crate) fn lookup(&self, vaddr: usize) -> Option<(&str, usize)> {
+        let index = self
+            .symbols
+            .partition_point(|s| s.0 <= vaddr)
+            .checked_sub(1)?;
+        let (start, size, name) = &self.symbols[index];
+        let offset = vaddr - start;
+        (*size == 0 || offset < *size).then_some((name.as_str(), offset))
+    }
+
+    /// File-relative address of a runtime `address` inside `mapping`.
+    fn to_vaddr(&self, mapping: &Mapping, address: usize) -> Option<usize> {
+        let file_offset = mapping.offset + (address - mapping.start);
+        self.segments
+            .iter()
+            .find(|&&(offset, _, filesz)| file_offset >= offset && file_offset < offset + filesz)
+            .map(|&(offset, vaddr, _)| vaddr + (file_offset - offset))
+    }
+}
+
+unsafe extern "C" fn read_load_counts(
+    info: *mut libc::dl_phdr_info,
+    _size: usize,
+    data: *mut c_void,
+) -> c_int {
+    *(data as *mut (u64, u64)) = ((*info).dlpi_adds, (*info).dlpi_subs);
+    1 // the counts are the same in every entry
+}
+
+/// How many objects the dynamic loader has loaded and unloaded so far.
+fn load_counts() -> (u64, u64) {
+    let mut counts = (0, 0);
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(read_load_counts),
+            &mut counts as *mut (u64, u64) as *mut c_void,
+        );
+    }
+    counts
+}
+
+/// Executable file-backed mappings of this process. /proc/self/maps is only read again
+/// after a dlopen or dlclose; symbol tables of unmapped libraries are dropped then.
+pub(crate) fn code_mappings() -> Arc<Vec<Mapping>> {
+    let generation = load_counts();
+    let mut loaded = MAPPINGS.lock().unwrap();
+    if loaded.generation != Some(generation) {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap_or_default();
+        loaded.mappings = Arc::new(parse_maps(&maps));
+        loaded.generation = Some(generation);
+        SYMBOL_TABLES
+            .lock()
+            .unwrap()
+            .retain(|path, _| loaded.mappings.iter().any(|m| &m.path == path));
+    }
+    Arc::clone(&loaded.mappings)
+}
+
+/// The mapping containing `address`, if it is in a library (not JIT-compiled code).
+pub(crate) fn find_mapping(mappings: &[Mapping], address: usize) -> Option<&Mapping> {
+    mappings
+        .iter()
+        .find(|m| address >= m.start && address < m.end)
+}
+
+/// Library file name, and function name and offset if the symbol tables cover `address`.
+fn resolve(mapping: &Mapping, address: usize) -> (&str, Option<(String, usize)>) {
+    let library = mapping.path.rsplit('/').next().unwrap_or(&mapping.path);
+    let mut tables = SYMBOL_TABLES.lock().unwrap();
+    let table = tables.entry(mapping.path.clone()).or_insert_with(|| {
+        fs::read(&mapping.path)
+            .ok()
+            .and_then(|data| parse_elf(&data))
+            .unwrap_or_default()
+    });
+    let symbol = table
+        .to_vaddr(mapping, address)
+        .and_then(|vaddr| table.lookup(vaddr))
+        .map(|(name, offset)| (name.to_string(), offset));
+    (library, symbol)
+}
+
+/// `library!symbol+0x12` for a native code address of this process, using the ELF
+/// symbol tables (including local `.symtab` symbols dladdr can't see).
+pub(crate) fn symbolize(address: usize) -> Option<String> {
+    let mappings = code_mappings();
+    let mapping = find_mapping(&mappings, address)?;
+    Some(match resolve(mapping, address) {
+        (library, Some((name, 0))) => format!("{}!{}", library, name),
+        (library, Some((name, offset))) => format!("{}!{}+{:#x}", library, name, offset),
+        (library, None) => format!(
+            "{}+{:#x}",
+            library,
+            address - mapping.start + mapping.offset
+        ),
+    })
+}
+
+/// `library!symbol` for a frame of a native stack; without the offset, so samples in
+/// the same function fold together.
+pub(crate) fn frame_symbol(mapping: &Mapping, address: usize) -> String {
+    match resolve(mapping, address) {
+        (library, Some((name, _))) => format!("{}!{}", library, name),
+        (library, None) => library.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = "\
+7f00aa000000-7f00aa100000 r--p 00000000 08:01 123 /usr/lib/libjvm.so
+7f00aa100000-7f00aa900000 r-xp 00100000 08:01 123 /usr/lib/libjvm.so
+7f00ab000000-7f00ab001000 rwxp 00000000 00:00 0
+7ffd00000000-7ffd00001000 r-xp 00000000 00:00 0 [vdso]
+";
+        assert_eq!(
+            parse_maps(maps),
+            vec![Mapping {
+                start: 0x7f00aa100000,
+                end: 0x7f00aa900000,
+                offset: 0x100000,
+                path: "/usr/lib/libjvm.so".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let table = SymbolTable {
+            segments: vec![(0x1000, 0x1000, 0x1000)],
+            symbols: vec![
+                (0x1000, 0x10, "a".to_string()),
+                (0x1100, 0, "b".to_string()),
+            ],
+        };
+        assert_eq!(table.lookup(0x1004), Some(("a", 4)));
+        assert_eq!(table.lookup(0x1020), None);
+        assert_eq!(table.lookup(0x1180), Some(("b", 0x80)));
+        assert_eq!(table.lookup(0x0fff), None);
+        let mapping = Mapping {
+            start: 0x7f0000001000,
+            end: 0x7f0000002000,
+            offset: 0x1000,
+            path: "/lib/x.so".to_string(),
+        };
+        assert_eq!(table.to_vaddr(&mapping, 0x7f0000001004), Some(0x1004));
+    }
+}
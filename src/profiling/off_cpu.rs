@@ -0,0 +1,357 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::native::is_native;
+use crate::profiling::options::agent_options;
+use crate::profiling::profiling::{
+    current_stack_names, elapsed_nanos, format_time, get_method_name_safe, MethodId,
+};
+use crate::profiling::threads::{thread_id, THREADS};
+use crate::profiling::virtual_threads::record_pinning;
+
+/// Why a thread is off-CPU
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub(crate) enum BlockReason {
+    /// MonitorContendedEnter .. MonitorContendedEntered
+    Monitor,
+    /// MonitorWait .. MonitorWaited
+    Wait,
+    /// Inside a native `Thread.sleep`
+    Sleep,
+    /// Inside `Unsafe.park` (LockSupport, j.u.c locks and queues)
+    Park,
+}
+
+pub(crate) const BLOCK_REASONS: [BlockReason; 4] = [
+    BlockReason::Monitor,
+    BlockReason::Wait,
+    BlockReason::Sleep,
+    BlockReason::Park,
+];
+
+impl BlockReason {
+    /// Synthetic leaf frame in `off_cpu.folded`
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            BlockReason::Monitor => "[blocked on monitor]",
+            BlockReason::Wait => "[waiting]",
+            BlockReason::Sleep => "[sleeping]",
+            BlockReason::Park => "[parked]",
+        }
+    }
+
+    /// Label value in metrics
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            BlockReason::Monitor => "monitor",
+            BlockReason::Wait => "wait",
+            BlockReason::Sleep => "sleep",
+            BlockReason::Park => "park",
+        }
+    }
+
+    /// Blocking reason for a native method, by name; the sleep natives were renamed in JDK 19+.
+    pub(crate) fn for_native_method(name: &str) -> Option<BlockReason> {
+        match name {
+            "java.lang.Thread.sleep"
+            | "java.lang.Thread.sleep0"
+            | "java.lang.Thread.sleepNanos0" => Some(BlockReason::Sleep),
+            "jdk.internal.misc.Unsafe.park" | "sun.misc.Unsafe.park" => Some(BlockReason::Park),
+            _ => None,
+        }
+    }
+}
+
+/// A thread's current blocking interval
+struct PendingBlock {
+    reason: BlockReason,
+    started_nanos: u64,
+    stack: Vec<String>,
+}
+
+// Open intervals by thread id (not thread-local: virtual threads move between carriers)
+static PENDING_BLOCKS: Lazy<Mutex<HashMap<u64, PendingBlock>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Blocked time by stack, the leaf being the BlockReason label
+static OFF_CPU_STACKS: Lazy<Mutex<HashMap<Vec<String>, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Blocked time by (thread id, reason)
+static OFF_CPU_BY_THREAD: Lazy<Mutex<HashMap<(u64, BlockReason), u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Blocking reason of each method seen at entry, None for ordinary methods
+static BLOCKING_METHODS: Lazy<Mutex<HashMap<MethodId, Option<BlockReason>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn begin_block(jvmti_env: *mut jvmtiEnv, thread: jthread, reason: BlockReason) {
+    let Some(id) = thread_id(jvmti_env, thread) else {
+        return;
+    };
+    let started_nanos = elapsed_nanos(jvmti_env);
+    let mut pending = PENDING_BLOCKS.lock().unwrap();
+    // Keep the outer interval, e.g. a contended re-entry after wait() returns
+    pending.entry(id).or_insert_with(|| PendingBlock {
+        reason,
+        started_nanos,
+        stack: current_stack_names(jvmti_env),
+    });
+}
+
+fn end_block(jvmti_env: *mut jvmtiEnv, thread: jthread, reason: BlockReason) {
+    let Some(id) = thread_id(jvmti_env, thread) else {
+        return;
+    };
+    let block = {
+        let mut pending = PENDING_BLOCKS.lock().unwrap();
+        match pending.get(&id) {
+            Some(block) if block.reason == reason => pending.remove(&id),
+            _ => None,
+        }
+    };
+    let Some(mut block) = block else {
+        return;
+    };
+
+    let blocked = elapsed_nanos(jvmti_env).saturating_sub(block.started_nanos);
+    *OFF_CPU_BY_THREAD
+        .lock()
+        .unwrap()
+        .entry((id, reason))
+        .or_insert(0) += blocked;
+    block.stack.push(reason.label().to_string());
+    *OFF_CPU_STACKS
+        .lock()
+        .unwrap()
+        .entry(block.stack)
+        .or_insert(0) += blocked;
+}
+
+fn blocking_reason(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<BlockReason> {
+    if let Some(&reason) = BLOCKING_METHODS.lock().unwrap().get(&MethodId(method)) {
+        return reason;
+    }
+    let reason = if is_native(jvmti_env, method) {
+        get_method_name_safe(jvmti_env, method)
+            .and_then(|name| BlockReason::for_native_method(&name))
+    } else {
+        None
+    };
+    BLOCKING_METHODS
+        .lock()
+        .unwrap()
+        .insert(MethodId(method), reason);
+    reason
+}
+
+/// MethodEntry hook: a sleep or park starts an off-CPU interval.
+pub(crate) fn off_cpu_method_entry(jvmti_env: *mut jvmtiEnv, thread: jthread, method: jmethodID) {
+    if let Some(reason) = blocking_reason(jvmti_env, method) {
+        begin_block(jvmti_env, thread, reason);
+    }
+}
+
+/// MethodExit hook: returning from a sleep or park ends the interval.
+pub(crate) fn off_cpu_method_exit(jvmti_env: *mut jvmtiEnv, thread: jthread, method: jmethodID) {
+    if let Some(reason) = blocking_reason(jvmti_env, method) {
+        end_block(jvmti_env, thread, reason);
+    }
+}
+
+pub(crate) extern "C" fn monitor_contended_enter_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    thread: jthread,
+    _object: jobject,
+) {
+    record_pinning(jvmti_env, thread);
+    if agent_options().off_cpu {
+        begin_block(jvmti_env, thread, BlockReason::Monitor);
+    }
+}
+
+pub(crate) extern "C" fn monitor_contended_entered_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    thread: jthread,
+    _object: jobject,
+) {
+    end_block(jvmti_env, thread, BlockReason::Monitor);
+}
+
+pub(crate) extern "C" fn monitor_wait_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    thread: jthread,
+    _object: jobject,
+    _timeout: jlong,
+) {
+    record_pinning(jvmti_env, thread);
+    if agent_options().off_cpu {
+        begin_block(jvmti_env, thread, BlockReason::Wait);
+    }
+}
+
+pub(crate) extern "C" fn monitor_waited_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    thread: jthread,
+    _object: jobject,
+    _timed_out: jboolean,
+) {
+    end_block(jvmti_env, thread, BlockReason::Wait);
+}
+
+/// Clear the recorded off-CPU time and open intervals.
+pub(crate) fn reset_off_cpu() {
+    PENDING_BLOCKS.lock().unwrap().clear();
+    OFF_CPU_STACKS.lock().unwrap().clear();
+    OFF_CPU_BY_THREAD.lock().unwrap().clear();
+}
+
+/// Blocked nanoseconds per reason, over all threads.
+pub(crate) fn off_cpu_totals() -> Vec<(BlockReason, u64)> {
+    let by_thread = OFF_CPU_BY_THREAD.lock().unwrap();
+    BLOCK_REASONS
+        .iter()
+        .map(|&reason| {
+            let nanos: u64 = by_thread
+                .iter()
+                .filter(|((_, r), _)| *r == reason)
+                .map(|(_, &n)| n)
+                .sum();
+            (reason, nanos)
+        })
+        .collect()
+}
+
+/// Blocked nanoseconds by reason, for snapshots.
+pub(crate) fn off_cpu_json() -> serde_json::Value {
+    let totals: serde_json::Map<String, serde_json::Value> = off_cpu_totals()
+        .into_iter()
+        .map(|(reason, nanos)| (reason.label().to_string(), serde_json::json!(nanos)))
+        .collect();
+    serde_json::Value::Object(totals)
+}
+
+/// Print off-CPU totals by reason, the most-blocked threads and the top blocking stacks.
+pub(crate) fn print_off_cpu_summary() {
+    let by_thread = OFF_CPU_BY_THREAD.lock().unwrap().clone();
+    if by_thread.is_empty() {
+        return;
+    }
+    let total: u64 = by_thread.values().sum();
+
+    println!("\n💤 === Off-CPU time ({} blocked) ===", format_time(total));
+    for reason in BLOCK_REASONS {
+        let nanos: u64 = by_thread
+            .iter()
+            .filter(|((_, r), _)| *r == reason)
+            .map(|(_, &n)| n)
+            .sum();
+        if nanos > 0 {
+            println!(
+                "{:<22} {:>10} {:>5.1}%",
+                reason.label(),
+                format_time(nanos),
+                nanos as f64 * 100.0 / total as f64
+            );
+        }
+    }
+
+    let mut per_thread: HashMap<u64, u64> = HashMap::new();
+    for (&(id, _), &nanos) in &by_thread {
+        *per_thread.entry(id).or_insert(0) += nanos;
+    }
+    let mut per_thread: Vec<(u64, u64)> = per_thread.into_iter().collect();
+    per_thread.sort_by_key(|&(_, nanos)| std::cmp::Reverse(nanos));
+    let threads = THREADS.lock().unwrap();
+    println!("\nMost blocked threads:");
+    for (id, nanos) in per_thread.iter().take(10) {
+        let name = threads.get(id).map_or("<unknown>", |t| t.name.as_str());
+        let reasons: Vec<String> = BLOCK_REASONS
+            .iter()
+            .filter_map(|&r| {
+                by_thread
+                    .get(&(*id, r))
+                    .map(|n| format!("{} {}", r.label(), format_time(*n)))
+            })
+            .collect();
+        println!(
+            "{:<30} {:>10}  {}",
+            name,
+            format_time(*nanos),
+            reasons.join(", ")
+        );
+    }
+    drop(threads);
+
+    let mut stacks: Vec<(Vec<String>, u64)> = OFF_CPU_STACKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(stack, &nanos)| (stack.clone(), nanos))
+        .collect();
+    stacks.sort_by_key(|(_, nanos)| std::cmp::Reverse(*nanos));
+    println!("\nTop blocking call sites:");
+    for (stack, nanos) in stacks.iter().take(10) {
+        // Leaf is the reason label; show it with the two innermost frames
+        let Some((reason, frames)) = stack.split_last() else {
+            continue;
+        };
+        let frames: Vec<&str> = frames.iter().rev().take(2).map(String::as_str).collect();
+        println!(
+            "{:>10}  {:<22} {}",
+            format_time(*nanos),
+            reason,
+            frames.join(" <- ")
+        );
+    }
+}
+
+/// Write the off-CPU folded stacks, weighted by nanoseconds blocked.
+pub(crate) fn write_off_cpu_folded(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let stacks = OFF_CPU_STACKS.lock().unwrap();
+    if stacks.is_empty() {
+        return Ok(());
+    }
+    let mut sorted: Vec<_> = stacks.iter().collect();
+    sorted.sort_by_key(|(_, nanos)| std::cmp::Reverse(**nanos));
+
+    let mut file = File::create(path)?;
+    for (stack, nanos) in sorted {
+        writeln!(file, "{} {}", stack.join(";"), nanos)?;
+    }
+    println!("💤 Off-CPU flamegraph data written to '{}'", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocking_natives() {
+        assert_eq!(
+            BlockReason::for_native_method("java.lang.Thread.sleep"),
+            Some(BlockReason::Sleep)
+        );
+        assert_eq!(
+            BlockReason::for_native_method("java.lang.Thread.sleepNanos0"),
+            Some(BlockReason::Sleep)
+        );
+        assert_eq!(
+            BlockReason::for_native_method("jdk.internal.misc.Unsafe.park"),
+            Some(BlockReason::Park)
+        );
+        assert_eq!(
+            BlockReason::for_native_method("java.lang.Object.wait"),
+            None
+        );
+    }
+}
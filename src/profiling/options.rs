@@ -0,0 +1,428 @@
+use once_cell::sync::OnceCell;
+use std::sync::RwLock;
+
+/// What a repeated load (`rjprof attach <pid> <command>`) asks an already loaded agent to do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionCommand {
+    /// Reset the stats and start recording
+    Start,
+    /// Stop recording, write the reports and reset the stats
+    Stop,
+    /// Write the reports of the running session without stopping it
+    Dump,
+    /// Stop recording and turn every event off for good
+    Detach,
+}
+
+impl SessionCommand {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "start" => Ok(SessionCommand::Start),
+            "stop" => Ok(SessionCommand::Stop),
+            "dump" => Ok(SessionCommand::Dump),
+            "detach" => Ok(SessionCommand::Detach),
+            _ => Err(format!("Unknown session command: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SessionCommand::Start => "start",
+            SessionCommand::Stop => "stop",
+            SessionCommand::Dump => "dump",
+            SessionCommand::Detach => "detach",
+        }
+    }
+}
+
+/// Encoding of the profiles sent to a Pyroscope `/ingest` endpoint
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UploadFormat {
+    /// Folded stacks (`a;b;c 123`) as plain text
+    #[default]
+    Folded,
+    /// pprof protobuf, as a multipart form upload
+    Pprof,
+}
+
+impl UploadFormat {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "folded" => Ok(UploadFormat::Folded),
+            "pprof" => Ok(UploadFormat::Pprof),
+            _ => Err(format!("Unknown upload format: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            UploadFormat::Folded => "folded",
+            UploadFormat::Pprof => "pprof",
+        }
+    }
+}
+
+/// Heap walk done when reports are written. Each one forces a full GC and iterates the
+/// whole heap with the application stopped, so both are off unless asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapReport {
+    /// Live instances and bytes per class (`heap_histogram.txt`); one pass over the heap
+    Histogram,
+    /// The histogram plus retained sizes from a dominator tree (`heap_retained.txt`). Walks
+    /// every reference and keeps the object graph in memory: the longest pause by far
+    Retained,
+}
+
+impl HeapReport {
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "histogram" => Ok(HeapReport::Histogram),
+            "retained" => Ok(HeapReport::Retained),
+            _ => Err(format!("Unknown heap report: {}", name)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            HeapReport::Histogram => "histogram",
+            HeapReport::Retained => "retained",
+        }
+    }
+}
+
+/// Options passed after `=` in `-agentpath:<lib>=<options>`, as comma-separated
+/// `key` or `key=value` entries, e.g. `perfmap,jitdump`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AgentOptions {
+    /// Write `/tmp/perf-<pid>.map` with JIT-compiled code ranges for Linux `perf`
+    pub perf_map: bool,
+    /// Write a `jit-<pid>.dump` file for `perf inject --jit` into the output directory
+    pub jitdump: bool,
+    /// Add source line numbers to flamegraph frames
+    pub lines: bool,
+    /// Record time blocked on monitors, waits, sleeps and parks with its stack
+    pub off_cpu: bool,
+    /// Sample CPU time with native frames (libjvm, libc, JNI libraries) around the Java ones
+    pub native_stacks: bool,
+    /// Walk the heap when writing reports and snapshots (stops the application meanwhile)
+    pub heap: Option<HeapReport>,
+    /// Thread state sampling interval in milliseconds
+    pub sampling_interval_ms: Option<u64>,
+    /// Write a thread dump every this many seconds
+    pub thread_dump_interval_secs: Option<u64>,
+    /// Directory for report files, instead of the JVM's working directory
+    pub output_dir: Option<String>,
+    /// Write into a `jvm-<pid>` subdirectory of the output directory, so several JVMs
+    /// (e.g. forked by a build tool) can share it
+    pub per_process: bool,
+    /// Stop profiling and write reports after this many seconds (e.g. when attached)
+    pub duration_secs: Option<u64>,
+    /// Write a snapshot on `kill -USR2 <pid>` and on DataDumpRequest (SIGQUIT, `jcmd`)
+    pub snapshot: bool,
+    /// Reset the per-session stats after each snapshot
+    pub snapshot_reset: bool,
+    /// Serve the control protocol on a Unix socket at this path
+    pub control_socket: Option<String>,
+    /// Serve live profiles over HTTP on this localhost port
+    pub http_port: Option<u64>,
+    /// Rewrite this file with Prometheus metrics periodically (node_exporter textfile collector)
+    pub metrics_file: Option<String>,
+    /// Seconds between metrics file updates
+    pub metrics_interval_secs: Option<u64>,
+    /// Write a profile every this many seconds and reset the stats
+    pub continuous_interval_secs: Option<u64>,
+    /// Delete interval profiles older than this many seconds
+    pub retain_secs: Option<u64>,
+    /// Send each interval profile to this Pyroscope server (`http://host:port`)
+    pub pyroscope_url: Option<String>,
+    /// Encoding of the uploaded profiles
+    pub upload_format: Option<UploadFormat>,
+    /// `app` label of uploaded profiles
+    pub app_name: Option<String>,
+    /// `version` label of uploaded profiles
+    pub app_version: Option<String>,
+    /// Keep profiles here while the Pyroscope server is unreachable
+    pub spool_dir: Option<String>,
+    /// Session command for an agent that is already loaded
+    pub command: Option<SessionCommand>,
+}
+
+impl AgentOptions {
+    pub fn parse(options: &str) -> Result<Self, String> {
+        let mut parsed = AgentOptions::default();
+
+        for entry in options.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (entry, None),
+            };
+
+            match key {
+                "perfmap" => parsed.perf_map = parse_flag(key, value)?,
+                "jitdump" => parsed.jitdump = parse_flag(key, value)?,
+                "lines" => parsed.lines = parse_flag(key, value)?,
+                "offcpu" => parsed.off_cpu = parse_flag(key, value)?,
+                "nativestacks" => parsed.native_stacks = parse_flag(key, value)?,
+                "heap" => parsed.heap = Some(HeapReport::parse(&parse_text(key, value)?)?),
+                "interval" => parsed.sampling_interval_ms = Some(parse_number(key, value)?),
+                "threaddump" => parsed.thread_dump_interval_secs = Some(parse_number(key, value)?),
+                "output" => parsed.output_dir = Some(parse_text(key, value)?),
+                "perprocess" => parsed.per_process = parse_flag(key, value)?,
+                "duration" => parsed.duration_secs = Some(parse_number(key, value)?),
+                "snapshot" => parsed.snapshot = parse_flag(key, value)?,
+                "snapshotreset" => parsed.snapshot_reset = parse_flag(key, value)?,
+                "control" => parsed.control_socket = Some(parse_text(key, value)?),
+                "http" => parsed.http_port = Some(parse_number(key, value)?),
+                "metricsfile" => parsed.metrics_file = Some(parse_text(key, value)?),
+                "metricsinterval" => parsed.metrics_interval_secs = Some(parse_number(key, value)?),
+                "continuous" => parsed.continuous_interval_secs = Some(parse_number(key, value)?),
+                "retain" => parsed.retain_secs = Some(parse_number(key, value)?),
+                "pyroscope" => parsed.pyroscope_url = Some(parse_text(key, value)?),
+                "uploadformat" => {
+                    parsed.upload_format = Some(UploadFormat::parse(&parse_text(key, value)?)?)
+                }
+                "app" => parsed.app_name = Some(parse_text(key, value)?),
+                "version" => parsed.app_version = Some(parse_text(key, value)?),
+                "spool" => parsed.spool_dir = Some(parse_text(key, value)?),
+                "command" => {
+                    parsed.command = Some(SessionCommand::parse(&parse_text(key, value)?)?)
+                }
+                _ => return Err(format!("Unknown agent option: {}", key)),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    /// Render back into the `-agentpath` option string understood by [`AgentOptions::parse`].
+    pub fn to_option_string(&self) -> String {
+        let mut entries = Vec::new();
+        if self.perf_map {
+            entries.push("perfmap".to_string());
+        }
+        if self.jitdump {
+            entries.push("jitdump".to_string());
+        }
+        if self.lines {
+            entries.push("lines".to_string());
+        }
+        if self.off_cpu {
+            entries.push("offcpu".to_string());
+        }
+        if self.native_stacks {
+            entries.push("nativestacks".to_string());
+        }
+        if let Some(heap) = self.heap {
+            entries.push(format!("heap={}", heap.name()));
+        }
+        if let Some(interval) = self.sampling_interval_ms {
+            entries.push(format!("interval={}", interval));
+        }
+        if let Some(interval) = self.thread_dump_interval_secs {
+            entries.push(format!("threaddump={}", interval));
+        }
+        if let Some(dir) = &self.output_dir {
+            entries.push(format!("output={}", dir));
+        }
+        if self.per_process {
+            entries.push("perprocess".to_string());
+        }
+        if let Some(duration) = self.duration_secs {
+            entries.push(format!("duration={}", duration));
+        }
+        if self.snapshot {
+            entries.push("snapshot".to_string());
+        }
+        if self.snapshot_reset {
+            entries.push("snapshotreset".to_string());
+        }
+        if let Some(path) = &self.control_socket {
+            entries.push(format!("control={}", path));
+        }
+        if let Some(port) = self.http_port {
+            entries.push(format!("http={}", port));
+        }
+        if let Some(path) = &self.metrics_file {
+            entries.push(format!("metricsfile={}", path));
+        }
+        if let Some(interval) = self.metrics_interval_secs {
+            entries.push(format!("metricsinterval={}", interval));
+        }
+        if let Some(interval) = self.continuous_interval_secs {
+            entries.push(format!("continuous={}", interval));
+        }
+        if let Some(retain) = self.retain_secs {
+            entries.push(format!("retain={}", retain));
+        }
+        if let Some(url) = &self.pyroscope_url {
+            entries.push(format!("pyroscope={}", url));
+        }
+        if let Some(format) = self.upload_format {
+            entries.push(format!("uploadformat={}", format.name()));
+        }
+        if let Some(app) = &self.app_name {
+            entries.push(format!("app={}", app));
+        }
+        if let Some(version) = &self.app_version {
+            entries.push(format!("version={}", version));
+        }
+        if let Some(dir) = &self.spool_dir {
+            entries.push(format!("spool={}", dir));
+        }
+        if let Some(command) = self.command {
+            entries.push(format!("command={}", command.name()));
+        }
+        entries.join(",")
+    }
+}
+
+fn parse_flag(key: &str, value: Option<&str>) -> Result<bool, String> {
+    match value {
+        None | Some("true") | Some("1") | Some("yes") => Ok(true),
+        Some("false") | Some("0") | Some("no") => Ok(false),
+        Some(other) => Err(format!("Invalid value for {}: {}", key, other)),
+    }
+}
+
+fn parse_number(key: &str, value: Option<&str>) -> Result<u64, String> {
+    match value.map(str::parse::<u64>) {
+        Some(Ok(n)) if n > 0 => Ok(n),
+        Some(_) => Err(format!(
+            "Invalid value for {}: {}",
+            key,
+            value.unwrap_or("")
+        )),
+        None => Err(format!("Missing value for {}", key)),
+    }
+}
+
+fn parse_text(key: &str, value: Option<&str>) -> Result<String, String> {
+    match value {
+        Some(text) if !text.is_empty() => Ok(text.to_string()),
+        _ => Err(format!("Missing value for {}", key)),
+    }
+}
+
+static AGENT_OPTIONS: OnceCell<AgentOptions> = OnceCell::new();
+
+// Report directory; unlike the other options, each session command may change it
+static OUTPUT_DIR: RwLock<Option<String>> = RwLock::new(None);
+
+/// Options the agent was loaded with (defaults if none were given).
+pub(crate) fn agent_options() -> &'static AgentOptions {
+    AGENT_OPTIONS.get_or_init(AgentOptions::default)
+}
+
+/// Directory name prefix of each JVM's reports with `perprocess`: `jvm-<pid>`
+pub const PROCESS_DIR_PREFIX: &str = "jvm-";
+
+/// The directory reports go to, if not the JVM's working directory.
+pub(crate) fn output_dir() -> Option<String> {
+    OUTPUT_DIR.read().unwrap().clone()
+}
+
+/// Path of a report file: inside the `output` directory if one was given, else relative
+/// to the JVM's working directory.
+pub(crate) fn output_path(name: &str) -> String {
+    match &*OUTPUT_DIR.read().unwrap() {
+        Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), name),
+        None => name.to_string(),
+    }
+}
+
+/// Write the reports of later sessions into `dir`.
+pub(crate) fn set_output_dir(dir: &str) {
+    *OUTPUT_DIR.write().unwrap() = Some(dir.to_string());
+}
+
+pub(crate) fn set_agent_options(options: AgentOptions) {
+    if options.per_process {
+        let parent = options.output_dir.as_deref().unwrap_or(".");
+        set_output_dir(&format!(
+            "{}/{}{}",
+            parent.trim_end_matches('/'),
+            PROCESS_DIR_PREFIX,
+            std::process::id()
+        ));
+    } else if let Some(dir) = &options.output_dir {
+        set_output_dir(dir);
+    }
+    if AGENT_OPTIONS.set(options).is_err() {
+        eprintln!("Agent options already set, ignoring new options");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_agent_options() {
+        assert_eq!(AgentOptions::parse("").unwrap(), AgentOptions::default());
+
+        let options = AgentOptions::parse("perfmap, jitdump=false").unwrap();
+        assert!(options.perf_map);
+        assert!(!options.jitdump);
+
+        assert_eq!(options.sampling_interval_ms, None);
+
+        let options = AgentOptions::parse("interval=5").unwrap();
+        assert_eq!(options.sampling_interval_ms, Some(5));
+
+        assert!(AgentOptions::parse("perfmap=maybe").is_err());
+        assert!(AgentOptions::parse("interval").is_err());
+        assert!(AgentOptions::parse("interval=0").is_err());
+        assert!(AgentOptions::parse("bogus").is_err());
+        assert!(AgentOptions::parse("output=").is_err());
+        assert!(AgentOptions::parse("command=pause").is_err());
+        assert!(AgentOptions::parse("uploadformat=jfr").is_err());
+        assert!(AgentOptions::parse("heap").is_err());
+        assert_eq!(
+            AgentOptions::parse("heap=histogram").unwrap().heap,
+            Some(HeapReport::Histogram)
+        );
+    }
+
+    #[test]
+    fn test_option_string_round_trip() {
+        let options = AgentOptions {
+            perf_map: true,
+            jitdump: true,
+            lines: true,
+            off_cpu: true,
+            native_stacks: true,
+            heap: Some(HeapReport::Retained),
+            sampling_interval_ms: Some(20),
+            thread_dump_interval_secs: Some(30),
+            output_dir: Some("/tmp/out".to_string()),
+            per_process: true,
+            duration_secs: Some(60),
+            snapshot: true,
+            snapshot_reset: true,
+            control_socket: Some("/tmp/rjprof.sock".to_string()),
+            http_port: Some(8080),
+            metrics_file: Some("/tmp/rjprof.prom".to_string()),
+            metrics_interval_secs: Some(10),
+            continuous_interval_secs: Some(60),
+            retain_secs: Some(86400),
+            pyroscope_url: Some("http://127.0.0.1:4040".to_string()),
+            upload_format: Some(UploadFormat::Pprof),
+            app_name: Some("shop".to_string()),
+            app_version: Some("1.2.3".to_string()),
+            spool_dir: Some("/tmp/spool".to_string()),
+            command: Some(SessionCommand::Dump),
+        };
+        assert_eq!(
+            options.to_option_string(),
+            "perfmap,jitdump,lines,offcpu,nativestacks,heap=retained,interval=20,threaddump=30,\
+             output=/tmp/out,perprocess,duration=60,snapshot,snapshotreset,control=/tmp/rjprof.sock,http=8080,\
+             metricsfile=/tmp/rjprof.prom,metricsinterval=10,\
+             continuous=60,retain=86400,pyroscope=http://127.0.0.1:4040,uploadformat=pprof,\
+             app=shop,version=1.2.3,spool=/tmp/spool,command=dump"
+        );
+        assert_eq!(
+            AgentOptions::parse(&options.to_option_string()).unwrap(),
+            options
+        );
+    }
+}
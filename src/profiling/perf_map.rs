@@ -0,0 +1,211 @@
+use once_cell::sync::Lazy;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::profiling::options::{output_path, AgentOptions};
+
+// jitdump format, see tools/perf/Documentation/jitdump-specification.txt in the kernel tree
+const JITDUMP_MAGIC: u32 = 0x4A69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JITDUMP_HEADER_SIZE: u32 = 40;
+const JIT_CODE_LOAD: u32 = 0;
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MACHINE: u32 = 62; // EM_X86_64
+#[cfg(target_arch = "aarch64")]
+const ELF_MACHINE: u32 = 183; // EM_AARCH64
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ELF_MACHINE: u32 = 0;
+
+/// Open symbol files for Linux `perf`: the plain-text perf map and/or a jitdump file
+struct PerfSymbolWriter {
+    pid: u32,
+    map_file: Option<File>,
+    jitdump: Option<File>,
+    code_index: u64,
+}
+
+static PERF_SYMBOLS: Lazy<Mutex<Option<PerfSymbolWriter>>> = Lazy::new(|| Mutex::new(None));
+
+/// Timestamp in the clock `perf record -k mono` uses, so jitdump records line up with samples.
+fn monotonic_nanos() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+#[cfg(target_os = "linux")]
+fn current_tid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_tid() -> u32 {
+    std::process::id()
+}
+
+/// `perf inject` only picks up a jitdump file if the process mapped it executable.
+fn mark_jitdump_mapped(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    unsafe {
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            page_size,
+            libc::PROT_READ | libc::PROT_EXEC,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            eprintln!("Warning: failed to mmap jitdump file, perf inject won't find it");
+        }
+    }
+}
+
+fn write_jitdump_header(file: &mut File, pid: u32) -> std::io::Result<()> {
+    let mut header = Vec::with_capacity(JITDUMP_HEADER_SIZE as usize);
+    header.extend_from_slice(&JITDUMP_MAGIC.to_ne_bytes());
+    header.extend_from_slice(&JITDUMP_VERSION.to_ne_bytes());
+    header.extend_from_slice(&JITDUMP_HEADER_SIZE.to_ne_bytes());
+    header.extend_from_slice(&ELF_MACHINE.to_ne_bytes());
+    header.extend_from_slice(&0u32.to_ne_bytes()); // pad1
+    header.extend_from_slice(&pid.to_ne_bytes());
+    header.extend_from_slice(&monotonic_nanos().to_ne_bytes());
+    header.extend_from_slice(&0u64.to_ne_bytes()); // flags
+    file.write_all(&header)
+}
+
+/// Open the perf map and/or jitdump files requested in the agent options.
+pub(crate) fn init_perf_symbols(options: &AgentOptions) {
+    if !options.perf_map && !options.jitdump {
+        return;
+    }
+
+    let pid = std::process::id();
+    let mut writer = PerfSymbolWriter {
+        pid,
+        map_file: None,
+        jitdump: None,
+        code_index: 0,
+    };
+
+    if options.perf_map {
+        let path = format!("/tmp/perf-{}.map", pid);
+        match File::create(&path) {
+            Ok(file) => {
+                println!("🗺️  Writing perf map to {}", path);
+                writer.map_file = Some(file);
+            }
+            Err(e) => eprintln!("Failed to create perf map {}: {}", path, e),
+        }
+    }
+
+    if options.jitdump {
+        let path = output_path(&format!("jit-{}.dump", pid));
+        // Opened read/write: the executable mapping below needs read access
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path);
+        match file {
+            Ok(mut file) => match write_jitdump_header(&mut file, pid) {
+                Ok(()) => {
+                    mark_jitdump_mapped(&file);
+                    println!("🗺️  Writing jitdump to {}", path);
+                    writer.jitdump = Some(file);
+                }
+                Err(e) => eprintln!("Failed to write jitdump header: {}", e),
+            },
+            Err(e) => eprintln!("Failed to create jitdump {}: {}", path, e),
+        }
+    }
+
+    *PERF_SYMBOLS.lock().unwrap() = Some(writer);
+}
+
+/// Record a code range (compiled method or VM stub) under a human-readable name.
+pub(crate) fn record_code_range(name: &str, address: usize, size: usize) {
+    let mut guard = PERF_SYMBOLS.lock().unwrap();
+    let writer = match guard.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+
+    if let Some(map_file) = writer.map_file.as_mut() {
+        // perf map lines are `START SIZE name` with hex numbers, no 0x prefix
+        let line = format!("{:x} {:x} {}\n", address, size, perf_safe_name(name));
+        if let Err(e) = map_file.write_all(line.as_bytes()) {
+            eprintln!("Failed to write perf map entry: {}", e);
+        }
+    }
+
+    if writer.jitdump.is_some() {
+        let record = code_load_record(writer.pid, writer.code_index, name, address, size);
+        writer.code_index += 1;
+        if let Some(jitdump) = writer.jitdump.as_mut() {
+            if let Err(e) = jitdump.write_all(&record) {
+                eprintln!("Failed to write jitdump record: {}", e);
+            }
+        }
+    }
+}
+
+/// Names end at the newline in the map format; keep everything on one line.
+fn perf_safe_name(name: &str) -> String {
+    name.replace(['\n', '\r'], " ")
+}
+
+/// A `JIT_CODE_LOAD` record, including a copy of the machine code itself.
+fn code_load_record(pid: u32, code_index: u64, name: &str, address: usize, size: usize) -> Vec<u8> {
+    let name = perf_safe_name(name);
+    let code = if address == 0 || size == 0 {
+        &[][..]
+    } else {
+        // The code lives in our own address space, installed by the JIT
+        unsafe { std::slice::from_raw_parts(address as *const u8, size) }
+    };
+
+    // record header (16) + pid/tid (8) + vma/code_addr/code_size/code_index (32)
+    let total_size = 16 + 8 + 32 + name.len() + 1 + code.len();
+    let mut record = Vec::with_capacity(total_size);
+    record.extend_from_slice(&JIT_CODE_LOAD.to_ne_bytes());
+    record.extend_from_slice(&(total_size as u32).to_ne_bytes());
+    record.extend_from_slice(&monotonic_nanos().to_ne_bytes());
+    record.extend_from_slice(&pid.to_ne_bytes());
+    record.extend_from_slice(&current_tid().to_ne_bytes());
+    record.extend_from_slice(&(address as u64).to_ne_bytes()); // vma
+    record.extend_from_slice(&(address as u64).to_ne_bytes()); // code_addr
+    record.extend_from_slice(&(code.len() as u64).to_ne_bytes());
+    record.extend_from_slice(&code_index.to_ne_bytes());
+    record.extend_from_slice(name.as_bytes());
+    record.push(0);
+    record.extend_from_slice(code);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_load_record_layout() {
+        let record = code_load_record(42, 7, "com.acme.Foo.bar", 0, 0);
+        let total_size = u32::from_ne_bytes(record[4..8].try_into().unwrap());
+        assert_eq!(total_size as usize, record.len());
+        assert_eq!(u32::from_ne_bytes(record[0..4].try_into().unwrap()), 0);
+        assert_eq!(u32::from_ne_bytes(record[16..20].try_into().unwrap()), 42);
+        assert_eq!(u64::from_ne_bytes(record[48..56].try_into().unwrap()), 7);
+        assert_eq!(&record[56..72], b"com.acme.Foo.bar");
+        assert_eq!(record[72], 0);
+    }
+}
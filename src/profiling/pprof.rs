@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+// Hand-rolled encoder for the subset of pprof's profile.proto we produce:
+// https://github.com/google/pprof/blob/main/proto/profile.proto
+// `go tool pprof` and Pyroscope read the encoding with or without gzip.
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_LEN: u64 = 2;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, (field << 3) | wire_type);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_key(buf, field, WIRE_VARINT);
+    put_varint(buf, value);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, WIRE_LEN);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn put_packed(buf: &mut Vec<u8>, field: u64, values: &[u64]) {
+    let mut packed = Vec::new();
+    for &value in values {
+        put_varint(&mut packed, value);
+    }
+    put_bytes(buf, field, &packed);
+}
+
+/// Strings are referenced by index into the profile's string table; index 0 is "".
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<String>,
+    index: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        let mut table = StringTable::default();
+        table.intern("");
+        table
+    }
+
+    fn intern(&mut self, s: &str) -> u64 {
+        if let Some(&i) = self.index.get(s) {
+            return i;
+        }
+        let i = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.index.insert(s.to_string(), i);
+        i
+    }
+}
+
+fn value_type(strings: &mut StringTable, kind: &str, unit: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_uint(&mut buf, 1, strings.intern(kind));
+    put_uint(&mut buf, 2, strings.intern(unit));
+    buf
+}
+
+/// Encode stacks (root first, with their value) as a pprof profile with a single
+/// `sample_type`, e.g. `wall`/`nanoseconds`. Each distinct frame name becomes one
+/// function with one location.
+pub(crate) fn encode_profile(
+    stacks: &[(Vec<String>, u64)],
+    sample_type: (&str, &str),
+    time_nanos: u64,
+    duration_nanos: u64,
+) -> Vec<u8> {
+    let mut strings = StringTable::new();
+    let mut profile = Vec::new();
+
+    let value_type = value_type(&mut strings, sample_type.0, sample_type.1);
+    put_bytes(&mut profile, 1, &value_type);
+
+    // Location and function ids coincide: one location per function, from 1
+    let mut function_ids: HashMap<&str, u64> = HashMap::new();
+    let mut functions: Vec<&str> = Vec::new();
+    for (stack, value) in stacks {
+        let location_ids: Vec<u64> = stack
+            .iter()
+            .rev()
+            .map(|frame| {
+                *function_ids.entry(frame.as_str()).or_insert_with(|| {
+                    functions.push(frame.as_str());
+                    functions.len() as u64
+                })
+            })
+            .collect();
+        let mut sample = Vec::new();
+        put_packed(&mut sample, 1, &location_ids);
+        put_packed(&mut sample, 2, &[*value]);
+        put_bytes(&mut profile, 2, &sample);
+    }
+
+    for id in 1..=functions.len() as u64 {
+        let mut line = Vec::new();
+        put_uint(&mut line, 1, id);
+        let mut location = Vec::new();
+        put_uint(&mut location, 1, id);
+        put_bytes(&mut location, 4, &line);
+        put_bytes(&mut profile, 4, &location);
+    }
+    for (i, name) in functions.iter().enumerate() {
+        let name = strings.intern(name);
+        let mut function = Vec::new();
+        put_uint(&mut function, 1, i as u64 + 1);
+        put_uint(&mut function, 2, name);
+        put_uint(&mut function, 3, name);
+        put_bytes(&mut profile, 5, &function);
+    }
+
+    for s in &strings.strings {
+        put_bytes(&mut profile, 6, s.as_bytes());
+    }
+    put_uint(&mut profile, 9, time_nanos);
+    put_uint(&mut profile, 10, duration_nanos);
+    put_bytes(&mut profile, 11, &value_type);
+    put_uint(&mut profile, 12, 1);
+    profile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    #[test]
+    fn test_encode_profile() {
+        let mut varint = Vec::new();
+        put_varint(&mut varint, 300);
+        assert_eq!(varint, [0xAC, 0x02]);
+
+        let stacks = vec![(vec!["main".to_string(), "work".to_string()], 5)];
+        let profile = encode_profile(&stacks, ("wall", "nanoseconds"), 0, 0);
+
+        // sample_type: {type: "wall" (1), unit: "nanoseconds" (2)}
+        assert!(profile.starts_with(&[0x0A, 4, 0x08, 1, 0x10, 2]));
+        // sample: leaf first, so work is location 1 and main location 2; value [5]
+        assert!(contains(&profile, &[0x12, 7, 0x0A, 2, 1, 2, 0x12, 1, 5]));
+        // string table: "", "wall", "nanoseconds", "work", "main"
+        assert!(contains(&profile, &[0x32, 0, 0x32, 4]));
+        assert!(contains(&profile, b"\x32\x04work\x32\x04main"));
+    }
+}
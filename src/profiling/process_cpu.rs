@@ -0,0 +1,263 @@
+use std::fs::{self, File};
+use std::io::Write;
+
+use crate::profiling::agent_thread::AGENT_THREAD_PREFIX;
+use crate::profiling::profiling::format_time;
+
+/// What a native thread of the JVM process is doing, from its name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ThreadCategory {
+    Java,
+    Gc,
+    JitCompiler,
+    Vm,
+    Profiler,
+    /// Threads gone from /proc by the time of the report, whatever they were
+    Exited,
+}
+
+impl ThreadCategory {
+    /// Synthetic root frame used in reports and `cpu_by_thread.folded`
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            ThreadCategory::Java => "[Java]",
+            ThreadCategory::Gc => "[GC]",
+            ThreadCategory::JitCompiler => "[JIT compiler]",
+            ThreadCategory::Vm => "[VM]",
+            ThreadCategory::Profiler => "[rjprof]",
+            ThreadCategory::Exited => "[exited]",
+        }
+    }
+
+    /// Classify by the kernel thread name (`comm`, cut to 15 bytes). HotSpot names its
+    /// internal threads consistently across collectors and releases.
+    pub(crate) fn from_thread_name(name: &str) -> ThreadCategory {
+        const GC: &[&str] = &[
+            "GC Thread",
+            "G1 ",
+            "G1Conc",
+            "G1Refine",
+            "ZWorker",
+            "ZDirector",
+            "ZDriver",
+            "ZStat",
+            "Shenandoah",
+            "Parallel GC",
+            "ConcGC",
+            "Gang worker",
+        ];
+        const JIT: &[&str] = &["C1 Compiler", "C2 Compiler", "JVMCI", "Sweeper thread"];
+        const VM: &[&str] = &[
+            "VM Thread",
+            "VM Periodic",
+            "Service Thread",
+            "Monitor Deflati",
+            "Signal Dispatch",
+            "Notification Th",
+            "Attach Listener",
+            "StrDedup",
+        ];
+        let matches = |prefixes: &[&str]| prefixes.iter().any(|p| name.starts_with(p));
+        if name.starts_with(AGENT_THREAD_PREFIX) {
+            ThreadCategory::Profiler
+        } else if matches(GC) {
+            ThreadCategory::Gc
+        } else if matches(JIT) {
+            ThreadCategory::JitCompiler
+        } else if matches(VM) {
+            ThreadCategory::Vm
+        } else {
+            ThreadCategory::Java
+        }
+    }
+}
+
+/// CPU time of one live OS thread
+#[derive(Clone, Debug)]
+pub(crate) struct ThreadCpu {
+    pub(crate) name: String,
+    pub(crate) category: ThreadCategory,
+    pub(crate) cpu_nanos: u64,
+}
+
+/// Name and utime+stime ticks from a /proc `stat` line.
+pub(crate) fn parse_stat(stat: &str) -> Option<(String, u64)> {
+    // comm may contain spaces and parentheses, so split at the last ')'
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let name = stat.get(open + 1..close)?.to_string();
+    let fields: Vec<&str> = stat.get(close + 1..)?.split_whitespace().collect();
+    // fields[0] is state (field 3), so utime (14) and stime (15) are at 11 and 12
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some((name, utime + stime))
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    ticks * (1_000_000_000 / per_second)
+}
+
+/// Total CPU time of this process, including threads that already exited.
+pub(crate) fn process_cpu_nanos() -> u64 {
+    fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| parse_stat(&stat))
+        .map_or(0, |(_, ticks)| ticks_to_nanos(ticks))
+}
+
+/// CPU time of every live thread of this process, including GC, compiler and VM
+/// threads that JVMTI never reports.
+pub(crate) fn thread_cpu_times() -> Vec<ThreadCpu> {
+    let Ok(tasks) = fs::read_dir("/proc/self/task") else {
+        return Vec::new();
+    };
+    tasks
+        .flatten()
+        .filter_map(|task| {
+            let stat = fs::read_to_string(task.path().join("stat")).ok()?;
+            let (name, ticks) = parse_stat(&stat)?;
+            Some(ThreadCpu {
+                category: ThreadCategory::from_thread_name(&name),
+                name,
+                cpu_nanos: ticks_to_nanos(ticks),
+            })
+        })
+        .collect()
+}
+
+/// Per-category CPU totals; threads that exited are reported under `[exited]` as the
+/// difference to the process total, so the rows add up to process CPU usage.
+fn category_totals(threads: &[ThreadCpu], process_nanos: u64) -> Vec<(ThreadCategory, u64)> {
+    let mut totals: Vec<(ThreadCategory, u64)> = Vec::new();
+    for thread in threads {
+        match totals.iter_mut().find(|(c, _)| *c == thread.category) {
+            Some((_, nanos)) => *nanos += thread.cpu_nanos,
+            None => totals.push((thread.category, thread.cpu_nanos)),
+        }
+    }
+    let live: u64 = totals.iter().map(|&(_, n)| n).sum();
+    let exited = process_nanos.saturating_sub(live);
+    if exited > 0 {
+        totals.push((ThreadCategory::Exited, exited));
+    }
+    totals.sort_by_key(|&(_, nanos)| std::cmp::Reverse(nanos));
+    totals
+}
+
+/// Print process CPU split into Java, GC, JIT compiler, VM and profiler threads.
+pub(crate) fn print_process_cpu_summary(threads: &[ThreadCpu]) {
+    let process_nanos = process_cpu_nanos();
+    if process_nanos == 0 {
+        return;
+    }
+
+    println!(
+        "\n🖥️  === Process CPU by thread category ({} total) ===",
+        format_time(process_nanos)
+    );
+    for (category, nanos) in category_totals(threads, process_nanos)
+        .into_iter()
+        .filter(|&(_, nanos)| nanos > 0)
+    {
+        println!(
+            "{:<16} {:>10} {:>5.1}%",
+            category.label(),
+            format_time(nanos),
+            nanos as f64 * 100.0 / process_nanos as f64
+        );
+    }
+
+    let mut busiest: Vec<&ThreadCpu> = threads
+        .iter()
+        .filter(|t| t.category != ThreadCategory::Java && t.cpu_nanos > 0)
+        .collect();
+    busiest.sort_by_key(|t| std::cmp::Reverse(t.cpu_nanos));
+    for thread in busiest.iter().take(8) {
+        println!(
+            "  {:<16} {:<20} {:>10}",
+            thread.category.label(),
+            thread.name,
+            format_time(thread.cpu_nanos)
+        );
+    }
+}
+
+/// Write per-thread CPU as folded stacks with the category as a synthetic root frame,
+/// e.g. `[GC];GC Thread#0 1230000000`, so it can be rendered like the flamegraph.
+pub(crate) fn write_cpu_by_thread(
+    path: &str,
+    threads: &[ThreadCpu],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let process_nanos = process_cpu_nanos();
+    let mut file = File::create(path)?;
+    let mut live = 0;
+    for thread in threads.iter().filter(|t| t.cpu_nanos > 0) {
+        writeln!(
+            file,
+            "{};{} {}",
+            thread.category.label(),
+            thread.name.replace(';', ":"),
+            thread.cpu_nanos
+        )?;
+        live += thread.cpu_nanos;
+    }
+    let exited = process_nanos.saturating_sub(live);
+    if exited > 0 {
+        writeln!(file, "{} {}", ThreadCategory::Exited.label(), exited)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (C2 CompilerThre) S 1 1 1 0 -1 4194368 0 0 0 0 150 25 0 0 20 0 40 0";
+        assert_eq!(parse_stat(stat), Some(("C2 CompilerThre".to_string(), 175)));
+        let odd = "7 (a) b) R 1 1 1 0 -1 0 0 0 0 0 3 4 0 0";
+        assert_eq!(parse_stat(odd), Some(("a) b".to_string(), 7)));
+    }
+
+    #[test]
+    fn test_thread_category() {
+        use ThreadCategory::*;
+        assert_eq!(ThreadCategory::from_thread_name("GC Thread#3"), Gc);
+        assert_eq!(ThreadCategory::from_thread_name("G1 Conc#0"), Gc);
+        assert_eq!(
+            ThreadCategory::from_thread_name("C2 CompilerThre"),
+            JitCompiler
+        );
+        assert_eq!(ThreadCategory::from_thread_name("VM Thread"), Vm);
+        assert_eq!(ThreadCategory::from_thread_name("rjprof-sampler"), Profiler);
+        assert_eq!(ThreadCategory::from_thread_name("main"), Java);
+    }
+
+    #[test]
+    fn test_category_totals_add_up() {
+        let thread = |category, cpu_nanos| ThreadCpu {
+            name: String::new(),
+            category,
+            cpu_nanos,
+        };
+        let threads = [
+            thread(ThreadCategory::Gc, 30),
+            thread(ThreadCategory::Java, 50),
+        ];
+        let totals = category_totals(&threads, 100);
+        assert_eq!(
+            totals,
+            vec![
+                (ThreadCategory::Java, 50),
+                (ThreadCategory::Gc, 30),
+                (ThreadCategory::Exited, 20)
+            ]
+        );
+        assert_eq!(
+            category_totals(&threads, 80),
+            vec![(ThreadCategory::Java, 50), (ThreadCategory::Gc, 30)]
+        );
+    }
+}
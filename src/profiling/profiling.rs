@@ -3,15 +3,74 @@ use std::os::raw::c_void;
 use std::ptr;
 
 use once_cell::sync::Lazy;
-use std::cell::RefCell;
+use serde_json::json;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::ffi::CStr;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
 use std::time::Duration;
 
 use crate::bindings::gen_bindings::*;
+use crate::profiling::class_loading::{
+    class_file_load_hook_callback, class_load_callback, class_prepare_callback,
+    print_class_loading_summary, write_class_loading,
+};
+use crate::profiling::continuous::{finish_continuous_profile, start_continuous_profiler};
+use crate::profiling::control::{close_control_socket, start_control_server};
+use crate::profiling::cpu_sampler::{set_java_vm, start_cpu_sampler, write_cpu_samples};
+use crate::profiling::exceptions::exception_callback;
+use crate::profiling::filter::{filter_generation, is_excluded};
+use crate::profiling::gc::{garbage_collection_finish_callback, garbage_collection_start_callback};
+use crate::profiling::heap::{collect_heap_histogram, print_heap_histogram, write_heap_histogram};
+use crate::profiling::heap_graph::{
+    analyze_retained_heap, print_retained_heap, write_retained_heap,
+};
+use crate::profiling::http::start_http_server;
+use crate::profiling::jit::{
+    compilation_status, compiled_method_load_callback, compiled_method_unload_callback,
+    dynamic_code_generated_callback, generate_existing_code_events, print_jit_summary,
+    write_jit_log,
+};
+use crate::profiling::lines::{
+    line_number, print_line_hotspots, record_allocation_site, watch_class_unloads,
+    write_line_hotspots, write_method_code,
+};
+use crate::profiling::metrics::start_metrics_writer;
+use crate::profiling::native::{
+    frame_name, native_method_bind_callback, print_native_summary, write_native_methods,
+    write_native_palette,
+};
+use crate::profiling::off_cpu::{
+    monitor_contended_enter_callback, monitor_contended_entered_callback, monitor_wait_callback,
+    monitor_waited_callback, off_cpu_method_entry, off_cpu_method_exit, print_off_cpu_summary,
+    write_off_cpu_folded,
+};
+use crate::profiling::options::{
+    agent_options, output_dir, output_path, set_agent_options, AgentOptions, HeapReport,
+    SessionCommand,
+};
+use crate::profiling::perf_map::init_perf_symbols;
+use crate::profiling::process_cpu::{
+    print_process_cpu_summary, thread_cpu_times, write_cpu_by_thread,
+};
+use crate::profiling::session::{
+    agent_loaded, finish_session, open_session, profiling_events, run_command, session_generation,
+    set_agent_env, write_process_info,
+};
+use crate::profiling::snapshot::start_snapshot_writer;
+use crate::profiling::thread_dump::{data_dump_request_callback, start_thread_dumper};
+use crate::profiling::threads::{
+    print_thread_summary, register_existing_threads, start_thread_state_poller,
+    thread_end_callback, thread_start_callback, write_thread_timeline_csv,
+    write_thread_timeline_json, CpuReading,
+};
+use crate::profiling::virtual_threads::{
+    enable_virtual_thread_support, print_virtual_thread_summary, virtual_thread_end_callback,
+    virtual_thread_start_callback, write_virtual_threads,
+};
 
 thread_local! {
     static ENTRY_TIMES: RefCell<HashMap<jmethodID, u64>> = RefCell::new(HashMap::new());
@@ -20,16 +79,22 @@ thread_local! {
 
 /// Newtype wrapper for JVMTI method IDs, so we can safely share across threads.
 #[derive(Clone, Copy, Hash, Eq, PartialEq, Debug)]
-struct MethodId(jmethodID);
+pub(crate) struct MethodId(pub(crate) jmethodID);
 unsafe impl Send for MethodId {}
 unsafe impl Sync for MethodId {}
 
-/// Per-method call count and total time.
+/// Per-method call count, total wall time and total CPU time.
 #[derive(Clone, Copy, Debug)]
-struct MethodStats {
-    count: u64,
-    total_nanos: u64,
-    self_nanos: u64,
+pub(crate) struct MethodStats {
+    pub(crate) count: u64,
+    pub(crate) total_nanos: u64,
+    pub(crate) self_nanos: u64,
+    /// CPU time of the `cpu_count` calls whose CPU clock could be read, which took
+    /// `cpu_wall_nanos` of wall time. Unknown without the CPU-time capability (on attach)
+    /// or when a virtual thread changed carriers mid-call.
+    pub(crate) cpu_nanos: u64,
+    pub(crate) cpu_count: u64,
+    pub(crate) cpu_wall_nanos: u64,
 }
 
 /// Per-method allocation statistics
@@ -41,10 +106,10 @@ struct AllocationStats {
 
 /// Per-class allocation statistics
 #[derive(Clone, Default, Debug)]
-struct ClassAllocationStats {
-    object_count: u64,
-    total_bytes: u64,
-    class_name: String,
+pub(crate) struct ClassAllocationStats {
+    pub(crate) object_count: u64,
+    pub(crate) total_bytes: u64,
+    pub(crate) class_name: String,
 }
 
 /// Call relationship statistics
@@ -66,6 +131,8 @@ struct CallEdge {
 struct StackFrame {
     method_id: MethodId,
     start_time: u64,
+    /// Bytecode index this frame is calling from (-1 = not recorded)
+    call_location: jlocation,
     children: Vec<StackFrame>,
 }
 
@@ -76,13 +143,13 @@ struct FlameStackSample {
     self_time: u64,     // Time spent in the leaf method
 }
 
-static METHOD_STATS: Lazy<Mutex<HashMap<MethodId, MethodStats>>> =
+pub(crate) static METHOD_STATS: Lazy<Mutex<HashMap<MethodId, MethodStats>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
 static ALLOCATION_STATS: Lazy<Mutex<HashMap<MethodId, AllocationStats>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
-static CLASS_ALLOCATION_STATS: Lazy<Mutex<HashMap<String, ClassAllocationStats>>> =
+pub(crate) static CLASS_ALLOCATION_STATS: Lazy<Mutex<HashMap<String, ClassAllocationStats>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
 
 static CALL_GRAPH: Lazy<Mutex<HashMap<CallEdge, CallRelation>>> =
@@ -92,13 +159,120 @@ static CALL_GRAPH: Lazy<Mutex<HashMap<CallEdge, CallRelation>>> =
 static FLAMEGRAPH_SAMPLES: Lazy<Mutex<Vec<FlameStackSample>>> =
     Lazy::new(|| Mutex::new(Vec::new()));
 
+// Bumped whenever the flamegraph samples are cleared, so older marks can tell
+static SAMPLE_RESETS: AtomicU64 = AtomicU64::new(0);
+
+// Method exits that didn't match the top of the shadow stack, so no sample was taken
+static DROPPED_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn dropped_frames() -> u64 {
+    DROPPED_FRAMES.load(Ordering::Relaxed)
+}
+
+// jni.h version constant; bindgen skips it as a function-like expression
+pub(crate) const JNI_VERSION_1_8: jint = 0x0001_0008;
+
 // Global JVMTI env for method info lookup
 static mut GLOBAL_JVMTI_ENV: *mut jvmtiEnv = std::ptr::null_mut();
 
+// JVMTI timestamp taken when the agent was loaded, for relative event times
+static AGENT_START_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Nanoseconds since the agent was loaded, from the JVMTI clock.
+pub(crate) fn elapsed_nanos(jvmti_env: *mut jvmtiEnv) -> u64 {
+    let mut nanos: jlong = 0;
+    unsafe {
+        (**jvmti_env).GetTime.unwrap()(jvmti_env, &mut nanos);
+    }
+    (nanos as u64).saturating_sub(AGENT_START_NANOS.load(Ordering::Relaxed))
+}
+
+/// An open call on the timing stack
+#[derive(Clone, Copy)]
+struct EntryFrame {
+    method: jmethodID,
+    entry_time: u64,
+    entry_cpu: Option<CpuReading>,
+    /// Wall time of the calls it made that have returned, taken off its self time
+    child_nanos: u64,
+}
+
 // Track method entry times with call stack depth for self-time calculation
 thread_local! {
-    static METHOD_ENTRY_STACK: RefCell<Vec<(jmethodID, u64)>> = RefCell::new(Vec::new());
+    static METHOD_ENTRY_STACK: RefCell<Vec<EntryFrame>> = RefCell::new(Vec::new());
     static FLAMEGRAPH_STACK: RefCell<Vec<StackFrame>> = RefCell::new(Vec::new());
+    // Session and filter the shadow stacks were built under; frames entered before a
+    // restart or a filter change are stale
+    static SHADOW_STACK_GENERATION: Cell<(u64, u64)> = const { Cell::new((0, 0)) };
+}
+
+/// Flamegraph frame names from root to leaf, with the call site line when recorded.
+fn stack_names(jvmti_env: *mut jvmtiEnv, frames: &[StackFrame]) -> Vec<String> {
+    frames
+        .iter()
+        .filter_map(|frame| {
+            let name = frame_name(jvmti_env, frame.method_id.0)?;
+            Some(
+                match line_number(jvmti_env, frame.method_id.0, frame.call_location) {
+                    Some(line) => format!("{}:{}", name, line),
+                    None => name,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The current thread's shadow stack as flamegraph frame names.
+pub(crate) fn current_stack_names(jvmti_env: *mut jvmtiEnv) -> Vec<String> {
+    FLAMEGRAPH_STACK.with(|stack| stack_names(jvmti_env, &stack.borrow()))
+}
+
+/// A thread's shadow stacks, set aside while a virtual thread is unmounted from its carrier
+pub(crate) struct ShadowStacks {
+    call_stack: Vec<jmethodID>,
+    entry_stack: Vec<EntryFrame>,
+    flamegraph_stack: Vec<StackFrame>,
+}
+unsafe impl Send for ShadowStacks {}
+
+/// Take the current OS thread's shadow stacks, leaving them empty.
+pub(crate) fn take_shadow_stacks() -> ShadowStacks {
+    ShadowStacks {
+        call_stack: CALL_STACK.with(|s| std::mem::take(&mut *s.borrow_mut())),
+        entry_stack: METHOD_ENTRY_STACK.with(|s| std::mem::take(&mut *s.borrow_mut())),
+        flamegraph_stack: FLAMEGRAPH_STACK.with(|s| std::mem::take(&mut *s.borrow_mut())),
+    }
+}
+
+/// Drop shadow stacks left over from an earlier session or filter on the current OS thread.
+fn discard_stale_shadow_stacks() {
+    let generation = (session_generation(), filter_generation());
+    if SHADOW_STACK_GENERATION.with(|g| g.replace(generation)) != generation {
+        take_shadow_stacks();
+    }
+}
+
+/// Clear the per-session method, allocation, call graph and flamegraph stats.
+pub(crate) fn reset_profile_stats() {
+    METHOD_STATS.lock().unwrap().clear();
+    ALLOCATION_STATS.lock().unwrap().clear();
+    CLASS_ALLOCATION_STATS.lock().unwrap().clear();
+    CALL_GRAPH.lock().unwrap().clear();
+    let mut samples = FLAMEGRAPH_SAMPLES.lock().unwrap();
+    samples.clear();
+    SAMPLE_RESETS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Install previously taken shadow stacks on the current OS thread.
+pub(crate) fn restore_shadow_stacks(stacks: ShadowStacks) {
+    CALL_STACK.with(|s| *s.borrow_mut() = stacks.call_stack);
+    METHOD_ENTRY_STACK.with(|s| *s.borrow_mut() = stacks.entry_stack);
+    FLAMEGRAPH_STACK.with(|s| *s.borrow_mut() = stacks.flamegraph_stack);
+}
+
+/// Method currently on top of this thread's shadow stack.
+pub(crate) fn current_method() -> Option<MethodId> {
+    CALL_STACK.with(|s| s.borrow().last().copied().map(MethodId))
 }
 
 extern "C" fn method_entry_callback(
@@ -107,10 +281,19 @@ extern "C" fn method_entry_callback(
     thread: jthread,
     method: jmethodID,
 ) {
+    discard_stale_shadow_stacks();
+    if is_excluded(jvmti_env, method) {
+        // Filtered frames still open off-CPU intervals, e.g. Thread.sleep under `java.`
+        if agent_options().off_cpu {
+            off_cpu_method_entry(jvmti_env, thread, method);
+        }
+        return;
+    }
     unsafe {
         let mut nano: jlong = 0;
         (**jvmti_env).GetTime.unwrap()(jvmti_env, &mut nano);
         let entry_time = nano as u64;
+        let entry_cpu = CpuReading::now(jvmti_env);
 
         // Track call graph relationships
         CALL_STACK.with(|stack| {
@@ -133,18 +316,42 @@ extern "C" fn method_entry_callback(
 
         // Track method entry for timing
         METHOD_ENTRY_STACK.with(|stack| {
-            stack.borrow_mut().push((method, entry_time));
+            stack.borrow_mut().push(EntryFrame {
+                method,
+                entry_time,
+                entry_cpu,
+                child_nanos: 0,
+            });
         });
 
-        // Track for flamegraph
+        // Track for flamegraph, noting the caller's call site when line numbers are wanted
+        let lines = agent_options().lines;
         FLAMEGRAPH_STACK.with(|stack| {
+            let mut stack_ref = stack.borrow_mut();
+            if lines {
+                if let Some(caller) = stack_ref.last_mut() {
+                    let mut caller_method: jmethodID = ptr::null_mut();
+                    (**jvmti_env).GetFrameLocation.unwrap()(
+                        jvmti_env,
+                        thread,
+                        1,
+                        &mut caller_method,
+                        &mut caller.call_location,
+                    );
+                }
+            }
             let frame = StackFrame {
                 method_id: MethodId(method),
                 start_time: entry_time,
+                call_location: -1,
                 children: Vec::new(),
             };
-            stack.borrow_mut().push(frame);
+            stack_ref.push(frame);
         });
+
+        if agent_options().off_cpu {
+            off_cpu_method_entry(jvmti_env, thread, method);
+        }
     }
 }
 
@@ -156,10 +363,22 @@ extern "C" fn method_exit_callback(
     _was_popped_by_exception: jboolean,
     _return_value: jvalue,
 ) {
+    discard_stale_shadow_stacks();
+    if is_excluded(jvmti_env, method) {
+        if agent_options().off_cpu {
+            off_cpu_method_exit(jvmti_env, thread, method);
+        }
+        return;
+    }
     unsafe {
         let mut nano_exit: jlong = 0;
         (**jvmti_env).GetTime.unwrap()(jvmti_env, &mut nano_exit);
         let exit_time = nano_exit as u64;
+        let exit_cpu = CpuReading::now(jvmti_env);
+
+        if agent_options().off_cpu {
+            off_cpu_method_exit(jvmti_env, thread, method);
+        }
 
         // Pop from call stack
         CALL_STACK.with(|stack| {
@@ -184,19 +403,32 @@ extern "C" fn method_exit_callback(
         // Calculate timing and update stats
         METHOD_ENTRY_STACK.with(|stack| {
             let mut stack_ref = stack.borrow_mut();
-            if let Some((entry_method, entry_time)) = stack_ref.pop() {
-                if entry_method == method {
-                    let total_duration = exit_time.saturating_sub(entry_time);
-                    let child_time = 0u64; // Simplified for now
+            if let Some(frame) = stack_ref.pop() {
+                if frame.method == method {
+                    let total_duration = exit_time.saturating_sub(frame.entry_time);
+                    // Unknown when the clock can't be read or the frame changed carriers
+                    let cpu_duration = CpuReading::elapsed(frame.entry_cpu, exit_cpu);
+                    let child_time = frame.child_nanos;
+                    if let Some(caller) = stack_ref.last_mut() {
+                        caller.child_nanos += total_duration;
+                    }
 
                     let mut stats = METHOD_STATS.lock().unwrap();
                     let entry = stats.entry(MethodId(method)).or_insert(MethodStats {
                         count: 0,
                         total_nanos: 0,
                         self_nanos: 0,
+                        cpu_nanos: 0,
+                        cpu_count: 0,
+                        cpu_wall_nanos: 0,
                     });
                     entry.count += 1;
                     entry.total_nanos += total_duration;
+                    if let Some(cpu_duration) = cpu_duration {
+                        entry.cpu_nanos += cpu_duration;
+                        entry.cpu_count += 1;
+                        entry.cpu_wall_nanos += total_duration;
+                    }
                     entry.self_nanos += total_duration.saturating_sub(child_time);
 
                     // Update call graph timing
@@ -236,20 +468,11 @@ extern "C" fn method_exit_callback(
 
                     // Only create flamegraph sample if we have meaningful self-time
                     if self_time > 0 {
-                        // Build the stack trace
-                        let mut stack_trace = Vec::new();
-
-                        // Add all parent frames
-                        for parent_frame in stack_ref.iter() {
-                            if let Some(method_name) =
-                                get_method_name_safe(jvmti_env, parent_frame.method_id.0)
-                            {
-                                stack_trace.push(method_name);
-                            }
-                        }
+                        // Build the stack trace from all parent frames
+                        let mut stack_trace = stack_names(jvmti_env, &stack_ref);
 
                         // Add current frame
-                        if let Some(method_name) = get_method_name_safe(jvmti_env, method) {
+                        if let Some(method_name) = frame_name(jvmti_env, method) {
                             stack_trace.push(method_name);
                         }
 
@@ -261,6 +484,8 @@ extern "C" fn method_exit_callback(
 
                         FLAMEGRAPH_SAMPLES.lock().unwrap().push(sample);
                     }
+                } else {
+                    DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
                 }
             }
         });
@@ -275,65 +500,70 @@ extern "C" fn vm_object_alloc_callback(
     object_klass: jclass,
     size: jlong,
 ) {
-    unsafe {
-        // Get class name for the allocated object
-        let class_name = {
-            let mut class_sig_ptr: *mut c_char = std::ptr::null_mut();
-            let res = (**jvmti_env).GetClassSignature.unwrap()(
-                jvmti_env,
-                object_klass,
-                &mut class_sig_ptr,
-                std::ptr::null_mut(),
-            );
-
-            if res == jvmtiError_JVMTI_ERROR_NONE && !class_sig_ptr.is_null() {
-                let class_sig = CStr::from_ptr(class_sig_ptr).to_string_lossy();
-                let formatted = if class_sig.starts_with('L') && class_sig.ends_with(';') {
-                    class_sig[1..class_sig.len() - 1].replace('/', ".")
-                } else if class_sig.starts_with('[') {
-                    format!("Array: {}", class_sig)
-                } else {
-                    class_sig.into_owned()
-                };
-
-                (**jvmti_env).Deallocate.unwrap()(jvmti_env, class_sig_ptr as *mut u8);
-                formatted
-            } else {
-                "<unknown>".to_string()
-            }
-        };
+    // Get class name for the allocated object
+    let class_name = get_class_name(jvmti_env, object_klass);
+
+    // Update class allocation stats
+    {
+        let mut class_stats = CLASS_ALLOCATION_STATS.lock().unwrap();
+        let entry = class_stats
+            .entry(class_name.clone())
+            .or_insert_with(|| ClassAllocationStats {
+                object_count: 0,
+                total_bytes: 0,
+                class_name: class_name.clone(),
+            });
+        entry.object_count += 1;
+        entry.total_bytes += size as u64;
+    }
 
-        // Update class allocation stats
-        {
-            let mut class_stats = CLASS_ALLOCATION_STATS.lock().unwrap();
-            let entry =
-                class_stats
-                    .entry(class_name.clone())
-                    .or_insert_with(|| ClassAllocationStats {
-                        object_count: 0,
-                        total_bytes: 0,
-                        class_name: class_name.clone(),
-                    });
+    // Attribute allocation to the allocating line
+    record_allocation_site(jvmti_env, thread, size as u64);
+
+    // Attribute allocation to current method
+    CALL_STACK.with(|stack| {
+        let stack_ref = stack.borrow();
+        if let Some(&current_method) = stack_ref.last() {
+            let mut alloc_stats = ALLOCATION_STATS.lock().unwrap();
+            let entry = alloc_stats
+                .entry(MethodId(current_method))
+                .or_insert_with(Default::default);
             entry.object_count += 1;
             entry.total_bytes += size as u64;
         }
+    });
+}
 
-        // Attribute allocation to current method
-        CALL_STACK.with(|stack| {
-            let stack_ref = stack.borrow();
-            if let Some(&current_method) = stack_ref.last() {
-                let mut alloc_stats = ALLOCATION_STATS.lock().unwrap();
-                let entry = alloc_stats
-                    .entry(MethodId(current_method))
-                    .or_insert_with(Default::default);
-                entry.object_count += 1;
-                entry.total_bytes += size as u64;
-            }
-        });
+/// Human-readable class name, e.g. `java.lang.String` or `Array: [B`.
+pub(crate) fn get_class_name(jvmti_env: *mut jvmtiEnv, klass: jclass) -> String {
+    unsafe {
+        let mut class_sig_ptr: *mut c_char = std::ptr::null_mut();
+        let res = (**jvmti_env).GetClassSignature.unwrap()(
+            jvmti_env,
+            klass,
+            &mut class_sig_ptr,
+            std::ptr::null_mut(),
+        );
+
+        if res == jvmtiError_JVMTI_ERROR_NONE && !class_sig_ptr.is_null() {
+            let class_sig = CStr::from_ptr(class_sig_ptr).to_string_lossy();
+            let formatted = if class_sig.starts_with('L') && class_sig.ends_with(';') {
+                class_sig[1..class_sig.len() - 1].replace('/', ".")
+            } else if class_sig.starts_with('[') {
+                format!("Array: {}", class_sig)
+            } else {
+                class_sig.into_owned()
+            };
+
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, class_sig_ptr as *mut u8);
+            formatted
+        } else {
+            "<unknown>".to_string()
+        }
     }
 }
 
-fn get_method_name_safe(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<String> {
+pub(crate) fn get_method_name_safe(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> Option<String> {
     let (class_name, method_name, _) = get_method_info(jvmti_env, method);
     if class_name != "<unknown-class>" && method_name != "<unknown>" {
         Some(format!("{}.{}", class_name, method_name))
@@ -410,7 +640,7 @@ fn get_method_info(jvmti_env: *mut jvmtiEnv, method: jmethodID) -> (String, Stri
     }
 }
 
-fn format_bytes(bytes: u64) -> String {
+pub(crate) fn format_bytes(bytes: u64) -> String {
     if bytes < 1024 {
         format!("{}B", bytes)
     } else if bytes < 1024 * 1024 {
@@ -422,7 +652,7 @@ fn format_bytes(bytes: u64) -> String {
     }
 }
 
-fn format_time(nanos: u64) -> String {
+pub(crate) fn format_time(nanos: u64) -> String {
     if nanos < 1000 {
         format!("{}ns", nanos)
     } else if nanos < 1_000_000 {
@@ -460,6 +690,110 @@ fn generate_flamegraph_svg(
     Ok(folded_output)
 }
 
+/// The flamegraph samples so far as folded stacks.
+pub(crate) fn folded_stacks() -> String {
+    let samples = FLAMEGRAPH_SAMPLES.lock().unwrap();
+    generate_flamegraph_svg(&samples).unwrap_or_default()
+}
+
+/// Position in the flamegraph samples, to aggregate only what was recorded after it
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SampleMark {
+    resets: u64,
+    len: usize,
+}
+
+/// Mark the samples recorded so far.
+pub(crate) fn sample_mark() -> SampleMark {
+    let samples = FLAMEGRAPH_SAMPLES.lock().unwrap();
+    SampleMark {
+        resets: SAMPLE_RESETS.load(Ordering::Relaxed),
+        len: samples.len(),
+    }
+}
+
+pub(crate) fn flamegraph_sample_count() -> usize {
+    FLAMEGRAPH_SAMPLES.lock().unwrap().len()
+}
+
+/// Self time per distinct stack (root first) over the samples recorded after `mark`,
+/// largest first. If the stats were reset in the meantime, over all current samples.
+pub(crate) fn stacks_since(mark: SampleMark) -> Vec<(Vec<String>, u64)> {
+    let samples = FLAMEGRAPH_SAMPLES.lock().unwrap();
+    let start = if mark.resets == SAMPLE_RESETS.load(Ordering::Relaxed) {
+        mark.len.min(samples.len())
+    } else {
+        0
+    };
+    let window = &samples[start..];
+    let mut aggregated: HashMap<&[String], u64> = HashMap::new();
+    for sample in window {
+        *aggregated.entry(&sample.stack).or_insert(0) += sample.self_time;
+    }
+    let mut stacks: Vec<(Vec<String>, u64)> = aggregated
+        .into_iter()
+        .map(|(stack, nanos)| (stack.to_vec(), nanos))
+        .collect();
+    stacks.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    stacks
+}
+
+/// Render stacks as folded lines (`a;b;c 123`).
+pub(crate) fn format_folded(stacks: &[(Vec<String>, u64)]) -> String {
+    stacks
+        .iter()
+        .map(|(stack, nanos)| format!("{} {}\n", stack.join(";"), nanos))
+        .collect()
+}
+
+/// The `limit` methods with the most self time, for snapshots.
+pub(crate) fn method_stats_json(jvmti_env: *mut jvmtiEnv, limit: usize) -> serde_json::Value {
+    let mut stats: Vec<(MethodId, MethodStats)> = {
+        let guard = METHOD_STATS.lock().unwrap();
+        guard.iter().map(|(&m, st)| (m, *st)).collect()
+    };
+    stats.sort_by_key(|&(_, st)| std::cmp::Reverse(st.self_nanos));
+    let records: Vec<serde_json::Value> = stats
+        .iter()
+        .take(limit)
+        .map(|(MethodId(method), st)| {
+            let (class_name, method_name, _) = get_method_info(jvmti_env, *method);
+            json!({
+                "method": format!("{}.{}", class_name, method_name),
+                "calls": st.count,
+                "total_nanos": st.total_nanos,
+                "self_nanos": st.self_nanos,
+                "cpu_nanos": st.cpu_nanos,
+                "cpu_calls": st.cpu_count,
+            })
+        })
+        .collect();
+    json!(records)
+}
+
+/// The `limit` classes with the most bytes allocated, for snapshots.
+pub(crate) fn class_allocations_json(limit: usize) -> serde_json::Value {
+    let mut classes: Vec<ClassAllocationStats> = CLASS_ALLOCATION_STATS
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    classes.sort_by_key(|st| std::cmp::Reverse(st.total_bytes));
+    let records: Vec<serde_json::Value> = classes
+        .iter()
+        .take(limit)
+        .map(|st| {
+            json!({
+                "class": st.class_name,
+                "objects": st.object_count,
+                "bytes": st.total_bytes,
+            })
+        })
+        .collect();
+    json!(records)
+}
+
 fn write_flamegraph_data(jvmti_env: *mut jvmtiEnv) -> Result<(), Box<dyn std::error::Error>> {
     let samples = FLAMEGRAPH_SAMPLES.lock().unwrap();
 
@@ -472,15 +806,21 @@ fn write_flamegraph_data(jvmti_env: *mut jvmtiEnv) -> Result<(), Box<dyn std::er
     let folded_data = generate_flamegraph_svg(&samples)?;
 
     // Write to file
-    let mut file = File::create("flamegraph.folded")?;
+    let mut file = File::create(output_path("flamegraph.folded"))?;
     file.write_all(folded_data.as_bytes())?;
 
     println!("🔥 Flamegraph data written to 'flamegraph.folded'");
     println!("   Generate SVG with: flamegraph.pl flamegraph.folded > flamegraph.svg");
     println!("   Or use: inferno-flamegraph flamegraph.folded > flamegraph.svg");
 
+    // Native frames get their own colour with `--cp`
+    write_native_palette(
+        &output_path("palette.map"),
+        samples.iter().flat_map(|s| s.stack.iter()),
+    )?;
+
     // Also write a simple text summary
-    let mut summary_file = File::create("flamegraph_summary.txt")?;
+    let mut summary_file = File::create(output_path("flamegraph_summary.txt"))?;
     writeln!(summary_file, "Flamegraph Summary")?;
     writeln!(summary_file, "==================")?;
     writeln!(summary_file, "Total samples: {}", samples.len())?;
@@ -507,7 +847,18 @@ fn write_flamegraph_data(jvmti_env: *mut jvmtiEnv) -> Result<(), Box<dyn std::er
     Ok(())
 }
 
-extern "C" fn vm_death_callback(jvmti_env: *mut jvmtiEnv, _jni_env: *mut JNIEnv) {
+extern "C" fn vm_death_callback(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    finish_continuous_profile(jvmti_env);
+    finish_session(jvmti_env, jni_env);
+    close_control_socket();
+}
+
+/// Print the analysis and write every report file, plus the heap reports if asked for.
+pub(crate) fn write_reports(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    heap: Option<HeapReport>,
+) {
     println!("\n🔍 === PERFORMANCE & CALL GRAPH ANALYSIS ===");
 
     // Generate flamegraph data
@@ -532,15 +883,109 @@ extern "C" fn vm_death_callback(jvmti_env: *mut jvmtiEnv, _jni_env: *mut JNIEnv)
         let method_str = format!("{}.{}", class_name, method_name);
         let avg_self = st.self_nanos / st.count;
         let avg_total = st.total_nanos / st.count;
+        let avg_cpu = match st.cpu_count {
+            0 => "n/a".to_string(),
+            cpu_count => format_time(st.cpu_nanos / cpu_count),
+        };
         println!(
-            "{:<50} {:>6} calls | Self: {:>8} avg | Total: {:>8} avg",
+            "{:<50} {:>6} calls | Self: {:>8} avg | Total: {:>8} avg | CPU: {:>8} avg | JIT: {}",
             method_str,
             st.count,
             format_time(avg_self),
-            format_time(avg_total)
+            format_time(avg_total),
+            avg_cpu,
+            compilation_status(MethodId(*method))
         );
     }
 
+    // Wall vs CPU: methods whose time is mostly spent off-CPU (sleeping, waiting, I/O),
+    // over the calls whose CPU time is known
+    let mut waiting: Vec<&(MethodId, MethodStats)> = stats
+        .iter()
+        .filter(|(_, st)| st.cpu_count > 0 && st.cpu_nanos * 2 < st.cpu_wall_nanos)
+        .collect();
+    waiting.sort_by_key(|(_, st)| std::cmp::Reverse(st.cpu_wall_nanos - st.cpu_nanos));
+    let top_waiting = std::cmp::min(waiting.len(), 10);
+    if top_waiting > 0 {
+        println!(
+            "\n⌛ === Top {} methods by off-CPU time (wall vs CPU) ===",
+            top_waiting
+        );
+        for (MethodId(method), st) in waiting.iter().take(top_waiting) {
+            let (class_name, method_name, _) = get_method_info(jvmti_env, *method);
+            println!(
+                "{:<50} Wall: {:>8} | CPU: {:>8} | {:.0}% on CPU",
+                format!("{}.{}", class_name, method_name),
+                format_time(st.cpu_wall_nanos),
+                format_time(st.cpu_nanos),
+                st.cpu_nanos as f64 * 100.0 / st.cpu_wall_nanos as f64
+            );
+        }
+    }
+
+    // Java vs native split and NativeMethodBind targets
+    print_native_summary(jvmti_env);
+    if let Err(e) = write_native_methods(jvmti_env, &output_path("native_methods.txt")) {
+        eprintln!("Error writing native method report: {}", e);
+    }
+
+    // Time blocked on monitors, waits, sleeps and parks
+    print_off_cpu_summary();
+    if let Err(e) = write_off_cpu_folded(&output_path("off_cpu.folded")) {
+        eprintln!("Error writing off-CPU data: {}", e);
+    }
+
+    // CPU samples with native frames
+    if let Err(e) = write_cpu_samples(jvmti_env, &output_path("cpu_mixed.folded")) {
+        eprintln!("Error writing mixed-mode CPU samples: {}", e);
+    }
+
+    // Sampled hot lines and allocation lines
+    print_line_hotspots(jvmti_env, 15);
+    if let Err(e) = write_line_hotspots(
+        jvmti_env,
+        &output_path("line_hotspots.txt"),
+        &output_path("line_samples.json"),
+    ) {
+        eprintln!("Error writing line hotspots: {}", e);
+    }
+    if let Err(e) = write_method_code(jvmti_env, &output_path("method_code.json")) {
+        eprintln!("Error writing method bytecode: {}", e);
+    }
+
+    // JIT compilation status of the hot methods
+    print_jit_summary(jvmti_env);
+    if let Err(e) = write_jit_log(&output_path("jit_compilations.txt")) {
+        eprintln!("Error writing JIT compilation log: {}", e);
+    }
+
+    // Class loading and startup timeline
+    print_class_loading_summary();
+    if let Err(e) = write_class_loading(&output_path("class_loading.txt")) {
+        eprintln!("Error writing class loading report: {}", e);
+    }
+
+    // Per-thread state timeline
+    print_thread_summary();
+    if let Err(e) = write_thread_timeline_json(&output_path("thread_timeline.json")) {
+        eprintln!("Error writing thread timeline: {}", e);
+    }
+    if let Err(e) = write_thread_timeline_csv(&output_path("thread_states.csv")) {
+        eprintln!("Error writing thread states: {}", e);
+    }
+
+    // CPU of all OS threads, including GC, JIT compiler and VM threads JVMTI can't see
+    let thread_cpu = thread_cpu_times();
+    print_process_cpu_summary(&thread_cpu);
+    if let Err(e) = write_cpu_by_thread(&output_path("cpu_by_thread.folded"), &thread_cpu) {
+        eprintln!("Error writing per-thread CPU: {}", e);
+    }
+
+    print_virtual_thread_summary(jvmti_env);
+    if let Err(e) = write_virtual_threads(&output_path("virtual_threads.txt")) {
+        eprintln!("Error writing virtual thread report: {}", e);
+    }
+
     // Call graph analysis
     let call_graph = CALL_GRAPH.lock().unwrap();
     let mut call_relations: Vec<(CallEdge, CallRelation)> =
@@ -618,25 +1063,152 @@ extern "C" fn vm_death_callback(jvmti_env: *mut jvmtiEnv, _jni_env: *mut JNIEnv)
             );
         }
     }
+
+    if let Some(report) = heap {
+        write_heap_reports(jvmti_env, jni_env, report);
+    }
 }
 
-extern "C" fn vm_init_callback(jvmti_env: *mut jvmtiEnv, _jni_env: *mut JNIEnv, _thread: jthread) {
+/// Walk the heap for `heap=histogram|retained`. Both force a full GC and stop the
+/// application for the whole walk, which grows with the number of live objects.
+fn write_heap_reports(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, report: HeapReport) {
+    // Live heap histogram (retention vs. allocation churn)
+    match collect_heap_histogram(jvmti_env, jni_env) {
+        Ok(histogram) => {
+            print_heap_histogram(&histogram, 10);
+            if let Err(e) = write_heap_histogram(&output_path("heap_histogram.txt"), &histogram) {
+                eprintln!("Error writing heap histogram: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Error collecting heap histogram: {}", e),
+    }
+    if report != HeapReport::Retained {
+        return;
+    }
+
+    // Dominator tree over the reachable heap (who keeps what alive)
+    match analyze_retained_heap(jvmti_env, jni_env, 5) {
+        Ok(report) => {
+            print_retained_heap(&report, 10);
+            if let Err(e) = write_retained_heap(&output_path("heap_retained.txt"), &report) {
+                eprintln!("Error writing retained heap report: {}", e);
+            }
+        }
+        Err(e) => eprintln!("Error analyzing retained heap: {}", e),
+    }
+}
+
+extern "C" fn vm_init_callback(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, _thread: jthread) {
+    start_profiling(jvmti_env, jni_env);
+}
+
+/// Start the agent threads once the VM is live: at VM_INIT, or right away when attached.
+fn start_profiling(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
     unsafe {
         GLOBAL_JVMTI_ENV = jvmti_env;
 
-        let mut thread_count: jint = 0;
-        let mut threads: *mut jthread = ptr::null_mut();
-        let err = (**jvmti_env).GetAllThreads.unwrap()(jvmti_env, &mut thread_count, &mut threads);
+        let thread_count = register_existing_threads(jvmti_env, jni_env);
+        start_thread_state_poller(jvmti_env, jni_env);
+        start_thread_dumper(jvmti_env, jni_env);
+        start_snapshot_writer(jvmti_env, jni_env);
+        start_control_server(jvmti_env, jni_env);
+        start_http_server(jvmti_env, jni_env);
+        start_metrics_writer(jvmti_env, jni_env);
+        start_continuous_profiler(jvmti_env, jni_env);
+        start_cpu_sampler(jvmti_env, jni_env);
+        open_session(jvmti_env, jni_env);
 
         println!("✅ [VM_INIT] JVM thread count: {}", thread_count);
         println!("📊 Call graph analysis & allocation tracking enabled");
         println!("🔥 Flamegraph generation enabled");
+
+        // Pick up code compiled before our events were enabled (e.g. on dynamic attach)
+        generate_existing_code_events(jvmti_env);
     }
 }
 
-#[no_mangle]
-pub extern "C" fn Agent_OnAttach(vm: *mut JavaVM, _options: *mut c_char, _reserved: *mut c_void) {
+/// JNI environment of the calling thread (the attach listener, on a live load).
+unsafe fn current_jni_env(vm: *mut JavaVM) -> Option<*mut JNIEnv> {
+    let mut jni: *mut JNIEnv = ptr::null_mut();
+    let res = (**vm).GetEnv.unwrap()(
+        vm,
+        (&mut jni) as *mut *mut JNIEnv as *mut *mut c_void,
+        JNI_VERSION_1_8,
+    );
+    (res == JNI_OK as jint && !jni.is_null()).then_some(jni)
+}
+
+/// Drop the capabilities this VM can't grant now (a live VM refuses onload-only ones).
+/// Returns true if any were dropped.
+unsafe fn restrict_to_potential_capabilities(
+    jvmti: *mut jvmtiEnv,
+    caps: &mut jvmtiCapabilities,
+) -> bool {
+    let mut potential = std::mem::zeroed::<jvmtiCapabilities>();
+    if (**jvmti).GetPotentialCapabilities.unwrap()(jvmti, &mut potential)
+        != jvmtiError_JVMTI_ERROR_NONE
+    {
+        return false;
+    }
+    let size = std::mem::size_of::<jvmtiCapabilities>();
+    let wanted = std::slice::from_raw_parts_mut(caps as *mut jvmtiCapabilities as *mut u8, size);
+    let potential =
+        std::slice::from_raw_parts(&potential as *const jvmtiCapabilities as *const u8, size);
+    let mut dropped = false;
+    for (bits, available) in wanted.iter_mut().zip(potential) {
+        dropped |= *bits & !available != 0;
+        *bits &= available;
+    }
+    dropped
+}
+
+/// Set up the agent. `live` is true when attached to a running VM, where VM_INIT has
+/// already happened and profiling starts immediately.
+fn init_agent(vm: *mut JavaVM, options: *mut c_char, live: bool) -> jint {
     unsafe {
+        let options_str = if options.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(options).to_string_lossy().into_owned()
+        };
+        let parsed = AgentOptions::parse(&options_str);
+
+        // A repeated load drives the sessions of the agent that is already running
+        if agent_loaded() {
+            let result = parsed.and_then(|options| {
+                let jni = current_jni_env(vm).ok_or("no JNI environment")?;
+                run_command(jni, &options)
+            });
+            return match result {
+                Ok(()) => JNI_OK as jint,
+                Err(e) => {
+                    eprintln!("rjprof: {}", e);
+                    JNI_ERR
+                }
+            };
+        }
+
+        match parsed {
+            Ok(AgentOptions {
+                command: Some(command),
+                ..
+            }) if command != SessionCommand::Start => {
+                eprintln!(
+                    "rjprof: nothing to {}, the agent isn't running",
+                    command.name()
+                );
+                return JNI_ERR;
+            }
+            Ok(parsed) => set_agent_options(parsed),
+            Err(e) => eprintln!("Invalid agent options '{}': {}", options_str, e),
+        }
+        if let Some(dir) = output_dir() {
+            if let Err(e) = fs::create_dir_all(&dir) {
+                eprintln!("Error creating output directory {}: {}", dir, e);
+            }
+        }
+        init_perf_symbols(agent_options());
+
         let mut jvmti: *mut jvmtiEnv = ptr::null_mut();
 
         let get_env_fn = (**vm).GetEnv.unwrap();
@@ -645,16 +1217,46 @@ pub extern "C" fn Agent_OnAttach(vm: *mut JavaVM, _options: *mut c_char, _reserv
             (&mut jvmti) as *mut *mut jvmtiEnv as *mut *mut c_void,
             JVMTI_VERSION_1_2 as jint,
         );
+        set_agent_env(jvmti);
+        set_java_vm(vm);
+        if agent_options().per_process {
+            write_process_info(jvmti);
+        }
+
+        let mut start_nanos: jlong = 0;
+        (**jvmti).GetTime.unwrap()(jvmti, &mut start_nanos);
+        AGENT_START_NANOS.store(start_nanos as u64, Ordering::Relaxed);
 
         let mut caps = std::mem::zeroed::<jvmtiCapabilities>();
         caps.set_can_generate_method_entry_events(1);
         caps.set_can_generate_method_exit_events(1);
         caps.set_can_generate_vm_object_alloc_events(1);
-
+        caps.set_can_tag_objects(1);
+        caps.set_can_generate_compiled_method_load_events(1);
+        caps.set_can_get_thread_cpu_time(1);
+        caps.set_can_get_current_thread_cpu_time(1);
+        caps.set_can_get_owned_monitor_stack_depth_info(1);
+        caps.set_can_get_current_contended_monitor(1);
+        caps.set_can_get_line_numbers(1);
+        caps.set_can_get_source_file_name(1);
+        caps.set_can_get_bytecodes(1);
+        caps.set_can_generate_native_method_bind_events(1);
+        caps.set_can_generate_monitor_events(1);
+        caps.set_can_generate_exception_events(1);
+        caps.set_can_generate_garbage_collection_events(1);
+
+        if live && restrict_to_potential_capabilities(jvmti, &mut caps) {
+            eprintln!("⚠️  Some capabilities are only available at startup; attached profiling is limited");
+            if caps.can_generate_method_entry_events() == 0 {
+                eprintln!("⚠️  Method entry/exit events unavailable: no call graph or flamegraph");
+            }
+        }
         let err = (**jvmti).AddCapabilities.unwrap()(jvmti, &caps);
         if err != jvmtiError_JVMTI_ERROR_NONE {
             eprintln!("Failed to add JVMTI capabilities: {}", err);
         }
+        let virtual_threads = enable_virtual_thread_support(jvmti);
+        watch_class_unloads(jvmti);
 
         let callbacks = jvmtiEventCallbacks {
             VMInit: Some(vm_init_callback),
@@ -662,6 +1264,25 @@ pub extern "C" fn Agent_OnAttach(vm: *mut JavaVM, _options: *mut c_char, _reserv
             MethodEntry: Some(method_entry_callback),
             MethodExit: Some(method_exit_callback),
             VMObjectAlloc: Some(vm_object_alloc_callback),
+            CompiledMethodLoad: Some(compiled_method_load_callback),
+            CompiledMethodUnload: Some(compiled_method_unload_callback),
+            DynamicCodeGenerated: Some(dynamic_code_generated_callback),
+            ClassFileLoadHook: Some(class_file_load_hook_callback),
+            ClassLoad: Some(class_load_callback),
+            ClassPrepare: Some(class_prepare_callback),
+            ThreadStart: Some(thread_start_callback),
+            ThreadEnd: Some(thread_end_callback),
+            VirtualThreadStart: Some(virtual_thread_start_callback),
+            VirtualThreadEnd: Some(virtual_thread_end_callback),
+            MonitorContendedEnter: Some(monitor_contended_enter_callback),
+            MonitorContendedEntered: Some(monitor_contended_entered_callback),
+            MonitorWait: Some(monitor_wait_callback),
+            MonitorWaited: Some(monitor_waited_callback),
+            DataDumpRequest: Some(data_dump_request_callback),
+            NativeMethodBind: Some(native_method_bind_callback),
+            Exception: Some(exception_callback),
+            GarbageCollectionStart: Some(garbage_collection_start_callback),
+            GarbageCollectionFinish: Some(garbage_collection_finish_callback),
             ..std::mem::zeroed()
         };
 
@@ -674,13 +1295,27 @@ pub extern "C" fn Agent_OnAttach(vm: *mut JavaVM, _options: *mut c_char, _reserv
             eprintln!("Failed to set JVMTI event callbacks: {}", err);
         }
 
-        let events = [
+        let mut events = vec![
             jvmtiEvent_JVMTI_EVENT_VM_INIT,
             jvmtiEvent_JVMTI_EVENT_VM_DEATH,
-            jvmtiEvent_JVMTI_EVENT_METHOD_ENTRY,
-            jvmtiEvent_JVMTI_EVENT_METHOD_EXIT,
-            jvmtiEvent_JVMTI_EVENT_VM_OBJECT_ALLOC,
+            jvmtiEvent_JVMTI_EVENT_COMPILED_METHOD_LOAD,
+            jvmtiEvent_JVMTI_EVENT_COMPILED_METHOD_UNLOAD,
+            jvmtiEvent_JVMTI_EVENT_DYNAMIC_CODE_GENERATED,
+            jvmtiEvent_JVMTI_EVENT_CLASS_FILE_LOAD_HOOK,
+            jvmtiEvent_JVMTI_EVENT_CLASS_LOAD,
+            jvmtiEvent_JVMTI_EVENT_CLASS_PREPARE,
+            jvmtiEvent_JVMTI_EVENT_THREAD_START,
+            jvmtiEvent_JVMTI_EVENT_THREAD_END,
+            jvmtiEvent_JVMTI_EVENT_DATA_DUMP_REQUEST,
+            jvmtiEvent_JVMTI_EVENT_NATIVE_METHOD_BIND,
         ];
+        if virtual_threads {
+            events.extend([
+                jvmtiEvent_JVMTI_EVENT_VIRTUAL_THREAD_START,
+                jvmtiEvent_JVMTI_EVENT_VIRTUAL_THREAD_END,
+            ]);
+        }
+        events.extend(profiling_events(virtual_threads));
 
         for &event in &events {
             let err = (**jvmti).SetEventNotificationMode.unwrap()(
@@ -694,11 +1329,34 @@ pub extern "C" fn Agent_OnAttach(vm: *mut JavaVM, _options: *mut c_char, _reserv
             }
         }
 
-        println!("🔗 Agent attached with call graph analysis, waiting for VM_INIT...");
+        if live {
+            let Some(jni) = current_jni_env(vm) else {
+                eprintln!("Failed to get JNI environment on attach");
+                return JNI_ERR;
+            };
+            println!("🔗 Agent attached to running VM with call graph analysis");
+            start_profiling(jvmti, jni);
+        } else {
+            println!("🔗 Agent attached with call graph analysis, waiting for VM_INIT...");
+        }
     }
+    JNI_OK as jint
+}
+
+#[no_mangle]
+pub extern "C" fn Agent_OnAttach(
+    vm: *mut JavaVM,
+    options: *mut c_char,
+    _reserved: *mut c_void,
+) -> jint {
+    init_agent(vm, options, true)
 }
 
 #[no_mangle]
-pub extern "C" fn Agent_OnLoad(vm: *mut JavaVM, options: *mut c_char, reserved: *mut c_void) {
-    Agent_OnAttach(vm, options, reserved);
+pub extern "C" fn Agent_OnLoad(
+    vm: *mut JavaVM,
+    options: *mut c_char,
+    _reserved: *mut c_void,
+) -> jint {
+    init_agent(vm, options, false)
 }
@@ -0,0 +1,704 @@
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{agent_shut_down, sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::continuous::IntervalProfile;
+use crate::profiling::options::{agent_options, output_path, UploadFormat};
+use crate::profiling::snapshot::format_timestamp;
+
+/// Tries per interval profile before it is spooled
+const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// Profiles waiting for the uploader; more than this and the queue goes to the spool
+const MAX_QUEUED_UPLOADS: usize = 4;
+
+/// How often the uploader checks for queued profiles
+const UPLOAD_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Wait before the first retry; doubled after each one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Spooled profiles kept while the server is down (a day of 60s intervals); older ones go first
+const MAX_SPOOLED: usize = 1440;
+
+const SPOOL_EXTENSION: &str = "ingest";
+
+const MULTIPART_BOUNDARY: &str = "rjprof-pprof-upload";
+
+/// Folded values are wall nanoseconds; Pyroscope reads them as samples at this rate
+const FOLDED_SAMPLE_RATE: u64 = 1_000_000_000;
+
+/// `http://host[:port][/prefix]` of a Pyroscope server; TLS is left to a local proxy
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Endpoint {
+    host: String,
+    port: u16,
+    prefix: String,
+}
+
+impl Endpoint {
+    pub(crate) fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// Pyroscope URLs are supported: {}", url))?;
+        let (authority, prefix) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let invalid_port = || format!("Invalid port in Pyroscope URL: {}", url);
+        // An IPv6 address is bracketed, as in `http://[::1]:4040`
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("Unclosed [ in Pyroscope URL: {}", url))?;
+                match rest {
+                    "" => (host, None),
+                    _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid_port)?)),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid_port())?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in Pyroscope URL: {}", url));
+        }
+        Ok(Endpoint {
+            host: host.to_string(),
+            port,
+            prefix: prefix.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// `host:port`, with an IPv6 host in brackets
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority(), self.prefix)
+    }
+}
+
+/// One POST to `/ingest`
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct IngestRequest {
+    query: String,
+    content_type: String,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum UploadError {
+    /// Not reachable or failing (5xx); worth trying again later
+    Unavailable(String),
+    /// Refused (4xx); sending it again won't help
+    Rejected(String),
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Pyroscope application name with labels: `app.wall{host=web-1,version=1.2}`. Characters
+/// that delimit labels are replaced in values.
+pub(crate) fn application_name(app: &str, labels: &[(&str, &str)]) -> String {
+    let clean = |value: &str| value.replace([',', '=', '{', '}'], "_");
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, clean(value)))
+        .collect();
+    format!("{}.wall{{{}}}", clean(app), labels.join(","))
+}
+
+pub(crate) fn ingest_request(
+    profile: &IntervalProfile,
+    format: UploadFormat,
+    name: &str,
+) -> IngestRequest {
+    let mut query = format!(
+        "name={}&from={}&until={}&spyName=rjprof&format={}",
+        percent_encode(name),
+        profile.from_secs,
+        profile.until_secs,
+        format.name()
+    );
+    match format {
+        UploadFormat::Folded => {
+            query.push_str(&format!(
+                "&sampleRate={}&units=samples&aggregationType=sum",
+                FOLDED_SAMPLE_RATE
+            ));
+            IngestRequest {
+                query,
+                content_type: "text/plain".to_string(),
+                body: profile.folded.clone().into_bytes(),
+            }
+        }
+        UploadFormat::Pprof => {
+            let mut body = format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"profile\"; filename=\"profile.pprof\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n",
+                MULTIPART_BOUNDARY
+            )
+            .into_bytes();
+            body.extend_from_slice(&profile.pprof);
+            body.extend_from_slice(format!("\r\n--{}--\r\n", MULTIPART_BOUNDARY).as_bytes());
+            IngestRequest {
+                query,
+                content_type: format!("multipart/form-data; boundary={}", MULTIPART_BOUNDARY),
+                body,
+            }
+        }
+    }
+}
+
+/// Send one request and read the status line of the answer.
+pub(crate) fn post(endpoint: &Endpoint, request: &IngestRequest) -> Result<(), UploadError> {
+    let unavailable = |e: std::io::Error| UploadError::Unavailable(format!("{}: {}", endpoint, e));
+    let address = (endpoint.host.as_str(), endpoint.port)
+        .to_socket_addrs()
+        .map_err(unavailable)?
+        .next()
+        .ok_or_else(|| UploadError::Unavailable(format!("{}: no address", endpoint)))?;
+    let mut stream = TcpStream::connect_timeout(&address, UPLOAD_TIMEOUT).map_err(unavailable)?;
+    stream
+        .set_read_timeout(Some(UPLOAD_TIMEOUT))
+        .and_then(|_| stream.set_write_timeout(Some(UPLOAD_TIMEOUT)))
+        .map_err(unavailable)?;
+
+    let head = format!(
+        "POST {}/ingest?{} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rjprof\r\n\
+         Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.prefix,
+        request.query,
+        endpoint.authority(),
+        request.content_type,
+        request.body.len()
+    );
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(&request.body))
+        .map_err(unavailable)?;
+
+    let mut status_line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut status_line)
+        .map_err(unavailable)?;
+    let status: u16 = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| {
+            UploadError::Unavailable(format!("{}: invalid response {:?}", endpoint, status_line))
+        })?;
+    let message = format!("{} answered {}", endpoint, status_line.trim_end());
+    match status {
+        200..=299 => Ok(()),
+        400..=499 => Err(UploadError::Rejected(message)),
+        _ => Err(UploadError::Unavailable(message)),
+    }
+}
+
+/// [`post`] up to `attempts` times while the server is unavailable, backing off in between.
+fn post_with_retries(
+    endpoint: &Endpoint,
+    request: &IngestRequest,
+    attempts: u32,
+) -> Result<(), UploadError> {
+    let mut delay = RETRY_DELAY;
+    for _ in 1..attempts {
+        match post(endpoint, request) {
+            Err(UploadError::Unavailable(_)) => {
+                if sleep_unless_shut_down(delay) {
+                    break;
+                }
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    post(endpoint, request)
+}
+
+/// Spooled requests, oldest first (file names start with the interval's timestamp).
+fn spooled_requests(spool_dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(spool_dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == SPOOL_EXTENSION))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Sequence number of a spool file named `<timestamp>-<seq>.ingest`.
+fn spool_seq(path: &Path) -> Option<u64> {
+    let stem = path.file_stem()?.to_str()?;
+    stem.rsplit_once('-')?.1.parse().ok()
+}
+
+/// Spool file: the query and the content type on a line each, then the body. Named
+/// `<timestamp>-<seq>.ingest` with a zero-padded sequence number that only goes up, so
+/// profiles of the same second still sort in the order they were spooled.
+fn spool(spool_dir: &Path, from_secs: u64, request: &IngestRequest) -> std::io::Result<PathBuf> {
+    fs::create_dir_all(spool_dir)?;
+    let seq = spooled_requests(spool_dir)
+        .iter()
+        .filter_map(|path| spool_seq(path))
+        .max()
+        .map_or(1, |seq| seq + 1);
+    let path = spool_dir.join(format!(
+        "{}-{:06}.{}",
+        format_timestamp(from_secs),
+        seq,
+        SPOOL_EXTENSION
+    ));
+    let mut contents = format!("{}\n{}\n", request.query, request.content_type).into_bytes();
+    contents.extend_from_slice(&request.body);
+    fs::write(&path, contents)?;
+
+    let spooled = spooled_requests(spool_dir);
+    for old in &spooled[..spooled.len().saturating_sub(MAX_SPOOLED)] {
+        let _ = fs::remove_file(old);
+    }
+    Ok(path)
+}
+
+fn read_spooled(path: &Path) -> Option<IngestRequest> {
+    let contents = fs::read(path).ok()?;
+    let mut parts = contents.splitn(3, |&b| b == b'\n');
+    let query = String::from_utf8(parts.next()?.to_vec()).ok()?;
+    let content_type = String::from_utf8(parts.next()?.to_vec()).ok()?;
+    Some(IngestRequest {
+        query,
+        content_type,
+        body: parts.next()?.to_vec(),
+    })
+}
+
+/// Send spooled requests, oldest first, until the server is unavailable again.
+/// Returns how many were sent.
+fn flush_spool(endpoint: &Endpoint, spool_dir: &Path) -> Result<usize, UploadError> {
+    let mut sent = 0;
+    for path in spooled_requests(spool_dir) {
+        let Some(request) = read_spooled(&path) else {
+            eprintln!("Dropping unreadable spooled profile {}", path.display());
+            let _ = fs::remove_file(&path);
+            continue;
+        };
+        match post(endpoint, &request) {
+            Ok(()) => sent += 1,
+            Err(UploadError::Rejected(message)) => {
+                eprintln!("Dropping spooled profile {}: {}", path.display(), message)
+            }
+            Err(e) => return Err(e),
+        }
+        let _ = fs::remove_file(&path);
+    }
+    Ok(sent)
+}
+
+/// Send `request` after anything spooled before it, spooling it instead while the server
+/// is unavailable.
+pub(crate) fn deliver(
+    endpoint: &Endpoint,
+    spool_dir: &Path,
+    from_secs: u64,
+    request: &IngestRequest,
+    attempts: u32,
+) -> Result<(), UploadError> {
+    let result = match flush_spool(endpoint, spool_dir) {
+        Ok(_) => post_with_retries(endpoint, request, attempts),
+        // Keep the order: this one waits behind the spooled ones
+        Err(e) => Err(e),
+    };
+    if let Err(UploadError::Unavailable(message)) = &result {
+        if let Err(e) = spool(spool_dir, from_secs, request) {
+            return Err(UploadError::Unavailable(format!(
+                "{}; spooling failed too: {}",
+                message, e
+            )));
+        }
+    }
+    result
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+/// An interval profile ready to send
+struct Upload {
+    endpoint: Endpoint,
+    spool_dir: PathBuf,
+    from_secs: u64,
+    request: IngestRequest,
+}
+
+/// Profiles handed to the uploader thread, oldest first
+static UPLOAD_QUEUE: Lazy<Mutex<VecDeque<Upload>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// Held while sending, so the uploader and the last upload at VM death don't both flush
+/// the spool
+static DELIVERING: Mutex<()> = Mutex::new(());
+
+/// With `pyroscope=<url>`, the upload of `profile`.
+fn prepare_upload(profile: &IntervalProfile) -> Option<Upload> {
+    let options = agent_options();
+    let url = options.pyroscope_url.as_ref()?;
+    let endpoint = match Endpoint::parse(url) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("Error uploading profile: {}", e);
+            return None;
+        }
+    };
+    let host = hostname();
+    let mut labels = vec![("host", host.as_str())];
+    if let Some(version) = &options.app_version {
+        labels.push(("version", version));
+    }
+    let name = application_name(options.app_name.as_deref().unwrap_or("java"), &labels);
+    let request = ingest_request(profile, options.upload_format.unwrap_or_default(), &name);
+    let spool_dir = PathBuf::from(
+        options
+            .spool_dir
+            .clone()
+            .unwrap_or_else(|| output_path("spool")),
+    );
+    Some(Upload {
+        endpoint,
+        spool_dir,
+        from_secs: profile.from_secs,
+        request,
+    })
+}
+
+fn send_upload(upload: &Upload, attempts: u32) {
+    let _delivering = DELIVERING.lock().unwrap();
+    match deliver(
+        &upload.endpoint,
+        &upload.spool_dir,
+        upload.from_secs,
+        &upload.request,
+        attempts,
+    ) {
+        Ok(()) => println!("📤 Interval profile sent to {}", upload.endpoint),
+        Err(UploadError::Unavailable(message)) => eprintln!(
+            "Pyroscope unavailable, profile spooled in {}: {}",
+            upload.spool_dir.display(),
+            message
+        ),
+        Err(UploadError::Rejected(message)) => {
+            eprintln!("Pyroscope rejected the profile: {}", message)
+        }
+    }
+}
+
+fn spool_upload(upload: &Upload) {
+    if let Err(e) = spool(&upload.spool_dir, upload.from_secs, &upload.request) {
+        eprintln!("Error spooling profile: {}", e);
+    }
+}
+
+/// Spool every queued profile for a later upload to send.
+fn spool_queued(queue: &mut VecDeque<Upload>) {
+    for upload in queue.drain(..) {
+        spool_upload(&upload);
+    }
+}
+
+/// With `pyroscope=<url>`, hand an interval profile to the uploader thread. If it is
+/// falling behind (the server is slow or retrying), the queue goes to the spool instead,
+/// in order, and is sent once the server keeps up again.
+pub(crate) fn queue_upload(profile: &IntervalProfile) {
+    let Some(upload) = prepare_upload(profile) else {
+        return;
+    };
+    let mut queue = UPLOAD_QUEUE.lock().unwrap();
+    queue.push_back(upload);
+    if queue.len() > MAX_QUEUED_UPLOADS {
+        eprintln!(
+            "Pyroscope uploads are behind, {} profiles spooled",
+            queue.len()
+        );
+        spool_queued(&mut queue);
+    }
+}
+
+/// Send the last interval profile at VM death, after anything still queued. The VM is
+/// exiting, so no retries and no waiting for an upload in flight: it is spooled instead.
+pub(crate) fn upload_last_profile(profile: &IntervalProfile) {
+    spool_queued(&mut UPLOAD_QUEUE.lock().unwrap());
+    let Some(upload) = prepare_upload(profile) else {
+        return;
+    };
+    let in_flight = DELIVERING.try_lock().is_err();
+    if in_flight {
+        spool_upload(&upload);
+    } else {
+        send_upload(&upload, 1);
+    }
+}
+
+unsafe extern "C" fn pyroscope_uploader(
+    _jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    while !agent_shut_down() {
+        let next = UPLOAD_QUEUE.lock().unwrap().pop_front();
+        match next {
+            Some(upload) => send_upload(&upload, UPLOAD_ATTEMPTS),
+            None => {
+                if sleep_unless_shut_down(UPLOAD_POLL_INTERVAL) {
+                    break;
+                }
+            }
+        }
+    }
+    // Detached: keep what is left for the next run to send
+    spool_queued(&mut UPLOAD_QUEUE.lock().unwrap());
+}
+
+/// With `pyroscope=<url>`, start the thread that sends queued interval profiles.
+pub(crate) fn start_pyroscope_uploader(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if agent_options().pyroscope_url.is_none() {
+        return;
+    }
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-pyroscope",
+        pyroscope_uploader,
+        ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start Pyroscope uploader: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// A stub server answering `statuses` in turn, sending each request it got (head and body).
+    fn stub_server(statuses: Vec<u16>) -> (Endpoint, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let response = format!("HTTP/1.1 {} Stub\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                sender.send((head, body)).unwrap();
+            }
+        });
+        let endpoint = Endpoint::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        (endpoint, receiver)
+    }
+
+    fn profile(from_secs: u64) -> IntervalProfile {
+        IntervalProfile {
+            base: String::new(),
+            from_secs,
+            until_secs: from_secs + 60,
+            folded: "main;work 1500\n".to_string(),
+            pprof: vec![1, 2, 3],
+        }
+    }
+
+    fn temp_spool(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rjprof-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = Endpoint::parse("http://pyroscope.internal:4040/").unwrap();
+        assert_eq!(endpoint.to_string(), "http://pyroscope.internal:4040");
+        let endpoint = Endpoint::parse("http://proxy/pyroscope").unwrap();
+        assert_eq!(endpoint.to_string(), "http://proxy:80/pyroscope");
+        assert!(Endpoint::parse("https://pyroscope:4040").is_err());
+        assert!(Endpoint::parse("http://:4040").is_err());
+        assert!(Endpoint::parse("http://pyroscope:port").is_err());
+        let endpoint = Endpoint::parse("http://[::1]:4040/pyroscope").unwrap();
+        assert_eq!(endpoint.host, "::1");
+        assert_eq!(endpoint.to_string(), "http://[::1]:4040/pyroscope");
+        assert_eq!(
+            Endpoint::parse("http://[fe80::1]").unwrap().to_string(),
+            "http://[fe80::1]:80"
+        );
+        assert!(Endpoint::parse("http://[::1:4040").is_err());
+        assert!(Endpoint::parse("http://[::1]4040").is_err());
+        assert!(Endpoint::parse("http://[]:4040").is_err());
+    }
+
+    #[test]
+    fn test_ingest_request() {
+        let name = application_name("shop", &[("host", "web-1"), ("version", "1,2")]);
+        assert_eq!(name, "shop.wall{host=web-1,version=1_2}");
+
+        let request = ingest_request(&profile(1000), UploadFormat::Folded, &name);
+        assert_eq!(
+            request.query,
+            "name=shop.wall%7Bhost%3Dweb-1%2Cversion%3D1_2%7D&from=1000&until=1060\
+             &spyName=rjprof&format=folded&sampleRate=1000000000&units=samples&aggregationType=sum"
+        );
+        assert_eq!(request.body, b"main;work 1500\n");
+
+        let request = ingest_request(&profile(1000), UploadFormat::Pprof, &name);
+        assert!(request.query.ends_with("&format=pprof"));
+        assert!(request
+            .content_type
+            .starts_with("multipart/form-data; boundary="));
+        let body = String::from_utf8_lossy(&request.body);
+        assert!(body.contains("name=\"profile\""));
+        assert!(body.ends_with("--rjprof-pprof-upload--\r\n"));
+    }
+
+    #[test]
+    fn test_post_to_stub_server() {
+        let (endpoint, requests) = stub_server(vec![200, 400, 503]);
+        let request = ingest_request(&profile(1000), UploadFormat::Folded, "shop.wall{}");
+        assert_eq!(post(&endpoint, &request), Ok(()));
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("POST /ingest?name=shop.wall%7B%7D&from=1000&until=1060"));
+        assert!(head.contains("Content-Type: text/plain\r\n"));
+        assert_eq!(body, b"main;work 1500\n");
+
+        assert!(matches!(
+            post(&endpoint, &request),
+            Err(UploadError::Rejected(_))
+        ));
+        assert!(matches!(
+            post(&endpoint, &request),
+            Err(UploadError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_spool_while_unavailable() {
+        let spool_dir = temp_spool("spool");
+        // Nothing listens on a port that was just released
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let down = Endpoint::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let first = ingest_request(&profile(1000), UploadFormat::Folded, "shop.wall{}");
+        let second = ingest_request(&profile(1060), UploadFormat::Folded, "shop.wall{}");
+        assert!(matches!(
+            deliver(&down, &spool_dir, 1000, &first, 1),
+            Err(UploadError::Unavailable(_))
+        ));
+        assert!(matches!(
+            deliver(&down, &spool_dir, 1060, &second, 1),
+            Err(UploadError::Unavailable(_))
+        ));
+        let spooled = spooled_requests(&spool_dir);
+        assert_eq!(spooled.len(), 2);
+        assert_eq!(read_spooled(&spooled[0]).as_ref(), Some(&first));
+        assert!(spooled[0].ends_with("19700101T001640Z-000001.ingest"));
+
+        // Back up: the spooled profiles go first, in order
+        let (up, requests) = stub_server(vec![200, 200, 200]);
+        let third = ingest_request(&profile(1120), UploadFormat::Folded, "shop.wall{}");
+        assert_eq!(deliver(&up, &spool_dir, 1120, &third, 1), Ok(()));
+        let froms: Vec<bool> = ["from=1000", "from=1060", "from=1120"]
+            .iter()
+            .map(|from| requests.recv().unwrap().0.contains(from))
+            .collect();
+        assert_eq!(froms, vec![true, true, true]);
+        assert!(spooled_requests(&spool_dir).is_empty());
+        let _ = fs::remove_dir_all(&spool_dir);
+    }
+
+    #[test]
+    fn test_spool_order() {
+        let spool_dir = temp_spool("order");
+        // More profiles than one digit of sequence numbers, all from the same second
+        let queries: Vec<String> = (0..12)
+            .map(|n| {
+                let request = IngestRequest {
+                    query: format!("n={}", n),
+                    content_type: "text/plain".to_string(),
+                    body: Vec::new(),
+                };
+                spool(&spool_dir, 1000, &request).unwrap();
+                request.query
+            })
+            .collect();
+        let spooled: Vec<String> = spooled_requests(&spool_dir)
+            .iter()
+            .map(|path| read_spooled(path).unwrap().query)
+            .collect();
+        assert_eq!(spooled, queries);
+        let _ = fs::remove_dir_all(&spool_dir);
+    }
+}
@@ -0,0 +1,354 @@
+use serde_json::json;
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{
+    sleep_unless_shut_down, start_agent_thread, stop_agent_threads,
+};
+use crate::profiling::control::close_control_socket;
+use crate::profiling::cpu_sampler::{reset_cpu_samples, set_cpu_sampling};
+use crate::profiling::exceptions::reset_exception_counts;
+use crate::profiling::gc::reset_gc_pauses;
+use crate::profiling::jit::reset_jit_log;
+use crate::profiling::lines::{clear_line_caches, ALLOCATION_SITES, LOCATION_SAMPLES};
+use crate::profiling::off_cpu::reset_off_cpu;
+use crate::profiling::options::{
+    agent_options, output_path, set_output_dir, AgentOptions, HeapReport, SessionCommand,
+};
+use crate::profiling::profiling::{elapsed_nanos, reset_profile_stats, write_reports};
+use crate::profiling::snapshot::restore_snapshot_signal;
+use crate::profiling::threads::{
+    reset_thread_timelines, start_thread_state_poller, stop_thread_state_poller,
+};
+use crate::profiling::virtual_threads::reset_virtual_thread_stats;
+
+/// Written last whenever a session stops, so `rjprof attach` knows the reports are complete.
+pub const SESSION_MARKER: &str = "session.txt";
+
+/// Written at startup with `perprocess`: which JVM a `jvm-<pid>` directory belongs to.
+pub const PROCESS_INFO: &str = "process.json";
+
+/// How long `detach` waits for the agent threads to return
+const DETACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Whether profiling events are being recorded. The first session starts with the agent;
+// it ends on `stop`, when its timer fires or at VM death
+static SESSION_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Bumped by every `start`, so timers and shadow stacks of earlier sessions can tell
+static SESSION_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// Set by `detach`; the capabilities are gone, so no session can start again
+static DETACHED: AtomicBool = AtomicBool::new(false);
+
+// The agent's JVMTI environment, for commands arriving through a later load
+static AGENT_ENV: AtomicPtr<jvmtiEnv> = AtomicPtr::new(ptr::null_mut());
+
+// Events that feed the profile, as enabled at load; `stop` and `start` toggle exactly these
+static PROFILING_EVENTS: Mutex<Vec<jvmtiEvent>> = Mutex::new(Vec::new());
+
+// Serializes session changes from the attach listener, the control socket, the session
+// timer and VM death
+static SESSION_LOCK: Mutex<()> = Mutex::new(());
+
+/// Hold off session changes, e.g. while a snapshot is written.
+pub(crate) fn lock_session() -> MutexGuard<'static, ()> {
+    SESSION_LOCK.lock().unwrap()
+}
+
+/// Current session number, 0 for the session started with the agent.
+pub(crate) fn session_generation() -> u64 {
+    SESSION_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Whether profiling events are being recorded right now.
+pub(crate) fn session_active() -> bool {
+    SESSION_ACTIVE.load(Ordering::SeqCst)
+}
+
+/// Whether an earlier load already set the agent up in this VM.
+pub(crate) fn agent_loaded() -> bool {
+    !AGENT_ENV.load(Ordering::Relaxed).is_null()
+}
+
+pub(crate) fn set_agent_env(jvmti_env: *mut jvmtiEnv) {
+    AGENT_ENV.store(jvmti_env, Ordering::Relaxed);
+}
+
+/// The events that only feed the profile; lifecycle, class and code events stay on.
+/// Monitor events feed pinning detection and the off-CPU profile.
+pub(crate) fn profiling_events(virtual_threads: bool) -> Vec<jvmtiEvent> {
+    let mut events = vec![
+        jvmtiEvent_JVMTI_EVENT_METHOD_ENTRY,
+        jvmtiEvent_JVMTI_EVENT_METHOD_EXIT,
+        jvmtiEvent_JVMTI_EVENT_VM_OBJECT_ALLOC,
+        jvmtiEvent_JVMTI_EVENT_EXCEPTION,
+        jvmtiEvent_JVMTI_EVENT_GARBAGE_COLLECTION_START,
+        jvmtiEvent_JVMTI_EVENT_GARBAGE_COLLECTION_FINISH,
+    ];
+    if virtual_threads || agent_options().off_cpu {
+        events.extend([
+            jvmtiEvent_JVMTI_EVENT_MONITOR_CONTENDED_ENTER,
+            jvmtiEvent_JVMTI_EVENT_MONITOR_WAIT,
+        ]);
+    }
+    if agent_options().off_cpu {
+        events.extend([
+            jvmtiEvent_JVMTI_EVENT_MONITOR_CONTENDED_ENTERED,
+            jvmtiEvent_JVMTI_EVENT_MONITOR_WAITED,
+        ]);
+    }
+    *PROFILING_EVENTS.lock().unwrap() = events.clone();
+    events
+}
+
+fn set_profiling_events(jvmti_env: *mut jvmtiEnv, mode: jvmtiEventMode) {
+    for &event in PROFILING_EVENTS.lock().unwrap().iter() {
+        let err = unsafe {
+            (**jvmti_env).SetEventNotificationMode.unwrap()(jvmti_env, mode, event, ptr::null_mut())
+        };
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("Failed to set event {} to mode {}: {}", event, mode, err);
+        }
+    }
+}
+
+/// Clear everything a session records. JIT, class loading and heap reports describe the
+/// VM as a whole and are kept.
+pub(crate) fn reset_stats(jvmti_env: *mut jvmtiEnv) {
+    reset_profile_stats();
+    LOCATION_SAMPLES.lock().unwrap().clear();
+    ALLOCATION_SITES.lock().unwrap().clear();
+    clear_line_caches();
+    reset_off_cpu();
+    reset_gc_pauses();
+    reset_exception_counts();
+    reset_cpu_samples();
+    reset_jit_log();
+    let now = elapsed_nanos(jvmti_env);
+    reset_virtual_thread_stats(now);
+    reset_thread_timelines(now);
+}
+
+/// Mark the session started with the agent as running and arm its timer, if any.
+pub(crate) fn open_session(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    SESSION_ACTIVE.store(true, Ordering::SeqCst);
+    if let Some(duration) = agent_options().duration_secs {
+        start_session_timer(jvmti_env, jni_env, 0, duration);
+    }
+}
+
+fn start_session(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    duration_secs: Option<u64>,
+) -> Result<(), String> {
+    if SESSION_ACTIVE.load(Ordering::SeqCst) {
+        return Err("a profiling session is already running".to_string());
+    }
+    let generation = SESSION_GENERATION.fetch_add(1, Ordering::Relaxed) + 1;
+    reset_stats(jvmti_env);
+    set_profiling_events(jvmti_env, jvmtiEventMode_JVMTI_ENABLE);
+    set_cpu_sampling(true);
+    start_thread_state_poller(jvmti_env, jni_env);
+    SESSION_ACTIVE.store(true, Ordering::SeqCst);
+    if let Some(duration) = duration_secs {
+        start_session_timer(jvmti_env, jni_env, generation, duration);
+    }
+    println!("▶️  Profiling session {} started", generation);
+    Ok(())
+}
+
+fn stop_session(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    heap: Option<HeapReport>,
+) -> Result<(), String> {
+    if !SESSION_ACTIVE.swap(false, Ordering::SeqCst) {
+        return Err("no profiling session is running".to_string());
+    }
+    set_profiling_events(jvmti_env, jvmtiEventMode_JVMTI_DISABLE);
+    set_cpu_sampling(false);
+    stop_thread_state_poller(jvmti_env, jni_env);
+    write_reports(jvmti_env, jni_env, heap);
+    write_session_marker();
+    reset_stats(jvmti_env);
+    println!("⏹️  Profiling session {} stopped", session_generation());
+    Ok(())
+}
+
+fn write_session_marker() {
+    let marker = output_path(SESSION_MARKER);
+    let summary = format!(
+        "pid={}\nsession={}\n",
+        std::process::id(),
+        session_generation()
+    );
+    if let Err(e) = fs::write(&marker, summary) {
+        eprintln!("Error writing {}: {}", marker, e);
+    }
+}
+
+/// Record the pid, parent and main class or jar (`sun.java.command`) of this JVM.
+pub(crate) fn write_process_info(jvmti_env: *mut jvmtiEnv) {
+    let mut value: *mut c_char = ptr::null_mut();
+    let command = unsafe {
+        let err = (**jvmti_env).GetSystemProperty.unwrap()(
+            jvmti_env,
+            c"sun.java.command".as_ptr(),
+            &mut value,
+        );
+        if err == jvmtiError_JVMTI_ERROR_NONE && !value.is_null() {
+            let command = CStr::from_ptr(value).to_string_lossy().into_owned();
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, value as *mut u8);
+            command
+        } else {
+            String::new()
+        }
+    };
+    let started_millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let info = json!({
+        "pid": std::process::id(),
+        "ppid": std::os::unix::process::parent_id(),
+        "command": command,
+        "started_millis": started_millis,
+    });
+    let path = output_path(PROCESS_INFO);
+    if let Err(e) = fs::write(&path, info.to_string()) {
+        eprintln!("Error writing {}: {}", path, e);
+    }
+}
+
+/// Stop recording for good: end the session, stop the agent threads and wait for them,
+/// turn every event off and give `SIGUSR2` and the capabilities back. The library stays
+/// loaded (HotSpot never unloads agents) but idle, and no session can start again.
+fn detach(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, session: MutexGuard<'static, ()>) {
+    let _ = stop_session(jvmti_env, jni_env, agent_options().heap);
+    DETACHED.store(true, Ordering::SeqCst);
+    // Agent threads may be waiting for the session lock before they can see the flag
+    drop(session);
+    let running = stop_agent_threads(DETACH_TIMEOUT);
+    if running > 0 {
+        eprintln!(
+            "⚠️  {} agent thread(s) still busy after {}s; they stop once done",
+            running,
+            DETACH_TIMEOUT.as_secs()
+        );
+    }
+
+    let _session = lock_session();
+    close_control_socket();
+    restore_snapshot_signal();
+    for event in jvmtiEvent_JVMTI_MIN_EVENT_TYPE_VAL..=jvmtiEvent_JVMTI_MAX_EVENT_TYPE_VAL {
+        unsafe {
+            (**jvmti_env).SetEventNotificationMode.unwrap()(
+                jvmti_env,
+                jvmtiEventMode_JVMTI_DISABLE,
+                event,
+                ptr::null_mut(),
+            );
+        }
+    }
+    unsafe {
+        let mut caps = std::mem::zeroed::<jvmtiCapabilities>();
+        (**jvmti_env).GetCapabilities.unwrap()(jvmti_env, &mut caps);
+        let err = (**jvmti_env).RelinquishCapabilities.unwrap()(jvmti_env, &caps);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("Failed to relinquish capabilities: {}", err);
+        }
+    }
+    println!("🔌 rjprof detached; the agent stays loaded but idle");
+}
+
+/// Run the session command of a repeated load (`start` if none was given).
+pub(crate) fn run_command(jni_env: *mut JNIEnv, options: &AgentOptions) -> Result<(), String> {
+    let session = SESSION_LOCK.lock().unwrap();
+    let jvmti_env = AGENT_ENV.load(Ordering::Relaxed);
+    if DETACHED.load(Ordering::SeqCst) {
+        return Err("the agent was detached from this JVM".to_string());
+    }
+    if let Some(dir) = &options.output_dir {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Error creating output directory {}: {}", dir, e))?;
+        set_output_dir(dir);
+    }
+    // A command may ask for heap reports the agent wasn't loaded with
+    let heap = options.heap.or(agent_options().heap);
+    match options.command.unwrap_or(SessionCommand::Start) {
+        SessionCommand::Start => start_session(jvmti_env, jni_env, options.duration_secs),
+        SessionCommand::Stop => stop_session(jvmti_env, jni_env, heap),
+        SessionCommand::Dump => {
+            if !SESSION_ACTIVE.load(Ordering::SeqCst) {
+                return Err("no profiling session is running".to_string());
+            }
+            write_reports(jvmti_env, jni_env, heap);
+            println!("📝 Reports of the running session written");
+            Ok(())
+        }
+        SessionCommand::Detach => {
+            detach(jvmti_env, jni_env, session);
+            Ok(())
+        }
+    }
+}
+
+/// End the running session at VM death, if one is still running.
+pub(crate) fn finish_session(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    let _lock = SESSION_LOCK.lock().unwrap();
+    let _ = stop_session(jvmti_env, jni_env, agent_options().heap);
+}
+
+/// Session number and length handed to a timer thread
+struct SessionTimer {
+    generation: u64,
+    duration_secs: u64,
+}
+
+unsafe extern "C" fn session_timer(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    arg: *mut c_void,
+) {
+    let timer = Box::from_raw(arg as *mut SessionTimer);
+    if sleep_unless_shut_down(Duration::from_secs(timer.duration_secs)) {
+        return;
+    }
+
+    let _lock = SESSION_LOCK.lock().unwrap();
+    // The session may have been stopped, or stopped and restarted, in the meantime
+    if session_generation() == timer.generation && SESSION_ACTIVE.load(Ordering::SeqCst) {
+        println!("⏱️  Profiling session of {}s finished", timer.duration_secs);
+        let _ = stop_session(jvmti_env, jni_env, agent_options().heap);
+    }
+}
+
+/// Start the agent thread that stops session `generation` after `duration_secs`.
+fn start_session_timer(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    generation: u64,
+    duration_secs: u64,
+) {
+    let timer = Box::into_raw(Box::new(SessionTimer {
+        generation,
+        duration_secs,
+    }));
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-session",
+        session_timer,
+        timer as *mut c_void,
+    ) {
+        eprintln!("Failed to start session timer: {}", e);
+        drop(unsafe { Box::from_raw(timer) });
+    }
+}
@@ -0,0 +1,277 @@
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use std::fs::{self, File};
+use std::os::raw::{c_int, c_void};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::heap::{collect_heap_histogram, heap_histogram_json};
+use crate::profiling::lines::LOCATION_SAMPLES;
+use crate::profiling::off_cpu::off_cpu_json;
+use crate::profiling::options::{agent_options, output_path};
+use crate::profiling::profiling::{
+    class_allocations_json, elapsed_nanos, folded_stacks, method_stats_json,
+};
+use crate::profiling::session::{lock_session, reset_stats, session_generation};
+use crate::profiling::threads::thread_states_json;
+
+/// Signal that asks for a snapshot (`kill -USR2 <pid>`)
+const SNAPSHOT_SIGNAL: c_int = libc::SIGUSR2;
+
+/// How often the snapshot thread checks for requests
+const SNAPSHOT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Methods and classes listed in a snapshot
+const SNAPSHOT_TOP_N: usize = 100;
+
+static SNAPSHOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// The JVM's own handler for the snapshot signal, which we chain to
+struct PreviousAction(libc::sigaction);
+unsafe impl Send for PreviousAction {}
+unsafe impl Sync for PreviousAction {}
+
+static PREVIOUS_ACTION: OnceCell<PreviousAction> = OnceCell::new();
+
+/// Ask the snapshot thread for a snapshot; safe to call from a signal handler.
+pub(crate) fn request_snapshot() {
+    SNAPSHOT_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// `kill` sends SI_USER. HotSpot suspends threads with `pthread_kill(SIGUSR2)`, which
+/// arrives as SI_TKILL and must still reach the VM's handler.
+extern "C" fn snapshot_signal_handler(
+    signal: c_int,
+    info: *mut libc::siginfo_t,
+    context: *mut c_void,
+) {
+    if !info.is_null() && unsafe { (*info).si_code } == libc::SI_USER {
+        request_snapshot();
+        return;
+    }
+    let Some(PreviousAction(previous)) = PREVIOUS_ACTION.get() else {
+        return;
+    };
+    let handler = previous.sa_sigaction;
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        return;
+    }
+    unsafe {
+        if previous.sa_flags & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                std::mem::transmute(handler);
+            handler(signal, info, context);
+        } else {
+            let handler: extern "C" fn(c_int) = std::mem::transmute(handler);
+            handler(signal);
+        }
+    }
+}
+
+/// Install the snapshot signal handler in front of the JVM's. Must run after the VM
+/// set up its own handlers (VM_INIT), or it would replace ours.
+fn install_snapshot_signal() -> Result<(), String> {
+    unsafe {
+        // Record the previous handler first: the VM may signal its threads at any time
+        let mut previous = std::mem::zeroed::<libc::sigaction>();
+        if libc::sigaction(SNAPSHOT_SIGNAL, ptr::null(), &mut previous) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        if PREVIOUS_ACTION.set(PreviousAction(previous)).is_err() {
+            return Ok(());
+        }
+
+        let mut action = std::mem::zeroed::<libc::sigaction>();
+        action.sa_sigaction = snapshot_signal_handler as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(SNAPSHOT_SIGNAL, &action, ptr::null_mut()) != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Give `SIGUSR2` back to the handler it had before ours (on detach).
+pub(crate) fn restore_snapshot_signal() {
+    let Some(PreviousAction(previous)) = PREVIOUS_ACTION.get() else {
+        return;
+    };
+    if unsafe { libc::sigaction(SNAPSHOT_SIGNAL, previous, ptr::null_mut()) } != 0 {
+        eprintln!(
+            "Failed to restore the SIGUSR2 handler: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// `20261018T153012Z` for seconds since the epoch (UTC).
+pub(crate) fn format_timestamp(unix_secs: u64) -> String {
+    let days = (unix_secs / 86_400) as i64;
+    let secs_of_day = unix_secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// The current stats as written to a snapshot, minus its timestamp.
+pub(crate) fn stats_json(jvmti_env: *mut jvmtiEnv) -> serde_json::Value {
+    let line_samples: u64 = LOCATION_SAMPLES.lock().unwrap().values().sum();
+    json!({
+        "pid": std::process::id(),
+        "session": session_generation(),
+        "elapsed_nanos": elapsed_nanos(jvmti_env),
+        "methods": method_stats_json(jvmti_env, SNAPSHOT_TOP_N),
+        "class_allocations": class_allocations_json(SNAPSHOT_TOP_N),
+        "off_cpu_nanos": off_cpu_json(),
+        "line_samples": line_samples,
+        "threads": thread_states_json(),
+    })
+}
+
+/// Seconds since the epoch for a [`format_timestamp`] string.
+pub(crate) fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let digits = |range: std::ops::Range<usize>| -> Option<i64> {
+        let field = timestamp.get(range)?;
+        field
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| field.parse().ok())?
+    };
+    if timestamp.len() != 16 || &timestamp[8..9] != "T" || &timestamp[15..] != "Z" {
+        return None;
+    }
+    let (year, month, day) = (digits(0..4)?, digits(4..6)?, digits(6..8)?);
+    let (hour, minute, second) = (digits(9..11)?, digits(11..13)?, digits(13..15)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+
+    // Days since 1970-01-01 from the civil date, the inverse of format_timestamp
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second).ok()
+}
+
+/// Write the current stats to `snapshot-<timestamp>.json` and the folded stacks to
+/// `snapshot-<timestamp>.folded`, then reset the stats if asked to. Returns the base path.
+///
+/// With `heap`, the JSON also gets a live heap histogram, paying for a full GC and heap
+/// walk per snapshot; retained sizes are left to the reports.
+pub(crate) fn write_snapshot(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let _session = lock_session();
+    let unix_secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let timestamp = format_timestamp(unix_secs);
+
+    // Two snapshots within a second get a sequence suffix
+    let mut base = output_path(&format!("snapshot-{}", timestamp));
+    let mut seq = 1;
+    while Path::new(&format!("{}.json", base)).exists() {
+        seq += 1;
+        base = output_path(&format!("snapshot-{}-{}", timestamp, seq));
+    }
+
+    fs::write(format!("{}.folded", base), folded_stacks())?;
+    let mut snapshot = stats_json(jvmti_env);
+    snapshot["timestamp"] = json!(timestamp);
+    if agent_options().heap.is_some() {
+        match collect_heap_histogram(jvmti_env, jni_env) {
+            Ok(histogram) => snapshot["heap"] = heap_histogram_json(&histogram, SNAPSHOT_TOP_N),
+            Err(e) => eprintln!("Error collecting heap histogram: {}", e),
+        }
+    }
+    serde_json::to_writer_pretty(File::create(format!("{}.json", base))?, &snapshot)?;
+
+    if agent_options().snapshot_reset {
+        reset_stats(jvmti_env);
+    }
+    Ok(base)
+}
+
+unsafe extern "C" fn snapshot_writer(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    while !sleep_unless_shut_down(SNAPSHOT_POLL_INTERVAL) {
+        if !SNAPSHOT_REQUESTED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        match write_snapshot(jvmti_env, jni_env) {
+            Ok(base) => println!("📸 Snapshot written to {}.json", base),
+            Err(e) => eprintln!("Error writing snapshot: {}", e),
+        }
+    }
+}
+
+/// With `snapshot`, start the snapshot thread and take over `SIGUSR2`.
+pub(crate) fn start_snapshot_writer(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if !agent_options().snapshot {
+        return;
+    }
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-snapshot",
+        snapshot_writer,
+        ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start snapshot writer: {}", e);
+        return;
+    }
+    if let Err(e) = install_snapshot_signal() {
+        eprintln!("Failed to install the SIGUSR2 snapshot handler: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(format_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_timestamp(951_782_400), "20000229T000000Z");
+        assert_eq!(format_timestamp(1_792_337_412), "20261018T153012Z");
+
+        for secs in [0, 951_782_400, 1_792_337_412, 4_102_444_799] {
+            assert_eq!(parse_timestamp(&format_timestamp(secs)), Some(secs));
+        }
+        assert_eq!(parse_timestamp("20261018T153012"), None);
+        assert_eq!(parse_timestamp("20261318T153012Z"), None);
+        assert_eq!(parse_timestamp("2026-10-18T15:30Z"), None);
+    }
+}
@@ -0,0 +1,458 @@
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{sleep_unless_shut_down, start_agent_thread};
+use crate::profiling::lines::method_at_location;
+use crate::profiling::options::{agent_options, output_path};
+use crate::profiling::profiling::{elapsed_nanos, format_time, get_class_name};
+use crate::profiling::snapshot::request_snapshot;
+use crate::profiling::threads::{thread_info, ThreadStateKind};
+
+/// Frames captured per thread in a dump
+const MAX_DUMP_FRAMES: jint = 128;
+/// Local references of a dump outside the per-thread frames
+const DUMP_LOCAL_REFS: jint = 16;
+/// Local references per thread besides its frames: name lookup, monitors and their classes
+const THREAD_LOCAL_REFS: jint = 32;
+/// Local references per stack frame: declaring class for the method name and source file
+const FRAME_LOCAL_REFS: jint = 2;
+/// How often the dumper thread checks for a pending dump request
+const DUMP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A monitor as shown in a dump: identity hash and class
+#[derive(Clone, Debug)]
+pub(crate) struct MonitorInfo {
+    pub(crate) hash: i32,
+    pub(crate) class_name: String,
+}
+
+impl MonitorInfo {
+    fn describe(&self) -> String {
+        format!("<0x{:08x}> (a {})", self.hash, self.class_name)
+    }
+}
+
+/// One frame of a dumped stack
+#[derive(Clone, Debug)]
+pub(crate) struct DumpFrame {
+    pub(crate) name: String,
+}
+
+/// One thread in a dump
+#[derive(Clone, Debug)]
+pub(crate) struct ThreadSnapshot {
+    pub(crate) name: String,
+    pub(crate) is_daemon: bool,
+    pub(crate) state: ThreadStateKind,
+    pub(crate) frames: Vec<DumpFrame>,
+    /// Monitors held, with the frame depth that locked them (-1 = locked via JNI)
+    pub(crate) owned_monitors: Vec<(MonitorInfo, i32)>,
+    /// Monitor the thread is blocked on or waiting in
+    pub(crate) contended_monitor: Option<MonitorInfo>,
+    /// Index of the thread owning `contended_monitor`, when blocked entering it
+    pub(crate) blocked_on: Option<usize>,
+}
+
+/// All threads' stacks and monitors at one point in time
+#[derive(Clone, Debug)]
+pub(crate) struct ThreadDump {
+    pub(crate) taken_at_nanos: u64,
+    pub(crate) threads: Vec<ThreadSnapshot>,
+    /// Each deadlock is a cycle of thread indices, each blocked on the next
+    pub(crate) deadlocks: Vec<Vec<usize>>,
+}
+
+static DUMP_REQUESTED: AtomicBool = AtomicBool::new(false);
+static DUMP_SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Cycles in a wait-for graph where each thread waits for at most one other thread.
+pub(crate) fn find_deadlocks(waits_for: &[Option<usize>]) -> Vec<Vec<usize>> {
+    // 0 = unvisited, otherwise the walk that first reached the node
+    let mut visited_by = vec![0usize; waits_for.len()];
+    let mut cycles = Vec::new();
+
+    for start in 0..waits_for.len() {
+        if visited_by[start] != 0 {
+            continue;
+        }
+        let walk = start + 1;
+        let mut path = Vec::new();
+        let mut current = Some(start);
+        while let Some(node) = current {
+            if visited_by[node] == walk {
+                // Back on this walk's own path: the tail from `node` is a cycle
+                let pos = path.iter().position(|&n| n == node).unwrap();
+                cycles.push(path[pos..].to_vec());
+                break;
+            }
+            if visited_by[node] != 0 {
+                break; // joins a chain already explored
+            }
+            visited_by[node] = walk;
+            path.push(node);
+            current = waits_for[node];
+        }
+    }
+    cycles
+}
+
+fn monitor_info(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv, monitor: jobject) -> MonitorInfo {
+    unsafe {
+        let mut hash: jint = 0;
+        (**jvmti_env).GetObjectHashCode.unwrap()(jvmti_env, monitor, &mut hash);
+        let klass = (**jni_env).GetObjectClass.unwrap()(jni_env, monitor);
+        let class_name = get_class_name(jvmti_env, klass);
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, klass);
+        MonitorInfo { hash, class_name }
+    }
+}
+
+/// Snapshot every thread's stack, held monitors and contended monitor, and look for
+/// monitor deadlocks. Local references are confined to JNI frames popped on return.
+pub(crate) fn take_thread_dump(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+) -> Result<ThreadDump, String> {
+    unsafe {
+        // Holds the thread references from GetAllStackTraces; each thread gets its own frame
+        if (**jni_env).PushLocalFrame.unwrap()(jni_env, DUMP_LOCAL_REFS) != 0 {
+            return Err("PushLocalFrame failed".to_string());
+        }
+        let dump = collect_thread_dump(jvmti_env, jni_env);
+        (**jni_env).PopLocalFrame.unwrap()(jni_env, std::ptr::null_mut());
+        dump
+    }
+}
+
+/// One thread of a dump, with global references to the monitors it owns and the one it
+/// is contending for, kept past the thread's local frame to match owners up.
+unsafe fn snapshot_thread(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    info: &jvmtiStackInfo,
+) -> (ThreadSnapshot, Vec<jobject>, jobject) {
+    let (name, is_daemon) = thread_info(jvmti_env, jni_env, info.thread);
+    let frames = if info.frame_count > 0 {
+        std::slice::from_raw_parts(info.frame_buffer, info.frame_count as usize)
+    } else {
+        &[]
+    };
+    let frames = frames
+        .iter()
+        .map(|frame| DumpFrame {
+            name: method_at_location(jvmti_env, frame.method, frame.location),
+        })
+        .collect();
+
+    let mut owned = Vec::new();
+    let mut owned_objects = Vec::new();
+    let mut monitor_count: jint = 0;
+    let mut monitors: *mut jvmtiMonitorStackDepthInfo = std::ptr::null_mut();
+    let err = (**jvmti_env).GetOwnedMonitorStackDepthInfo.unwrap()(
+        jvmti_env,
+        info.thread,
+        &mut monitor_count,
+        &mut monitors,
+    );
+    if err == jvmtiError_JVMTI_ERROR_NONE && !monitors.is_null() {
+        for m in std::slice::from_raw_parts(monitors, monitor_count as usize) {
+            owned.push((monitor_info(jvmti_env, jni_env, m.monitor), m.stack_depth));
+            owned_objects.push((**jni_env).NewGlobalRef.unwrap()(jni_env, m.monitor));
+        }
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, monitors as *mut u8);
+    }
+
+    let mut contended: jobject = std::ptr::null_mut();
+    (**jvmti_env).GetCurrentContendedMonitor.unwrap()(jvmti_env, info.thread, &mut contended);
+    let contended_monitor =
+        (!contended.is_null()).then(|| monitor_info(jvmti_env, jni_env, contended));
+    let contended = if contended.is_null() {
+        contended
+    } else {
+        (**jni_env).NewGlobalRef.unwrap()(jni_env, contended)
+    };
+
+    let snapshot = ThreadSnapshot {
+        name,
+        is_daemon,
+        state: ThreadStateKind::from_jvmti(info.state),
+        frames,
+        owned_monitors: owned,
+        contended_monitor,
+        blocked_on: None,
+    };
+    (snapshot, owned_objects, contended)
+}
+
+unsafe fn collect_thread_dump(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+) -> Result<ThreadDump, String> {
+    let taken_at_nanos = elapsed_nanos(jvmti_env);
+    let mut stack_info: *mut jvmtiStackInfo = std::ptr::null_mut();
+    let mut thread_count: jint = 0;
+    let err = (**jvmti_env).GetAllStackTraces.unwrap()(
+        jvmti_env,
+        MAX_DUMP_FRAMES,
+        &mut stack_info,
+        &mut thread_count,
+    );
+    if err != jvmtiError_JVMTI_ERROR_NONE {
+        return Err(format!("GetAllStackTraces failed: {}", err));
+    }
+    let infos = std::slice::from_raw_parts(stack_info, thread_count as usize);
+    // One reference per thread came back with the stacks
+    (**jni_env).EnsureLocalCapacity.unwrap()(jni_env, thread_count + DUMP_LOCAL_REFS);
+
+    let mut threads = Vec::with_capacity(infos.len());
+    // Monitor refs alongside each snapshot, to match contended monitors to owners
+    let mut owned_refs: Vec<Vec<jobject>> = Vec::with_capacity(infos.len());
+    let mut contended_refs: Vec<jobject> = Vec::with_capacity(infos.len());
+
+    for info in infos {
+        let capacity = THREAD_LOCAL_REFS + FRAME_LOCAL_REFS * info.frame_count;
+        if (**jni_env).PushLocalFrame.unwrap()(jni_env, capacity) != 0 {
+            continue;
+        }
+        let (snapshot, owned_objects, contended) = snapshot_thread(jvmti_env, jni_env, info);
+        (**jni_env).PopLocalFrame.unwrap()(jni_env, std::ptr::null_mut());
+        (**jni_env).DeleteLocalRef.unwrap()(jni_env, info.thread);
+        threads.push(snapshot);
+        owned_refs.push(owned_objects);
+        contended_refs.push(contended);
+    }
+    (**jvmti_env).Deallocate.unwrap()(jvmti_env, stack_info as *mut u8);
+
+    // Wait-for edges: a thread blocked entering a monitor waits for that monitor's owner
+    for (i, &contended) in contended_refs.iter().enumerate() {
+        if contended.is_null() || threads[i].state != ThreadStateKind::Blocked {
+            continue;
+        }
+        threads[i].blocked_on = owned_refs.iter().position(|objects| {
+            objects
+                .iter()
+                .any(|&owned| (**jni_env).IsSameObject.unwrap()(jni_env, owned, contended) != 0)
+        });
+    }
+    for global in owned_refs.into_iter().flatten().chain(contended_refs) {
+        if !global.is_null() {
+            (**jni_env).DeleteGlobalRef.unwrap()(jni_env, global);
+        }
+    }
+
+    let waits_for: Vec<Option<usize>> = threads.iter().map(|t| t.blocked_on).collect();
+    Ok(ThreadDump {
+        taken_at_nanos,
+        threads,
+        deadlocks: find_deadlocks(&waits_for),
+    })
+}
+
+/// jstack-style text rendering of a dump.
+pub(crate) fn format_thread_dump(dump: &ThreadDump) -> String {
+    let mut out = format!(
+        "Thread dump at {} ({} threads)\n\n",
+        format_time(dump.taken_at_nanos),
+        dump.threads.len()
+    );
+
+    for thread in &dump.threads {
+        out.push_str(&format!(
+            "\"{}\"{} {}\n",
+            thread.name,
+            if thread.is_daemon { " daemon" } else { "" },
+            thread.state.label()
+        ));
+        for (depth, frame) in thread.frames.iter().enumerate() {
+            out.push_str(&format!("\tat {}\n", frame.name));
+            if depth == 0 {
+                if let Some(monitor) = &thread.contended_monitor {
+                    let verb = if thread.state == ThreadStateKind::Blocked {
+                        "waiting to lock"
+                    } else {
+                        "waiting on"
+                    };
+                    out.push_str(&format!("\t- {} {}", verb, monitor.describe()));
+                    if let Some(owner) = thread.blocked_on {
+                        out.push_str(&format!(" held by \"{}\"", dump.threads[owner].name));
+                    }
+                    out.push('\n');
+                }
+            }
+            for (monitor, _) in thread
+                .owned_monitors
+                .iter()
+                .filter(|(_, d)| *d == depth as i32)
+            {
+                out.push_str(&format!("\t- locked {}\n", monitor.describe()));
+            }
+        }
+        for (monitor, _) in thread.owned_monitors.iter().filter(|(_, d)| *d < 0) {
+            out.push_str(&format!("\t- locked {} via JNI\n", monitor.describe()));
+        }
+        out.push('\n');
+    }
+
+    for (i, cycle) in dump.deadlocks.iter().enumerate() {
+        out.push_str(&format!("Found Java-level deadlock #{}:\n", i + 1));
+        for &t in cycle {
+            let thread = &dump.threads[t];
+            let monitor = thread
+                .contended_monitor
+                .as_ref()
+                .map(MonitorInfo::describe)
+                .unwrap_or_default();
+            let owner = thread.blocked_on.map(|o| dump.threads[o].name.as_str());
+            out.push_str(&format!(
+                "\"{}\" waiting to lock {}, held by \"{}\"\n",
+                thread.name,
+                monitor,
+                owner.unwrap_or("?")
+            ));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Machine-readable rendering of a dump.
+pub(crate) fn thread_dump_json(dump: &ThreadDump) -> serde_json::Value {
+    let threads: Vec<serde_json::Value> = dump
+        .threads
+        .iter()
+        .map(|t| {
+            json!({
+                "name": t.name,
+                "daemon": t.is_daemon,
+                "state": t.state.label(),
+                "frames": t.frames.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+                "locked": t.owned_monitors.iter().map(|(m, depth)| json!({
+                    "monitor": format!("0x{:08x}", m.hash),
+                    "class": m.class_name,
+                    "depth": depth,
+                })).collect::<Vec<_>>(),
+                "waiting_on": t.contended_monitor.as_ref().map(|m| json!({
+                    "monitor": format!("0x{:08x}", m.hash),
+                    "class": m.class_name,
+                })),
+                "blocked_by": t.blocked_on.map(|o| dump.threads[o].name.as_str()),
+            })
+        })
+        .collect();
+    let deadlocks: Vec<Vec<&str>> = dump
+        .deadlocks
+        .iter()
+        .map(|cycle| {
+            cycle
+                .iter()
+                .map(|&t| dump.threads[t].name.as_str())
+                .collect()
+        })
+        .collect();
+
+    json!({
+        "taken_at_ms": dump.taken_at_nanos / 1_000_000,
+        "threads": threads,
+        "deadlocks": deadlocks,
+    })
+}
+
+/// Take a dump and write it as `thread_dump_NNN.txt` and `.json`; report deadlocks.
+pub(crate) fn write_thread_dump(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let dump = take_thread_dump(jvmti_env, jni_env)?;
+    let seq = DUMP_SEQUENCE.fetch_add(1, Ordering::Relaxed) + 1;
+    let base = output_path(&format!("thread_dump_{:03}", seq));
+
+    File::create(format!("{}.txt", base))?.write_all(format_thread_dump(&dump).as_bytes())?;
+    serde_json::to_writer_pretty(
+        File::create(format!("{}.json", base))?,
+        &thread_dump_json(&dump),
+    )?;
+
+    for cycle in &dump.deadlocks {
+        let names: Vec<&str> = cycle
+            .iter()
+            .map(|&t| dump.threads[t].name.as_str())
+            .collect();
+        eprintln!(
+            "💀 Deadlock detected: {} (see {}.txt)",
+            names.join(" -> "),
+            base
+        );
+    }
+    Ok(base)
+}
+
+pub(crate) extern "C" fn data_dump_request_callback(_jvmti_env: *mut jvmtiEnv) {
+    request_thread_dump();
+    if agent_options().snapshot {
+        request_snapshot();
+    }
+}
+
+/// Ask the dumper thread for a thread dump (e.g. from a `DataDumpRequest`).
+pub(crate) fn request_thread_dump() {
+    DUMP_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+unsafe extern "C" fn thread_dumper(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    _arg: *mut c_void,
+) {
+    let interval = agent_options()
+        .thread_dump_interval_secs
+        .map(Duration::from_secs);
+    let mut next_dump = interval.map(|i| Instant::now() + i);
+
+    while !sleep_unless_shut_down(DUMP_POLL_INTERVAL) {
+        let due = next_dump.is_some_and(|at| Instant::now() >= at);
+        if !DUMP_REQUESTED.swap(false, Ordering::Relaxed) && !due {
+            continue;
+        }
+        if let (true, Some(interval)) = (due, interval) {
+            next_dump = Some(Instant::now() + interval);
+        }
+        match write_thread_dump(jvmti_env, jni_env) {
+            Ok(base) => println!("🧾 Thread dump written to {}.txt", base),
+            Err(e) => eprintln!("Error writing thread dump: {}", e),
+        }
+    }
+}
+
+/// Start the agent thread serving on-demand and periodic thread dumps.
+pub(crate) fn start_thread_dumper(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-thread-dumper",
+        thread_dumper,
+        std::ptr::null_mut(),
+    ) {
+        eprintln!("Failed to start thread dumper: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_deadlocks() {
+        // 0 -> 1 -> 2 -> 0 is a deadlock; 3 waits on the cycle but isn't part of it
+        let waits_for = [Some(1), Some(2), Some(0), Some(0), None, Some(6), Some(5)];
+        let mut cycles = find_deadlocks(&waits_for);
+        cycles.iter_mut().for_each(|c| c.sort());
+        assert_eq!(cycles, vec![vec![0, 1, 2], vec![5, 6]]);
+
+        assert!(find_deadlocks(&[Some(1), None, Some(1)]).is_empty());
+    }
+}
@@ -0,0 +1,749 @@
+use once_cell::sync::Lazy;
+use serde_json::json;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::Write;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::agent_thread::{
+    sleep_unless_shut_down, start_agent_thread, AGENT_THREAD_PREFIX,
+};
+use crate::profiling::lines::sample_top_frame;
+use crate::profiling::options::agent_options;
+use crate::profiling::profiling::{elapsed_nanos, format_time};
+
+/// Default thread state polling interval when none is given in the agent options.
+const DEFAULT_POLL_INTERVAL_MS: u64 = 10;
+/// Per-thread cap on recorded state spans; totals keep accumulating past it.
+const MAX_SPANS_PER_THREAD: usize = 10_000;
+/// A thread spending at least this share of its time in one waiting state gets called out.
+const CALLOUT_THRESHOLD_PCT: f64 = 50.0;
+
+/// Coarse Java thread state, folded from the JVMTI thread state bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum ThreadStateKind {
+    Runnable,
+    Blocked,
+    Waiting,
+    TimedWaiting,
+    Parked,
+    Terminated,
+}
+
+/// States shown in reports, in display order
+const REPORTED_STATES: [ThreadStateKind; 5] = [
+    ThreadStateKind::Runnable,
+    ThreadStateKind::Blocked,
+    ThreadStateKind::Waiting,
+    ThreadStateKind::TimedWaiting,
+    ThreadStateKind::Parked,
+];
+
+impl ThreadStateKind {
+    pub(crate) fn from_jvmti(state: jint) -> Self {
+        let state = state as u32;
+        if state & JVMTI_THREAD_STATE_ALIVE == 0 {
+            ThreadStateKind::Terminated
+        } else if state & JVMTI_THREAD_STATE_BLOCKED_ON_MONITOR_ENTER != 0 {
+            ThreadStateKind::Blocked
+        } else if state & JVMTI_THREAD_STATE_PARKED != 0 {
+            ThreadStateKind::Parked
+        } else if state & JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT != 0 {
+            ThreadStateKind::TimedWaiting
+        } else if state & JVMTI_THREAD_STATE_WAITING != 0 {
+            ThreadStateKind::Waiting
+        } else {
+            ThreadStateKind::Runnable
+        }
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            ThreadStateKind::Runnable => "RUNNABLE",
+            ThreadStateKind::Blocked => "BLOCKED",
+            ThreadStateKind::Waiting => "WAITING",
+            ThreadStateKind::TimedWaiting => "TIMED_WAITING",
+            ThreadStateKind::Parked => "PARKED",
+            ThreadStateKind::Terminated => "TERMINATED",
+        }
+    }
+}
+
+/// A stretch of time a thread spent in one state
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct StateSpan {
+    pub(crate) state: ThreadStateKind,
+    pub(crate) start_nanos: u64,
+    pub(crate) end_nanos: u64,
+}
+
+/// Lifetime and sampled state history of one Java thread
+#[derive(Clone, Debug)]
+pub(crate) struct ThreadTimeline {
+    pub(crate) name: String,
+    pub(crate) is_daemon: bool,
+    /// Virtual threads are tracked by mount/unmount rather than by polling
+    pub(crate) is_virtual: bool,
+    pub(crate) started_nanos: u64,
+    pub(crate) ended_nanos: Option<u64>,
+    pub(crate) state_nanos: HashMap<ThreadStateKind, u64>,
+    pub(crate) spans: Vec<StateSpan>,
+    /// Thread CPU time as of the last sample (or thread end)
+    pub(crate) cpu_nanos: u64,
+    current: Option<(ThreadStateKind, u64)>,
+    last_sample_nanos: u64,
+}
+
+impl ThreadTimeline {
+    fn new(name: String, is_daemon: bool, started_nanos: u64) -> Self {
+        Self {
+            name,
+            is_daemon,
+            is_virtual: false,
+            started_nanos,
+            ended_nanos: None,
+            state_nanos: HashMap::new(),
+            spans: Vec::new(),
+            cpu_nanos: 0,
+            current: None,
+            last_sample_nanos: started_nanos,
+        }
+    }
+
+    /// Attribute the time since the previous sample to the previous state, then move on.
+    fn record_sample(&mut self, state: ThreadStateKind, now: u64) {
+        match self.current {
+            Some((previous, since)) => {
+                *self.state_nanos.entry(previous).or_insert(0) +=
+                    now.saturating_sub(self.last_sample_nanos);
+                if previous != state {
+                    self.push_span(previous, since, now);
+                    self.current = Some((state, now));
+                }
+            }
+            None => self.current = Some((state, now)),
+        }
+        self.last_sample_nanos = now;
+    }
+
+    /// Close the open span at `now` (thread end or end of the profile).
+    fn finish(&mut self, now: u64) {
+        if let Some((state, since)) = self.current.take() {
+            *self.state_nanos.entry(state).or_insert(0) +=
+                now.saturating_sub(self.last_sample_nanos);
+            self.push_span(state, since, now);
+        }
+        self.last_sample_nanos = now;
+    }
+
+    fn push_span(&mut self, state: ThreadStateKind, start_nanos: u64, end_nanos: u64) {
+        if self.spans.len() < MAX_SPANS_PER_THREAD {
+            self.spans.push(StateSpan {
+                state,
+                start_nanos,
+                end_nanos,
+            });
+        }
+    }
+
+    /// Forget the recorded history and observe the thread afresh from `now`.
+    fn restart(&mut self, now: u64) {
+        self.started_nanos = now;
+        self.state_nanos.clear();
+        self.spans.clear();
+        self.current = None;
+        self.last_sample_nanos = now;
+    }
+
+    /// Time from thread start (or agent start) to thread end or the last sample.
+    pub(crate) fn lifetime_nanos(&self) -> u64 {
+        self.ended_nanos
+            .unwrap_or(self.last_sample_nanos)
+            .saturating_sub(self.started_nanos)
+    }
+
+    pub(crate) fn observed_nanos(&self) -> u64 {
+        self.state_nanos.values().sum()
+    }
+
+    pub(crate) fn state_pct(&self, state: ThreadStateKind) -> f64 {
+        let observed = self.observed_nanos();
+        if observed == 0 {
+            return 0.0;
+        }
+        *self.state_nanos.get(&state).unwrap_or(&0) as f64 * 100.0 / observed as f64
+    }
+}
+
+/// Global reference to a live `java.lang.Thread`, polled by the sampler thread
+#[derive(Clone, Copy, Debug)]
+struct ThreadRef(jthread);
+unsafe impl Send for ThreadRef {}
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+pub(crate) static THREADS: Lazy<Mutex<HashMap<u64, ThreadTimeline>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static THREAD_REFS: Lazy<Mutex<HashMap<u64, ThreadRef>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Bumped to stop the running poller; each poller runs while the epoch it started in lasts
+static POLLER_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+// Sampling passes, the time they took, and intervals missed because a pass overran
+static SAMPLER_PASSES: AtomicU64 = AtomicU64::new(0);
+static SAMPLER_NANOS: AtomicU64 = AtomicU64::new(0);
+static SAMPLER_MISSED: AtomicU64 = AtomicU64::new(0);
+
+/// Thread state sampler overhead
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SamplerStats {
+    pub(crate) passes: u64,
+    pub(crate) nanos: u64,
+    /// Intervals skipped because a pass took longer than the interval
+    pub(crate) missed: u64,
+}
+
+pub(crate) fn sampler_stats() -> SamplerStats {
+    SamplerStats {
+        passes: SAMPLER_PASSES.load(Ordering::Relaxed),
+        nanos: SAMPLER_NANOS.load(Ordering::Relaxed),
+        missed: SAMPLER_MISSED.load(Ordering::Relaxed),
+    }
+}
+
+/// Our id for a thread, stored in its JVMTI thread-local storage (null `thread` = current).
+pub(crate) fn thread_id(jvmti_env: *mut jvmtiEnv, thread: jthread) -> Option<u64> {
+    unsafe {
+        let mut data: *mut c_void = std::ptr::null_mut();
+        let err = (**jvmti_env).GetThreadLocalStorage.unwrap()(jvmti_env, thread, &mut data);
+        if err != jvmtiError_JVMTI_ERROR_NONE || data.is_null() {
+            None
+        } else {
+            Some(data as u64)
+        }
+    }
+}
+
+/// CPU time consumed so far by the calling thread, if the VM can tell.
+pub(crate) fn current_thread_cpu_nanos(jvmti_env: *mut jvmtiEnv) -> Option<u64> {
+    let mut nanos: jlong = 0;
+    let err = unsafe { (**jvmti_env).GetCurrentThreadCpuTime.unwrap()(jvmti_env, &mut nanos) };
+    (err == jvmtiError_JVMTI_ERROR_NONE).then_some(nanos as u64)
+}
+
+/// CPU clock of the OS thread a method entry or exit ran on. A virtual thread may enter a
+/// method on one carrier and leave it on another, whose clocks can't be subtracted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct CpuReading {
+    os_thread: usize,
+    nanos: u64,
+}
+
+impl CpuReading {
+    /// Read the calling OS thread's CPU clock.
+    pub(crate) fn now(jvmti_env: *mut jvmtiEnv) -> Option<Self> {
+        Some(CpuReading {
+            os_thread: unsafe { libc::pthread_self() } as usize,
+            nanos: current_thread_cpu_nanos(jvmti_env)?,
+        })
+    }
+
+    /// CPU time between two readings, if both were taken on the same OS thread.
+    pub(crate) fn elapsed(entry: Option<Self>, exit: Option<Self>) -> Option<u64> {
+        let (entry, exit) = (entry?, exit?);
+        (entry.os_thread == exit.os_thread).then(|| exit.nanos.saturating_sub(entry.nanos))
+    }
+}
+
+/// CPU time consumed so far by `thread`, if the VM can tell.
+pub(crate) fn thread_cpu_nanos(jvmti_env: *mut jvmtiEnv, thread: jthread) -> Option<u64> {
+    let mut nanos: jlong = 0;
+    let err = unsafe { (**jvmti_env).GetThreadCpuTime.unwrap()(jvmti_env, thread, &mut nanos) };
+    (err == jvmtiError_JVMTI_ERROR_NONE).then_some(nanos as u64)
+}
+
+/// Name and daemon flag of a thread.
+pub(crate) fn thread_info(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    thread: jthread,
+) -> (String, bool) {
+    unsafe {
+        let mut info = std::mem::zeroed::<jvmtiThreadInfo>();
+        let err = (**jvmti_env).GetThreadInfo.unwrap()(jvmti_env, thread, &mut info);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            return ("<unknown>".to_string(), false);
+        }
+
+        let name = if info.name.is_null() {
+            "<unnamed>".to_string()
+        } else {
+            let name = CStr::from_ptr(info.name).to_string_lossy().into_owned();
+            (**jvmti_env).Deallocate.unwrap()(jvmti_env, info.name as *mut u8);
+            name
+        };
+        if !jni_env.is_null() {
+            if !info.thread_group.is_null() {
+                (**jni_env).DeleteLocalRef.unwrap()(jni_env, info.thread_group);
+            }
+            if !info.context_class_loader.is_null() {
+                (**jni_env).DeleteLocalRef.unwrap()(jni_env, info.context_class_loader);
+            }
+        }
+        (name, info.is_daemon != 0)
+    }
+}
+
+/// Start tracking a thread (idempotent) and return its id.
+pub(crate) fn register_thread(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    thread: jthread,
+) -> u64 {
+    if let Some(id) = thread_id(jvmti_env, thread) {
+        return id;
+    }
+
+    let id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    let (name, is_daemon) = thread_info(jvmti_env, jni_env, thread);
+    unsafe {
+        (**jvmti_env).SetThreadLocalStorage.unwrap()(jvmti_env, thread, id as *const c_void);
+    }
+    // The profiler's own threads aren't part of the application
+    if name.starts_with(AGENT_THREAD_PREFIX) {
+        return id;
+    }
+
+    THREADS.lock().unwrap().insert(
+        id,
+        ThreadTimeline::new(name, is_daemon, elapsed_nanos(jvmti_env)),
+    );
+
+    if !jni_env.is_null() {
+        let global = unsafe { (**jni_env).NewGlobalRef.unwrap()(jni_env, thread) };
+        if !global.is_null() {
+            THREAD_REFS.lock().unwrap().insert(id, ThreadRef(global));
+        }
+    }
+    id
+}
+
+/// Start tracking a virtual thread and return its id. Virtual threads can number in the
+/// millions, so they get no global reference and are never polled.
+pub(crate) fn register_virtual_thread(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    vthread: jthread,
+) -> u64 {
+    if let Some(id) = thread_id(jvmti_env, vthread) {
+        return id;
+    }
+
+    let id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+    let (name, _) = thread_info(jvmti_env, jni_env, vthread);
+    unsafe {
+        (**jvmti_env).SetThreadLocalStorage.unwrap()(jvmti_env, vthread, id as *const c_void);
+    }
+
+    // Virtual threads are unnamed unless the application names them
+    let name = if name.is_empty() || name == "<unnamed>" {
+        format!("virtual-{}", id)
+    } else {
+        name
+    };
+    let mut timeline = ThreadTimeline::new(name, true, elapsed_nanos(jvmti_env));
+    timeline.is_virtual = true;
+    THREADS.lock().unwrap().insert(id, timeline);
+    id
+}
+
+/// Record an observed state change for a thread that isn't polled.
+pub(crate) fn record_thread_state(id: u64, state: ThreadStateKind, now: u64) {
+    if let Some(timeline) = THREADS.lock().unwrap().get_mut(&id) {
+        timeline.record_sample(state, now);
+    }
+}
+
+/// Stop tracking a virtual thread that ended and return its name. Unlike platform
+/// threads, ended virtual threads keep no timeline: there can be millions of them.
+pub(crate) fn forget_virtual_thread(id: u64) -> Option<String> {
+    THREADS
+        .lock()
+        .unwrap()
+        .remove(&id)
+        .map(|timeline| timeline.name)
+}
+
+/// Register the threads that were already running when the agent came up.
+pub(crate) fn register_existing_threads(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) -> usize {
+    unsafe {
+        let mut thread_count: jint = 0;
+        let mut threads: *mut jthread = std::ptr::null_mut();
+        let err = (**jvmti_env).GetAllThreads.unwrap()(jvmti_env, &mut thread_count, &mut threads);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            eprintln!("GetAllThreads failed: {}", err);
+            return 0;
+        }
+
+        for &thread in std::slice::from_raw_parts(threads, thread_count as usize) {
+            register_thread(jvmti_env, jni_env, thread);
+            (**jni_env).DeleteLocalRef.unwrap()(jni_env, thread);
+        }
+        (**jvmti_env).Deallocate.unwrap()(jvmti_env, threads as *mut u8);
+        thread_count as usize
+    }
+}
+
+pub(crate) extern "C" fn thread_start_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    thread: jthread,
+) {
+    register_thread(jvmti_env, jni_env, thread);
+}
+
+pub(crate) extern "C" fn thread_end_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    thread: jthread,
+) {
+    let id = match thread_id(jvmti_env, thread) {
+        Some(id) => id,
+        None => return,
+    };
+    let now = elapsed_nanos(jvmti_env);
+    // Threads are often renamed after they start, so pick up the final name
+    let (name, _) = thread_info(jvmti_env, jni_env, thread);
+    // ThreadEnd is delivered on the ending thread itself
+    let cpu_nanos = current_thread_cpu_nanos(jvmti_env);
+
+    if let Some(timeline) = THREADS.lock().unwrap().get_mut(&id) {
+        timeline.name = name;
+        if let Some(cpu_nanos) = cpu_nanos {
+            timeline.cpu_nanos = cpu_nanos;
+        }
+        timeline.finish(now);
+        timeline.ended_nanos = Some(now);
+    }
+
+    if let Some(ThreadRef(global)) = THREAD_REFS.lock().unwrap().remove(&id) {
+        unsafe {
+            (**jni_env).DeleteGlobalRef.unwrap()(jni_env, global);
+        }
+    }
+}
+
+/// Copies of the global references of the polled threads. The copies stay valid after the
+/// lock is released, even if a thread ends and its own reference is deleted meanwhile.
+fn snapshot_thread_refs(jni_env: *mut JNIEnv) -> Vec<(u64, ThreadRef)> {
+    if jni_env.is_null() {
+        return Vec::new();
+    }
+    let refs = THREAD_REFS.lock().unwrap();
+    refs.iter()
+        .filter_map(|(&id, &ThreadRef(thread))| {
+            let copy = unsafe { (**jni_env).NewGlobalRef.unwrap()(jni_env, thread) };
+            (!copy.is_null()).then_some((id, ThreadRef(copy)))
+        })
+        .collect()
+}
+
+/// Poll `GetThreadState` for every live thread and extend their timelines.
+fn sample_thread_states(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    let now = elapsed_nanos(jvmti_env);
+    // JVMTI calls run without THREAD_REFS held, so starting and ending threads don't wait
+    let refs = snapshot_thread_refs(jni_env);
+    let states: Vec<(u64, ThreadStateKind, Option<u64>)> = refs
+        .iter()
+        .filter_map(|&(id, ThreadRef(thread))| {
+            let mut state: jint = 0;
+            let err =
+                unsafe { (**jvmti_env).GetThreadState.unwrap()(jvmti_env, thread, &mut state) };
+            if err != jvmtiError_JVMTI_ERROR_NONE {
+                return None;
+            }
+            // Threads blocked in native code also report RUNNABLE; only sample Java code
+            let kind = ThreadStateKind::from_jvmti(state);
+            if kind == ThreadStateKind::Runnable && state as u32 & JVMTI_THREAD_STATE_IN_NATIVE == 0
+            {
+                sample_top_frame(jvmti_env, thread);
+            }
+            Some((id, kind, thread_cpu_nanos(jvmti_env, thread)))
+        })
+        .collect();
+    for (_, ThreadRef(copy)) in refs {
+        unsafe { (**jni_env).DeleteGlobalRef.unwrap()(jni_env, copy) };
+    }
+
+    let mut threads = THREADS.lock().unwrap();
+    for (id, state, cpu_nanos) in states {
+        if state == ThreadStateKind::Terminated {
+            continue;
+        }
+        if let Some(timeline) = threads.get_mut(&id) {
+            timeline.record_sample(state, now);
+            if let Some(cpu_nanos) = cpu_nanos {
+                timeline.cpu_nanos = cpu_nanos;
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn thread_state_poller(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    arg: *mut c_void,
+) {
+    let interval = Duration::from_millis(
+        agent_options()
+            .sampling_interval_ms
+            .unwrap_or(DEFAULT_POLL_INTERVAL_MS),
+    );
+    let epoch = arg as u64;
+    while POLLER_EPOCH.load(Ordering::Relaxed) == epoch {
+        let started = Instant::now();
+        sample_thread_states(jvmti_env, jni_env);
+        let took = started.elapsed();
+        SAMPLER_PASSES.fetch_add(1, Ordering::Relaxed);
+        SAMPLER_NANOS.fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+        SAMPLER_MISSED.fetch_add(
+            (took.as_nanos() / interval.as_nanos()) as u64,
+            Ordering::Relaxed,
+        );
+        if sleep_unless_shut_down(interval) {
+            return;
+        }
+    }
+}
+
+/// Start the agent thread that polls thread states.
+pub(crate) fn start_thread_state_poller(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    if let Err(e) = start_agent_thread(
+        jvmti_env,
+        jni_env,
+        "rjprof-thread-sampler",
+        thread_state_poller,
+        POLLER_EPOCH.load(Ordering::Relaxed) as *mut c_void,
+    ) {
+        eprintln!("Failed to start thread state sampler: {}", e);
+    }
+}
+
+/// Stop polling and close the open state span of every thread still alive.
+pub(crate) fn stop_thread_state_poller(jvmti_env: *mut jvmtiEnv, jni_env: *mut JNIEnv) {
+    POLLER_EPOCH.fetch_add(1, Ordering::Relaxed);
+    sample_thread_states(jvmti_env, jni_env);
+    let now = elapsed_nanos(jvmti_env);
+    for timeline in THREADS.lock().unwrap().values_mut() {
+        timeline.finish(now);
+    }
+}
+
+/// Drop threads that ended and restart the timelines of the live ones at `now`.
+pub(crate) fn reset_thread_timelines(now: u64) {
+    let mut threads = THREADS.lock().unwrap();
+    threads.retain(|_, timeline| timeline.ended_nanos.is_none());
+    for timeline in threads.values_mut() {
+        timeline.restart(now);
+    }
+}
+
+/// Print each thread's time split across states, and call out threads that mostly wait.
+pub(crate) fn print_thread_summary() {
+    let threads = THREADS.lock().unwrap();
+    let mut timelines: Vec<&ThreadTimeline> = threads
+        .values()
+        .filter(|t| t.observed_nanos() > 0 && !t.is_virtual)
+        .collect();
+    if timelines.is_empty() {
+        return;
+    }
+    timelines.sort_by_key(|t| std::cmp::Reverse(t.observed_nanos()));
+
+    let top = std::cmp::min(timelines.len(), 15);
+    println!(
+        "\n🧵 === Thread states ({} of {} threads) ===",
+        top,
+        threads.values().filter(|t| !t.is_virtual).count()
+    );
+    println!("{:<35} {:>8}   {:>8} | states", "thread", "wall", "CPU");
+    for timeline in timelines.iter().take(top) {
+        let split: Vec<String> = REPORTED_STATES
+            .iter()
+            .filter(|&&state| timeline.state_pct(state) > 0.0)
+            .map(|&state| format!("{} {:.1}%", state.label(), timeline.state_pct(state)))
+            .collect();
+        println!(
+            "{:<35} {:>8}   {:>8} | {}",
+            timeline.name,
+            format_time(timeline.lifetime_nanos()),
+            format_time(timeline.cpu_nanos),
+            split.join(" | ")
+        );
+    }
+
+    for timeline in &timelines {
+        for state in &REPORTED_STATES[1..] {
+            let pct = timeline.state_pct(*state);
+            if pct >= CALLOUT_THRESHOLD_PCT && !timeline.is_daemon {
+                println!("⚠️  {} spent {:.0}% {}", timeline.name, pct, state.label());
+            }
+        }
+    }
+}
+
+/// Per-thread time by state and CPU so far, for snapshots.
+pub(crate) fn thread_states_json() -> serde_json::Value {
+    let threads = THREADS.lock().unwrap();
+    let records: Vec<serde_json::Value> = threads
+        .values()
+        .filter(|t| t.observed_nanos() > 0)
+        .map(|t| {
+            let states: serde_json::Map<String, serde_json::Value> = t
+                .state_nanos
+                .iter()
+                .map(|(state, &nanos)| (state.label().to_string(), json!(nanos)))
+                .collect();
+            json!({
+                "name": t.name,
+                "daemon": t.is_daemon,
+                "virtual": t.is_virtual,
+                "ended": t.ended_nanos.is_some(),
+                "cpu_nanos": t.cpu_nanos,
+                "state_nanos": states,
+            })
+        })
+        .collect();
+    json!(records)
+}
+
+/// Write the state timelines in Chrome trace event format (chrome://tracing, Perfetto).
+pub(crate) fn write_thread_timeline_json(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let threads = THREADS.lock().unwrap();
+    let mut events = Vec::new();
+
+    for (&id, timeline) in threads.iter() {
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": 1,
+            "tid": id,
+            "args": { "name": timeline.name },
+        }));
+        for span in &timeline.spans {
+            events.push(json!({
+                "name": span.state.label(),
+                "cat": "thread-state",
+                "ph": "X",
+                "pid": 1,
+                "tid": id,
+                "ts": span.start_nanos as f64 / 1000.0,
+                "dur": span.end_nanos.saturating_sub(span.start_nanos) as f64 / 1000.0,
+            }));
+        }
+    }
+
+    let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+    let mut file = File::create(path)?;
+    serde_json::to_writer(&mut file, &trace)?;
+    Ok(())
+}
+
+/// Write one line per state span, for spreadsheets and scripts.
+pub(crate) fn write_thread_timeline_csv(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let threads = THREADS.lock().unwrap();
+    let mut ids: Vec<&u64> = threads.keys().collect();
+    ids.sort();
+
+    let mut file = File::create(path)?;
+    writeln!(file, "thread_id,thread_name,state,start_ns,end_ns")?;
+    for id in ids {
+        let timeline = &threads[id];
+        for span in &timeline.spans {
+            writeln!(
+                file,
+                "{},\"{}\",{},{},{}",
+                id,
+                timeline.name.replace('"', "\"\""),
+                span.state.label(),
+                span.start_nanos,
+                span.end_nanos
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_elapsed_on_one_carrier() {
+        let reading = |os_thread, nanos| Some(CpuReading { os_thread, nanos });
+        assert_eq!(
+            CpuReading::elapsed(reading(1, 100), reading(1, 250)),
+            Some(150)
+        );
+        assert_eq!(CpuReading::elapsed(reading(1, 100), reading(2, 250)), None);
+        assert_eq!(CpuReading::elapsed(None, reading(1, 250)), None);
+        assert_eq!(CpuReading::elapsed(reading(1, 100), None), None);
+    }
+
+    #[test]
+    fn test_state_from_jvmti_bits() {
+        let alive = JVMTI_THREAD_STATE_ALIVE;
+        let waiting = alive | JVMTI_THREAD_STATE_WAITING;
+        let cases = [
+            (0, ThreadStateKind::Terminated),
+            (
+                alive | JVMTI_THREAD_STATE_RUNNABLE,
+                ThreadStateKind::Runnable,
+            ),
+            (
+                alive | JVMTI_THREAD_STATE_BLOCKED_ON_MONITOR_ENTER,
+                ThreadStateKind::Blocked,
+            ),
+            (
+                waiting
+                    | JVMTI_THREAD_STATE_WAITING_INDEFINITELY
+                    | JVMTI_THREAD_STATE_IN_OBJECT_WAIT,
+                ThreadStateKind::Waiting,
+            ),
+            (
+                waiting | JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT | JVMTI_THREAD_STATE_SLEEPING,
+                ThreadStateKind::TimedWaiting,
+            ),
+            (
+                waiting | JVMTI_THREAD_STATE_WAITING_WITH_TIMEOUT | JVMTI_THREAD_STATE_PARKED,
+                ThreadStateKind::Parked,
+            ),
+        ];
+        for (bits, expected) in cases {
+            assert_eq!(ThreadStateKind::from_jvmti(bits as jint), expected);
+        }
+    }
+
+    #[test]
+    fn test_timeline_accounting() {
+        let mut timeline = ThreadTimeline::new("worker-5".to_string(), false, 0);
+        timeline.record_sample(ThreadStateKind::Runnable, 0);
+        timeline.record_sample(ThreadStateKind::Blocked, 20);
+        timeline.record_sample(ThreadStateKind::Blocked, 60);
+        timeline.finish(100);
+
+        assert_eq!(timeline.observed_nanos(), 100);
+        assert_eq!(timeline.lifetime_nanos(), 100);
+        assert_eq!(timeline.state_pct(ThreadStateKind::Runnable), 20.0);
+        assert_eq!(timeline.state_pct(ThreadStateKind::Blocked), 80.0);
+        assert_eq!(timeline.spans.len(), 2);
+        assert_eq!(timeline.spans[1].start_nanos, 20);
+        assert_eq!(timeline.spans[1].end_nanos, 100);
+    }
+}
@@ -0,0 +1,444 @@
+use once_cell::sync::Lazy;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+
+use crate::bindings::gen_bindings::*;
+use crate::profiling::extension_events::set_extension_event_callbacks;
+use crate::profiling::profiling::{
+    current_method, elapsed_nanos, format_time, get_method_name_safe, restore_shadow_stacks,
+    take_shadow_stacks, MethodId, ShadowStacks,
+};
+use crate::profiling::threads::{
+    forget_virtual_thread, record_thread_state, register_virtual_thread, thread_id,
+    ThreadStateKind, ThreadTimeline, THREADS,
+};
+
+// HotSpot reports mounts and unmounts as extension events rather than standard ones
+const MOUNT_EVENT_ID: &str = "com.sun.hotspot.events.VirtualThreadMount";
+const UNMOUNT_EVENT_ID: &str = "com.sun.hotspot.events.VirtualThreadUnmount";
+
+/// Scheduling statistics for one virtual thread
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct VirtualThreadStats {
+    pub(crate) mounts: u64,
+    pub(crate) unmounts: u64,
+    pub(crate) mounted_nanos: u64,
+    /// Times the virtual thread blocked while pinned to its carrier
+    pub(crate) pinned: u64,
+    last_mount_nanos: Option<u64>,
+}
+
+impl VirtualThreadStats {
+    fn mount(&mut self, now: u64) {
+        self.mounts += 1;
+        self.last_mount_nanos = Some(now);
+    }
+
+    fn unmount(&mut self, now: u64) {
+        self.unmounts += 1;
+        if let Some(mounted_at) = self.last_mount_nanos.take() {
+            self.mounted_nanos += now.saturating_sub(mounted_at);
+        }
+    }
+}
+
+/// Sums over many virtual threads
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+struct VirtualThreadTotals {
+    threads: u64,
+    mounts: u64,
+    unmounts: u64,
+    mounted_nanos: u64,
+    pinned: u64,
+}
+
+impl VirtualThreadTotals {
+    fn add(&mut self, st: &VirtualThreadStats) {
+        self.threads += 1;
+        self.mounts += st.mounts;
+        self.unmounts += st.unmounts;
+        self.mounted_nanos += st.mounted_nanos;
+        self.pinned += st.pinned;
+    }
+}
+
+/// Ended virtual threads kept by themselves in the reports, busiest first
+const BUSIEST_ENDED_KEPT: usize = 10;
+
+/// Virtual threads that ended: added to the totals, and kept only if among the busiest
+#[derive(Default)]
+struct EndedVirtualThreads {
+    totals: VirtualThreadTotals,
+    busiest: Vec<(u64, VirtualThreadStats)>,
+    /// Names of the busiest, whose timelines are gone
+    names: HashMap<u64, String>,
+}
+
+impl EndedVirtualThreads {
+    fn retire(&mut self, id: u64, name: Option<String>, stats: VirtualThreadStats) {
+        self.totals.add(&stats);
+        self.busiest.push((id, stats));
+        self.busiest
+            .sort_by_key(|(_, st)| std::cmp::Reverse(st.mounted_nanos));
+        self.busiest.truncate(BUSIEST_ENDED_KEPT);
+        if let Some(name) = name {
+            self.names.insert(id, name);
+        }
+        let busiest = &self.busiest;
+        self.names
+            .retain(|id, _| busiest.iter().any(|(kept, _)| kept == id));
+    }
+}
+
+/// Name of a live or a kept ended virtual thread.
+fn virtual_thread_name<'a>(
+    id: u64,
+    threads: &'a HashMap<u64, ThreadTimeline>,
+    ended: &'a EndedVirtualThreads,
+) -> &'a str {
+    threads
+        .get(&id)
+        .map(|t| t.name.as_str())
+        .or_else(|| ended.names.get(&id).map(String::as_str))
+        .unwrap_or("<unknown>")
+}
+
+/// Live virtual threads only; ended ones move to ENDED_VIRTUAL_THREADS
+pub(crate) static VIRTUAL_THREAD_STATS: Lazy<Mutex<HashMap<u64, VirtualThreadStats>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static ENDED_VIRTUAL_THREADS: Lazy<Mutex<EndedVirtualThreads>> =
+    Lazy::new(|| Mutex::new(EndedVirtualThreads::default()));
+
+/// Shadow stacks of unmounted virtual threads, by thread id
+static PARKED_STACKS: Lazy<Mutex<HashMap<u64, ShadowStacks>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Methods holding a monitor or waiting when a virtual thread got pinned
+pub(crate) static PINNING_SITES: Lazy<Mutex<HashMap<MethodId, u64>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// The carrier's own stacks, set aside while a virtual thread runs on it
+thread_local! {
+    static CARRIER_STACKS: RefCell<Option<ShadowStacks>> = const { RefCell::new(None) };
+}
+
+/// Request virtual thread support. Done separately from the other capabilities because
+/// older VMs reject the whole request when one capability is unknown.
+pub(crate) fn enable_virtual_thread_support(jvmti_env: *mut jvmtiEnv) -> bool {
+    unsafe {
+        let mut caps = std::mem::zeroed::<jvmtiCapabilities>();
+        caps.set_can_support_virtual_threads(1);
+        let err = (**jvmti_env).AddCapabilities.unwrap()(jvmti_env, &caps);
+        if err != jvmtiError_JVMTI_ERROR_NONE {
+            return false;
+        }
+    }
+
+    register_mount_events(jvmti_env);
+    true
+}
+
+/// Hook the HotSpot mount/unmount extension events, if this VM has them.
+fn register_mount_events(jvmti_env: *mut jvmtiEnv) {
+    set_extension_event_callbacks(
+        jvmti_env,
+        &[
+            (MOUNT_EVENT_ID, virtual_thread_mount_callback),
+            (UNMOUNT_EVENT_ID, virtual_thread_unmount_callback),
+        ],
+    );
+}
+
+/// Park the carrier's stacks and install `stacks` for the virtual thread taking over.
+fn switch_to_virtual(stacks: Option<ShadowStacks>) {
+    let carrier = take_shadow_stacks();
+    CARRIER_STACKS.with(|c| *c.borrow_mut() = Some(carrier));
+    if let Some(stacks) = stacks {
+        restore_shadow_stacks(stacks);
+    }
+}
+
+/// Hand the OS thread back to its carrier, returning the virtual thread's stacks.
+fn switch_to_carrier() -> ShadowStacks {
+    let stacks = take_shadow_stacks();
+    if let Some(carrier) = CARRIER_STACKS.with(|c| c.borrow_mut().take()) {
+        restore_shadow_stacks(carrier);
+    }
+    stacks
+}
+
+fn on_mount(id: u64, now: u64) {
+    record_thread_state(id, ThreadStateKind::Runnable, now);
+    VIRTUAL_THREAD_STATS
+        .lock()
+        .unwrap()
+        .entry(id)
+        .or_default()
+        .mount(now);
+}
+
+fn on_unmount(id: u64, now: u64) {
+    VIRTUAL_THREAD_STATS
+        .lock()
+        .unwrap()
+        .entry(id)
+        .or_default()
+        .unmount(now);
+}
+
+/// Move an ended virtual thread's stats out of the live map.
+fn on_end(id: u64, name: Option<String>, now: u64) {
+    let Some(mut stats) = VIRTUAL_THREAD_STATS.lock().unwrap().remove(&id) else {
+        return;
+    };
+    stats.unmount(now);
+    ENDED_VIRTUAL_THREADS
+        .lock()
+        .unwrap()
+        .retire(id, name, stats);
+}
+
+/// Start the counts of every live virtual thread over at `now` and forget ended ones.
+pub(crate) fn reset_virtual_thread_stats(now: u64) {
+    for st in VIRTUAL_THREAD_STATS.lock().unwrap().values_mut() {
+        // Still mounted: the new session counts from now
+        let mounted = st.last_mount_nanos.is_some();
+        *st = VirtualThreadStats::default();
+        st.last_mount_nanos = mounted.then_some(now);
+    }
+    *ENDED_VIRTUAL_THREADS.lock().unwrap() = EndedVirtualThreads::default();
+    PINNING_SITES.lock().unwrap().clear();
+}
+
+pub(crate) extern "C" fn virtual_thread_start_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    vthread: jthread,
+) {
+    let id = register_virtual_thread(jvmti_env, jni_env, vthread);
+    switch_to_virtual(None);
+    on_mount(id, elapsed_nanos(jvmti_env));
+}
+
+pub(crate) extern "C" fn virtual_thread_end_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    vthread: jthread,
+) {
+    let now = elapsed_nanos(jvmti_env);
+    drop(switch_to_carrier());
+    if let Some(id) = thread_id(jvmti_env, vthread) {
+        let name = forget_virtual_thread(id);
+        on_end(id, name, now);
+        PARKED_STACKS.lock().unwrap().remove(&id);
+    }
+}
+
+unsafe extern "C" fn virtual_thread_mount_callback(
+    jvmti_env: *mut jvmtiEnv,
+    jni_env: *mut JNIEnv,
+    vthread: jthread,
+) {
+    let id = register_virtual_thread(jvmti_env, jni_env, vthread);
+    let stacks = PARKED_STACKS.lock().unwrap().remove(&id);
+    switch_to_virtual(stacks);
+    on_mount(id, elapsed_nanos(jvmti_env));
+}
+
+unsafe extern "C" fn virtual_thread_unmount_callback(
+    jvmti_env: *mut jvmtiEnv,
+    _jni_env: *mut JNIEnv,
+    vthread: jthread,
+) {
+    let now = elapsed_nanos(jvmti_env);
+    let stacks = switch_to_carrier();
+    if let Some(id) = thread_id(jvmti_env, vthread) {
+        on_unmount(id, now);
+        // Unmounting means the virtual thread blocked (park, sleep, I/O) without its carrier
+        record_thread_state(id, ThreadStateKind::Parked, now);
+        PARKED_STACKS.lock().unwrap().insert(id, stacks);
+    }
+}
+
+/// A virtual thread blocking on a monitor can't unmount, so it pins its carrier.
+/// Count a monitor wait or contended enter as pinning if it happens on a virtual thread.
+pub(crate) fn record_pinning(jvmti_env: *mut jvmtiEnv, thread: jthread) {
+    let id = match thread_id(jvmti_env, thread) {
+        Some(id) => id,
+        None => return,
+    };
+    let mut stats = VIRTUAL_THREAD_STATS.lock().unwrap();
+    let entry = match stats.get_mut(&id) {
+        Some(entry) => entry,
+        None => return, // platform thread
+    };
+    entry.pinned += 1;
+    drop(stats);
+
+    if let Some(method) = current_method() {
+        *PINNING_SITES.lock().unwrap().entry(method).or_insert(0) += 1;
+    }
+}
+
+/// Totals over live and ended virtual threads, and the busiest of both by mounted time.
+fn summarize(
+    stats: &HashMap<u64, VirtualThreadStats>,
+    ended: &EndedVirtualThreads,
+) -> (VirtualThreadTotals, Vec<(u64, VirtualThreadStats)>) {
+    let mut totals = ended.totals;
+    for st in stats.values() {
+        totals.add(st);
+    }
+    let mut busiest: Vec<(u64, VirtualThreadStats)> = stats
+        .iter()
+        .map(|(&id, &st)| (id, st))
+        .chain(ended.busiest.iter().copied())
+        .collect();
+    busiest.sort_by_key(|&(id, st)| (std::cmp::Reverse(st.mounted_nanos), id));
+    (totals, busiest)
+}
+
+/// Print mount/unmount totals, the busiest virtual threads and where pinning happened.
+pub(crate) fn print_virtual_thread_summary(jvmti_env: *mut jvmtiEnv) {
+    let ended = ENDED_VIRTUAL_THREADS.lock().unwrap();
+    let (totals, busiest) = summarize(&VIRTUAL_THREAD_STATS.lock().unwrap(), &ended);
+    if totals.threads == 0 {
+        return;
+    }
+
+    println!("\n🪡 === Virtual threads ===");
+    println!(
+        "{} virtual threads, {} mounts, {} unmounts, {} mounted, {} pinning events",
+        totals.threads,
+        totals.mounts,
+        totals.unmounts,
+        format_time(totals.mounted_nanos),
+        totals.pinned
+    );
+
+    let threads = THREADS.lock().unwrap();
+    for (id, st) in busiest.iter().take(10) {
+        let name = virtual_thread_name(*id, &threads, &ended);
+        println!(
+            "{:<35} {:>6} mounts | {:>8} mounted | {:>4} pinned",
+            name,
+            st.mounts,
+            format_time(st.mounted_nanos),
+            st.pinned
+        );
+    }
+
+    let sites = PINNING_SITES.lock().unwrap();
+    if !sites.is_empty() {
+        let mut sites: Vec<(&MethodId, &u64)> = sites.iter().collect();
+        sites.sort_by_key(|&(_, &n)| std::cmp::Reverse(n));
+        println!("\n📌 Pinned in (synchronized blocks / Object.wait):");
+        for (MethodId(method), n) in sites.iter().take(10) {
+            let name =
+                get_method_name_safe(jvmti_env, *method).unwrap_or_else(|| "<unknown>".into());
+            println!("{:<60} {:>6} times", name, n);
+        }
+    }
+}
+
+/// Write one line per live virtual thread and per busiest ended one with its scheduling
+/// statistics, then the totals over all of them.
+pub(crate) fn write_virtual_threads(path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let ended = ENDED_VIRTUAL_THREADS.lock().unwrap();
+    let (totals, mut listed) = summarize(&VIRTUAL_THREAD_STATS.lock().unwrap(), &ended);
+    if totals.threads == 0 {
+        return Ok(());
+    }
+    let threads = THREADS.lock().unwrap();
+    listed.sort_by_key(|&(id, _)| id);
+
+    let mut file = File::create(path)?;
+    writeln!(
+        file,
+        "{:<30} {:>8} {:>8} {:>12} {:>8}",
+        "thread", "mounts", "unmounts", "mounted", "pinned"
+    )?;
+    for (id, st) in &listed {
+        let name = virtual_thread_name(*id, &threads, &ended);
+        writeln!(
+            file,
+            "{:<30} {:>8} {:>8} {:>12} {:>8}",
+            name,
+            st.mounts,
+            st.unmounts,
+            format_time(st.mounted_nanos),
+            st.pinned
+        )?;
+    }
+    writeln!(
+        file,
+        "{:<30} {:>8} {:>8} {:>12} {:>8}",
+        format!("total ({} threads)", totals.threads),
+        totals.mounts,
+        totals.unmounts,
+        format_time(totals.mounted_nanos),
+        totals.pinned
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mount_accounting() {
+        let mut stats = VirtualThreadStats::default();
+        stats.mount(100);
+        stats.unmount(150);
+        stats.mount(400);
+        stats.unmount(430);
+        assert_eq!(stats.mounts, 2);
+        assert_eq!(stats.unmounts, 2);
+        assert_eq!(stats.mounted_nanos, 80);
+    }
+
+    #[test]
+    fn test_ended_threads_stay_bounded() {
+        let mut ended = EndedVirtualThreads::default();
+        for id in 0..BUSIEST_ENDED_KEPT as u64 * 3 {
+            let stats = VirtualThreadStats {
+                mounts: 1,
+                mounted_nanos: id * 10,
+                ..Default::default()
+            };
+            ended.retire(id, Some(format!("virtual-{}", id)), stats);
+        }
+        assert_eq!(ended.totals.threads, BUSIEST_ENDED_KEPT as u64 * 3);
+        assert_eq!(ended.totals.mounts, BUSIEST_ENDED_KEPT as u64 * 3);
+        assert_eq!(ended.busiest.len(), BUSIEST_ENDED_KEPT);
+        assert_eq!(ended.busiest[0].0, BUSIEST_ENDED_KEPT as u64 * 3 - 1);
+        // Only the kept threads' names stay
+        assert_eq!(ended.names.len(), BUSIEST_ENDED_KEPT);
+        let threads = HashMap::new();
+        let id = BUSIEST_ENDED_KEPT as u64 * 3 - 1;
+        assert_eq!(
+            virtual_thread_name(id, &threads, &ended),
+            format!("virtual-{}", id)
+        );
+        assert_eq!(virtual_thread_name(0, &threads, &ended), "<unknown>");
+
+        let mut live = HashMap::new();
+        live.insert(
+            1000,
+            VirtualThreadStats {
+                mounts: 5,
+                mounted_nanos: 1_000_000,
+                ..Default::default()
+            },
+        );
+        let (totals, busiest) = summarize(&live, &ended);
+        assert_eq!(totals.threads, BUSIEST_ENDED_KEPT as u64 * 3 + 1);
+        assert_eq!(totals.mounts, BUSIEST_ENDED_KEPT as u64 * 3 + 5);
+        assert_eq!(busiest[0].0, 1000);
+    }
+}